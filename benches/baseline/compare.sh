@@ -0,0 +1,32 @@
+#!/bin/sh
+# Time the interpreter of the current tree against a baseline revision, the first commit unless
+# one is given, on the loop in runner.rs.
+#
+#     benches/baseline/compare.sh [revision]
+set -e
+
+root=$(git rev-parse --show-toplevel)
+revision=${1:-$(git -C "$root" rev-list --max-parents=0 HEAD)}
+scratch=$(mktemp -d)
+trap 'git -C "$root" worktree remove --force "$scratch/baseline"; rm -rf "$scratch"' EXIT
+git -C "$root" worktree add --quiet --detach "$scratch/baseline" "$revision"
+
+for tree in "$scratch/baseline" "$root"; do
+    runner="$scratch/runner-$(basename "$tree")"
+    mkdir -p "$runner/src"
+    cp "$root/benches/baseline/runner.rs" "$runner/src/main.rs"
+    cat > "$runner/Cargo.toml" <<TOML
+[package]
+name = "runner"
+version = "0.1.0"
+edition = "2018"
+
+[dependencies]
+emulator-rs = { path = "$tree" }
+
+[workspace]
+TOML
+    printf '%s: ' "$(git -C "$tree" log -1 --format='%h %s')"
+    CARGO_TARGET_DIR="$scratch/target" cargo run --quiet --release \
+        --manifest-path "$runner/Cargo.toml" 2>&1 >/dev/null | tail -n 1
+done
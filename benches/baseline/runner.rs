@@ -0,0 +1,39 @@
+//! Times `LOOP` on a bare hart, RAM and nothing else, using only API the baseline revision has as
+//! well. Built against both trees by `compare.sh`.
+
+use emulator_rs::frontend::rv32i::cpu::{CPUStatus, CPU};
+use std::time::Instant;
+
+/// A store, a load and a counter incremented until it reaches t1
+const LOOP: [u32; 5] = [
+    0x00128293, // loop: addi t0, t0, 1
+    0x005E2023, // sw t0, 0(t3)
+    0x000E2383, // lw t2, 0(t3)
+    0xFE629AE3, // bne t0, t1, loop
+    0x00000073, // ecall
+];
+
+const ITERATIONS: u32 = 1_000_000;
+
+fn main() {
+    let path = std::env::temp_dir().join(format!("emulator-rs-loop-{}.bin", std::process::id()));
+    let bytes: Vec<u8> = LOOP.iter().flat_map(|instr| instr.to_le_bytes().to_vec()).collect();
+    std::fs::write(&path, bytes).unwrap();
+
+    let mut best = f64::MAX;
+    for _ in 0..10 {
+        let mut cpu = CPU::new(0, 0x2000);
+        cpu.load_raw_assembly(path.to_str().unwrap().to_string());
+        cpu.get_registers()[6] = ITERATIONS;
+        cpu.get_registers()[28] = 0x1000;
+        let start = Instant::now();
+        let mut steps = 0u64;
+        while let Ok(CPUStatus::Continue) = cpu.run_for_steps(1) {
+            steps += 1;
+        }
+        assert_eq!(steps, ITERATIONS as u64 * 4);
+        best = best.min(start.elapsed().as_secs_f64() * 1e9 / steps as f64);
+    }
+    std::fs::remove_file(&path).unwrap();
+    eprintln!("{:.1} ns per instruction", best);
+}
@@ -0,0 +1,51 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+use emulator_rs::frontend::rv32i::cpu::{CPUStatus, CPU};
+use emulator_rs::frontend::rv32i::machine::MachineConfig;
+
+const BINARIES: [&str; 4] = ["add", "beq", "jal", "sw"];
+
+/// A hart booted into a compliance binary, cloned for every iteration, and its entry point
+fn booted(name: &str) -> (CPU, u32) {
+    let mut cpu = CPU::with_config(&MachineConfig {
+        memory_size: 16384,
+        uart: false,
+        ..MachineConfig::default()
+    });
+    let entry_point = cpu.load_elf(format!("tests/rv32i-compliance/{}", name));
+    cpu.boot(entry_point, "").unwrap();
+    (cpu, entry_point)
+}
+
+fn run_to_halt(mut cpu: CPU) -> CPU {
+    while cpu.step().unwrap() == CPUStatus::Continue {}
+    cpu
+}
+
+fn compliance(c: &mut Criterion) {
+    for name in BINARIES.iter() {
+        let mut group = c.benchmark_group(format!("compliance/{}", name));
+        for &(label, cached, warm) in [
+            ("decode", false, false),
+            ("block_cache_cold", true, false),
+            ("block_cache_warm", true, true),
+        ]
+        .iter()
+        {
+            let (mut cpu, entry_point) = booted(name);
+            cpu.set_block_cache(cached);
+            if warm {
+                // Run once to fill the cache, then boot the same image again
+                cpu = run_to_halt(cpu);
+                cpu.boot(entry_point, "").unwrap();
+            }
+            group.bench_function(label, |b| {
+                b.iter_batched(|| cpu.clone(), run_to_halt, BatchSize::SmallInput)
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, compliance);
+criterion_main!(benches);
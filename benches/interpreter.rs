@@ -0,0 +1,90 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+use emulator_rs::frontend::rv32i::cpu::{CPUStatus, CPU};
+use emulator_rs::frontend::rv32i::machine::MachineConfig;
+use emulator_rs::frontend::rv32i::mem::Mem;
+
+const BINARIES: [&str; 4] = ["add", "beq", "jal", "sw"];
+
+/// A hart booted into a compliance binary, with or without the block cache, cloned for every
+/// iteration
+fn booted(name: &str, cached: bool) -> CPU {
+    let mut cpu = CPU::with_config(&MachineConfig {
+        memory_size: 16384,
+        uart: false,
+        block_cache: cached,
+        ..MachineConfig::default()
+    });
+    let entry_point = cpu.load_elf(format!("tests/rv32i-compliance/{}", name));
+    cpu.boot(entry_point, "").unwrap();
+    if cached {
+        // Run once to fill the cache so only execution is measured, then boot the image again
+        cpu = run_to_halt(cpu);
+        cpu.boot(entry_point, "").unwrap();
+    }
+    cpu
+}
+
+fn run_to_halt(mut cpu: CPU) -> CPU {
+    while cpu.step().unwrap() == CPUStatus::Continue {}
+    cpu
+}
+
+fn interpreters(c: &mut Criterion) {
+    for name in BINARIES.iter() {
+        let mut group = c.benchmark_group(format!("interpreter/{}", name));
+        for &(label, cached) in [("decode", false), ("block_cache", true)].iter() {
+            let cpu = booted(name, cached);
+            group.bench_function(label, |b| {
+                b.iter_batched(|| cpu.clone(), run_to_halt, BatchSize::SmallInput)
+            });
+        }
+        group.finish();
+    }
+}
+
+/// A store, a load and a counter incremented until it reaches t1
+const LOOP: [u32; 5] = [
+    0x00128293, // loop: addi t0, t0, 1
+    0x005E2023, // sw t0, 0(t3)
+    0x000E2383, // lw t2, 0(t3)
+    0xFE629AE3, // bne t0, t1, loop
+    0x00000073, // ecall
+];
+
+/// A hart about to run `LOOP` for `iterations`, with either the block cache or the JIT
+fn looping(iterations: u32, jit: bool) -> CPU {
+    let config = MachineConfig {
+        memory_size: 16384,
+        uart: false,
+        block_cache: true,
+        jit,
+        ..MachineConfig::default()
+    };
+    let mut cpu = CPU::with_config(&config);
+    for (index, instr) in LOOP.iter().enumerate() {
+        cpu.get_memory()
+            .write_word(config.memory_base + index as u32 * 4, *instr)
+            .unwrap();
+    }
+    let registers = cpu.get_registers();
+    registers.set_pc(config.memory_base);
+    registers[6] = iterations;
+    registers[28] = config.memory_base + 0x1000;
+    cpu
+}
+
+/// Cloning a hart drops its compiled code, so compiling the loop is part of every iteration
+fn jit(c: &mut Criterion) {
+    let mut group = c.benchmark_group("jit/loop");
+    for &(label, jit) in [("block_cache", false), ("jit", true)].iter() {
+        let cpu = looping(100_000, jit);
+        group.bench_function(label, |b| {
+            b.iter_batched(|| cpu.clone(), run_to_halt, BatchSize::SmallInput)
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, interpreters, jit);
+criterion_main!(benches);
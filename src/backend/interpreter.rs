@@ -0,0 +1,305 @@
+//! Reference backend for the IR: executes blocks operation by operation on any `Guest`. It is
+//! slower than the native interpreters and exists to check that lifted code means what the
+//! frontend's own interpreter does.
+
+use crate::ir::{Block, Exit, Guest, Misaligned, Op, Width};
+
+/// What the guest does after a block ran
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    Continue,
+    Syscall,
+    Breakpoint,
+    /// The frontend has to execute the instruction at the pc itself
+    Unlifted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Outcome {
+    /// Guest instructions executed completely
+    pub retired: u32,
+    pub event: Event,
+}
+
+fn extend(value: u32, width: Width) -> u32 {
+    match width {
+        Width::Byte => value as u8 as i8 as u32,
+        Width::Half => value as u16 as i16 as u32,
+        Width::Word => value,
+    }
+}
+
+/// Run `block`, leaving the pc of `guest` where execution continues. When an operation faults,
+/// the pc is left at the instruction it belongs to, after everything before it took effect.
+pub fn run<G: Guest>(block: &Block, guest: &mut G) -> Result<Outcome, G::Error> {
+    let mut values = vec![0; block.values as usize];
+    let mut pc = block.start;
+    // Instructions are counted once the next one starts, or the block exits
+    let mut retired = 0;
+    let mut started = false;
+    // Set when a store hits code, the rest of the block may be stale
+    let mut stale = false;
+
+    for op in &block.ops {
+        match *op {
+            Op::Guest(addr) => {
+                retired += started as u32;
+                started = true;
+                pc = addr;
+                if stale {
+                    guest.set_pc(pc);
+                    return Ok(Outcome {
+                        retired,
+                        event: Event::Continue,
+                    });
+                }
+            }
+            Op::Const { dst, value } => values[dst.0 as usize] = value,
+            Op::ReadReg { dst, reg } => values[dst.0 as usize] = guest.read_reg(reg),
+            Op::WriteReg { reg, src } => guest.write_reg(reg, values[src.0 as usize]),
+            Op::ReadSys { dst, reg } => values[dst.0 as usize] = guest.read_sys(reg),
+            Op::WriteSys { reg, src } => guest.write_sys(reg, values[src.0 as usize]),
+            Op::Binary { dst, op, lhs, rhs } => {
+                values[dst.0 as usize] = op.apply(values[lhs.0 as usize], values[rhs.0 as usize])
+            }
+            Op::Load {
+                dst,
+                addr,
+                width,
+                signed,
+            } => match guest.load(values[addr.0 as usize], width) {
+                Ok(value) if signed => values[dst.0 as usize] = extend(value, width),
+                Ok(value) => values[dst.0 as usize] = value,
+                Err(err) => {
+                    guest.set_pc(pc);
+                    return Err(err);
+                }
+            },
+            Op::Store { addr, src, width } => {
+                let addr = values[addr.0 as usize];
+                if let Err(err) = guest.store(addr, values[src.0 as usize], width) {
+                    guest.set_pc(pc);
+                    return Err(err);
+                }
+                stale |= guest.code_modified();
+            }
+            Op::CheckAlign { addr, align } => {
+                let addr = values[addr.0 as usize];
+                if addr % align != 0 {
+                    guest.set_pc(pc);
+                    return Err(Misaligned(addr).into());
+                }
+            }
+            Op::FlushCode => guest.flush_code(),
+        }
+    }
+
+    retired += started as u32;
+    let (next, event) = match block.exit {
+        Exit::Jump(target) => (values[target.0 as usize], Event::Continue),
+        Exit::Branch {
+            cond,
+            taken,
+            not_taken,
+        } => {
+            let next = if values[cond.0 as usize] != 0 {
+                taken
+            } else {
+                not_taken
+            };
+            (next, Event::Continue)
+        }
+        Exit::Syscall { next } => (next, Event::Syscall),
+        Exit::Breakpoint { next } => (next, Event::Breakpoint),
+        Exit::Unlifted(addr) => (addr, Event::Unlifted),
+    };
+    guest.set_pc(next);
+    Ok(Outcome { retired, event })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{BinOp, Builder, Reg};
+
+    use std::collections::HashMap;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum TestError {
+        Misaligned(u32),
+        Unmapped(u32),
+    }
+
+    impl From<Misaligned> for TestError {
+        fn from(err: Misaligned) -> Self {
+            TestError::Misaligned(err.0)
+        }
+    }
+
+    /// 16 registers and 256 bytes of memory, of which the first 16 hold code
+    #[derive(Default)]
+    struct TestGuest {
+        regs: [u32; 16],
+        memory: HashMap<u32, u8>,
+        pc: u32,
+        code_written: bool,
+    }
+
+    impl Guest for TestGuest {
+        type Error = TestError;
+
+        fn read_reg(&mut self, reg: Reg) -> u32 {
+            self.regs[reg as usize]
+        }
+
+        fn write_reg(&mut self, reg: Reg, value: u32) {
+            self.regs[reg as usize] = value;
+        }
+
+        fn read_sys(&mut self, _: u16) -> u32 {
+            0
+        }
+
+        fn write_sys(&mut self, _: u16, _: u32) {}
+
+        fn load(&mut self, addr: u32, width: Width) -> Result<u32, TestError> {
+            (0..width.bytes()).try_fold(0, |value, byte| {
+                if addr + byte >= 256 {
+                    return Err(TestError::Unmapped(addr));
+                }
+                let data = *self.memory.get(&(addr + byte)).unwrap_or(&0) as u32;
+                Ok(value | data << (byte * 8))
+            })
+        }
+
+        fn store(&mut self, addr: u32, value: u32, width: Width) -> Result<(), TestError> {
+            for byte in 0..width.bytes() {
+                if addr + byte >= 256 {
+                    return Err(TestError::Unmapped(addr));
+                }
+                self.memory.insert(addr + byte, (value >> (byte * 8)) as u8);
+                self.code_written |= addr + byte < 16;
+            }
+            Ok(())
+        }
+
+        fn code_modified(&mut self) -> bool {
+            std::mem::take(&mut self.code_written)
+        }
+
+        fn flush_code(&mut self) {}
+
+        fn set_pc(&mut self, pc: u32) {
+            self.pc = pc;
+        }
+    }
+
+    /// r1 = r1 + 1 at 0, store r1 to [r2] at 4, r3 = r1 at 8, then jump to r3
+    fn increment_and_store() -> Block {
+        let mut builder = Builder::new();
+        builder.guest(0);
+        let value = builder.read_reg(1);
+        let one = builder.constant(1);
+        let sum = builder.binary(BinOp::Add, value, one);
+        builder.write_reg(1, sum);
+        builder.guest(4);
+        let addr = builder.read_reg(2);
+        builder.store(addr, sum, Width::Word);
+        builder.guest(8);
+        builder.write_reg(3, sum);
+        builder.finish(0, Exit::Jump(sum))
+    }
+
+    #[test]
+    fn runs_to_the_exit() {
+        let mut guest = TestGuest::default();
+        guest.regs[1] = 0x3F;
+        guest.regs[2] = 0x80;
+        let outcome = run(&increment_and_store(), &mut guest).unwrap();
+
+        assert_eq!(
+            outcome,
+            Outcome {
+                retired: 3,
+                event: Event::Continue
+            }
+        );
+        assert_eq!(guest.pc, 0x40);
+        assert_eq!(guest.regs[3], 0x40);
+        assert_eq!(guest.load(0x80, Width::Word), Ok(0x40));
+    }
+
+    #[test]
+    fn faults_stop_at_the_instruction() {
+        let mut guest = TestGuest::default();
+        guest.regs[2] = 0x100;
+        let err = run(&increment_and_store(), &mut guest).unwrap_err();
+
+        assert_eq!(err, TestError::Unmapped(0x100));
+        assert_eq!(guest.pc, 4);
+        assert_eq!(guest.regs[1], 1);
+        assert_eq!(guest.regs[3], 0);
+    }
+
+    #[test]
+    fn stores_to_code_end_the_block() {
+        let mut guest = TestGuest::default();
+        guest.regs[2] = 8;
+        let outcome = run(&increment_and_store(), &mut guest).unwrap();
+
+        assert_eq!(outcome.retired, 2);
+        assert_eq!(guest.pc, 8);
+        assert_eq!(guest.regs[3], 0);
+    }
+
+    #[test]
+    fn loads_are_extended() {
+        let mut guest = TestGuest::default();
+        guest.store(0x40, 0x8080, Width::Half).unwrap();
+        let mut builder = Builder::new();
+        builder.guest(0);
+        let addr = builder.constant(0x40);
+        let signed = builder.load(addr, Width::Byte, true);
+        let unsigned = builder.load(addr, Width::Half, false);
+        builder.write_reg(1, signed);
+        builder.write_reg(2, unsigned);
+        let next = builder.constant(4);
+        run(&builder.finish(0, Exit::Jump(next)), &mut guest).unwrap();
+
+        assert_eq!(guest.regs[1], 0xFFFF_FF80);
+        assert_eq!(guest.regs[2], 0x8080);
+    }
+
+    #[test]
+    fn misaligned_addresses_fault() {
+        let mut guest = TestGuest::default();
+        let mut builder = Builder::new();
+        builder.guest(0x10);
+        let target = builder.constant(0x22);
+        builder.check_align(target, 4);
+        let block = builder.finish(0x10, Exit::Jump(target));
+
+        assert_eq!(run(&block, &mut guest), Err(TestError::Misaligned(0x22)));
+        assert_eq!(guest.pc, 0x10);
+    }
+
+    #[test]
+    fn unlifted_instructions_are_not_retired() {
+        let mut guest = TestGuest::default();
+        let mut builder = Builder::new();
+        builder.guest(0);
+        let value = builder.constant(7);
+        builder.write_reg(1, value);
+        let block = builder.finish(0, Exit::Unlifted(4));
+
+        let outcome = run(&block, &mut guest).unwrap();
+        assert_eq!(
+            outcome,
+            Outcome {
+                retired: 1,
+                event: Event::Unlifted
+            }
+        );
+        assert_eq!(guest.pc, 4);
+    }
+}
@@ -0,0 +1,8 @@
+//! Execution of guest code other than by the frontends' own interpreters: host code generation
+//! for the guest architectures in `frontend`, an interpreter for the IR and Rust source for
+//! recompiling it ahead of time
+
+pub mod interpreter;
+pub mod rust;
+#[cfg(target_arch = "x86_64")]
+pub mod x86_64;
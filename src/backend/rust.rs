@@ -0,0 +1,335 @@
+//! Rust source backend for the IR, for recompiling guest code ahead of time. Each block becomes a
+//! function over an `ir::Guest` with the same behaviour as `backend::interpreter::run` on it,
+//! and `dispatch` calls the function of the block starting at a given address.
+
+use crate::ir::{BinOp, Block, Exit, Op, Value, Width};
+
+use std::fmt::Write;
+
+/// Used by generated code to leave the pc at the instruction whose memory access failed
+pub fn fault_at<G: crate::ir::Guest, T>(
+    result: Result<T, G::Error>,
+    guest: &mut G,
+    pc: u32,
+) -> Result<T, G::Error> {
+    if result.is_err() {
+        guest.set_pc(pc);
+    }
+    result
+}
+
+fn binary(op: BinOp, lhs: Value, rhs: Value) -> String {
+    let (lhs, rhs) = (format!("v{}", lhs.0), format!("v{}", rhs.0));
+    match op {
+        BinOp::Add => format!("{}.wrapping_add({})", lhs, rhs),
+        BinOp::Sub => format!("{}.wrapping_sub({})", lhs, rhs),
+        BinOp::And => format!("{} & {}", lhs, rhs),
+        BinOp::Or => format!("{} | {}", lhs, rhs),
+        BinOp::Xor => format!("{} ^ {}", lhs, rhs),
+        BinOp::Shl => format!("{} << ({} & 0x1f)", lhs, rhs),
+        BinOp::Shr => format!("{} >> ({} & 0x1f)", lhs, rhs),
+        BinOp::Sar => format!("(({} as i32) >> ({} & 0x1f)) as u32", lhs, rhs),
+        BinOp::Eq => format!("({} == {}) as u32", lhs, rhs),
+        BinOp::Ne => format!("({} != {}) as u32", lhs, rhs),
+        BinOp::Lt => format!("(({} as i32) < ({} as i32)) as u32", lhs, rhs),
+        BinOp::Ltu => format!("({} < {}) as u32", lhs, rhs),
+        BinOp::Ge => format!("(({} as i32) >= ({} as i32)) as u32", lhs, rhs),
+        BinOp::Geu => format!("({} >= {}) as u32", lhs, rhs),
+    }
+}
+
+fn width(width: Width) -> &'static str {
+    match width {
+        Width::Byte => "Width::Byte",
+        Width::Half => "Width::Half",
+        Width::Word => "Width::Word",
+    }
+}
+
+/// Uses of each value, so values nobody reads aren't bound to variables the compiler warns about
+fn uses(block: &Block) -> Vec<u32> {
+    let mut uses = vec![0; block.values as usize];
+    let mut used = |value: Value| uses[value.0 as usize] += 1;
+    for op in &block.ops {
+        match *op {
+            Op::WriteReg { src, .. } | Op::WriteSys { src, .. } => used(src),
+            Op::Binary { lhs, rhs, .. } => {
+                used(lhs);
+                used(rhs);
+            }
+            Op::Load { addr, .. } | Op::CheckAlign { addr, .. } => used(addr),
+            Op::Store { addr, src, .. } => {
+                used(addr);
+                used(src);
+            }
+            _ => {}
+        }
+    }
+    match block.exit {
+        Exit::Jump(target) => used(target),
+        Exit::Branch { cond, .. } => used(cond),
+        _ => {}
+    }
+    uses
+}
+
+/// Name of the function generated for the block at `start`
+pub fn block_name(start: u32) -> String {
+    format!("block_{:08x}", start)
+}
+
+/// The function implementing `block`
+pub fn emit_block(block: &Block) -> String {
+    let uses = uses(block);
+    let bind = |value: Value| {
+        if uses[value.0 as usize] > 0 {
+            format!("let v{} = ", value.0)
+        } else {
+            String::new()
+        }
+    };
+    // Stores only need to check for writes to code when another instruction of the block follows
+    let last_marker = block.ops.iter().rposition(|op| matches!(op, Op::Guest(_)));
+    let checked = |index: usize| last_marker.is_some_and(|last| index < last);
+    let stores = block
+        .ops
+        .iter()
+        .enumerate()
+        .any(|(index, op)| matches!(op, Op::Store { .. }) && checked(index));
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "fn {}<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {{",
+        block_name(block.start)
+    )
+    .unwrap();
+    if stores {
+        out.push_str("    let mut stale = false;\n");
+    }
+    let mut pc = block.start;
+    let mut retired = 0;
+    let mut stored = false;
+    for (index, op) in block.ops.iter().enumerate() {
+        let line = match *op {
+            Op::Guest(addr) => {
+                // A store to code makes the rest of the block stale, like in the interpreter
+                if stored {
+                    writeln!(
+                        out,
+                        "    if stale {{\n        guest.set_pc({:#010x});\n        \
+                         return Ok(Outcome {{ retired: {}, event: Event::Continue }});\n    }}",
+                        addr, retired
+                    )
+                    .unwrap();
+                }
+                pc = addr;
+                retired += 1;
+                format!("// {:#010x}", addr)
+            }
+            Op::Const { dst, value } if uses[dst.0 as usize] > 0 => {
+                format!("let v{}: u32 = {:#x};", dst.0, value)
+            }
+            Op::Const { .. } => continue,
+            Op::ReadReg { dst, reg } if uses[dst.0 as usize] > 0 => {
+                format!("let v{} = guest.read_reg({});", dst.0, reg)
+            }
+            Op::ReadReg { .. } => continue,
+            Op::WriteReg { reg, src } => format!("guest.write_reg({}, v{});", reg, src.0),
+            Op::ReadSys { dst, reg } => format!("{}guest.read_sys({:#x});", bind(dst), reg),
+            Op::WriteSys { reg, src } => format!("guest.write_sys({:#x}, v{});", reg, src.0),
+            Op::Binary { dst, op, lhs, rhs } if uses[dst.0 as usize] > 0 => {
+                format!("let v{} = {};", dst.0, binary(op, lhs, rhs))
+            }
+            Op::Binary { .. } => continue,
+            Op::Load {
+                dst,
+                addr,
+                width: size,
+                signed,
+            } => {
+                let used = uses[dst.0 as usize] > 0;
+                let extend = match (signed && used, size) {
+                    (true, Width::Byte) => " as u8 as i8 as u32",
+                    (true, Width::Half) => " as u16 as i16 as u32",
+                    _ => "",
+                };
+                format!(
+                    "{}fault_at(guest.load(v{}, {}), guest, {:#010x})?{};",
+                    bind(dst),
+                    addr.0,
+                    width(size),
+                    pc,
+                    extend
+                )
+            }
+            Op::Store {
+                addr,
+                src,
+                width: size,
+            } => {
+                let mut line = format!(
+                    "fault_at(guest.store(v{}, v{}, {}), guest, {:#010x})?;",
+                    addr.0,
+                    src.0,
+                    width(size),
+                    pc
+                );
+                if checked(index) {
+                    stored = true;
+                    line.push_str("\n    stale |= guest.code_modified();");
+                }
+                line
+            }
+            Op::CheckAlign { addr, align } => format!(
+                "if v{} % {} != 0 {{\n        guest.set_pc({:#010x});\n        \
+                 return Err(Misaligned(v{}).into());\n    }}",
+                addr.0, align, pc, addr.0
+            ),
+            Op::FlushCode => "guest.flush_code();".to_string(),
+        };
+        writeln!(out, "    {}", line).unwrap();
+    }
+
+    let (next, event) = match block.exit {
+        Exit::Jump(target) => (format!("v{}", target.0), "Continue"),
+        Exit::Branch {
+            cond,
+            taken,
+            not_taken,
+        } => (
+            format!(
+                "if v{} != 0 {{ {:#010x} }} else {{ {:#010x} }}",
+                cond.0, taken, not_taken
+            ),
+            "Continue",
+        ),
+        Exit::Syscall { next } => (format!("{:#010x}", next), "Syscall"),
+        Exit::Breakpoint { next } => (format!("{:#010x}", next), "Breakpoint"),
+        Exit::Unlifted(addr) => (format!("{:#010x}", addr), "Unlifted"),
+    };
+    writeln!(out, "    guest.set_pc({});", next).unwrap();
+    writeln!(
+        out,
+        "    Ok(Outcome {{ retired: {}, event: Event::{} }})",
+        retired, event
+    )
+    .unwrap();
+    out.push_str("}\n");
+    out
+}
+
+/// A module implementing `blocks`: `BLOCKS` lists their start addresses, `dispatch` runs the
+/// block at the pc of a guest and returns `None` when there is none. `crate_name` is the path
+/// the generated code reaches this crate by, `uses` are paths needed by code the caller adds.
+pub fn emit(blocks: &[Block], crate_name: &str, uses: &[String]) -> String {
+    let any = |check: fn(&Op) -> bool| blocks.iter().flat_map(|b| &b.ops).any(check);
+    let mut ir = vec!["Guest"];
+    if any(|op| matches!(op, Op::CheckAlign { .. })) {
+        ir.push("Misaligned");
+    }
+    let memory = any(|op| matches!(op, Op::Load { .. } | Op::Store { .. }));
+    if memory {
+        ir.push("Width");
+    }
+
+    let mut imports = uses.to_vec();
+    imports.push(format!(
+        "{}::backend::interpreter::{{Event, Outcome}}",
+        crate_name
+    ));
+    if memory {
+        imports.push(format!("{}::backend::rust::fault_at", crate_name));
+    }
+    imports.push(format!("{}::ir::{{{}}}", crate_name, ir.join(", ")));
+    imports.sort();
+
+    let mut out = String::new();
+    for import in imports {
+        writeln!(out, "use {};", import).unwrap();
+    }
+    out.push_str("\npub const BLOCKS: &[u32] = &[\n");
+    for block in blocks {
+        writeln!(out, "    {:#010x},", block.start).unwrap();
+    }
+    out.push_str("];\n\n");
+
+    out.push_str(
+        "pub fn dispatch<G: Guest>(guest: &mut G, pc: u32) -> Option<Result<Outcome, G::Error>> {\n",
+    );
+    out.push_str("    let result = match pc {\n");
+    for block in blocks {
+        writeln!(
+            out,
+            "        {:#010x} => {}(guest),",
+            block.start,
+            block_name(block.start)
+        )
+        .unwrap();
+    }
+    out.push_str("        _ => return None,\n    };\n    Some(result)\n}\n");
+
+    for block in blocks {
+        out.push('\n');
+        out.push_str(&emit_block(block));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::Builder;
+
+    #[test]
+    fn emit_load_and_branch() {
+        let mut builder = Builder::new();
+        builder.guest(0x100);
+        let base = builder.read_reg(10);
+        let unused = builder.constant(4);
+        let value = builder.load(base, Width::Byte, true);
+        builder.write_reg(11, value);
+        builder.guest(0x104);
+        let zero = builder.constant(0);
+        let cond = builder.binary(BinOp::Lt, value, zero);
+        let _ = unused;
+        let block = builder.finish(
+            0x100,
+            Exit::Branch {
+                cond,
+                taken: 0x200,
+                not_taken: 0x108,
+            },
+        );
+
+        assert_eq!(
+            emit_block(&block),
+            "fn block_00000100<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {\n    \
+             // 0x00000100\n    \
+             let v0 = guest.read_reg(10);\n    \
+             let v2 = fault_at(guest.load(v0, Width::Byte), guest, 0x00000100)? as u8 as i8 as u32;\n    \
+             guest.write_reg(11, v2);\n    \
+             // 0x00000104\n    \
+             let v3: u32 = 0x0;\n    \
+             let v4 = ((v2 as i32) < (v3 as i32)) as u32;\n    \
+             guest.set_pc(if v4 != 0 { 0x00000200 } else { 0x00000108 });\n    \
+             Ok(Outcome { retired: 2, event: Event::Continue })\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn stores_check_for_stale_code() {
+        let mut builder = Builder::new();
+        builder.guest(0);
+        let addr = builder.read_reg(1);
+        builder.store(addr, addr, Width::Word);
+        builder.guest(4);
+        let next = builder.constant(8);
+        let code = emit_block(&builder.finish(0, Exit::Jump(next)));
+
+        assert!(code.contains("let mut stale = false;"));
+        assert!(code.contains("stale |= guest.code_modified();"));
+        assert!(code.contains("if stale {\n        guest.set_pc(0x00000004);"));
+    }
+}
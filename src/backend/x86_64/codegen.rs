@@ -0,0 +1,592 @@
+use dynasmrt::x64::X64Relocation;
+use dynasmrt::{dynasm, DynasmApi, DynasmLabelApi, VecAssembler};
+
+use super::{
+    load, store, ChainSlots, JitContext, EXIT_FALLBACK, EXIT_NORMAL, LOAD_FAULT, STORE_FAULT,
+};
+use crate::frontend::rv32i::instructions::Instruction;
+use crate::frontend::rv32i::mem::PAGE_SHIFT;
+
+use std::mem::offset_of;
+
+type Assembler = VecAssembler<X64Relocation>;
+
+// Compiled code keeps the context in rbx and the guest register file in r12
+const CTX_REGS: i32 = offset_of!(JitContext, regs) as i32;
+const CTX_RAM: i32 = offset_of!(JitContext, ram) as i32;
+const CTX_CODE_PAGES: i32 = offset_of!(JitContext, code_pages) as i32;
+const CTX_RAM_SIZE: i32 = offset_of!(JitContext, ram_size) as i32;
+const CTX_RAM_BASE: i32 = offset_of!(JitContext, ram_base) as i32;
+const CTX_CODE_PAGE_BASE: i32 = offset_of!(JitContext, code_page_base) as i32;
+const CTX_PC: i32 = offset_of!(JitContext, pc) as i32;
+const CTX_RETIRED: i32 = offset_of!(JitContext, retired) as i32;
+const CTX_BUDGET: i32 = offset_of!(JitContext, budget) as i32;
+
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+
+/// Callee-saved host registers guest registers are allocated to: rbp, r13, r14 and r15. Helper
+/// calls preserve them, so they only have to be written back when leaving compiled code.
+const HOST_REGS: [u8; 4] = [5, 13, 14, 15];
+
+/// Instructions compiled code can execute. Everything touching CSRs, privilege or the SBI is
+/// left to the interpreter, as are jumps which always fault.
+pub fn supported(instr: &Instruction) -> bool {
+    match instr {
+        Instruction::JAL(_, imm) => imm % 4 == 0,
+        Instruction::FENCE_I
+        | Instruction::ECALL
+        | Instruction::EBREAK
+        | Instruction::SRET
+        | Instruction::MRET
+        | Instruction::WFI
+        | Instruction::CSRRW(..)
+        | Instruction::CSRRS(..)
+        | Instruction::CSRRC(..)
+        | Instruction::CSRRWI(..)
+        | Instruction::CSRRSI(..)
+        | Instruction::CSRRCI(..) => false,
+        _ => true,
+    }
+}
+
+pub fn ends_block(instr: &Instruction) -> bool {
+    matches!(
+        instr,
+        Instruction::JAL(..)
+            | Instruction::JALR(..)
+            | Instruction::BEQ(..)
+            | Instruction::BNE(..)
+            | Instruction::BLT(..)
+            | Instruction::BGE(..)
+            | Instruction::BLTU(..)
+            | Instruction::BGEU(..)
+    )
+}
+
+/// Guest registers read or written by `instr`, x0 standing for none
+fn registers(instr: &Instruction) -> [u32; 3] {
+    match *instr {
+        Instruction::LUI(rd, _) | Instruction::AUIPC(rd, _) | Instruction::JAL(rd, _) => [rd, 0, 0],
+        Instruction::BEQ(rs1, rs2, _)
+        | Instruction::BNE(rs1, rs2, _)
+        | Instruction::BLT(rs1, rs2, _)
+        | Instruction::BGE(rs1, rs2, _)
+        | Instruction::BLTU(rs1, rs2, _)
+        | Instruction::BGEU(rs1, rs2, _)
+        | Instruction::SB(rs1, rs2, _)
+        | Instruction::SH(rs1, rs2, _)
+        | Instruction::SW(rs1, rs2, _) => [rs1, rs2, 0],
+        Instruction::JALR(rd, rs1, _)
+        | Instruction::LB(rd, rs1, _)
+        | Instruction::LH(rd, rs1, _)
+        | Instruction::LW(rd, rs1, _)
+        | Instruction::LBU(rd, rs1, _)
+        | Instruction::LHU(rd, rs1, _)
+        | Instruction::ADDI(rd, rs1, _)
+        | Instruction::SLTI(rd, rs1, _)
+        | Instruction::SLTIU(rd, rs1, _)
+        | Instruction::XORI(rd, rs1, _)
+        | Instruction::ORI(rd, rs1, _)
+        | Instruction::ANDI(rd, rs1, _)
+        | Instruction::SLLI(rd, rs1, _)
+        | Instruction::SRLI(rd, rs1, _)
+        | Instruction::SRAI(rd, rs1, _) => [rd, rs1, 0],
+        Instruction::ADD(rd, rs1, rs2)
+        | Instruction::SUB(rd, rs1, rs2)
+        | Instruction::SLL(rd, rs1, rs2)
+        | Instruction::SLT(rd, rs1, rs2)
+        | Instruction::SLTU(rd, rs1, rs2)
+        | Instruction::XOR(rd, rs1, rs2)
+        | Instruction::SRL(rd, rs1, rs2)
+        | Instruction::SRA(rd, rs1, rs2)
+        | Instruction::OR(rd, rs1, rs2)
+        | Instruction::AND(rd, rs1, rs2) => [rd, rs1, rs2],
+        _ => [0, 0, 0],
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum AluOp {
+    Add,
+    Sub,
+    Slt,
+    Sltu,
+    Xor,
+    Or,
+    And,
+    Sll,
+    Srl,
+    Sra,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Condition {
+    Eq,
+    Ne,
+    Lt,
+    Ge,
+    Ltu,
+    Geu,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum LoadKind {
+    Byte,
+    Half,
+    Word,
+    ByteUnsigned,
+    HalfUnsigned,
+}
+
+impl LoadKind {
+    fn width(self) -> u32 {
+        match self {
+            LoadKind::Byte | LoadKind::ByteUnsigned => 1,
+            LoadKind::Half | LoadKind::HalfUnsigned => 2,
+            LoadKind::Word => 4,
+        }
+    }
+}
+
+/// The entry trampoline and the offset of the epilogue in it. The trampoline is called as
+/// `extern "sysv64" fn(*mut JitContext, entry: usize) -> u32` and jumps to `entry`, compiled
+/// code jumps to the epilogue to return the exit reason in eax.
+pub fn trampoline() -> (Vec<u8>, usize) {
+    let mut ops = Assembler::new(0);
+    dynasm!(ops
+        ; .arch x64
+        ; push rbx
+        ; push rbp
+        ; push r12
+        ; push r13
+        ; push r14
+        ; push r15
+        // Keep the stack 16 byte aligned for helper calls
+        ; sub rsp, 8
+        ; mov rbx, rdi
+        ; mov r12, QWORD [rbx + CTX_REGS]
+        ; jmp rsi
+    );
+    let epilogue = ops.offset().0;
+    dynasm!(ops
+        ; .arch x64
+        ; add rsp, 8
+        ; pop r15
+        ; pop r14
+        ; pop r13
+        ; pop r12
+        ; pop rbp
+        ; pop rbx
+        ; ret
+    );
+    (ops.finalize().unwrap(), epilogue)
+}
+
+/// Translates one basic block. The generated code is position independent, it can be copied
+/// anywhere in executable memory.
+pub struct BlockCompiler<'a> {
+    ops: Assembler,
+    /// Host register holding each guest register, `None` for registers left in memory
+    allocation: [Option<u8>; 32],
+    epilogue: usize,
+    chain: &'a mut ChainSlots,
+}
+
+impl<'a> BlockCompiler<'a> {
+    pub fn new(epilogue: usize, chain: &'a mut ChainSlots) -> Self {
+        BlockCompiler {
+            ops: Assembler::new(0),
+            allocation: [None; 32],
+            epilogue,
+            chain,
+        }
+    }
+
+    /// Give the most used guest registers of the block a host register, when they are used more
+    /// than once. The others are accessed in the register file.
+    fn allocate(&mut self, instructions: &[Instruction]) {
+        let mut uses = [0u32; 32];
+        for instr in instructions {
+            for &reg in registers(instr).iter() {
+                uses[reg as usize] += 1;
+            }
+        }
+        uses[0] = 0;
+        let mut candidates: Vec<usize> = (1..32).filter(|&reg| uses[reg] > 1).collect();
+        candidates.sort_by_key(|&reg| std::cmp::Reverse(uses[reg]));
+        for (&guest, &host) in candidates.iter().zip(HOST_REGS.iter()) {
+            self.allocation[guest] = Some(host);
+        }
+    }
+
+    fn allocated(&self) -> Vec<(i32, u8)> {
+        self.allocation
+            .iter()
+            .enumerate()
+            .filter_map(|(guest, host)| host.map(|host| (guest as i32 * 4, host)))
+            .collect()
+    }
+
+    fn load_allocated(&mut self) {
+        for (offset, host) in self.allocated() {
+            dynasm!(self.ops ; .arch x64 ; mov Rd(host), DWORD [r12 + offset]);
+        }
+    }
+
+    fn write_back(&mut self) {
+        for (offset, host) in self.allocated() {
+            dynasm!(self.ops ; .arch x64 ; mov DWORD [r12 + offset], Rd(host));
+        }
+    }
+
+    /// Load guest register `guest` into the scratch register `host`
+    fn read(&mut self, host: u8, guest: u32) {
+        match (guest, self.allocation[guest as usize]) {
+            (0, _) => dynasm!(self.ops ; .arch x64 ; xor Rd(host), Rd(host)),
+            (_, Some(reg)) => dynasm!(self.ops ; .arch x64 ; mov Rd(host), Rd(reg)),
+            (_, None) => {
+                dynasm!(self.ops ; .arch x64 ; mov Rd(host), DWORD [r12 + guest as i32 * 4])
+            }
+        }
+    }
+
+    /// Store the scratch register `host` to guest register `guest`, dropping writes to x0
+    fn write(&mut self, host: u8, guest: u32) {
+        match (guest, self.allocation[guest as usize]) {
+            (0, _) => {}
+            (_, Some(reg)) => dynasm!(self.ops ; .arch x64 ; mov Rd(reg), Rd(host)),
+            (_, None) => {
+                dynasm!(self.ops ; .arch x64 ; mov DWORD [r12 + guest as i32 * 4], Rd(host))
+            }
+        }
+    }
+
+    fn retire(&mut self, retired: u32) {
+        if retired != 0 {
+            dynasm!(self.ops ; .arch x64 ; add DWORD [rbx + CTX_RETIRED], retired as i32);
+        }
+    }
+
+    fn jump_to_epilogue(&mut self, reason: u32) {
+        dynasm!(self.ops
+            ; .arch x64
+            ; mov eax, reason as i32
+            ; mov rcx, QWORD self.epilogue as i64
+            ; jmp rcx
+        );
+    }
+
+    /// Leave compiled code with `retired` instructions of the block done and the pc at `pc`
+    fn exit(&mut self, retired: u32, pc: u32, reason: u32) {
+        self.write_back();
+        self.retire(retired);
+        dynasm!(self.ops ; .arch x64 ; mov DWORD [rbx + CTX_PC], pc as i32);
+        self.jump_to_epilogue(reason);
+    }
+
+    /// Continue at the static address `target`, jumping straight into its block when it is
+    /// compiled and the budget isn't used up
+    fn chain_to(&mut self, retired: u32, target: u32) {
+        let slot = self.chain.slot(target);
+        let leave = self.ops.new_dynamic_label();
+        self.write_back();
+        self.retire(retired);
+        dynasm!(self.ops
+            ; .arch x64
+            ; mov DWORD [rbx + CTX_PC], target as i32
+            ; mov eax, DWORD [rbx + CTX_RETIRED]
+            ; cmp eax, DWORD [rbx + CTX_BUDGET]
+            ; jae =>leave
+            ; mov rax, QWORD slot as i64
+            ; mov rax, QWORD [rax]
+            ; test rax, rax
+            ; jz =>leave
+            ; jmp rax
+            ; =>leave
+        );
+        self.jump_to_epilogue(EXIT_NORMAL);
+    }
+
+    pub fn compile(mut self, start: u32, instructions: &[Instruction]) -> Vec<u8> {
+        self.allocate(instructions);
+        self.load_allocated();
+
+        let mut pc = start;
+        let mut terminated = false;
+        for (index, instr) in instructions.iter().enumerate() {
+            let index = index as u32;
+            terminated = self.instruction(index, pc, instr);
+            pc = pc.wrapping_add(4);
+        }
+        if !terminated {
+            self.chain_to(instructions.len() as u32, pc);
+        }
+        self.ops.finalize().unwrap()
+    }
+
+    /// Emit instruction `index` of the block, returning whether it left the block
+    fn instruction(&mut self, index: u32, pc: u32, instr: &Instruction) -> bool {
+        match *instr {
+            Instruction::LUI(rd, imm) => self.constant(rd, imm),
+            Instruction::AUIPC(rd, imm) => self.constant(rd, pc.wrapping_add(imm)),
+            Instruction::JAL(rd, imm) => {
+                self.constant(rd, pc.wrapping_add(4));
+                self.chain_to(index + 1, pc.wrapping_add(imm));
+                return true;
+            }
+            Instruction::JALR(rd, rs1, imm) => {
+                self.jalr(index, pc, rd, rs1, imm);
+                return true;
+            }
+            Instruction::BEQ(rs1, rs2, imm) => self.branch(index, pc, Condition::Eq, rs1, rs2, imm),
+            Instruction::BNE(rs1, rs2, imm) => self.branch(index, pc, Condition::Ne, rs1, rs2, imm),
+            Instruction::BLT(rs1, rs2, imm) => self.branch(index, pc, Condition::Lt, rs1, rs2, imm),
+            Instruction::BGE(rs1, rs2, imm) => self.branch(index, pc, Condition::Ge, rs1, rs2, imm),
+            Instruction::BLTU(rs1, rs2, imm) => {
+                self.branch(index, pc, Condition::Ltu, rs1, rs2, imm)
+            }
+            Instruction::BGEU(rs1, rs2, imm) => {
+                self.branch(index, pc, Condition::Geu, rs1, rs2, imm)
+            }
+            Instruction::LB(rd, rs1, imm) => self.load(index, pc, LoadKind::Byte, rd, rs1, imm),
+            Instruction::LH(rd, rs1, imm) => self.load(index, pc, LoadKind::Half, rd, rs1, imm),
+            Instruction::LW(rd, rs1, imm) => self.load(index, pc, LoadKind::Word, rd, rs1, imm),
+            Instruction::LBU(rd, rs1, imm) => {
+                self.load(index, pc, LoadKind::ByteUnsigned, rd, rs1, imm)
+            }
+            Instruction::LHU(rd, rs1, imm) => {
+                self.load(index, pc, LoadKind::HalfUnsigned, rd, rs1, imm)
+            }
+            Instruction::SB(rs1, rs2, imm) => self.store(index, pc, 1, rs1, rs2, imm),
+            Instruction::SH(rs1, rs2, imm) => self.store(index, pc, 2, rs1, rs2, imm),
+            Instruction::SW(rs1, rs2, imm) => self.store(index, pc, 4, rs1, rs2, imm),
+            Instruction::ADDI(rd, rs1, imm) => self.alu_imm(AluOp::Add, rd, rs1, imm),
+            Instruction::SLTI(rd, rs1, imm) => self.alu_imm(AluOp::Slt, rd, rs1, imm),
+            Instruction::SLTIU(rd, rs1, imm) => self.alu_imm(AluOp::Sltu, rd, rs1, imm),
+            Instruction::XORI(rd, rs1, imm) => self.alu_imm(AluOp::Xor, rd, rs1, imm),
+            Instruction::ORI(rd, rs1, imm) => self.alu_imm(AluOp::Or, rd, rs1, imm),
+            Instruction::ANDI(rd, rs1, imm) => self.alu_imm(AluOp::And, rd, rs1, imm),
+            Instruction::SLLI(rd, rs1, shamt) => self.alu_imm(AluOp::Sll, rd, rs1, shamt),
+            Instruction::SRLI(rd, rs1, shamt) => self.alu_imm(AluOp::Srl, rd, rs1, shamt),
+            Instruction::SRAI(rd, rs1, shamt) => self.alu_imm(AluOp::Sra, rd, rs1, shamt),
+            Instruction::ADD(rd, rs1, rs2) => self.alu(AluOp::Add, rd, rs1, rs2),
+            Instruction::SUB(rd, rs1, rs2) => self.alu(AluOp::Sub, rd, rs1, rs2),
+            Instruction::SLL(rd, rs1, rs2) => self.alu(AluOp::Sll, rd, rs1, rs2),
+            Instruction::SLT(rd, rs1, rs2) => self.alu(AluOp::Slt, rd, rs1, rs2),
+            Instruction::SLTU(rd, rs1, rs2) => self.alu(AluOp::Sltu, rd, rs1, rs2),
+            Instruction::XOR(rd, rs1, rs2) => self.alu(AluOp::Xor, rd, rs1, rs2),
+            Instruction::SRL(rd, rs1, rs2) => self.alu(AluOp::Srl, rd, rs1, rs2),
+            Instruction::SRA(rd, rs1, rs2) => self.alu(AluOp::Sra, rd, rs1, rs2),
+            Instruction::OR(rd, rs1, rs2) => self.alu(AluOp::Or, rd, rs1, rs2),
+            Instruction::AND(rd, rs1, rs2) => self.alu(AluOp::And, rd, rs1, rs2),
+            // Memory accesses are performed in program order, so there is nothing to order here
+            Instruction::FENCE(_, _) => {}
+            _ => unreachable!("{:?} is not supported by the JIT", instr),
+        }
+        ends_block(instr)
+    }
+
+    fn constant(&mut self, rd: u32, value: u32) {
+        if rd != 0 {
+            dynasm!(self.ops ; .arch x64 ; mov eax, value as i32);
+            self.write(RAX, rd);
+        }
+    }
+
+    fn alu_imm(&mut self, op: AluOp, rd: u32, rs1: u32, imm: u32) {
+        if rd == 0 {
+            return;
+        }
+        self.read(RAX, rs1);
+        let imm = imm as i32;
+        match op {
+            AluOp::Add => dynasm!(self.ops ; .arch x64 ; add eax, imm),
+            AluOp::Sub => dynasm!(self.ops ; .arch x64 ; sub eax, imm),
+            AluOp::Slt => dynasm!(self.ops ; .arch x64 ; cmp eax, imm ; setl al ; movzx eax, al),
+            AluOp::Sltu => dynasm!(self.ops ; .arch x64 ; cmp eax, imm ; setb al ; movzx eax, al),
+            AluOp::Xor => dynasm!(self.ops ; .arch x64 ; xor eax, imm),
+            AluOp::Or => dynasm!(self.ops ; .arch x64 ; or eax, imm),
+            AluOp::And => dynasm!(self.ops ; .arch x64 ; and eax, imm),
+            AluOp::Sll => dynasm!(self.ops ; .arch x64 ; shl eax, imm as i8),
+            AluOp::Srl => dynasm!(self.ops ; .arch x64 ; shr eax, imm as i8),
+            AluOp::Sra => dynasm!(self.ops ; .arch x64 ; sar eax, imm as i8),
+        }
+        self.write(RAX, rd);
+    }
+
+    fn alu(&mut self, op: AluOp, rd: u32, rs1: u32, rs2: u32) {
+        if rd == 0 {
+            return;
+        }
+        self.read(RAX, rs1);
+        self.read(RCX, rs2);
+        // x86 masks 32 bit shift counts in cl to 5 bits, as RV32I does
+        match op {
+            AluOp::Add => dynasm!(self.ops ; .arch x64 ; add eax, ecx),
+            AluOp::Sub => dynasm!(self.ops ; .arch x64 ; sub eax, ecx),
+            AluOp::Slt => dynasm!(self.ops ; .arch x64 ; cmp eax, ecx ; setl al ; movzx eax, al),
+            AluOp::Sltu => dynasm!(self.ops ; .arch x64 ; cmp eax, ecx ; setb al ; movzx eax, al),
+            AluOp::Xor => dynasm!(self.ops ; .arch x64 ; xor eax, ecx),
+            AluOp::Or => dynasm!(self.ops ; .arch x64 ; or eax, ecx),
+            AluOp::And => dynasm!(self.ops ; .arch x64 ; and eax, ecx),
+            AluOp::Sll => dynasm!(self.ops ; .arch x64 ; shl eax, cl),
+            AluOp::Srl => dynasm!(self.ops ; .arch x64 ; shr eax, cl),
+            AluOp::Sra => dynasm!(self.ops ; .arch x64 ; sar eax, cl),
+        }
+        self.write(RAX, rd);
+    }
+
+    fn branch(&mut self, index: u32, pc: u32, condition: Condition, rs1: u32, rs2: u32, imm: u32) {
+        let taken = self.ops.new_dynamic_label();
+        self.read(RAX, rs1);
+        self.read(RCX, rs2);
+        dynasm!(self.ops ; .arch x64 ; cmp eax, ecx);
+        match condition {
+            Condition::Eq => dynasm!(self.ops ; .arch x64 ; je =>taken),
+            Condition::Ne => dynasm!(self.ops ; .arch x64 ; jne =>taken),
+            Condition::Lt => dynasm!(self.ops ; .arch x64 ; jl =>taken),
+            Condition::Ge => dynasm!(self.ops ; .arch x64 ; jge =>taken),
+            Condition::Ltu => dynasm!(self.ops ; .arch x64 ; jb =>taken),
+            Condition::Geu => dynasm!(self.ops ; .arch x64 ; jae =>taken),
+        }
+        self.chain_to(index + 1, pc.wrapping_add(4));
+        dynasm!(self.ops ; .arch x64 ; =>taken);
+        self.chain_to(index + 1, pc.wrapping_add(imm));
+    }
+
+    fn jalr(&mut self, index: u32, pc: u32, rd: u32, rs1: u32, imm: u32) {
+        let aligned = self.ops.new_dynamic_label();
+        self.read(RAX, rs1);
+        dynasm!(self.ops
+            ; .arch x64
+            ; add eax, imm as i32
+            ; and eax, -2
+            ; test al, 2
+            ; jz =>aligned
+        );
+        // The interpreter raises the misaligned jump, before rd is written
+        self.exit(index, pc, EXIT_FALLBACK);
+        dynasm!(self.ops ; .arch x64 ; =>aligned ; mov ecx, pc.wrapping_add(4) as i32);
+        self.write(RCX, rd);
+        // Indirect jumps go back to the dispatcher, which looks the target block up
+        self.write_back();
+        self.retire(index + 1);
+        dynasm!(self.ops ; .arch x64 ; mov DWORD [rbx + CTX_PC], eax);
+        self.jump_to_epilogue(EXIT_NORMAL);
+    }
+
+    /// Compute rs1 + imm into ecx and its offset into the RAM segment into rax. Jumps to `slow`
+    /// unless `width` bytes at the address are inside the segment.
+    fn address(&mut self, rs1: u32, imm: u32, width: u32, slow: dynasmrt::DynamicLabel) {
+        self.read(RCX, rs1);
+        if imm != 0 {
+            dynasm!(self.ops ; .arch x64 ; add ecx, imm as i32);
+        }
+        dynasm!(self.ops
+            ; .arch x64
+            ; mov eax, ecx
+            ; sub eax, DWORD [rbx + CTX_RAM_BASE]
+            ; lea rsi, [rax + width as i32]
+            ; cmp rsi, QWORD [rbx + CTX_RAM_SIZE]
+            ; ja =>slow
+        );
+    }
+
+    fn load(&mut self, index: u32, pc: u32, kind: LoadKind, rd: u32, rs1: u32, imm: u32) {
+        let slow = self.ops.new_dynamic_label();
+        let loaded = self.ops.new_dynamic_label();
+        let done = self.ops.new_dynamic_label();
+        let width = kind.width();
+
+        self.address(rs1, imm, width, slow);
+        dynasm!(self.ops ; .arch x64 ; mov rdx, QWORD [rbx + CTX_RAM]);
+        match kind {
+            LoadKind::Byte => dynasm!(self.ops ; .arch x64 ; movsx eax, BYTE [rdx + rax]),
+            LoadKind::Half => dynasm!(self.ops ; .arch x64 ; movsx eax, WORD [rdx + rax]),
+            LoadKind::Word => dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rdx + rax]),
+            LoadKind::ByteUnsigned => dynasm!(self.ops ; .arch x64 ; movzx eax, BYTE [rdx + rax]),
+            LoadKind::HalfUnsigned => dynasm!(self.ops ; .arch x64 ; movzx eax, WORD [rdx + rax]),
+        }
+        dynasm!(self.ops
+            ; .arch x64
+            ; jmp =>done
+            // Other segments and devices go through the bus
+            ; =>slow
+            ; mov rdi, rbx
+            ; mov esi, ecx
+            ; mov edx, width as i32
+            ; mov rax, QWORD load as *const () as i64
+            ; call rax
+            ; mov rcx, QWORD LOAD_FAULT as i64
+            ; test rax, rcx
+            ; jz =>loaded
+        );
+        self.exit(index, pc, EXIT_FALLBACK);
+        dynasm!(self.ops ; .arch x64 ; =>loaded);
+        match kind {
+            LoadKind::Byte => dynasm!(self.ops ; .arch x64 ; movsx eax, al),
+            LoadKind::Half => dynasm!(self.ops ; .arch x64 ; movsx eax, ax),
+            _ => {}
+        }
+        dynasm!(self.ops ; .arch x64 ; =>done);
+        self.write(RAX, rd);
+    }
+
+    fn store(&mut self, index: u32, pc: u32, width: u32, rs1: u32, rs2: u32, imm: u32) {
+        let slow = self.ops.new_dynamic_label();
+        let modified = self.ops.new_dynamic_label();
+        let done = self.ops.new_dynamic_label();
+
+        // The value is needed on both paths, so it is read before the address is checked
+        self.read(RDX, rs2);
+        self.address(rs1, imm, width, slow);
+        // Stores to pages code was compiled or decoded from take the slow path, which keeps
+        // track of them
+        dynasm!(self.ops
+            ; .arch x64
+            ; mov rdi, QWORD [rbx + CTX_CODE_PAGES]
+            ; mov esi, ecx
+            ; shr esi, PAGE_SHIFT as i8
+            ; sub esi, DWORD [rbx + CTX_CODE_PAGE_BASE]
+            ; cmp BYTE [rdi + rsi], 0
+            ; jne =>slow
+        );
+        if width > 1 {
+            dynasm!(self.ops
+                ; .arch x64
+                ; lea esi, [rcx + (width - 1) as i32]
+                ; shr esi, PAGE_SHIFT as i8
+                ; sub esi, DWORD [rbx + CTX_CODE_PAGE_BASE]
+                ; cmp BYTE [rdi + rsi], 0
+                ; jne =>slow
+            );
+        }
+        dynasm!(self.ops ; .arch x64 ; mov rsi, QWORD [rbx + CTX_RAM]);
+        match width {
+            1 => dynasm!(self.ops ; .arch x64 ; mov BYTE [rsi + rax], dl),
+            2 => dynasm!(self.ops ; .arch x64 ; mov WORD [rsi + rax], dx),
+            _ => dynasm!(self.ops ; .arch x64 ; mov DWORD [rsi + rax], edx),
+        }
+        dynasm!(self.ops
+            ; .arch x64
+            ; jmp =>done
+            ; =>slow
+            ; mov rdi, rbx
+            ; mov esi, ecx
+            ; mov ecx, width as i32
+            ; mov rax, QWORD store as *const () as i64
+            ; call rax
+            ; test eax, eax
+            ; jz =>done
+            ; cmp eax, STORE_FAULT as i32
+            ; jne =>modified
+        );
+        self.exit(index, pc, EXIT_FALLBACK);
+        // The store hit code, leave so the stale blocks are dropped before anything else runs
+        dynasm!(self.ops ; .arch x64 ; =>modified);
+        self.exit(index + 1, pc.wrapping_add(4), EXIT_NORMAL);
+        dynasm!(self.ops ; .arch x64 ; =>done);
+    }
+}
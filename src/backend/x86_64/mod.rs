@@ -0,0 +1,493 @@
+//! x86-64 JIT for RV32I. Basic blocks executed often enough are translated to host code, which
+//! runs directly on the hart's registers and RAM. Guest registers used several times in a block
+//! live in host registers, loads and stores inside the RAM segment being executed are done
+//! inline and everything else goes through the bus. Blocks with a static successor jump straight
+//! into it once it is compiled.
+//!
+//! Compiled code never raises exceptions itself: when an instruction would fault, or isn't
+//! compiled at all (CSR accesses, ECALL, MRET, WFI, ...), it stops in front of it with the hart
+//! exactly as the interpreter would have left it, and the interpreter executes that instruction.
+
+mod codegen;
+
+use codegen::BlockCompiler;
+use dynasmrt::mmap::{ExecutableBuffer, MutableBuffer};
+
+use crate::frontend::rv32i::cpu::CPU;
+use crate::frontend::rv32i::instructions::Instruction;
+use crate::frontend::rv32i::mem::{Mem, PAGE_SHIFT, RAM};
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Guest instructions compiled code runs before returning, so devices and interrupts are
+/// serviced regularly
+const BUDGET: u32 = 256;
+const MAX_BLOCK_LEN: usize = 64;
+const CHUNK_SIZE: usize = 1 << 20;
+
+const EXIT_NORMAL: u32 = 0;
+/// The interpreter has to execute the instruction at the pc
+const EXIT_FALLBACK: u32 = 1;
+
+/// Returned by `load` in place of a value when the bus access fails
+const LOAD_FAULT: u64 = 1 << 32;
+/// Returned by `store` when the bus access fails
+const STORE_FAULT: u32 = 1;
+/// Returned by `store` when it wrote to a watched code page
+const STORE_CODE_MODIFIED: u32 = 2;
+
+/// State shared between the dispatcher and compiled code, which finds it in rbx
+#[repr(C)]
+struct JitContext {
+    regs: *mut u32,
+    /// The RAM segment the dispatcher was entered in
+    ram: *mut u8,
+    code_pages: *const u8,
+    ram_size: u64,
+    ram_base: u32,
+    code_page_base: u32,
+    pc: u32,
+    retired: u32,
+    budget: u32,
+    memory: *mut RAM,
+}
+
+extern "sysv64" fn load(context: *mut JitContext, addr: u32, width: u32) -> u64 {
+    let memory = unsafe { &mut *(*context).memory };
+    let value = match width {
+        1 => memory.read_byte(addr).map(u32::from),
+        2 => memory.read_halfword(addr).map(u32::from),
+        _ => memory.read_word(addr),
+    };
+    match value {
+        Ok(value) => value as u64,
+        Err(_) => LOAD_FAULT,
+    }
+}
+
+extern "sysv64" fn store(context: *mut JitContext, addr: u32, value: u32, width: u32) -> u32 {
+    let memory = unsafe { &mut *(*context).memory };
+    let generation = memory.get_code_generation();
+    let result = match width {
+        1 => memory.write_byte(addr, value as u8),
+        2 => memory.write_halfword(addr, value as u16),
+        _ => memory.write_word(addr, value),
+    };
+    match result {
+        Err(_) => STORE_FAULT,
+        Ok(()) if memory.get_code_generation() != generation => STORE_CODE_MODIFIED,
+        Ok(()) => 0,
+    }
+}
+
+/// One slot for each address compiled code jumps to, holding the entry of its block or 0.
+/// Compiled code reads the slot on every jump, so blocks are linked and unlinked by writing it.
+#[derive(Default)]
+struct ChainSlots {
+    slots: HashMap<u32, Box<u64>>,
+}
+
+impl ChainSlots {
+    fn slot(&mut self, target: u32) -> *const u64 {
+        &**self.slots.entry(target).or_insert_with(|| Box::new(0))
+    }
+
+    fn link(&mut self, target: u32, entry: usize) {
+        **self.slots.entry(target).or_insert_with(|| Box::new(0)) = entry as u64;
+    }
+
+    fn unlink(&mut self, target: u32) {
+        if let Some(slot) = self.slots.get_mut(&target) {
+            **slot = 0;
+        }
+    }
+}
+
+/// Executable memory, filled one block at a time. Code never moves once it is installed.
+struct CodeMemory {
+    chunks: Vec<ExecutableBuffer>,
+    /// Bytes used in the last chunk
+    used: usize,
+    /// The entry trampoline and the epilogue compiled code returns through
+    entry: usize,
+    epilogue: usize,
+}
+
+impl CodeMemory {
+    fn new() -> Self {
+        let mut memory = CodeMemory {
+            chunks: Vec::new(),
+            used: CHUNK_SIZE,
+            entry: 0,
+            epilogue: 0,
+        };
+        let (trampoline, epilogue) = codegen::trampoline();
+        memory.entry = memory.install(&trampoline);
+        memory.epilogue = memory.entry + epilogue;
+        memory
+    }
+
+    /// Copy `code` into executable memory, returning its address
+    fn install(&mut self, code: &[u8]) -> usize {
+        assert!(code.len() <= CHUNK_SIZE);
+        let mut buffer = if self.used + code.len() > CHUNK_SIZE {
+            self.used = 0;
+            MutableBuffer::new(CHUNK_SIZE).expect("can't map JIT code memory")
+        } else {
+            let chunk = self.chunks.pop().unwrap();
+            chunk.make_mut().expect("can't make JIT code writable")
+        };
+        buffer.set_len(self.used + code.len());
+        buffer[self.used..].copy_from_slice(code);
+        let addr = buffer.as_ptr() as usize + self.used;
+        self.used += code.len();
+        self.chunks
+            .push(buffer.make_exec().expect("can't make JIT code executable"));
+        addr
+    }
+}
+
+struct CompiledBlock {
+    entry: usize,
+    page: u32,
+    /// Version of the code page when the block was compiled
+    version: u64,
+}
+
+/// How a call to `Jit::run` ended
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JitExit {
+    /// Guest instructions retired by compiled code
+    pub retired: u32,
+    /// The interpreter has to execute the instruction at the pc next
+    pub fallback: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct JitStats {
+    pub blocks_compiled: u64,
+    /// Blocks dropped because the code they were compiled from was overwritten
+    pub blocks_invalidated: u64,
+    /// Guest instructions retired by compiled code
+    pub instructions: u64,
+    /// Times compiled code handed an instruction over to the interpreter
+    pub fallbacks: u64,
+}
+
+pub struct Jit {
+    threshold: u32,
+    /// Allocated when the first block is compiled
+    code: Option<CodeMemory>,
+    blocks: HashMap<u32, CompiledBlock>,
+    /// How often addresses without a block were reached
+    counters: HashMap<u32, u32>,
+    /// Addresses no block can be compiled at, until code changes
+    rejected: HashSet<u32>,
+    chain: ChainSlots,
+    /// `RAM::get_code_generation` when the blocks were last checked against their pages
+    generation: u64,
+    stats: JitStats,
+}
+
+impl Jit {
+    pub fn new(threshold: u32) -> Self {
+        Jit {
+            threshold,
+            code: None,
+            blocks: HashMap::new(),
+            counters: HashMap::new(),
+            rejected: HashSet::new(),
+            chain: ChainSlots::default(),
+            generation: 0,
+            stats: JitStats::default(),
+        }
+    }
+
+    pub fn get_threshold(&self) -> u32 {
+        self.threshold
+    }
+
+    pub fn get_stats(&self) -> JitStats {
+        self.stats
+    }
+
+    /// Drop every compiled block and free the code memory
+    pub fn flush(&mut self) {
+        *self = Jit {
+            stats: self.stats,
+            ..Jit::new(self.threshold)
+        };
+    }
+
+    /// Drop the blocks whose page was written since they were compiled
+    fn invalidate_stale(&mut self, memory: &RAM) {
+        let chain = &mut self.chain;
+        let stats = &mut self.stats;
+        self.blocks.retain(|start, block| {
+            let valid = memory.get_code_page_version(block.page) == Some(block.version);
+            if !valid {
+                chain.unlink(*start);
+                stats.blocks_invalidated += 1;
+            }
+            valid
+        });
+        self.rejected.clear();
+        self.generation = memory.get_code_generation();
+    }
+
+    /// Whether a block is compiled at `pc`, compiling one if the address got hot
+    fn lookup(&mut self, pc: u32, memory: &mut RAM) -> bool {
+        if memory.get_code_generation() != self.generation {
+            self.invalidate_stale(memory);
+        }
+        if self.blocks.contains_key(&pc) {
+            return true;
+        }
+        if self.rejected.contains(&pc) {
+            return false;
+        }
+        let count = self.counters.entry(pc).or_insert(0);
+        if *count < self.threshold {
+            *count += 1;
+            return false;
+        }
+        self.counters.remove(&pc);
+        self.compile(pc, memory)
+    }
+
+    fn compile(&mut self, start: u32, memory: &mut RAM) -> bool {
+        let mut instructions = Vec::new();
+        if memory.is_mapped(start) {
+            let mut addr = start;
+            // RAM only, so compiling ahead of execution never reads devices
+            while let Some(instr) = memory
+                .peek_word(addr)
+                .ok()
+                .and_then(|word| Instruction::decode(word).ok())
+                .filter(codegen::supported)
+            {
+                instructions.push(instr);
+                addr = addr.wrapping_add(4);
+                if codegen::ends_block(&instr)
+                    || instructions.len() == MAX_BLOCK_LEN
+                    || addr >> PAGE_SHIFT != start >> PAGE_SHIFT
+                {
+                    break;
+                }
+            }
+        }
+        if instructions.is_empty() {
+            self.rejected.insert(start);
+            return false;
+        }
+
+        let page = start >> PAGE_SHIFT;
+        let version = memory.watch_code_page(page);
+        let code = self.code.get_or_insert_with(CodeMemory::new);
+        let compiled =
+            BlockCompiler::new(code.epilogue, &mut self.chain).compile(start, &instructions);
+        let entry = code.install(&compiled);
+        self.chain.link(start, entry);
+        self.blocks.insert(
+            start,
+            CompiledBlock {
+                entry,
+                page,
+                version,
+            },
+        );
+        self.stats.blocks_compiled += 1;
+        true
+    }
+
+    /// Run compiled code from the pc of `cpu`. Returns `None`, leaving the hart untouched, when
+    /// there is no compiled code for the pc yet.
+    pub fn run(&mut self, cpu: &mut CPU) -> Option<JitExit> {
+        let pc = cpu.get_registers().get_pc();
+        if !self.lookup(pc, cpu.get_memory()) {
+            return None;
+        }
+
+        let segment = cpu.get_memory().segment_view(pc)?;
+        let mut context = JitContext {
+            regs: cpu.get_registers().as_mut_ptr(),
+            ram: segment.data,
+            code_pages: segment.code_pages,
+            ram_size: segment.size as u64,
+            ram_base: segment.base,
+            code_page_base: segment.base >> PAGE_SHIFT,
+            pc,
+            retired: 0,
+            budget: BUDGET,
+            memory: cpu.get_memory(),
+        };
+        let code = self.code.as_ref().unwrap();
+        let enter: unsafe extern "sysv64" fn(*mut JitContext, usize) -> u32 =
+            unsafe { std::mem::transmute(code.entry) };
+
+        let mut fallback = false;
+        loop {
+            let entry = self.blocks[&context.pc].entry;
+            if unsafe { enter(&mut context, entry) } == EXIT_FALLBACK {
+                fallback = true;
+                break;
+            }
+            // Indirect jumps and stores to code come back here
+            let memory = unsafe { &mut *context.memory };
+            if context.retired >= BUDGET || !self.lookup(context.pc, memory) {
+                break;
+            }
+        }
+
+        cpu.get_registers().set_pc(context.pc);
+        self.stats.instructions += context.retired as u64;
+        self.stats.fallbacks += fallback as u64;
+        Some(JitExit {
+            retired: context.retired,
+            fallback,
+        })
+    }
+}
+
+/// Compiled code belongs to one hart, a copy starts out empty
+impl Clone for Jit {
+    fn clone(&self) -> Self {
+        Jit::new(self.threshold)
+    }
+}
+
+impl fmt::Debug for Jit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Jit")
+            .field("threshold", &self.threshold)
+            .field("blocks", &self.blocks.len())
+            .field("stats", &self.stats)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::rv32i::devices::clint::{Clint, CLINT_BASE, CLINT_SIZE};
+    use std::sync::{Arc, Mutex};
+
+    const ADDI_T0_1: u32 = 0x00128293; // addi t0, t0, 1
+    const ADDI_T0_2: u32 = 0x00228293; // addi t0, t0, 2
+    const ECALL: u32 = 0x00000073;
+
+    fn load_program(cpu: &mut CPU, base: u32, program: &[u32]) {
+        for (index, instr) in program.iter().enumerate() {
+            cpu.get_memory()
+                .write_word(base + index as u32 * 4, *instr)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn faults_stop_in_front_of_the_instruction() {
+        let mut cpu = CPU::new(0, 0x2000);
+        load_program(
+            &mut cpu,
+            0,
+            &[
+                ADDI_T0_1, 0xFFC00313, // addi t1, zero, -4
+                0x00032383, // lw t2, 0(t1)
+                ADDI_T0_1,
+            ],
+        );
+        let mut jit = Jit::new(0);
+        let exit = jit.run(&mut cpu).unwrap();
+
+        assert_eq!(
+            exit,
+            JitExit {
+                retired: 2,
+                fallback: true
+            }
+        );
+        assert_eq!(cpu.get_registers().get_pc(), 8);
+        assert_eq!(cpu.get_registers()[5], 1);
+        assert_eq!(cpu.get_registers()[6], 0xFFFF_FFFC);
+        assert_eq!(cpu.get_registers()[7], 0);
+    }
+
+    #[test]
+    fn devices_are_accessed_through_the_bus() {
+        let mut cpu = CPU::new(0, 0x2000);
+        let clint = Arc::new(Mutex::new(Clint::new(1)));
+        cpu.get_memory()
+            .attach_device(CLINT_BASE, CLINT_SIZE, clint.clone())
+            .unwrap();
+        load_program(
+            &mut cpu,
+            0,
+            &[
+                0x02000337, // lui t1, 0x2000
+                0x00100293, // addi t0, zero, 1
+                0x00532023, // sw t0, 0(t1)
+                0x00032383, // lw t2, 0(t1)
+                ECALL,
+            ],
+        );
+        let mut jit = Jit::new(0);
+        let exit = jit.run(&mut cpu).unwrap();
+
+        assert_eq!(
+            exit,
+            JitExit {
+                retired: 4,
+                fallback: false
+            }
+        );
+        assert_eq!(cpu.get_registers().get_pc(), 16);
+        assert_eq!(cpu.get_registers()[7], 1);
+        assert_eq!(clint.lock().unwrap().read_word(0).unwrap(), 1);
+    }
+
+    #[test]
+    fn stores_to_code_invalidate_blocks() {
+        let mut cpu = CPU::new(0, 0x2000);
+        load_program(&mut cpu, 0, &[ADDI_T0_1, 0x0FC0006F]); // j 0x100
+        load_program(&mut cpu, 0x100, &[0x00702023, ECALL]); // sw t2, 0(zero)
+        cpu.get_registers()[7] = ADDI_T0_2;
+        let mut jit = Jit::new(0);
+
+        jit.run(&mut cpu).unwrap();
+        assert_eq!(cpu.get_registers().get_pc(), 0x104);
+        assert_eq!(cpu.get_registers()[5], 1);
+        assert_eq!(jit.get_stats().blocks_invalidated, 2);
+
+        cpu.get_registers().set_pc(0);
+        jit.run(&mut cpu).unwrap();
+        assert_eq!(cpu.get_registers()[5], 3);
+    }
+
+    #[test]
+    fn code_runs_after_reaching_the_threshold() {
+        let mut cpu = CPU::new(0, 0x2000);
+        load_program(&mut cpu, 0, &[ADDI_T0_1, ECALL]);
+        let mut jit = Jit::new(2);
+        assert!(jit.run(&mut cpu).is_none());
+        assert!(jit.run(&mut cpu).is_none());
+        assert!(jit.run(&mut cpu).is_some());
+        assert_eq!(cpu.get_registers()[5], 1);
+        assert_eq!(jit.get_stats().blocks_compiled, 1);
+    }
+
+    #[test]
+    fn hot_loops_stay_in_compiled_code() {
+        let mut cpu = CPU::new(0, 0x2000);
+        // loop: addi t0, t0, 1; bne t0, t1, loop
+        load_program(&mut cpu, 0, &[ADDI_T0_1, 0xFE629EE3, ECALL]);
+        cpu.get_registers()[6] = 100;
+        let mut jit = Jit::new(0);
+        let exit = jit.run(&mut cpu).unwrap();
+
+        assert_eq!(exit.retired, 200);
+        assert_eq!(cpu.get_registers()[5], 100);
+        assert_eq!(cpu.get_registers().get_pc(), 8);
+        assert_eq!(jit.get_stats().blocks_compiled, 1);
+    }
+}
@@ -1,11 +1,15 @@
-use emulator_rs;
+use emulator_rs::frontend::rv32i::cpu::CPU;
+use emulator_rs::frontend::rv32i::machine::MachineConfig;
 
 fn main() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0,4096);
+    let mut cpu = CPU::with_config(&MachineConfig {
+        memory_size: 16384,
+        ..MachineConfig::default()
+    });
     let entry_point = cpu.load_elf("src/bin/rv32i-sb".to_string());
     println!("Entry Point loaded");
     println!("{:?}", cpu);
-    cpu.get_registers().set_pc(entry_point + 0x100);
+    cpu.boot(entry_point, "").unwrap();
     cpu.run().unwrap();
     println!("{:?}", cpu.get_registers()[10]);
 }
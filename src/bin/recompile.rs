@@ -0,0 +1,27 @@
+use emulator_rs::frontend::rv32i::recompile::recompile;
+
+use std::process;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 || args.len() > 3 {
+        eprintln!("usage: {} <elf> [output.rs]", args[0]);
+        process::exit(2);
+    }
+
+    let elf = std::fs::read(&args[1]).unwrap_or_else(|err| {
+        eprintln!("can't read {}: {}", args[1], err);
+        process::exit(1);
+    });
+    let source = recompile(&elf, "emulator_rs").unwrap_or_else(|err| {
+        eprintln!("can't recompile {}: {:?}", args[1], err);
+        process::exit(1);
+    });
+    match args.get(2) {
+        Some(path) => std::fs::write(path, source).unwrap_or_else(|err| {
+            eprintln!("can't write {}: {}", path, err);
+            process::exit(1);
+        }),
+        None => print!("{}", source),
+    }
+}
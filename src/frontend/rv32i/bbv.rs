@@ -0,0 +1,186 @@
+//! Basic block vectors for SimPoint. Execution is cut into intervals of a fixed number of
+//! instructions, and for each one the instructions executed in every basic block are counted.
+//! Blocks are dynamic: one starts wherever execution lands after a control transfer, trap or
+//! interrupt, and ends at the next one. They are numbered from 1 in the order they first run.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, Write};
+
+/// Instructions executed in each block during one interval
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BbvInterval {
+    /// Instructions retired before the interval, where `CPU::fast_forward` has to stop to run it
+    pub start: u64,
+    /// (block number, instructions) of every block which ran, by block number
+    pub counts: Vec<(usize, u64)>,
+}
+
+impl BbvInterval {
+    pub fn instructions(&self) -> u64 {
+        self.counts.iter().map(|(_, count)| count).sum()
+    }
+}
+
+impl fmt::Display for BbvInterval {
+    /// A line of a SimPoint `.bb` file, `T:block:count :block:count ...`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "T")?;
+        for (index, (block, count)) in self.counts.iter().enumerate() {
+            let separator = if index == 0 { "" } else { " " };
+            write!(f, "{}:{}:{}", separator, block, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BbvProfiler {
+    interval: u64,
+    /// Block number by start address
+    blocks: HashMap<u32, usize>,
+    /// Start address by block number - 1
+    starts: Vec<u32>,
+    /// The block being executed, `None` when the next instruction starts one
+    current: Option<usize>,
+    /// Where execution continues when it doesn't leave the block
+    next_pc: u32,
+    counts: BTreeMap<usize, u64>,
+    retired: u64,
+    interval_start: u64,
+    intervals: Vec<BbvInterval>,
+}
+
+impl BbvProfiler {
+    /// Cut execution into intervals of `interval` instructions, the first starting after
+    /// `retired` instructions
+    pub fn new(interval: u64, retired: u64) -> Self {
+        BbvProfiler {
+            interval: interval.max(1),
+            blocks: HashMap::new(),
+            starts: Vec::new(),
+            current: None,
+            next_pc: 0,
+            counts: BTreeMap::new(),
+            retired,
+            interval_start: retired,
+            intervals: Vec::new(),
+        }
+    }
+
+    pub fn get_interval(&self) -> u64 {
+        self.interval
+    }
+
+    /// Start address of every block, block 1 first
+    pub fn get_blocks(&self) -> &[u32] {
+        &self.starts
+    }
+
+    /// Count the instruction which executed at `pc` and continued at `next_pc`
+    pub fn observe(&mut self, pc: u32, next_pc: u32) {
+        let block = match self.current {
+            Some(block) if pc == self.next_pc => block,
+            _ => {
+                let starts = &mut self.starts;
+                *self.blocks.entry(pc).or_insert_with(|| {
+                    starts.push(pc);
+                    starts.len()
+                })
+            }
+        };
+        *self.counts.entry(block).or_insert(0) += 1;
+        self.retired += 1;
+
+        self.current = if next_pc == pc.wrapping_add(4) {
+            Some(block)
+        } else {
+            None
+        };
+        self.next_pc = next_pc;
+        if self.retired - self.interval_start == self.interval {
+            self.finish();
+        }
+    }
+
+    /// End the current interval early, e.g. when the workload exits. Does nothing when no
+    /// instruction executed since the last interval.
+    pub fn finish(&mut self) {
+        if self.counts.is_empty() {
+            return;
+        }
+        let counts = std::mem::take(&mut self.counts);
+        self.intervals.push(BbvInterval {
+            start: self.interval_start,
+            counts: counts.into_iter().collect(),
+        });
+        self.interval_start = self.retired;
+    }
+
+    /// Every interval completed since the last call
+    pub fn take_intervals(&mut self) -> Vec<BbvInterval> {
+        std::mem::take(&mut self.intervals)
+    }
+
+    /// Write the intervals completed since the last call in the `.bb` format, one per line
+    pub fn write<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        for interval in self.take_intervals() {
+            writeln!(out, "{}", interval)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_end_at_control_transfers() {
+        let mut bbv = BbvProfiler::new(100, 0);
+        // A two instruction block looping three times, then falling through
+        bbv.observe(0x100, 0x104);
+        for _ in 0..3 {
+            bbv.observe(0x104, 0x108);
+            bbv.observe(0x108, 0x104);
+        }
+        bbv.observe(0x104, 0x108);
+        bbv.observe(0x108, 0x10c);
+        bbv.finish();
+
+        assert_eq!(bbv.get_blocks(), &[0x100, 0x104]);
+        let intervals = bbv.take_intervals();
+        assert_eq!(intervals.len(), 1);
+        // The first pass through the loop is still part of the first block
+        assert_eq!(intervals[0].counts, vec![(1, 3), (2, 6)]);
+        assert_eq!(intervals[0].to_string(), "T:1:3 :2:6");
+    }
+
+    #[test]
+    fn intervals_have_a_fixed_length() {
+        let mut bbv = BbvProfiler::new(4, 0);
+        for index in 0..10 {
+            bbv.observe(index * 4, index * 4 + 4);
+        }
+        bbv.finish();
+        bbv.finish();
+
+        let mut out = Vec::new();
+        bbv.write(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "T:1:4\nT:1:4\nT:1:2\n");
+    }
+
+    #[test]
+    fn interval_starts() {
+        let mut bbv = BbvProfiler::new(3, 10);
+        for _ in 0..7 {
+            bbv.observe(0x100, 0x100);
+        }
+        bbv.finish();
+
+        let intervals = bbv.take_intervals();
+        let starts: Vec<u64> = intervals.iter().map(|interval| interval.start).collect();
+        assert_eq!(starts, vec![10, 13, 16]);
+        assert_eq!(intervals[1].instructions(), 3);
+    }
+}
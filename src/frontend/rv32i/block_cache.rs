@@ -0,0 +1,229 @@
+use super::cpu::CPUResult;
+use super::instructions::Instruction;
+use super::mem::{Mem, PAGE_SHIFT, RAM};
+use super::threaded::Decoded;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Longest run of instructions decoded into one block
+const MAX_BLOCK_LEN: usize = 64;
+
+/// Straight-line code starting at `start`, ending at the first control transfer, at the end of
+/// its page or RAM segment, or before an instruction which doesn't decode
+#[derive(Debug)]
+struct BasicBlock {
+    start: u32,
+    instructions: Vec<Decoded>,
+    /// Version of the code page when the block was decoded
+    version: u64,
+}
+
+impl BasicBlock {
+    fn page(&self) -> u32 {
+        self.start >> PAGE_SHIFT
+    }
+}
+
+/// Instructions after which execution doesn't simply continue with the next one
+fn ends_block(instr: &Instruction) -> bool {
+    matches!(
+        instr,
+        Instruction::JAL(..)
+            | Instruction::JALR(..)
+            | Instruction::BEQ(..)
+            | Instruction::BNE(..)
+            | Instruction::BLT(..)
+            | Instruction::BGE(..)
+            | Instruction::BLTU(..)
+            | Instruction::BGEU(..)
+            | Instruction::ECALL
+            | Instruction::EBREAK
+            | Instruction::MRET
+            | Instruction::SRET
+            | Instruction::WFI
+            | Instruction::FENCE_I
+    )
+}
+
+/// Pre-decoded basic blocks keyed by their start address. Writes to the pages blocks were decoded
+/// from are tracked by `RAM`, so stale blocks are dropped no matter which hart or device wrote.
+#[derive(Debug, Clone, Default)]
+pub struct BlockCache {
+    blocks: HashMap<u32, Arc<BasicBlock>>,
+    /// `RAM::get_code_generation` when the blocks were last checked against their pages
+    generation: u64,
+    /// The block being executed and the index of the next instruction in it
+    current: Option<(Arc<BasicBlock>, usize)>,
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        BlockCache::default()
+    }
+
+    /// Drop every block, as FENCE.I requires
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+        self.current = None;
+    }
+
+    /// Drop the blocks whose page was written since they were decoded
+    fn invalidate_stale(&mut self, memory: &RAM) {
+        self.blocks
+            .retain(|_, block| memory.get_code_page_version(block.page()) == Some(block.version));
+        if let Some((block, _)) = &self.current {
+            if !self.blocks.contains_key(&block.start) {
+                self.current = None;
+            }
+        }
+        self.generation = memory.get_code_generation();
+    }
+
+    /// Code is read with `RAM::peek_word`, so decoding ahead of execution never reads devices
+    fn decode_block(memory: &mut RAM, start: u32) -> CPUResult<BasicBlock> {
+        let version = memory.watch_code_page(start >> PAGE_SHIFT);
+        let mut instructions = Vec::new();
+        let mut addr = start;
+        loop {
+            let word = match memory.peek_word(addr) {
+                Ok(word) => word,
+                // Running off the end of the segment faults once execution gets there
+                Err(_) if !instructions.is_empty() => break,
+                Err(err) => return Err(err.into()),
+            };
+            let decoded = match Decoded::decode(word) {
+                Ok(decoded) => decoded,
+                // The bad instruction is reported once execution actually reaches it
+                Err(_) if !instructions.is_empty() => break,
+                Err(err) => return Err(err.into()),
+            };
+            instructions.push(decoded);
+            addr = addr.wrapping_add(4);
+            if ends_block(&decoded.instr)
+                || instructions.len() == MAX_BLOCK_LEN
+                || addr >> PAGE_SHIFT != start >> PAGE_SHIFT
+            {
+                break;
+            }
+        }
+        Ok(BasicBlock {
+            start,
+            instructions,
+            version,
+        })
+    }
+
+    /// The decoded instruction at `pc`. Only code in RAM is cached, anything else is fetched and
+    /// decoded every time.
+    pub fn fetch(&mut self, pc: u32, memory: &mut RAM) -> CPUResult<Decoded> {
+        if memory.get_code_generation() != self.generation {
+            self.invalidate_stale(memory);
+        }
+
+        if let Some((block, index)) = &mut self.current {
+            if *index < block.instructions.len()
+                && block.start.wrapping_add(*index as u32 * 4) == pc
+            {
+                let instr = block.instructions[*index];
+                *index += 1;
+                return Ok(instr);
+            }
+        }
+
+        if !memory.is_mapped(pc) {
+            self.current = None;
+            return Ok(Decoded::decode(memory.read_word(pc)?)?);
+        }
+        let block = match self.blocks.get(&pc) {
+            Some(block) => block.clone(),
+            None => {
+                let block = Arc::new(BlockCache::decode_block(memory, pc)?);
+                self.blocks.insert(pc, block.clone());
+                block
+            }
+        };
+        let instr = block.instructions[0];
+        self.current = Some((block, 1));
+        Ok(instr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDI_1: u32 = 0x00128293; // addi t0, t0, 1
+    const ADDI_2: u32 = 0x00228293; // addi t0, t0, 2
+    const JAL_0: u32 = 0x0000006f; // j .
+
+    #[test]
+    fn blocks_end_at_control_transfer() {
+        let mut memory = RAM::new(0, 0x2000);
+        for (index, instr) in [ADDI_1, ADDI_1, JAL_0, ADDI_2].iter().enumerate() {
+            memory.write_word(index as u32 * 4, *instr).unwrap();
+        }
+        let mut cache = BlockCache::new();
+        for pc in [0, 4, 8].iter() {
+            cache.fetch(*pc, &mut memory).unwrap();
+        }
+        assert_eq!(cache.blocks.len(), 1);
+        assert_eq!(cache.blocks[&0].instructions.len(), 3);
+
+        assert_eq!(
+            cache.fetch(12, &mut memory).unwrap().instr,
+            Instruction::ADDI(5, 5, 2)
+        );
+        assert_eq!(cache.blocks.len(), 2);
+    }
+
+    #[test]
+    fn blocks_end_at_page_boundary() {
+        let mut memory = RAM::new(0, 0x2000);
+        memory.write_word(0xFFC, ADDI_1).unwrap();
+        memory.write_word(0x1000, ADDI_1).unwrap();
+        let block = BlockCache::decode_block(&mut memory, 0xFFC).unwrap();
+        assert_eq!(block.instructions.len(), 1);
+    }
+
+    #[test]
+    fn blocks_end_at_segment_end() {
+        let mut memory = RAM::new(0, 8);
+        memory.write_word(0, ADDI_1).unwrap();
+        memory.write_word(4, ADDI_2).unwrap();
+        let mut cache = BlockCache::new();
+        assert_eq!(
+            cache.fetch(0, &mut memory).unwrap().instr,
+            Instruction::ADDI(5, 5, 1)
+        );
+        assert_eq!(
+            cache.fetch(4, &mut memory).unwrap().instr,
+            Instruction::ADDI(5, 5, 2)
+        );
+        assert_eq!(cache.blocks[&0].instructions.len(), 2);
+    }
+
+    #[test]
+    fn writes_invalidate_blocks() {
+        let mut memory = RAM::new(0, 0x3000);
+        memory.write_word(0, ADDI_1).unwrap();
+        memory.write_word(4, JAL_0).unwrap();
+        memory.write_word(0x1000, ADDI_1).unwrap();
+        let mut cache = BlockCache::new();
+        cache.fetch(0, &mut memory).unwrap();
+        cache.fetch(0x1000, &mut memory).unwrap();
+
+        memory.write_word(0, ADDI_2).unwrap();
+        assert_eq!(
+            cache.fetch(0, &mut memory).unwrap().instr,
+            Instruction::ADDI(5, 5, 2)
+        );
+        // Only the written page was dropped
+        assert!(cache.blocks.contains_key(&0x1000));
+
+        // Writes outside of code pages leave the blocks alone
+        let generation = memory.get_code_generation();
+        memory.write_word(0x2000, 0).unwrap();
+        assert_eq!(memory.get_code_generation(), generation);
+    }
+}
@@ -0,0 +1,35 @@
+/// Where harts start executing after reset, the boot ROM is placed here
+pub const DEFAULT_RESET_VECTOR: u32 = 0x1000;
+pub const BOOT_ROM_SIZE: u32 = 0x1000;
+
+// Offsets of the data words following the ROM code
+const ENTRY_OFFSET: u32 = 20;
+const DTB_OFFSET: u32 = 24;
+
+/// Boot ROM in the style of QEMU's virt machine. It loads the boot register convention,
+/// a0 = mhartid and a1 = DTB address, then jumps to the image entry point.
+pub fn boot_rom(entry: u32, dtb: u32) -> Vec<u32> {
+    vec![
+        0x0000_0297,                        // auipc t0, 0
+        0xF140_2573,                        // csrr  a0, mhartid
+        0x0002_A583 | (DTB_OFFSET << 20),   // lw    a1, DTB_OFFSET(t0)
+        0x0002_A283 | (ENTRY_OFFSET << 20), // lw    t0, ENTRY_OFFSET(t0)
+        0x0002_8067,                        // jr    t0
+        entry,
+        dtb,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_follows_code() {
+        let rom = boot_rom(0x8000_0000, 0x8020_0000);
+        assert_eq!(rom[2], 0x0182_A583);
+        assert_eq!(rom[3], 0x0142_A283);
+        assert_eq!(rom[(ENTRY_OFFSET / 4) as usize], 0x8000_0000);
+        assert_eq!(rom[(DTB_OFFSET / 4) as usize], 0x8020_0000);
+    }
+}
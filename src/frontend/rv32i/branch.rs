@@ -0,0 +1,709 @@
+//! Branch prediction models for microarchitecture exploration. A `BranchProfiler` is shown every
+//! conditional branch and jump the hart resolves. It asks each of its predictors about every
+//! conditional branch before training it with the outcome, and predicts the targets of returns
+//! with a return-address stack. Execution never depends on the predictions.
+
+use super::instructions::Instruction;
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug};
+
+/// A conditional branch direction predictor
+pub trait BranchPredictor: Debug + Send {
+    fn name(&self) -> String;
+
+    /// Whether the branch at `pc` to `target` will be taken
+    fn predict(&self, pc: u32, target: u32) -> bool;
+
+    /// Train with the outcome of the branch at `pc`, right after it was predicted
+    fn update(&mut self, pc: u32, target: u32, taken: bool);
+
+    /// Forget everything learned, for another hart starting out with the same configuration
+    fn reset(&mut self);
+
+    fn box_clone(&self) -> Box<dyn BranchPredictor>;
+}
+
+impl Clone for Box<dyn BranchPredictor> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StaticPolicy {
+    NotTaken,
+    Taken,
+    /// Backward branches, which usually close loops, taken and forward branches not taken
+    BackwardTaken,
+}
+
+/// The predictors this module provides
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PredictorKind {
+    Static(StaticPolicy),
+    /// A table of 2-bit counters indexed by pc
+    Bimodal {
+        entries: usize,
+    },
+    /// 2-bit counters indexed by pc xor the last `history_bits` branch outcomes
+    Gshare {
+        entries: usize,
+        history_bits: u32,
+    },
+    /// A bimodal base predictor and four tagged tables of `entries` each, indexed with 5, 11, 22
+    /// and 44 bits of global history
+    TageLite {
+        entries: usize,
+    },
+}
+
+impl PredictorKind {
+    pub fn build(self) -> Box<dyn BranchPredictor> {
+        match self {
+            PredictorKind::Static(policy) => Box::new(Static(policy)),
+            PredictorKind::Bimodal { entries } => Box::new(Bimodal::new(entries)),
+            PredictorKind::Gshare {
+                entries,
+                history_bits,
+            } => Box::new(Gshare::new(entries, history_bits)),
+            PredictorKind::TageLite { entries } => Box::new(TageLite::new(entries)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Static(pub StaticPolicy);
+
+impl BranchPredictor for Static {
+    fn name(&self) -> String {
+        match self.0 {
+            StaticPolicy::NotTaken => "static not-taken".into(),
+            StaticPolicy::Taken => "static taken".into(),
+            StaticPolicy::BackwardTaken => "static BTFN".into(),
+        }
+    }
+
+    fn predict(&self, pc: u32, target: u32) -> bool {
+        match self.0 {
+            StaticPolicy::NotTaken => false,
+            StaticPolicy::Taken => true,
+            StaticPolicy::BackwardTaken => target <= pc,
+        }
+    }
+
+    fn update(&mut self, _pc: u32, _target: u32, _taken: bool) {}
+
+    fn reset(&mut self) {}
+
+    fn box_clone(&self) -> Box<dyn BranchPredictor> {
+        Box::new(self.clone())
+    }
+}
+
+/// Saturating 2-bit counters, 0 and 1 predict not taken, 2 and 3 taken
+#[derive(Debug, Clone)]
+struct Counters(Vec<u8>);
+
+impl Counters {
+    /// Starting out weakly not taken
+    fn new(entries: usize) -> Self {
+        Counters(vec![1; entries.max(1)])
+    }
+
+    fn index(&self, hash: u32) -> usize {
+        hash as usize % self.0.len()
+    }
+
+    fn predict(&self, hash: u32) -> bool {
+        self.0[self.index(hash)] >= 2
+    }
+
+    fn update(&mut self, hash: u32, taken: bool) {
+        let index = self.index(hash);
+        let counter = &mut self.0[index];
+        *counter = if taken {
+            (*counter + 1).min(3)
+        } else {
+            counter.saturating_sub(1)
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Bimodal {
+    counters: Counters,
+}
+
+impl Bimodal {
+    pub fn new(entries: usize) -> Self {
+        Bimodal {
+            counters: Counters::new(entries),
+        }
+    }
+}
+
+impl BranchPredictor for Bimodal {
+    fn name(&self) -> String {
+        format!("bimodal ({} entries)", self.counters.0.len())
+    }
+
+    fn predict(&self, pc: u32, _target: u32) -> bool {
+        self.counters.predict(pc >> 2)
+    }
+
+    fn update(&mut self, pc: u32, _target: u32, taken: bool) {
+        self.counters.update(pc >> 2, taken);
+    }
+
+    fn reset(&mut self) {
+        *self = Bimodal::new(self.counters.0.len());
+    }
+
+    fn box_clone(&self) -> Box<dyn BranchPredictor> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Gshare {
+    counters: Counters,
+    history_bits: u32,
+    history: u32,
+}
+
+impl Gshare {
+    pub fn new(entries: usize, history_bits: u32) -> Self {
+        Gshare {
+            counters: Counters::new(entries),
+            history_bits: history_bits.min(32),
+            history: 0,
+        }
+    }
+
+    fn hash(&self, pc: u32) -> u32 {
+        (pc >> 2) ^ self.history
+    }
+}
+
+impl BranchPredictor for Gshare {
+    fn name(&self) -> String {
+        format!(
+            "gshare ({} entries, {} bits of history)",
+            self.counters.0.len(),
+            self.history_bits
+        )
+    }
+
+    fn predict(&self, pc: u32, _target: u32) -> bool {
+        self.counters.predict(self.hash(pc))
+    }
+
+    fn update(&mut self, pc: u32, _target: u32, taken: bool) {
+        self.counters.update(self.hash(pc), taken);
+        let mask = (1u64 << self.history_bits) - 1;
+        self.history = (((self.history as u64) << 1 | taken as u64) & mask) as u32;
+    }
+
+    fn reset(&mut self) {
+        *self = Gshare::new(self.counters.0.len(), self.history_bits);
+    }
+
+    fn box_clone(&self) -> Box<dyn BranchPredictor> {
+        Box::new(self.clone())
+    }
+}
+
+/// History lengths of the tagged tables of `TageLite`, shortest first
+const TAGE_HISTORY: [u32; 4] = [5, 11, 22, 44];
+const TAGE_TAG_BITS: u32 = 8;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct TageEntry {
+    valid: bool,
+    tag: u32,
+    /// 3-bit signed counter, predicts taken when not negative
+    counter: i8,
+    /// 2-bit usefulness, only entries which aren't useful are replaced
+    useful: u8,
+}
+
+/// A small TAGE: the longest history whose tagged entry matches provides the prediction, the base
+/// predictor does when none matches. Mispredictions allocate an entry with a longer history.
+#[derive(Debug, Clone)]
+pub struct TageLite {
+    base: Counters,
+    tables: Vec<Vec<TageEntry>>,
+    history: u64,
+}
+
+/// Fold the last `length` bits of `history` down to `bits` bits
+fn fold(history: u64, length: u32, bits: u32) -> u32 {
+    let mut history = history & ((1u64 << length) - 1);
+    let mut folded = 0;
+    while history != 0 {
+        folded ^= history & ((1u64 << bits) - 1);
+        history >>= bits;
+    }
+    folded as u32
+}
+
+impl TageLite {
+    pub fn new(entries: usize) -> Self {
+        let entries = entries.max(1);
+        TageLite {
+            base: Counters::new(entries),
+            tables: vec![vec![TageEntry::default(); entries]; TAGE_HISTORY.len()],
+            history: 0,
+        }
+    }
+
+    /// Index and tag of `pc` in each tagged table
+    fn lookup(&self, pc: u32) -> Vec<(usize, u32)> {
+        let entries = self.tables[0].len();
+        let index_bits = usize::BITS - (entries - 1).leading_zeros();
+        TAGE_HISTORY
+            .iter()
+            .map(|&length| {
+                let index = (pc >> 2) ^ fold(self.history, length, index_bits.max(1));
+                let tag = (pc >> 2)
+                    ^ fold(self.history, length, TAGE_TAG_BITS)
+                    ^ (fold(self.history, length, TAGE_TAG_BITS - 1) << 1);
+                (index as usize % entries, tag & ((1 << TAGE_TAG_BITS) - 1))
+            })
+            .collect()
+    }
+
+    /// The tables which match, longest history first
+    fn matches(&self, slots: &[(usize, u32)]) -> Vec<usize> {
+        (0..self.tables.len())
+            .rev()
+            .filter(|&table| {
+                let (index, tag) = slots[table];
+                let entry = &self.tables[table][index];
+                entry.valid && entry.tag == tag
+            })
+            .collect()
+    }
+}
+
+impl BranchPredictor for TageLite {
+    fn name(&self) -> String {
+        format!("TAGE-lite (4x{} entries)", self.tables[0].len())
+    }
+
+    fn predict(&self, pc: u32, _target: u32) -> bool {
+        let slots = self.lookup(pc);
+        match self.matches(&slots).first() {
+            Some(&table) => self.tables[table][slots[table].0].counter >= 0,
+            None => self.base.predict(pc >> 2),
+        }
+    }
+
+    fn update(&mut self, pc: u32, _target: u32, taken: bool) {
+        let slots = self.lookup(pc);
+        let matches = self.matches(&slots);
+        let alternate = match matches.get(1) {
+            Some(&table) => self.tables[table][slots[table].0].counter >= 0,
+            None => self.base.predict(pc >> 2),
+        };
+
+        let mispredicted = match matches.first() {
+            Some(&table) => {
+                let entry = &mut self.tables[table][slots[table].0];
+                let prediction = entry.counter >= 0;
+                if prediction != alternate {
+                    entry.useful = if prediction == taken {
+                        (entry.useful + 1).min(3)
+                    } else {
+                        entry.useful.saturating_sub(1)
+                    };
+                }
+                entry.counter = if taken {
+                    (entry.counter + 1).min(3)
+                } else {
+                    (entry.counter - 1).max(-4)
+                };
+                prediction != taken
+            }
+            None => {
+                let prediction = self.base.predict(pc >> 2);
+                self.base.update(pc >> 2, taken);
+                prediction != taken
+            }
+        };
+
+        // Give the branch an entry with more history, or age the candidates so one frees up
+        if mispredicted {
+            let longer = matches.first().map_or(0, |&table| table + 1);
+            let free = (longer..self.tables.len())
+                .find(|&table| self.tables[table][slots[table].0].useful == 0);
+            match free {
+                Some(table) => {
+                    self.tables[table][slots[table].0] = TageEntry {
+                        valid: true,
+                        tag: slots[table].1,
+                        counter: if taken { 0 } else { -1 },
+                        useful: 0,
+                    }
+                }
+                None => {
+                    let candidates = self.tables.iter_mut().zip(&slots).skip(longer);
+                    for (table, &(index, _)) in candidates {
+                        table[index].useful = table[index].useful.saturating_sub(1);
+                    }
+                }
+            }
+        }
+
+        self.history = self.history << 1 | taken as u64;
+    }
+
+    fn reset(&mut self) {
+        *self = TageLite::new(self.tables[0].len());
+    }
+
+    fn box_clone(&self) -> Box<dyn BranchPredictor> {
+        Box::new(self.clone())
+    }
+}
+
+/// Link registers, as in the hints for calls and returns of the unprivileged spec
+pub(crate) fn is_link(reg: u32) -> bool {
+    reg == 1 || reg == 5
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReturnStackStats {
+    pub calls: u64,
+    pub returns: u64,
+    /// Returns which didn't go to the address on top of the stack
+    pub mispredictions: u64,
+    /// Calls which pushed the oldest return address out of the full stack
+    pub overflows: u64,
+}
+
+/// Return-address stack predicting where returns go
+#[derive(Debug, Clone)]
+pub struct ReturnStack {
+    depth: usize,
+    stack: Vec<u32>,
+    stats: ReturnStackStats,
+}
+
+impl ReturnStack {
+    pub fn new(depth: usize) -> Self {
+        ReturnStack {
+            depth,
+            stack: Vec::with_capacity(depth),
+            stats: ReturnStackStats::default(),
+        }
+    }
+
+    pub fn get_stats(&self) -> ReturnStackStats {
+        self.stats
+    }
+
+    fn call(&mut self, return_address: u32) {
+        self.stats.calls += 1;
+        if self.depth == 0 {
+            return;
+        }
+        if self.stack.len() == self.depth {
+            self.stack.remove(0);
+            self.stats.overflows += 1;
+        }
+        self.stack.push(return_address);
+    }
+
+    fn ret(&mut self, target: u32) -> bool {
+        self.stats.returns += 1;
+        let correct = self.stack.pop() == Some(target);
+        self.stats.mispredictions += !correct as u64;
+        correct
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PredictorStats {
+    pub predictions: u64,
+    pub mispredictions: u64,
+}
+
+impl PredictorStats {
+    /// Fraction of correct predictions, 1 before any branch
+    pub fn accuracy(&self) -> f64 {
+        if self.predictions == 0 {
+            return 1.0;
+        }
+        1.0 - self.mispredictions as f64 / self.predictions as f64
+    }
+}
+
+/// What happened at one branch instruction
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BranchSite {
+    pub executed: u64,
+    pub taken: u64,
+    /// Mispredictions of each predictor, in the order they were added
+    pub mispredictions: Vec<u64>,
+}
+
+/// Predictors and return-address stack of a `BranchProfiler`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BranchConfig {
+    pub predictors: Vec<PredictorKind>,
+    /// Entries of the return-address stack, 0 predicts no return
+    pub return_stack: usize,
+}
+
+impl Default for BranchConfig {
+    /// One of each predictor with 4096 entries, and a 16-entry return-address stack
+    fn default() -> Self {
+        BranchConfig {
+            predictors: vec![
+                PredictorKind::Static(StaticPolicy::BackwardTaken),
+                PredictorKind::Bimodal { entries: 4096 },
+                PredictorKind::Gshare {
+                    entries: 4096,
+                    history_bits: 12,
+                },
+                PredictorKind::TageLite { entries: 1024 },
+            ],
+            return_stack: 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BranchProfiler {
+    predictors: Vec<(Box<dyn BranchPredictor>, PredictorStats)>,
+    return_stack: ReturnStack,
+    sites: BTreeMap<u32, BranchSite>,
+    /// Direct and indirect jumps which are neither calls nor returns
+    jumps: u64,
+}
+
+impl BranchProfiler {
+    pub fn new(config: &BranchConfig) -> Self {
+        let mut profiler = BranchProfiler {
+            predictors: Vec::new(),
+            return_stack: ReturnStack::new(config.return_stack),
+            sites: BTreeMap::new(),
+            jumps: 0,
+        };
+        for kind in &config.predictors {
+            profiler.add_predictor(kind.build());
+        }
+        profiler
+    }
+
+    /// Compare `predictor` with the others from the next branch on
+    pub fn add_predictor(&mut self, predictor: Box<dyn BranchPredictor>) {
+        self.predictors.push((predictor, PredictorStats::default()));
+        for site in self.sites.values_mut() {
+            site.mispredictions.push(0);
+        }
+    }
+
+    /// The same predictors and stack depth, untrained and without statistics
+    pub fn fresh(&self) -> Self {
+        let mut profiler = BranchProfiler {
+            predictors: Vec::new(),
+            return_stack: ReturnStack::new(self.return_stack.depth),
+            sites: BTreeMap::new(),
+            jumps: 0,
+        };
+        for (predictor, _) in &self.predictors {
+            let mut predictor = predictor.clone();
+            predictor.reset();
+            profiler.add_predictor(predictor);
+        }
+        profiler
+    }
+
+    /// Name and statistics of each predictor
+    pub fn get_stats(&self) -> Vec<(String, PredictorStats)> {
+        self.predictors
+            .iter()
+            .map(|(predictor, stats)| (predictor.name(), *stats))
+            .collect()
+    }
+
+    pub fn get_return_stack_stats(&self) -> ReturnStackStats {
+        self.return_stack.get_stats()
+    }
+
+    /// Every conditional branch executed, by address
+    pub fn get_sites(&self) -> &BTreeMap<u32, BranchSite> {
+        &self.sites
+    }
+
+    /// Look at `instr`, which executed at `pc` and continued at `next_pc`
+    pub fn observe(&mut self, instr: Instruction, pc: u32, next_pc: u32) {
+        use Instruction::*;
+
+        match instr {
+            BEQ(_, _, imm)
+            | BNE(_, _, imm)
+            | BLT(_, _, imm)
+            | BGE(_, _, imm)
+            | BLTU(_, _, imm)
+            | BGEU(_, _, imm) => {
+                let target = pc.wrapping_add(imm);
+                let taken = next_pc == target && target != pc.wrapping_add(4);
+                // A trap isn't an outcome of the branch
+                if !taken && next_pc != pc.wrapping_add(4) {
+                    return;
+                }
+                self.branch(pc, target, taken);
+            }
+            JAL(rd, _) if is_link(rd) => self.return_stack.call(pc.wrapping_add(4)),
+            JALR(rd, rs1, _) => {
+                // A return to one link register while calling through the other is both
+                if is_link(rs1) && (!is_link(rd) || rd != rs1) {
+                    self.return_stack.ret(next_pc);
+                }
+                if is_link(rd) {
+                    self.return_stack.call(pc.wrapping_add(4));
+                } else if !is_link(rs1) {
+                    self.jumps += 1;
+                }
+            }
+            JAL(..) => self.jumps += 1,
+            _ => {}
+        }
+    }
+
+    fn branch(&mut self, pc: u32, target: u32, taken: bool) {
+        let predictors = self.predictors.len();
+        let site = self.sites.entry(pc).or_insert_with(|| BranchSite {
+            mispredictions: vec![0; predictors],
+            ..BranchSite::default()
+        });
+        site.executed += 1;
+        site.taken += taken as u64;
+        for (index, (predictor, stats)) in self.predictors.iter_mut().enumerate() {
+            let mispredicted = predictor.predict(pc, target) != taken;
+            predictor.update(pc, target, taken);
+            stats.predictions += 1;
+            stats.mispredictions += mispredicted as u64;
+            site.mispredictions[index] += mispredicted as u64;
+        }
+    }
+}
+
+impl fmt::Display for BranchProfiler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, stats) in self.get_stats() {
+            writeln!(
+                f,
+                "{}: {} branches, {} mispredicted, {:.2}% accuracy",
+                name,
+                stats.predictions,
+                stats.mispredictions,
+                stats.accuracy() * 100.0
+            )?;
+        }
+        let ras = self.return_stack.get_stats();
+        write!(
+            f,
+            "return stack ({} entries): {} calls, {} returns, {} mispredicted, {} overflows; {} \
+             other jumps",
+            self.return_stack.depth,
+            ras.calls,
+            ras.returns,
+            ras.mispredictions,
+            ras.overflows,
+            self.jumps
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Outcomes of a branch taken `period - 1` times, then not taken once
+    fn loop_outcomes(period: usize, count: usize) -> Vec<bool> {
+        (0..count).map(|i| (i + 1) % period != 0).collect()
+    }
+
+    fn mispredictions(predictor: &mut dyn BranchPredictor, outcomes: &[bool]) -> usize {
+        outcomes
+            .iter()
+            .filter(|&&taken| {
+                let mispredicted = predictor.predict(0x100, 0x80) != taken;
+                predictor.update(0x100, 0x80, taken);
+                mispredicted
+            })
+            .count()
+    }
+
+    #[test]
+    fn static_predictors() {
+        let backward = Static(StaticPolicy::BackwardTaken);
+        assert!(backward.predict(0x100, 0x80));
+        assert!(!backward.predict(0x100, 0x180));
+        assert!(!Static(StaticPolicy::NotTaken).predict(0x100, 0x80));
+    }
+
+    #[test]
+    fn bimodal_learns_a_bias() {
+        let outcomes = loop_outcomes(10, 100);
+        // Warming up once, then missing each loop exit
+        assert_eq!(mispredictions(&mut Bimodal::new(16), &outcomes), 1 + 10);
+    }
+
+    #[test]
+    fn history_predicts_patterns() {
+        let outcomes = loop_outcomes(4, 400);
+        let bimodal = mispredictions(&mut Bimodal::new(64), &outcomes);
+        let gshare = mispredictions(&mut Gshare::new(64, 6), &outcomes);
+        let tage = mispredictions(&mut TageLite::new(64), &outcomes);
+
+        assert!(bimodal >= 100);
+        assert!(gshare < 20, "gshare missed {}", gshare);
+        assert!(tage < 20, "TAGE missed {}", tage);
+    }
+
+    #[test]
+    fn return_stack_follows_calls() {
+        let mut profiler = BranchProfiler::new(&BranchConfig {
+            predictors: Vec::new(),
+            return_stack: 2,
+        });
+        let call = Instruction::JAL(1, 0x100);
+        let ret = Instruction::JALR(0, 1, 0);
+        profiler.observe(call, 0x000, 0x100);
+        profiler.observe(call, 0x100, 0x200);
+        profiler.observe(call, 0x200, 0x300);
+        profiler.observe(ret, 0x300, 0x204);
+        profiler.observe(ret, 0x204, 0x104);
+        // Pushed out by the third call
+        profiler.observe(ret, 0x104, 0x004);
+
+        let stats = profiler.get_return_stack_stats();
+        assert_eq!((stats.calls, stats.returns), (3, 3));
+        assert_eq!((stats.mispredictions, stats.overflows), (1, 1));
+    }
+
+    #[test]
+    fn statistics_per_site() {
+        let mut profiler = BranchProfiler::new(&BranchConfig {
+            predictors: vec![PredictorKind::Static(StaticPolicy::NotTaken)],
+            return_stack: 0,
+        });
+        let beqz = Instruction::BEQ(10, 0, 0xFFFF_FFF0);
+        profiler.observe(beqz, 0x110, 0x100);
+        profiler.observe(beqz, 0x110, 0x114);
+        profiler.observe(beqz, 0x110, 0x100);
+        // Trapped, e.g. on a misaligned target
+        profiler.observe(beqz, 0x110, 0x8000_0000);
+
+        let site = &profiler.get_sites()[&0x110];
+        assert_eq!((site.executed, site.taken), (3, 2));
+        assert_eq!(site.mispredictions, vec![2]);
+        assert_eq!(profiler.get_stats()[0].1.mispredictions, 2);
+    }
+}
@@ -0,0 +1,354 @@
+//! Set-associative cache model for L1 instruction and data caches. It only tracks tags, data
+//! always comes from memory, so caches change statistics and timing but never results.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Replacement {
+    /// Evict the line used longest ago
+    Lru,
+    /// Evict the line filled longest ago
+    Fifo,
+    /// Evict a pseudo-random line, from a fixed seed so runs are reproducible
+    Random,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WritePolicy {
+    /// Writes mark the line dirty, it is written back when evicted
+    WriteBack,
+    /// Writes go to memory immediately, lines are never dirty
+    WriteThrough,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheConfig {
+    /// Capacity in bytes
+    pub size: u32,
+    /// Lines per set
+    pub associativity: u32,
+    /// Bytes per line
+    pub line_size: u32,
+    pub replacement: Replacement,
+    pub write_policy: WritePolicy,
+    /// Whether a write miss fills the line, otherwise only the memory is written
+    pub write_allocate: bool,
+    /// Cycles each miss adds to mcycle and pipeline timing, 0 to only collect statistics
+    pub miss_penalty: u32,
+}
+
+impl Default for CacheConfig {
+    /// 16 KiB, 4-way, 32-byte lines, LRU, write-back with write allocate
+    fn default() -> Self {
+        CacheConfig {
+            size: 16 * 1024,
+            associativity: 4,
+            line_size: 32,
+            replacement: Replacement::Lru,
+            write_policy: WritePolicy::WriteBack,
+            write_allocate: true,
+            miss_penalty: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheError {
+    /// Line size and number of sets have to be non-zero powers of two
+    InvalidGeometry,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub read_misses: u64,
+    pub write_misses: u64,
+    /// Valid lines replaced to make room for another
+    pub evictions: u64,
+    /// Evicted lines which were dirty
+    pub writebacks: u64,
+}
+
+impl CacheStats {
+    pub fn accesses(&self) -> u64 {
+        self.reads + self.writes
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.read_misses + self.write_misses
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.accesses() - self.misses()
+    }
+
+    /// Fraction of accesses which missed, 0 before any access
+    pub fn miss_rate(&self) -> f64 {
+        if self.accesses() == 0 {
+            return 0.0;
+        }
+        self.misses() as f64 / self.accesses() as f64
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} accesses, {} hits, {} misses ({:.2}%), {} evictions, {} writebacks",
+            self.accesses(),
+            self.hits(),
+            self.misses(),
+            self.miss_rate() * 100.0,
+            self.evictions,
+            self.writebacks
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Line {
+    valid: bool,
+    dirty: bool,
+    tag: u32,
+    /// Time of the last access for LRU, of the fill for FIFO
+    stamp: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cache {
+    config: CacheConfig,
+    /// `associativity` lines for each set, one set after the other
+    lines: Vec<Line>,
+    offset_bits: u32,
+    set_mask: u32,
+    /// Advanced by every access, for the replacement stamps
+    clock: u64,
+    /// xorshift state for random replacement
+    seed: u32,
+    stats: CacheStats,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Result<Self, CacheError> {
+        let set_size = config.line_size.checked_mul(config.associativity);
+        let sets = match set_size {
+            Some(set_size) if set_size > 0 && config.size % set_size == 0 => {
+                config.size / set_size
+            }
+            _ => return Err(CacheError::InvalidGeometry),
+        };
+        if !config.line_size.is_power_of_two() || !sets.is_power_of_two() {
+            return Err(CacheError::InvalidGeometry);
+        }
+
+        Ok(Cache {
+            config,
+            lines: vec![Line::default(); (sets * config.associativity) as usize],
+            offset_bits: config.line_size.trailing_zeros(),
+            set_mask: sets - 1,
+            clock: 0,
+            seed: 0x2545_F491,
+            stats: CacheStats::default(),
+        })
+    }
+
+    pub fn get_config(&self) -> CacheConfig {
+        self.config
+    }
+
+    pub fn get_stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Drop every line without writing dirty ones back, like FENCE.I does to an instruction cache
+    pub fn invalidate(&mut self) {
+        for line in &mut self.lines {
+            line.valid = false;
+        }
+    }
+
+    /// Access the `size` bytes at `addr`, counting each line touched. Returns the number of
+    /// misses.
+    pub fn access(&mut self, addr: u32, size: u32, kind: Access) -> u32 {
+        let first = addr >> self.offset_bits;
+        let last = addr.wrapping_add(size.max(1) - 1) >> self.offset_bits;
+        let mut misses = 0;
+        let mut line = first;
+        loop {
+            misses += !self.access_line(line, kind) as u32;
+            if line == last {
+                return misses;
+            }
+            line = line.wrapping_add(1);
+        }
+    }
+
+    /// Returns whether the access hit
+    fn access_line(&mut self, line_addr: u32, kind: Access) -> bool {
+        self.clock += 1;
+        let set = (line_addr & self.set_mask) as usize;
+        let tag = line_addr >> self.set_mask.count_ones();
+        let ways = self.config.associativity as usize;
+        let write = kind == Access::Write;
+        let dirty = write && self.config.write_policy == WritePolicy::WriteBack;
+        match kind {
+            Access::Read => self.stats.reads += 1,
+            Access::Write => self.stats.writes += 1,
+        }
+
+        let set_lines = &mut self.lines[set * ways..(set + 1) * ways];
+        if let Some(line) = set_lines
+            .iter_mut()
+            .find(|line| line.valid && line.tag == tag)
+        {
+            if self.config.replacement == Replacement::Lru {
+                line.stamp = self.clock;
+            }
+            line.dirty |= dirty;
+            return true;
+        }
+
+        match kind {
+            Access::Read => self.stats.read_misses += 1,
+            Access::Write => self.stats.write_misses += 1,
+        }
+        if write && !self.config.write_allocate {
+            return false;
+        }
+        let way = match set_lines.iter().position(|line| !line.valid) {
+            Some(way) => way,
+            None => {
+                let way = match self.config.replacement {
+                    Replacement::Lru | Replacement::Fifo => {
+                        let oldest = set_lines.iter().enumerate().min_by_key(|(_, l)| l.stamp);
+                        oldest.map(|(way, _)| way).unwrap_or(0)
+                    }
+                    Replacement::Random => {
+                        self.seed ^= self.seed << 13;
+                        self.seed ^= self.seed >> 17;
+                        self.seed ^= self.seed << 5;
+                        self.seed as usize % ways
+                    }
+                };
+                self.stats.evictions += 1;
+                self.stats.writebacks += set_lines[way].dirty as u64;
+                way
+            }
+        };
+        set_lines[way] = Line {
+            valid: true,
+            dirty,
+            tag,
+            stamp: self.clock,
+        };
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 4 sets of 2 lines of 16 bytes
+    fn small(replacement: Replacement) -> Cache {
+        Cache::new(CacheConfig {
+            size: 128,
+            associativity: 2,
+            line_size: 16,
+            replacement,
+            ..CacheConfig::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn geometry_is_checked() {
+        let config = CacheConfig {
+            size: 96,
+            associativity: 2,
+            line_size: 16,
+            ..CacheConfig::default()
+        };
+        assert_eq!(Cache::new(config).err(), Some(CacheError::InvalidGeometry));
+        let config = CacheConfig {
+            associativity: 0,
+            ..CacheConfig::default()
+        };
+        assert_eq!(Cache::new(config).err(), Some(CacheError::InvalidGeometry));
+        assert!(Cache::new(CacheConfig::default()).is_ok());
+    }
+
+    #[test]
+    fn hits_after_the_first_miss() {
+        let mut cache = small(Replacement::Lru);
+        assert_eq!(cache.access(0x100, 4, Access::Read), 1);
+        assert_eq!(cache.access(0x10C, 4, Access::Read), 0);
+        // Straddles two lines, the second one misses
+        assert_eq!(cache.access(0x10E, 4, Access::Read), 1);
+
+        let stats = cache.get_stats();
+        assert_eq!((stats.reads, stats.read_misses, stats.hits()), (4, 2, 2));
+    }
+
+    #[test]
+    fn lru_and_fifo_replacement() {
+        // 0x000, 0x040 and 0x080 all map to set 0
+        for (replacement, survivor) in [(Replacement::Lru, 0x000), (Replacement::Fifo, 0x040)] {
+            let mut cache = small(replacement);
+            cache.access(0x000, 4, Access::Read);
+            cache.access(0x040, 4, Access::Read);
+            cache.access(0x000, 4, Access::Read);
+            cache.access(0x080, 4, Access::Read);
+
+            assert_eq!(cache.get_stats().evictions, 1);
+            assert_eq!(
+                cache.access(survivor, 4, Access::Read),
+                0,
+                "{:?}",
+                replacement
+            );
+        }
+    }
+
+    #[test]
+    fn random_replacement_is_reproducible() {
+        let run = || {
+            let mut cache = small(Replacement::Random);
+            (0..64)
+                .map(|i| cache.access(i * 0x40, 4, Access::Read))
+                .sum::<u32>()
+                + (0..64)
+                    .map(|i| cache.access(i * 0x40, 4, Access::Read))
+                    .sum::<u32>()
+        };
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn write_policies() {
+        let mut cache = small(Replacement::Lru);
+        cache.access(0x000, 4, Access::Write);
+        cache.access(0x040, 4, Access::Read);
+        cache.access(0x080, 4, Access::Read);
+        assert_eq!(cache.get_stats().writebacks, 1);
+
+        let mut cache = Cache::new(CacheConfig {
+            write_policy: WritePolicy::WriteThrough,
+            write_allocate: false,
+            ..CacheConfig::default()
+        })
+        .unwrap();
+        assert_eq!(cache.access(0x000, 4, Access::Write), 1);
+        assert_eq!(cache.access(0x000, 4, Access::Read), 1);
+        assert_eq!(cache.access(0x000, 4, Access::Write), 0);
+        assert_eq!(cache.get_stats().writebacks, 0);
+    }
+}
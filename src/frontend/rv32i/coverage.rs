@@ -0,0 +1,190 @@
+//! Code coverage of the guest. Executed basic blocks are written as drcov files, which
+//! Lighthouse loads, and executed lines as lcov tracefiles when the binary has DWARF line
+//! information. Blocks are dynamic like those of basic block vectors: one starts wherever
+//! execution lands after a control transfer, trap or interrupt.
+
+use super::dwarf::LineTable;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+/// drcov block sizes are 16 bits, longer blocks are split
+const MAX_BLOCK_SIZE: u32 = 0xfffc;
+
+/// A binary blocks are attributed to in drcov files
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+    pub path: String,
+    pub base: u32,
+    /// Address after the end of the binary
+    pub end: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    /// Size of every block by start address
+    blocks: BTreeMap<u32, u32>,
+    /// Times the instruction at each address executed
+    counts: HashMap<u32, u64>,
+    /// Start of the block being executed, `None` when the next instruction starts one
+    current: Option<u32>,
+    /// Where execution continues when it doesn't leave the block
+    next_pc: u32,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage::default()
+    }
+
+    /// (start, size) of every block executed, by start address
+    pub fn get_blocks(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.blocks.iter().map(|(&start, &size)| (start, size))
+    }
+
+    /// Times the instruction at `addr` executed
+    pub fn get_count(&self, addr: u32) -> u64 {
+        self.counts.get(&addr).copied().unwrap_or(0)
+    }
+
+    /// Record the instruction which executed at `pc` and continued at `next_pc`
+    pub fn observe(&mut self, pc: u32, next_pc: u32) {
+        *self.counts.entry(pc).or_insert(0) += 1;
+        let start = match self.current {
+            Some(start) if pc == self.next_pc => start,
+            _ => pc,
+        };
+        let size = self.blocks.entry(start).or_insert(0);
+        *size = (*size).max(pc.wrapping_sub(start) + 4);
+
+        self.current = if next_pc == pc.wrapping_add(4) {
+            Some(start)
+        } else {
+            None
+        };
+        self.next_pc = next_pc;
+    }
+
+    /// Write the blocks in the drcov 2 format. Blocks outside of `modules` are left out.
+    pub fn write_drcov<W: Write>(&self, modules: &[Module], out: &mut W) -> io::Result<()> {
+        let mut entries = Vec::new();
+        for (start, size) in self.get_blocks() {
+            let id = match modules
+                .iter()
+                .position(|module| start >= module.base && start < module.end)
+            {
+                Some(id) => id,
+                None => continue,
+            };
+            let offset = start - modules[id].base;
+            for chunk in (0..size).step_by(MAX_BLOCK_SIZE as usize) {
+                entries.push((offset + chunk, (size - chunk).min(MAX_BLOCK_SIZE), id));
+            }
+        }
+
+        writeln!(out, "DRCOV VERSION: 2")?;
+        writeln!(out, "DRCOV FLAVOR: emulator-rs")?;
+        writeln!(out, "Module Table: version 2, count {}", modules.len())?;
+        writeln!(out, "Columns: id, base, end, entry, path")?;
+        for (id, module) in modules.iter().enumerate() {
+            writeln!(
+                out,
+                "{:2}, {:#010x}, {:#010x}, {:#010x}, {}",
+                id, module.base, module.end, 0, module.path
+            )?;
+        }
+        writeln!(out, "BB Table: {} bbs", entries.len())?;
+        for (offset, size, id) in entries {
+            out.write_all(&offset.to_le_bytes())?;
+            out.write_all(&(size as u16).to_le_bytes())?;
+            out.write_all(&(id as u16).to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Write the lines of `lines` in the lcov tracefile format. A line's count is the most any of
+    /// its instructions executed. Nothing is written when `lines` is empty.
+    pub fn write_lcov<W: Write>(&self, lines: &LineTable, out: &mut W) -> io::Result<()> {
+        let mut files: BTreeMap<&str, BTreeMap<u32, u64>> = BTreeMap::new();
+        for row in lines.get_rows() {
+            let count = (row.addr..row.end)
+                .step_by(4)
+                .map(|addr| self.get_count(addr))
+                .max()
+                .unwrap_or(0);
+            let line = files
+                .entry(&lines.get_files()[row.file])
+                .or_default()
+                .entry(row.line)
+                .or_insert(0);
+            *line = (*line).max(count);
+        }
+
+        for (file, counts) in files {
+            writeln!(out, "TN:")?;
+            writeln!(out, "SF:{}", file)?;
+            for (line, count) in &counts {
+                writeln!(out, "DA:{},{}", line, count)?;
+            }
+            writeln!(out, "LF:{}", counts.len())?;
+            let hit = counts.values().filter(|&&count| count > 0).count();
+            writeln!(out, "LH:{}", hit)?;
+            writeln!(out, "end_of_record")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_end_at_control_transfers() {
+        let mut coverage = Coverage::new();
+        coverage.observe(0x100, 0x104);
+        for _ in 0..3 {
+            coverage.observe(0x104, 0x108);
+            coverage.observe(0x108, 0x104);
+        }
+        coverage.observe(0x104, 0x108);
+        coverage.observe(0x108, 0x10c);
+        // Trapping out of a block
+        coverage.observe(0x10c, 0x200);
+
+        let blocks: Vec<(u32, u32)> = coverage.get_blocks().collect();
+        assert_eq!(blocks, vec![(0x100, 12), (0x104, 12)]);
+        assert_eq!(coverage.get_count(0x104), 4);
+        assert_eq!(coverage.get_count(0x10c), 1);
+        assert_eq!(coverage.get_count(0x200), 0);
+    }
+
+    #[test]
+    fn drcov_format() {
+        let mut coverage = Coverage::new();
+        coverage.observe(0x1000, 0x8000_0010);
+        coverage.observe(0x8000_0010, 0x8000_0014);
+        coverage.observe(0x8000_0014, 0x8000_0000);
+        coverage.observe(0x8000_0000, 0x8000_0004);
+
+        let modules = [Module {
+            path: "/bin/guest".to_string(),
+            base: 0x8000_0000,
+            end: 0x8000_0100,
+        }];
+        let mut out = Vec::new();
+        coverage.write_drcov(&modules, &mut out).unwrap();
+
+        let header = "DRCOV VERSION: 2\n\
+                      DRCOV FLAVOR: emulator-rs\n\
+                      Module Table: version 2, count 1\n\
+                      Columns: id, base, end, entry, path\n \
+                      0, 0x80000000, 0x80000100, 0x00000000, /bin/guest\n\
+                      BB Table: 2 bbs\n";
+        assert_eq!(&out[..header.len()], header.as_bytes());
+        assert_eq!(
+            &out[header.len()..],
+            &[0, 0, 0, 0, 4, 0, 0, 0, 0x10, 0, 0, 0, 8, 0, 0, 0]
+        );
+    }
+}
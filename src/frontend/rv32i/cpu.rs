@@ -1,21 +1,83 @@
+#[cfg(target_arch = "x86_64")]
+use crate::backend::x86_64::{Jit, JitStats};
+
+use super::block_cache::BlockCache;
+use super::bbv::BbvProfiler;
+use super::boot;
+use super::branch::{BranchConfig, BranchPredictor, BranchProfiler};
+use super::cache::{Access, Cache, CacheConfig, CacheError, CacheStats};
+use super::coverage::{Coverage, Module};
+use super::csr;
+use super::devices::clic::{Clic, ClicInterrupt, CLIC_BASE, CLIC_SIZE};
+use super::devices::clint::{Clint, CLINT_BASE, CLINT_SIZE};
+use super::devices::plic::{Plic, PLIC_BASE, PLIC_SIZE};
+use super::devices::uart::{Uart, UART_BASE, UART_IRQ, UART_SIZE};
+use super::devices::virtio::{
+    VirtioDevice, VirtioMmio, VIRTIO_IRQ_BASE, VIRTIO_MMIO_BASE, VIRTIO_MMIO_SIZE,
+    VIRTIO_MMIO_SLOTS,
+};
+use super::devices::clic::CLIC_FIRST_EXTERNAL;
+use super::disasm;
+use super::dwarf::LineTable;
+use super::fdt;
 use super::instructions;
+use super::lift::{self, LiftCache};
+use super::machine::{InterruptMode, MachineConfig};
 use super::mem;
+use super::profile::CallProfiler;
+use super::recompile::{LoadedCode, Recompiled};
 use super::registers;
+use super::sbi::Sbi;
+pub use super::snapshot::Snapshot;
+use super::symbols::{SymbolTable, Symbolized};
+use super::threaded::ThreadedOp;
+use super::timing::{Pipeline, PipelineConfig, PipelineStats};
+use super::trace::{MemoryAccess, MemoryTracer, TraceKind, TracingMem};
+use super::unwind::{Frame, Unwinder};
+use crate::backend::interpreter::{Event, Outcome};
+
+use csr::PrivilegeMode;
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::{Arc, Mutex};
 
-use instructions::Executable;
 use instructions::{DecodeError, ExecuteError, Instruction, ExecuteStatus};
 use mem::Mem;
 use mem::MemoryError;
 
-use xmas_elf::{ElfFile, header, program};
+use xmas_elf::ElfFile;
 use xmas_elf::sections;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum CPUError {
     FetchError,
     DecodeError(DecodeError),
     ExecuteError(ExecuteError),
-    MemoryError(MemoryError)
+    MemoryError(MemoryError),
+    /// An error `run` stopped on, with where it happened
+    Crash(Box<CrashReport>),
+    /// A snapshot taken on a hart with other interrupt controllers
+    SnapshotMismatch,
+}
+
+/// Where a hart was when an error stopped it, see `CPU::run`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrashReport {
+    pub error: CPUError,
+    /// The pc, symbolized with the loaded binary's symbols and source lines
+    pub pc: Symbolized,
+    /// The call stack, symbolized, innermost frame first
+    pub backtrace: Vec<Symbolized>,
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} at {}", self.error, self.pc)?;
+        for (index, frame) in self.backtrace.iter().enumerate() {
+            write!(f, "\n  #{} {}", index, frame)?;
+        }
+        Ok(())
+    }
 }
 
 impl From<DecodeError> for CPUError {
@@ -36,7 +98,11 @@ impl From<MemoryError> for CPUError {
     }
 }
 
-type CPUResult<T> = Result<T, CPUError>;
+pub type CPUResult<T> = Result<T, CPUError>;
+
+/// Instructions `CPU::fast_forward` leaves to the interpreter, more than any compiled or lifted
+/// code runs at once
+const FAST_FORWARD_MARGIN: u64 = 1024;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CPUStatus {
@@ -53,18 +119,281 @@ impl From<ExecuteStatus> for CPUStatus {
     }
 }
 
+/// ECALL, the only instruction with its encoding
+const ECALL_WORD: u32 = 0x0000_0073;
+
+/// Interrupts in the order they are taken when several are pending at once
+const INTERRUPT_PRIORITY: [u32; 6] = [
+    csr::IRQ_M_EXT,
+    csr::IRQ_M_SOFT,
+    csr::IRQ_M_TIMER,
+    csr::IRQ_S_EXT,
+    csr::IRQ_S_SOFT,
+    csr::IRQ_S_TIMER,
+];
+
+/// Exceptions delegated to S-mode when the built-in SBI starts a kernel: misaligned fetch,
+/// breakpoint, ecall from U-mode and the page faults, like OpenSBI
+const SUPERVISOR_EXCEPTIONS: u32 = (1 << 0) | (1 << 3) | (1 << csr::EXC_ECALL_U) | (1 << 12) | (1 << 13) | (1 << 15);
+
 #[derive(Debug, Clone)]
 pub struct CPU {
     registers: registers::RV32Registers,
+    csrs: csr::CSRFile,
+    privilege: PrivilegeMode,
+    /// Set by WFI, the hart stalls until an enabled interrupt becomes pending
+    waiting: bool,
+    /// Number of harts in the machine, all of them are described in the device tree
+    num_harts: u32,
     memory: mem::RAM,
+    /// Decoded basic blocks, `None` when every instruction is fetched and decoded
+    block_cache: Option<BlockCache>,
+    /// Lifted blocks run on the IR interpreter, when enabled
+    lift_cache: Option<LiftCache>,
+    /// Code recompiled ahead of time, run instead of anything else where it covers the pc
+    recompiled: Option<LoadedCode>,
+    /// Pipeline timing model, when cycles are estimated instead of counted one per instruction
+    timing: Option<Pipeline>,
+    /// L1 instruction cache model, looked up by every fetch from RAM
+    icache: Option<Cache>,
+    /// L1 data cache model, looked up by every load and store to RAM
+    dcache: Option<Cache>,
+    /// Branch predictors shown every branch and jump
+    branches: Option<BranchProfiler>,
+    /// Basic block vectors of the instructions executed
+    bbv: Option<BbvProfiler>,
+    /// Records every fetch, load and store
+    tracer: Option<MemoryTracer>,
+    /// Fetch, loads and stores of the instruction being executed, for the trace and the caches
+    accesses: RefCell<Vec<MemoryAccess>>,
+    /// Function profile of the instructions executed
+    call_profile: Option<CallProfiler>,
+    /// Basic blocks and instructions executed
+    coverage: Option<Coverage>,
+    /// Code symbols of the loaded binary
+    symbols: SymbolTable,
+    /// Source lines of the loaded binary
+    lines: LineTable,
+    /// Where the loaded binary is, for coverage files
+    image: Option<Module>,
+    /// Call frame information of the loaded binary
+    unwinder: Unwinder,
+    /// Compiles hot code to host code when enabled
+    #[cfg(target_arch = "x86_64")]
+    jit: Option<Jit>,
+    reset_vector: u32,
+    clint: Option<Arc<Mutex<Clint>>>,
+    plic: Option<Arc<Mutex<Plic>>>,
+    /// In CLIC mode interrupts come from here instead of mip/mie
+    clic: Option<Arc<Mutex<Clic>>>,
+    uart: Option<Arc<Mutex<Uart>>>,
+    /// Advance the devices with every instruction. Harts of a `Machine` share their devices, the
+    /// machine advances them instead.
+    ticks_devices: bool,
+    /// When set, ecalls from S-mode are serviced here instead of halting the hart. All harts of a
+    /// machine share it.
+    sbi: Option<Arc<Mutex<Sbi>>>,
+    /// M-mode ecalls halt the hart instead of trapping
+    halt_on_ecall: bool,
+    /// Start and end of the initramfs, passed to the kernel through /chosen
+    initrd: Option<(u32, u32)>,
+    /// (base, interrupt) of each attached virtio-mmio transport, in slot order
+    virtio: Vec<(u32, u32)>,
 }
 
 impl CPU {
     pub fn new(memory_base: u32, memory_size: u32) -> Self {
         CPU {
             registers: registers::RV32Registers::new(),
+            csrs: csr::CSRFile::new(0),
+            privilege: PrivilegeMode::Machine,
+            waiting: false,
+            num_harts: 1,
             memory: mem::RAM::new(memory_base, memory_size),
+            block_cache: Some(BlockCache::new()),
+            lift_cache: None,
+            recompiled: None,
+            timing: None,
+            icache: None,
+            dcache: None,
+            branches: None,
+            bbv: None,
+            tracer: None,
+            accesses: RefCell::new(Vec::new()),
+            call_profile: None,
+            coverage: None,
+            symbols: SymbolTable::default(),
+            lines: LineTable::default(),
+            image: None,
+            unwinder: Unwinder::default(),
+            #[cfg(target_arch = "x86_64")]
+            jit: None,
+            reset_vector: memory_base,
+            clint: None,
+            plic: None,
+            clic: None,
+            uart: None,
+            ticks_devices: true,
+            sbi: None,
+            halt_on_ecall: true,
+            initrd: None,
+            virtio: Vec::new(),
+        }
+    }
+
+    /// Build a hart with memory and interrupt controllers attached as described by `config`
+    pub fn with_config(config: &MachineConfig) -> Self {
+        let mut cpu = CPU::new(config.memory_base, config.memory_size);
+        cpu.reset_vector = config.reset_vector;
+        cpu.num_harts = config.harts;
+        cpu.set_block_cache(config.block_cache);
+        cpu.set_ir(config.ir);
+        cpu.set_timing(config.timing);
+        cpu.set_caches(config.icache, config.dcache)
+            .expect("invalid cache geometry");
+        cpu.set_branch_prediction(config.branch_prediction.as_ref());
+        cpu.set_bbv(config.bbv_interval);
+        cpu.set_call_profile(config.call_profile);
+        cpu.set_coverage(config.coverage);
+        #[cfg(target_arch = "x86_64")]
+        {
+            if config.jit {
+                cpu.jit = Some(Jit::new(config.jit_threshold));
+            }
+        }
+
+        // The CLINT provides mtime/mtimecmp in both interrupt modes
+        let clint = Arc::new(Mutex::new(Clint::new(config.harts)));
+        cpu.memory.attach_device(CLINT_BASE, CLINT_SIZE, clint.clone()).unwrap();
+        cpu.clint = Some(clint);
+
+        match config.interrupt_mode {
+            InterruptMode::Clint => {
+                if config.plic_sources > 0 {
+                    let plic = Arc::new(Mutex::new(Plic::new(config.plic_sources, config.harts)));
+                    cpu.memory.attach_device(PLIC_BASE, PLIC_SIZE, plic.clone()).unwrap();
+                    cpu.plic = Some(plic);
+                }
+            }
+            InterruptMode::Clic => {
+                let clic = Arc::new(Mutex::new(Clic::new(
+                    config.clic_interrupts,
+                    config.clic_ctl_bits,
+                )));
+                cpu.memory.attach_device(CLIC_BASE, CLIC_SIZE, clic.clone()).unwrap();
+                cpu.clic = Some(clic);
+            }
+        }
+
+        if config.sbi {
+            cpu.sbi = Some(Arc::new(Mutex::new(Sbi::stdout(config.harts))));
+        }
+        cpu.halt_on_ecall = config.halt_on_ecall;
+
+        if config.uart {
+            let mut uart = Uart::stdout();
+            if let Some(plic) = &cpu.plic {
+                let plic = plic.lock().unwrap();
+                if UART_IRQ <= plic.num_sources() {
+                    uart.set_irq_line(plic.irq_line(UART_IRQ));
+                }
+            }
+            let uart = Arc::new(Mutex::new(uart));
+            cpu.memory.attach_device(UART_BASE, UART_SIZE, uart.clone()).unwrap();
+            cpu.uart = Some(uart);
         }
+
+        cpu
+    }
+
+    /// Another hart of the machine this hart is part of, with its own registers and CSRs but the
+    /// same devices. It has no memory of its own, `Machine` lends it the shared memory while it
+    /// runs. The CLIC only delivers interrupts to hart 0, so other harts are not connected to it.
+    pub fn new_hart(&self, hartid: u32) -> CPU {
+        CPU {
+            registers: registers::RV32Registers::new(),
+            csrs: csr::CSRFile::new(hartid),
+            privilege: PrivilegeMode::Machine,
+            waiting: false,
+            num_harts: self.num_harts,
+            memory: mem::RAM::default(),
+            block_cache: self.block_cache.as_ref().map(|_| BlockCache::new()),
+            lift_cache: self.lift_cache.as_ref().map(|_| LiftCache::new()),
+            recompiled: self.recompiled.clone(),
+            timing: self
+                .timing
+                .as_ref()
+                .map(|pipeline| Pipeline::new(pipeline.get_config())),
+            icache: self.icache.as_ref().map(|cache| Cache::new(cache.get_config()).unwrap()),
+            dcache: self.dcache.as_ref().map(|cache| Cache::new(cache.get_config()).unwrap()),
+            branches: self.branches.as_ref().map(|profiler| profiler.fresh()),
+            bbv: self
+                .bbv
+                .as_ref()
+                .map(|bbv| BbvProfiler::new(bbv.get_interval(), 0)),
+            tracer: self.tracer.clone(),
+            accesses: RefCell::new(Vec::new()),
+            call_profile: self.call_profile.as_ref().map(|_| CallProfiler::new()),
+            coverage: self.coverage.as_ref().map(|_| Coverage::new()),
+            symbols: self.symbols.clone(),
+            lines: self.lines.clone(),
+            image: self.image.clone(),
+            unwinder: self.unwinder.clone(),
+            #[cfg(target_arch = "x86_64")]
+            jit: self.jit.clone(),
+            reset_vector: self.reset_vector,
+            clint: self.clint.clone(),
+            plic: self.plic.clone(),
+            clic: None,
+            uart: self.uart.clone(),
+            ticks_devices: self.ticks_devices,
+            sbi: self.sbi.clone(),
+            halt_on_ecall: self.halt_on_ecall,
+            initrd: self.initrd,
+            virtio: self.virtio.clone(),
+        }
+    }
+
+    /// Attach `device` behind a virtio-mmio transport in the next free slot. Its interrupt goes to
+    /// PLIC source `VIRTIO_IRQ_BASE + slot`, or CLIC interrupt `CLIC_FIRST_EXTERNAL + slot`.
+    pub fn attach_virtio<D: VirtioDevice + 'static>(
+        &mut self,
+        device: D,
+    ) -> CPUResult<Arc<Mutex<VirtioMmio<D>>>> {
+        let slot = self.virtio.len() as u32;
+        if slot >= VIRTIO_MMIO_SLOTS {
+            return Err(CPUError::MemoryError(MemoryError::AlreadyMappedRegion));
+        }
+        let base = VIRTIO_MMIO_BASE + slot * VIRTIO_MMIO_SIZE;
+
+        let mut mmio = VirtioMmio::new(device);
+        let irq = if let Some(plic) = &self.plic {
+            let plic = plic.lock().unwrap();
+            let source = VIRTIO_IRQ_BASE + slot;
+            if source <= plic.num_sources() {
+                mmio.set_irq_line(plic.irq_line(source));
+            }
+            source
+        } else if let Some(clic) = &self.clic {
+            let clic = clic.lock().unwrap();
+            let id = CLIC_FIRST_EXTERNAL + slot;
+            if id < clic.num_interrupts() {
+                mmio.set_irq_line(clic.irq_line(id));
+            }
+            id
+        } else {
+            0
+        };
+
+        let mmio = Arc::new(Mutex::new(mmio));
+        self.memory.attach_device(base, VIRTIO_MMIO_SIZE, mmio.clone())?;
+        self.virtio.push((base, irq));
+        Ok(mmio)
+    }
+
+    /// (base, interrupt) of each attached virtio-mmio transport
+    pub fn get_virtio(&self) -> &[(u32, u32)] {
+        &self.virtio
     }
 
     fn fetch(&self) -> CPUResult<u32> {
@@ -72,16 +401,525 @@ impl CPU {
         Ok(fetch)
     }
 
-    fn decode(&self, instr: u32) -> CPUResult<Instruction> {
-        let decode = Instruction::decode(instr)?;
+    fn decode(&self, instr: u32) -> CPUResult<ThreadedOp> {
+        let decode = ThreadedOp::decode(instr)?;
         Ok(decode)
     }
 
-    fn execute(&mut self, instr: Instruction) -> CPUResult<CPUStatus> {
-        let res = instr.execute(self)?;
+    /// Execute the instruction at the pc, taking it from the block cache when that is enabled
+    fn fetch_execute(&mut self) -> CPUResult<ExecuteStatus> {
+        let pc = self.registers.get_pc();
+        let word = if self.per_instruction() {
+            self.memory.read_word(pc).ok()
+        } else {
+            None
+        };
+        let instr = word.and_then(|word| Instruction::decode(word).ok());
+        let op = match &mut self.block_cache {
+            Some(cache) => cache.fetch(pc, &mut self.memory)?.op,
+            None => {
+                let fetch = self.fetch()?;
+                self.decode(fetch)?
+            }
+        };
+        let accesses = self.accesses.get_mut();
+        accesses.clear();
+        if self.tracer.is_some() || self.icache.is_some() {
+            accesses.push(MemoryAccess {
+                kind: TraceKind::Fetch,
+                addr: pc,
+                size: 4,
+            });
+        }
+        let status = op.execute(self)?;
+        let next_pc = self.registers.get_pc();
+        let stall = self.memory_accesses();
+        if let Some(Instruction::FENCE_I) = instr {
+            if let Some(icache) = &mut self.icache {
+                icache.invalidate();
+            }
+        }
+        let cycles = match (&mut self.timing, instr) {
+            (Some(pipeline), Some(instr)) => {
+                let cycles = pipeline.execute(instr, pc, next_pc);
+                pipeline.stall(stall);
+                cycles
+            }
+            _ => 1,
+        };
+        if let (Some(profiler), Some(instr)) = (&mut self.branches, instr) {
+            profiler.observe(instr, pc, next_pc);
+        }
+        if let Some(bbv) = &mut self.bbv {
+            bbv.observe(pc, next_pc);
+        }
+        if let (Some(profile), Some(instr)) = (&mut self.call_profile, instr) {
+            profile.observe(instr, pc, next_pc, cycles + stall);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.observe(pc, next_pc);
+        }
+        self.csrs.advance(cycles + stall, 1);
+        if let Some(word) = word {
+            self.count_events(pc, word);
+        }
+        Ok(status)
+    }
+
+    /// Whether instructions have to be looked at one by one, for event counters, timing, caches,
+    /// branch prediction, basic block vectors, memory traces, profiling or coverage
+    fn per_instruction(&self) -> bool {
+        self.timing.is_some()
+            || self.icache.is_some()
+            || self.dcache.is_some()
+            || self.branches.is_some()
+            || self.bbv.is_some()
+            || self.tracer.is_some()
+            || self.call_profile.is_some()
+            || self.coverage.is_some()
+            || self.csrs.counting_events()
+    }
+
+    /// Memory as loads and stores see it. Completed accesses are noted for the memory trace and
+    /// the data cache when either is enabled.
+    pub(crate) fn data_memory(&mut self) -> TracingMem<'_, mem::RAM> {
+        let accesses = if self.tracer.is_some() || self.dcache.is_some() {
+            Some(&self.accesses)
+        } else {
+            None
+        };
+        TracingMem::new(&mut self.memory, accesses)
+    }
+
+    /// Pass the fetch and the loads and stores the instruction completed to the memory trace and
+    /// the caches. Device accesses aren't cached. Returns the cycles cache misses cost.
+    fn memory_accesses(&mut self) -> u64 {
+        let mut stall = 0;
+        for access in self.accesses.get_mut().iter() {
+            if let Some(tracer) = &self.tracer {
+                tracer.record(access.kind, access.addr, access.size);
+            }
+            let (cache, kind) = match access.kind {
+                TraceKind::Fetch => (&mut self.icache, Access::Read),
+                TraceKind::Read => (&mut self.dcache, Access::Read),
+                TraceKind::Write => (&mut self.dcache, Access::Write),
+            };
+            if let Some(cache) = cache {
+                if self.memory.is_mapped(access.addr) {
+                    let misses = cache.access(access.addr, access.size, kind);
+                    stall += misses as u64 * cache.get_config().miss_penalty as u64;
+                }
+            }
+        }
+        stall
+    }
+
+    /// Model L1 instruction and data caches with these configurations, `None` to go without.
+    /// Misses then add their penalty to mcycle and the pipeline timing.
+    pub fn set_caches(
+        &mut self,
+        icache: Option<CacheConfig>,
+        dcache: Option<CacheConfig>,
+    ) -> Result<(), CacheError> {
+        self.icache = icache.map(Cache::new).transpose()?;
+        self.dcache = dcache.map(Cache::new).transpose()?;
+        Ok(())
+    }
+
+    pub fn get_icache_stats(&self) -> Option<CacheStats> {
+        self.icache.as_ref().map(|cache| cache.get_stats())
+    }
+
+    pub fn get_dcache_stats(&self) -> Option<CacheStats> {
+        self.dcache.as_ref().map(|cache| cache.get_stats())
+    }
+
+    /// Show every branch and jump to these predictors, or stop with `None`
+    pub fn set_branch_prediction(&mut self, config: Option<&BranchConfig>) {
+        self.branches = config.map(BranchProfiler::new);
+    }
+
+    /// Compare another predictor with the configured ones, starting branch prediction with no
+    /// other predictor and the default return-address stack when it is off
+    pub fn add_branch_predictor(&mut self, predictor: Box<dyn BranchPredictor>) {
+        let default = BranchConfig {
+            predictors: Vec::new(),
+            ..BranchConfig::default()
+        };
+        self.branches
+            .get_or_insert_with(|| BranchProfiler::new(&default))
+            .add_predictor(predictor);
+    }
+
+    pub fn get_branch_profiler(&self) -> Option<&BranchProfiler> {
+        self.branches.as_ref()
+    }
+
+    /// Collect basic block vectors in intervals of `interval` instructions from here on, or stop
+    /// with `None`
+    pub fn set_bbv(&mut self, interval: Option<u64>) {
+        let retired = self.csrs.get_retired();
+        self.bbv = interval.map(|interval| BbvProfiler::new(interval, retired));
+    }
+
+    pub fn get_bbv(&mut self) -> Option<&mut BbvProfiler> {
+        self.bbv.as_mut()
+    }
+
+    /// Record every instruction fetch, load and store with `tracer`, or stop with `None`. Harts
+    /// created from this one afterwards write to the same trace.
+    pub fn set_memory_trace(&mut self, tracer: Option<MemoryTracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn get_memory_trace(&self) -> Option<&MemoryTracer> {
+        self.tracer.as_ref()
+    }
+
+    /// Profile calls and the cost of every instruction from here on, for
+    /// `CallProfiler::write_callgrind`
+    pub fn set_call_profile(&mut self, enabled: bool) {
+        self.call_profile = if enabled {
+            Some(CallProfiler::new())
+        } else {
+            None
+        };
+    }
+
+    pub fn get_call_profile(&self) -> Option<&CallProfiler> {
+        self.call_profile.as_ref()
+    }
+
+    /// Record the code executed from here on, for `Coverage::write_drcov` and
+    /// `Coverage::write_lcov`
+    pub fn set_coverage(&mut self, enabled: bool) {
+        self.coverage = if enabled {
+            Some(Coverage::new())
+        } else {
+            None
+        };
+    }
+
+    pub fn get_coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Path and address range of the binary `load_elf` loaded last
+    pub fn get_image(&self) -> Option<&Module> {
+        self.image.as_ref()
+    }
+
+    /// Source lines of the binary `load_elf` loaded last, empty without DWARF line information
+    pub fn get_lines(&self) -> &LineTable {
+        &self.lines
+    }
+
+    /// Symbols of the binary `load_elf` loaded last
+    pub fn get_symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    /// Use `symbols` for code loaded some other way
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    /// The function and source line of `addr` in the binary `load_elf` loaded last
+    pub fn symbolize(&self, addr: u32) -> Symbolized {
+        self.symbols.symbolize(&self.lines, addr)
+    }
+
+    /// The guest's call stack, innermost frame first, unwound with the call frame information of
+    /// the binary `load_elf` loaded last, or by following frame pointers without it
+    pub fn backtrace(&self) -> Vec<Frame> {
+        let mut registers = [0; 32];
+        for (index, value) in registers.iter_mut().enumerate() {
+            *value = self.registers[index];
+        }
+        self.unwinder
+            .backtrace(&self.memory, self.registers.get_pc(), &registers)
+    }
+
+    /// `count` instructions from `start`, annotated with functions and source lines
+    pub fn disassemble(&self, start: u32, count: u32) -> String {
+        disasm::listing(&self.memory, &self.symbols, &self.lines, start, count)
+    }
+
+    /// Run until `retired` instructions retired since reset, e.g. to the start of a
+    /// `BbvInterval`. Returns `Halt` when the hart halts before. Most of the way is run as fast as
+    /// the configuration allows, the last instructions one at a time to stop exactly.
+    pub fn fast_forward(&mut self, retired: u64) -> CPUResult<CPUStatus> {
+        while self.csrs.get_retired() < retired {
+            let remaining = retired - self.csrs.get_retired();
+            if self.step_with(remaining > FAST_FORWARD_MARGIN)? == CPUStatus::Halt {
+                return Ok(CPUStatus::Halt);
+            }
+        }
+        Ok(CPUStatus::Continue)
+    }
+
+    /// Architectural state of the hart, the contents of RAM and the CLINT and PLIC state, to run
+    /// from here again later
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers,
+            csrs: self.csrs.clone(),
+            privilege: self.privilege,
+            waiting: self.waiting,
+            memory: self.memory.get_contents(),
+            clint: self.clint.as_ref().map(|clint| clint.lock().unwrap().clone()),
+            plic: self.plic.as_ref().map(|plic| plic.lock().unwrap().state()),
+        }
+    }
+
+    /// Go back to `snapshot`, taken on a hart with the same memory layout and interrupt
+    /// controllers. The UART and virtio devices keep their state, and timing, caches and
+    /// predictors carry on from where they are.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> CPUResult<()> {
+        let clint_fits = match (&self.clint, &snapshot.clint) {
+            (Some(clint), Some(saved)) => clint.lock().unwrap().num_harts() == saved.num_harts(),
+            (None, None) => true,
+            _ => false,
+        };
+        let plic_fits = match (&self.plic, &snapshot.plic) {
+            (Some(plic), Some(saved)) => plic.lock().unwrap().fits(saved),
+            (None, None) => true,
+            _ => false,
+        };
+        if !clint_fits || !plic_fits {
+            return Err(CPUError::SnapshotMismatch);
+        }
+        self.memory.set_contents(&snapshot.memory)?;
+        if let (Some(clint), Some(saved)) = (&self.clint, &snapshot.clint) {
+            *clint.lock().unwrap() = saved.clone();
+        }
+        if let (Some(plic), Some(saved)) = (&self.plic, &snapshot.plic) {
+            plic.lock().unwrap().set_state(saved);
+        }
+        self.memory.invalidate_interrupts();
+        self.registers = snapshot.registers;
+        self.csrs = snapshot.csrs.clone();
+        self.privilege = snapshot.privilege;
+        self.waiting = snapshot.waiting;
+        self.flush_block_cache();
+        Ok(())
+    }
+
+    /// Estimate cycles with a model of an in-order pipeline, or stop with `None`. mcycle then
+    /// follows the estimate.
+    pub fn set_timing(&mut self, config: Option<PipelineConfig>) {
+        self.timing = config.map(Pipeline::new);
+    }
+
+    pub fn get_timing_stats(&self) -> Option<PipelineStats> {
+        self.timing.as_ref().map(|pipeline| pipeline.get_stats())
+    }
+
+    /// Advance the hpmcounters for the events of the instruction `word` which ran at `pc`
+    fn count_events(&mut self, pc: u32, word: u32) {
+        let taken = self.registers.get_pc() != pc.wrapping_add(4);
+        let event = match word & 0x7F {
+            0b0000011 => csr::HPM_EVENT_LOAD,
+            0b0100011 => csr::HPM_EVENT_STORE,
+            0b1100011 if taken => csr::HPM_EVENT_BRANCH_TAKEN,
+            0b1101111 | 0b1100111 => csr::HPM_EVENT_JUMP,
+            0b1110011 if word == ECALL_WORD => csr::HPM_EVENT_ECALL,
+            _ => return,
+        };
+        self.csrs.count_event(event);
+    }
+
+    /// Read a CSR for a CSR instruction which goes on to write it when `write` is set. `None` when
+    /// the access isn't allowed from the current privilege mode or the CSR is read-only, the
+    /// instruction then raises an illegal instruction exception.
+    pub fn access_csr(&mut self, addr: u32, write: bool) -> Option<u32> {
+        if !self.csrs.accessible(addr, self.privilege, write) {
+            return None;
+        }
+        if let (csr::TIME | csr::TIMEH, Some(clint)) = (addr & 0xFFF, &self.clint) {
+            self.csrs.set_time(clint.lock().unwrap().get_mtime());
+        }
+        Some(self.csrs.read(addr))
+    }
+
+    /// Raise an illegal instruction exception for the instruction at the pc
+    pub fn illegal_instruction(&mut self) {
+        self.trap(csr::EXC_ILLEGAL_INSTRUCTION, 0);
+    }
+
+    pub fn set_block_cache(&mut self, enabled: bool) {
+        if enabled != self.block_cache.is_some() {
+            self.block_cache = if enabled { Some(BlockCache::new()) } else { None };
+        }
+    }
+
+    pub fn is_block_cache_enabled(&self) -> bool {
+        self.block_cache.is_some()
+    }
+
+    /// Run lifted blocks on the IR interpreter instead of interpreting instructions directly
+    pub fn set_ir(&mut self, enabled: bool) {
+        if enabled != self.lift_cache.is_some() {
+            self.lift_cache = if enabled { Some(LiftCache::new()) } else { None };
+        }
+    }
+
+    pub fn is_ir_enabled(&self) -> bool {
+        self.lift_cache.is_some()
+    }
+
+    /// Run the lifted block at the pc on the IR interpreter, if there is one. Devices are ticked
+    /// for each instruction it retired, like for compiled code.
+    fn ir_step(&mut self) -> CPUResult<Option<CPUStatus>> {
+        let pc = self.registers.get_pc();
+        let block = match &mut self.lift_cache {
+            Some(cache) => cache.fetch(pc, &mut self.memory),
+            None => None,
+        };
+        let block = match block {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+
+        let outcome = lift::run_block(self, &block)?;
+        self.finish_block(outcome).map(Some)
+    }
+
+    /// Run code from `recompile::recompile` where it has a block for the pc. It must have been
+    /// generated from what is in memory, blocks on pages written since are no longer used.
+    pub fn set_recompiled(&mut self, code: Option<Recompiled>) {
+        self.recompiled = code.map(|code| LoadedCode::new(code, &mut self.memory));
+    }
+
+    /// Run the recompiled block at the pc, if there is one
+    fn recompiled_step(&mut self) -> CPUResult<Option<CPUStatus>> {
+        let code = match self.recompiled.take() {
+            Some(code) => code,
+            None => return Ok(None),
+        };
+        let pc = self.registers.get_pc();
+        let outcome = code.run(self, pc);
+        self.recompiled = Some(code);
+        match outcome {
+            Some(outcome) => self.finish_block(outcome?).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Tick devices for the rest of the instructions a block on the IR retired, and handle
+    /// what it stopped for
+    fn finish_block(&mut self, outcome: Outcome) -> CPUResult<CPUStatus> {
+        let retired = outcome.retired as u64;
+        self.csrs.advance(retired, retired);
+        for _ in 1..outcome.retired {
+            self.tick_devices();
+        }
+        let status = match outcome.event {
+            Event::Continue => return Ok(CPUStatus::Continue),
+            Event::Syscall => ExecuteStatus::ECALL,
+            Event::Breakpoint => ExecuteStatus::EBREAK,
+            Event::Unlifted => {
+                if outcome.retired > 0 {
+                    self.tick_devices();
+                }
+                self.fetch_execute()?
+            }
+        };
+        self.complete(status)
+    }
+
+    /// Drop all decoded, lifted and compiled blocks, for FENCE.I
+    pub fn flush_block_cache(&mut self) {
+        if let Some(cache) = &mut self.block_cache {
+            cache.flush();
+        }
+        if let Some(cache) = &mut self.lift_cache {
+            cache.flush();
+        }
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(jit) = &mut self.jit {
+                jit.flush();
+            }
+        }
+    }
+
+    /// Compile code run more than `threshold` times to host code, or stop compiling with `None`
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_jit(&mut self, threshold: Option<u32>) {
+        self.jit = threshold.map(Jit::new);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn get_jit_stats(&self) -> Option<JitStats> {
+        self.jit.as_ref().map(|jit| jit.get_stats())
+    }
+
+    /// Run compiled code if there is some for the pc. Compiled code retires up to a few hundred
+    /// instructions at once, devices are ticked for each of them afterwards.
+    #[cfg(target_arch = "x86_64")]
+    fn jit_step(&mut self) -> CPUResult<Option<CPUStatus>> {
+        let mut jit = match self.jit.take() {
+            Some(jit) => jit,
+            None => return Ok(None),
+        };
+        let exit = jit.run(self);
+        self.jit = Some(jit);
+        let exit = match exit {
+            Some(exit) => exit,
+            None => return Ok(None),
+        };
+
+        let retired = exit.retired as u64;
+        self.csrs.advance(retired, retired);
+        // `step` already ticked the devices for the first instruction
+        for _ in 1..exit.retired {
+            self.tick_devices();
+        }
+        if !exit.fallback {
+            return Ok(Some(CPUStatus::Continue));
+        }
+        if exit.retired > 0 {
+            self.tick_devices();
+        }
+        let status = self.fetch_execute()?;
+        self.complete(status).map(Some)
+    }
+
+    /// Turn the status of an executed instruction into the hart status
+    fn complete(&mut self, res: ExecuteStatus) -> CPUResult<CPUStatus> {
+        if res == ExecuteStatus::ECALL && (self.sbi.is_some() || !self.halt_on_ecall) {
+            return Ok(self.environment_call());
+        }
         Ok(res.into())
     }
 
+    /// ECALL which doesn't simply halt the hart. With the built-in SBI enabled S-mode calls are
+    /// SBI calls. M-mode calls halt unless `halt_on_ecall` is cleared, everything else traps.
+    fn environment_call(&mut self) -> CPUStatus {
+        let cause = match self.privilege {
+            PrivilegeMode::Supervisor if self.sbi.is_some() => {
+                let sbi = self.sbi.clone().unwrap();
+                let status = sbi.lock().unwrap().call(self);
+                return status;
+            }
+            PrivilegeMode::Machine if self.halt_on_ecall => return CPUStatus::Halt,
+            PrivilegeMode::User => csr::EXC_ECALL_U,
+            PrivilegeMode::Supervisor => csr::EXC_ECALL_S,
+            PrivilegeMode::Machine => csr::EXC_ECALL_M,
+        };
+        // The pc was already moved past the ecall, the trap has to report the ecall itself
+        let pc = self.registers.get_pc();
+        self.registers.set_pc(pc - 4);
+        self.trap(cause, 0);
+        CPUStatus::Continue
+    }
+
+    /// Make M-mode ecalls trap to mtvec instead of halting the hart
+    pub fn set_halt_on_ecall(&mut self, halt: bool) {
+        self.halt_on_ecall = halt;
+    }
+
     pub fn get_registers(&mut self) -> &mut registers::RV32Registers {
         &mut self.registers
     }
@@ -90,6 +928,469 @@ impl CPU {
         &mut self.memory
     }
 
+    pub fn get_csrs(&mut self) -> &mut csr::CSRFile {
+        &mut self.csrs
+    }
+
+    pub fn get_clint(&self) -> Option<Arc<Mutex<Clint>>> {
+        self.clint.clone()
+    }
+
+    pub fn get_plic(&self) -> Option<Arc<Mutex<Plic>>> {
+        self.plic.clone()
+    }
+
+    pub fn get_clic(&self) -> Option<Arc<Mutex<Clic>>> {
+        self.clic.clone()
+    }
+
+    pub fn get_uart(&self) -> Option<Arc<Mutex<Uart>>> {
+        self.uart.clone()
+    }
+
+    pub fn get_sbi(&self) -> Option<Arc<Mutex<Sbi>>> {
+        self.sbi.clone()
+    }
+
+    pub fn get_initrd(&self) -> Option<(u32, u32)> {
+        self.initrd
+    }
+
+    pub fn set_initrd(&mut self, initrd: Option<(u32, u32)>) {
+        self.initrd = initrd;
+    }
+
+    pub fn get_num_harts(&self) -> u32 {
+        self.num_harts
+    }
+
+    pub fn get_hartid(&self) -> u32 {
+        self.csrs.get_hartid()
+    }
+
+    pub fn read_csr(&self, addr: u32) -> u32 {
+        self.csrs.read(addr)
+    }
+
+    /// (base, size) of every RAM segment, in address order
+    pub fn get_memory_segments(&self) -> Vec<(u32, u32)> {
+        self.memory.get_segments()
+    }
+
+    /// Generate a device tree for this machine and copy it to the end of the largest RAM segment,
+    /// returning the address of the blob
+    pub fn load_fdt(&mut self, bootargs: &str) -> CPUResult<u32> {
+        let blob = fdt::generate(self, bootargs);
+        let (base, size) = self
+            .memory
+            .get_segments()
+            .into_iter()
+            .max_by_key(|&(_, size)| size)
+            .ok_or(CPUError::MemoryError(MemoryError::UnmappedRegion))?;
+        if blob.len() as u32 > size {
+            return Err(CPUError::MemoryError(MemoryError::UnmappedRegion));
+        }
+
+        // The devicetree specification requires 8 byte alignment
+        let addr = (base + size - blob.len() as u32) & !0x7;
+        for (offset, byte) in blob.iter().enumerate() {
+            self.memory.write_byte(addr + offset as u32, *byte)?;
+        }
+        Ok(addr)
+    }
+
+    pub fn get_reset_vector(&self) -> u32 {
+        self.reset_vector
+    }
+
+    pub fn set_reset_vector(&mut self, addr: u32) {
+        self.reset_vector = addr;
+    }
+
+    /// Return the hart to its architectural reset state: registers cleared, machine mode, CSRs at
+    /// their reset values and the pc at the reset vector. Memory and devices are left untouched.
+    pub fn reset(&mut self) {
+        self.registers = registers::RV32Registers::new();
+        self.registers.set_pc(self.reset_vector);
+        self.csrs = csr::CSRFile::new(self.csrs.get_hartid());
+        self.privilege = PrivilegeMode::Machine;
+        self.waiting = false;
+    }
+
+    /// Write the boot ROM at the reset vector, mapping it first when it is outside of RAM, so that
+    /// the hart enters `entry` with a0 = hartid and a1 = `dtb` after `reset`
+    pub fn install_boot_rom(&mut self, entry: u32, dtb: u32) -> CPUResult<()> {
+        if !self.memory.is_mapped(self.reset_vector) {
+            self.memory.add_segment(self.reset_vector, boot::BOOT_ROM_SIZE)?;
+        }
+        for (index, word) in boot::boot_rom(entry, dtb).iter().enumerate() {
+            self.memory.write_word(self.reset_vector + index as u32 * 4, *word)?;
+        }
+        Ok(())
+    }
+
+    /// Follow the hardware boot sequence into a loaded image: generate and load the device tree,
+    /// install the boot ROM and reset the hart, returning the device tree address
+    pub fn boot(&mut self, entry: u32, bootargs: &str) -> CPUResult<u32> {
+        let dtb = self.load_fdt(bootargs)?;
+        self.install_boot_rom(entry, dtb)?;
+        self.reset();
+        if let Some(sbi) = &self.sbi {
+            sbi.lock().unwrap().boot(self.csrs.get_hartid());
+            self.enter_supervisor(entry, dtb);
+        }
+        Ok(dtb)
+    }
+
+    /// Do what machine mode firmware does before starting a kernel: delegate the supervisor
+    /// interrupts and the exceptions the kernel handles itself, give it the counters, then enter
+    /// `entry` in S-mode with a0 = hartid and a1 = `dtb`
+    pub fn enter_supervisor(&mut self, entry: u32, dtb: u32) {
+        self.csrs.write(csr::MIDELEG, csr::MIP_SSIP | csr::MIP_STIP | csr::MIP_SEIP);
+        self.csrs.write(csr::MEDELEG, SUPERVISOR_EXCEPTIONS);
+        // The kernel reads the counters itself, time in particular
+        self.csrs.write(csr::MCOUNTEREN, 0xFFFF_FFFF);
+        self.registers[10] = self.csrs.get_hartid();
+        self.registers[11] = dtb;
+        self.registers.set_pc(entry);
+        self.privilege = PrivilegeMode::Supervisor;
+        self.waiting = false;
+    }
+
+    pub fn get_interrupt_mode(&self) -> InterruptMode {
+        if self.clic.is_some() {
+            InterruptMode::Clic
+        } else {
+            InterruptMode::Clint
+        }
+    }
+
+    pub fn get_privilege(&self) -> PrivilegeMode {
+        self.privilege
+    }
+
+    pub fn is_waiting(&self) -> bool {
+        self.waiting
+    }
+
+    /// Enter a trap handler for `cause`, `pc` is the address of the instruction that is resumed by
+    /// xRET. Traps from below machine mode are taken in supervisor mode when delegated through
+    /// mideleg/medeleg.
+    pub fn trap(&mut self, cause: u32, tval: u32) {
+        let code = cause & !csr::MCAUSE_INTERRUPT;
+        let delegation = if cause & csr::MCAUSE_INTERRUPT != 0 {
+            self.csrs.read(csr::MIDELEG)
+        } else {
+            self.csrs.read(csr::MEDELEG)
+        };
+
+        if self.privilege != PrivilegeMode::Machine && code < 32 && delegation & (1 << code) != 0 {
+            self.trap_supervisor(cause, tval);
+        } else {
+            self.trap_machine(cause, tval);
+        }
+    }
+
+    fn trap_machine(&mut self, cause: u32, tval: u32) {
+        let pc = self.registers.get_pc();
+        let mstatus = self.csrs.read(csr::MSTATUS);
+
+        let mut new_status = mstatus & !(csr::MSTATUS_MPIE | csr::MSTATUS_MIE | csr::MSTATUS_MPP);
+        if mstatus & csr::MSTATUS_MIE != 0 {
+            new_status |= csr::MSTATUS_MPIE;
+        }
+        new_status |= (self.privilege as u32) << csr::MSTATUS_MPP_SHIFT;
+
+        self.csrs.write(csr::MSTATUS, new_status);
+        self.csrs.write(csr::MEPC, pc);
+        self.csrs.write(csr::MCAUSE, cause);
+        self.csrs.write(csr::MTVAL, tval);
+        self.privilege = PrivilegeMode::Machine;
+
+        let mtvec = self.csrs.read(csr::MTVEC);
+        self.jump_to_vector(mtvec, cause);
+    }
+
+    fn trap_supervisor(&mut self, cause: u32, tval: u32) {
+        let pc = self.registers.get_pc();
+        let sstatus = self.csrs.read(csr::SSTATUS);
+
+        let mut new_status = sstatus & !(csr::MSTATUS_SPIE | csr::MSTATUS_SIE | csr::MSTATUS_SPP);
+        if sstatus & csr::MSTATUS_SIE != 0 {
+            new_status |= csr::MSTATUS_SPIE;
+        }
+        if self.privilege == PrivilegeMode::Supervisor {
+            new_status |= csr::MSTATUS_SPP;
+        }
+
+        self.csrs.write(csr::SSTATUS, new_status);
+        self.csrs.write(csr::SEPC, pc);
+        self.csrs.write(csr::SCAUSE, cause);
+        self.csrs.write(csr::STVAL, tval);
+        self.privilege = PrivilegeMode::Supervisor;
+
+        let stvec = self.csrs.read(csr::STVEC);
+        self.jump_to_vector(stvec, cause);
+    }
+
+    fn jump_to_vector(&mut self, tvec: u32, cause: u32) {
+        // CLIC mode handlers are 64 byte aligned, the mode bits take up the low bits
+        if tvec & 0b11 == csr::MTVEC_MODE_CLIC {
+            self.registers.set_pc(tvec & !0x3F);
+            return;
+        }
+
+        let base = tvec & !0b11;
+        // Vectored mode only applies to interrupts, exceptions always use the base address
+        if tvec & 0b11 == 1 && cause & csr::MCAUSE_INTERRUPT != 0 {
+            self.registers.set_pc(base + 4 * (cause & !csr::MCAUSE_INTERRUPT));
+        } else {
+            self.registers.set_pc(base);
+        }
+    }
+
+    /// Return from a machine mode trap, used by MRET. Outside of M-mode MRET is illegal.
+    pub fn trap_return(&mut self) {
+        if self.privilege != PrivilegeMode::Machine {
+            return self.illegal_instruction();
+        }
+        let mstatus = self.csrs.read(csr::MSTATUS);
+
+        let mut new_status = mstatus & !(csr::MSTATUS_MIE | csr::MSTATUS_MPP);
+        if mstatus & csr::MSTATUS_MPIE != 0 {
+            new_status |= csr::MSTATUS_MIE;
+        }
+        new_status |= csr::MSTATUS_MPIE;
+        self.csrs.write(csr::MSTATUS, new_status);
+
+        self.privilege = PrivilegeMode::from((mstatus & csr::MSTATUS_MPP) >> csr::MSTATUS_MPP_SHIFT);
+        if self.clic.is_some() {
+            let mcause = self.csrs.read(csr::MCAUSE);
+            self.csrs
+                .set_interrupt_level((mcause & csr::MCAUSE_MPIL) >> csr::MCAUSE_MPIL_SHIFT);
+        }
+        let mepc = self.csrs.read(csr::MEPC);
+        self.registers.set_pc(mepc);
+    }
+
+    /// Return from a supervisor mode trap, used by SRET. In U-mode SRET is illegal.
+    pub fn supervisor_trap_return(&mut self) {
+        if self.privilege == PrivilegeMode::User {
+            return self.illegal_instruction();
+        }
+        let sstatus = self.csrs.read(csr::SSTATUS);
+
+        let mut new_status = sstatus & !(csr::MSTATUS_SIE | csr::MSTATUS_SPP);
+        if sstatus & csr::MSTATUS_SPIE != 0 {
+            new_status |= csr::MSTATUS_SIE;
+        }
+        new_status |= csr::MSTATUS_SPIE;
+        self.csrs.write(csr::SSTATUS, new_status);
+
+        self.privilege = if sstatus & csr::MSTATUS_SPP != 0 {
+            PrivilegeMode::Supervisor
+        } else {
+            PrivilegeMode::User
+        };
+        let sepc = self.csrs.read(csr::SEPC);
+        self.registers.set_pc(sepc);
+    }
+
+    /// Stall the hart until an interrupt enabled in mie, or in the CLIC's clicintie registers in
+    /// CLIC mode, is pending. If nothing is enabled the hart could never wake up again, so WFI is
+    /// treated as a NOP as the specification permits.
+    pub fn wait_for_interrupt(&mut self) {
+        let enabled = match &self.clic {
+            Some(clic) => clic.lock().unwrap().any_enabled(),
+            None => self.csrs.read(csr::MIE) != 0,
+        };
+        if enabled {
+            self.waiting = true;
+        }
+    }
+
+    /// Act on what the built-in SBI has for this hart: raise STIP once the deadline set through
+    /// the TIME extension has passed, SSIP for IPIs from other harts, drop code for a remote
+    /// FENCE.I and enter S-mode when started through HSM. Returns the status of the step while
+    /// the hart is stopped, or the whole system is halted.
+    fn poll_sbi(&mut self) -> Option<CPUStatus> {
+        let sbi = self.sbi.clone()?;
+        let mtime = self.clint.as_ref().map(|clint| clint.lock().unwrap().get_mtime());
+        let events = sbi.lock().unwrap().poll(self.csrs.get_hartid(), mtime);
+        if events.halt {
+            return Some(CPUStatus::Halt);
+        }
+        if let Some((entry, opaque)) = events.start {
+            self.reset();
+            self.enter_supervisor(entry, opaque);
+        }
+        if events.stopped {
+            self.csrs.advance(1, 0);
+            return Some(CPUStatus::Continue);
+        }
+        let mut mip = self.csrs.read(csr::MIP);
+        if events.timer {
+            mip |= csr::MIP_STIP;
+        }
+        if events.ipi {
+            mip |= csr::MIP_SSIP;
+        }
+        self.csrs.write(csr::MIP, mip);
+        if events.fence_i {
+            self.flush_block_cache();
+        }
+        None
+    }
+
+    /// Pull the interrupt lines from attached devices into mip and take the highest priority
+    /// interrupt if one is pending and enabled. Returns true if a trap was taken.
+    fn check_interrupts(&mut self) -> bool {
+        let hartid = self.csrs.get_hartid();
+        let lines = self.memory.pending_interrupts(hartid);
+        if let Some(clic) = self.clic.clone() {
+            return self.check_clic_interrupts(&clic, lines);
+        }
+        self.csrs.set_hardware_pending(lines);
+
+        let pending = self.csrs.pending_enabled();
+        if pending == 0 {
+            return false;
+        }
+        // Any enabled pending interrupt wakes a WFI, even when interrupts are globally disabled
+        self.waiting = false;
+
+        // Machine interrupts are always enabled below machine mode, supervisor interrupts are
+        // never taken while in machine mode
+        let mstatus = self.csrs.read(csr::MSTATUS);
+        let machine_enabled =
+            self.privilege != PrivilegeMode::Machine || mstatus & csr::MSTATUS_MIE != 0;
+        let supervisor_enabled = match self.privilege {
+            PrivilegeMode::User => true,
+            PrivilegeMode::Supervisor => mstatus & csr::MSTATUS_SIE != 0,
+            PrivilegeMode::Machine => false,
+        };
+
+        let mideleg = self.csrs.read(csr::MIDELEG);
+        let mut takeable = 0;
+        if machine_enabled {
+            takeable |= pending & !mideleg;
+        }
+        if supervisor_enabled {
+            takeable |= pending & mideleg;
+        }
+
+        // Interrupts destined for machine mode take priority over delegated ones
+        for irqs in [takeable & !mideleg, takeable & mideleg].iter() {
+            for irq in INTERRUPT_PRIORITY.iter() {
+                if irqs & (1 << irq) != 0 {
+                    self.trap(csr::MCAUSE_INTERRUPT | irq, 0);
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// CLIC mode interrupt selection, an interrupt preempts the running code when its level is
+    /// above both mintstatus.mil and mintthresh.
+    fn check_clic_interrupts(&mut self, clic: &Arc<Mutex<Clic>>, lines: u32) -> bool {
+        let irq = {
+            let mut clic = clic.lock().unwrap();
+            clic.set_local_interrupts(lines);
+            match clic.highest_pending() {
+                Some(irq) => irq,
+                None => return false,
+            }
+        };
+        self.waiting = false;
+
+        let mstatus = self.csrs.read(csr::MSTATUS);
+        if self.privilege == PrivilegeMode::Machine && mstatus & csr::MSTATUS_MIE == 0 {
+            return false;
+        }
+        let current_level = self.csrs.get_interrupt_level();
+        let threshold = self.csrs.read(csr::MINTTHRESH);
+        if irq.level <= current_level.max(threshold) {
+            return false;
+        }
+
+        clic.lock().unwrap().acknowledge(irq.id);
+        self.take_clic_interrupt(irq, current_level);
+        true
+    }
+
+    fn take_clic_interrupt(&mut self, irq: ClicInterrupt, previous_level: u32) {
+        let cause = csr::MCAUSE_INTERRUPT
+            | (previous_level << csr::MCAUSE_MPIL_SHIFT)
+            | (irq.id & csr::MCAUSE_EXCCODE);
+        self.trap_machine(cause, 0);
+        self.csrs.set_interrupt_level(irq.level);
+
+        // Selectively vectored interrupts jump through the mtvt table, others use the common
+        // handler at mtvec. A table entry which can't be read falls back to the common handler.
+        if irq.vectored {
+            let entry = self.csrs.read(csr::MTVT).wrapping_add(4 * irq.id);
+            if let Ok(handler) = self.memory.read_word(entry) {
+                self.registers.set_pc(handler & !1);
+            }
+        }
+    }
+
+    /// Stop advancing the devices in `step`, for harts whose devices are advanced by a `Machine`
+    pub fn set_ticks_devices(&mut self, ticks: bool) {
+        self.ticks_devices = ticks;
+    }
+
+    fn tick_devices(&mut self) {
+        if self.ticks_devices {
+            self.memory.tick_devices();
+        }
+    }
+
+    /// Advance the devices and execute a single instruction, unless an interrupt is taken or the
+    /// hart is stalled in WFI.
+    pub fn step(&mut self) -> CPUResult<CPUStatus> {
+        self.step_with(true)
+    }
+
+    /// `step`, only interpreting the instruction unless `blocks` allows running a whole block
+    fn step_with(&mut self, blocks: bool) -> CPUResult<CPUStatus> {
+        self.tick_devices();
+        if let Some(status) = self.poll_sbi() {
+            return Ok(status);
+        }
+        if self.check_interrupts() || self.waiting {
+            self.csrs.advance(1, 0);
+            if let Some(pipeline) = &mut self.timing {
+                pipeline.idle();
+            }
+            return Ok(CPUStatus::Continue);
+        }
+
+        // Events and timing are tracked instruction by instruction, which only the interpreter does
+        if blocks && !self.per_instruction() {
+            if let Some(status) = self.blocks_step()? {
+                return Ok(status);
+            }
+        }
+        let status = self.fetch_execute()?;
+        self.complete(status)
+    }
+
+    /// Run recompiled, compiled or lifted code for the pc, whichever exists first
+    fn blocks_step(&mut self) -> CPUResult<Option<CPUStatus>> {
+        if let Some(status) = self.recompiled_step()? {
+            return Ok(Some(status));
+        }
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(status) = self.jit_step()? {
+                return Ok(Some(status));
+            }
+        }
+        self.ir_step()
+    }
+
     pub fn load_image(&mut self, path: String) {
         use std::io::Read;
 
@@ -100,7 +1401,7 @@ impl CPU {
 
         while bytes_read != 0 {
             bytes_read = file.read(&mut buf).unwrap();
-            self.get_memory().write_byte(index, buf[0]);
+            self.get_memory().write_byte(index, buf[0]).unwrap();
             index += 1;
         }
     }
@@ -115,30 +1416,30 @@ impl CPU {
 
         while bytes_read != 0 {
             bytes_read = file.read(&mut buf).unwrap();
-            self.get_memory().write_byte(index, buf[0]);
+            self.get_memory().write_byte(index, buf[0]).unwrap();
             index += 1;
         }
     }
 
     pub fn load_elf(&mut self, path: String) -> u32 {
-        use std::io::Read;
-
-        let binary_blob = std::fs::read(path).expect("Can't read binary");
+        let binary_blob = std::fs::read(&path).expect("Can't read binary");
         let elf_file = ElfFile::new(&binary_blob).expect("What is happening");
         
         let mut sect_iter = elf_file.section_iter();
         sect_iter.next();
 
-        
+        let mut image: Option<Module> = None;
         for sect in sect_iter {
+            // Debug information and symbols aren't part of the program
+            if sect.flags() & sections::SHF_ALLOC == 0 {
+                continue;
+            }
             let section_type = sect.get_type();
 
-            if section_type.is_ok() {
-                if let Ok(result) = section_type {
-                    match result {
-                        sections::ShType::ProgBits => self.memory.add_segment(sect.address() as u32, sect.size() as u32).unwrap(),
-                        _ => {}
-                    }  
+            if let Ok(sections::ShType::ProgBits) = section_type {
+                // Sections inside already mapped RAM are loaded in place
+                if !self.memory.is_mapped(sect.address() as u32) {
+                    self.memory.add_segment(sect.address() as u32, sect.size() as u32).unwrap();
                 }
             }
 
@@ -147,40 +1448,59 @@ impl CPU {
 
             println!("{:?}", sect);
             let virt_addr = sect.address() as u32;
-            let mut virtual_offset = 0;
-            for byte in data {
-                self.get_memory().write_byte(virt_addr + virtual_offset, *byte);
-                virtual_offset += 1;
+            for (virtual_offset, byte) in data.iter().enumerate() {
+                self.get_memory().write_byte(virt_addr + virtual_offset as u32, *byte).unwrap();
             }
+
+            let end = virt_addr + sect.size() as u32;
+            let image = image.get_or_insert_with(|| Module {
+                path: path.clone(),
+                base: virt_addr,
+                end,
+            });
+            image.base = image.base.min(virt_addr);
+            image.end = image.end.max(end);
         }
 
+        self.image = image;
+        self.symbols = SymbolTable::from_elf(&elf_file);
+        // Malformed debug information only costs the source lines
+        self.lines = LineTable::from_elf(&elf_file).unwrap_or_default();
+        self.unwinder = Unwinder::from_elf(&elf_file);
         elf_file.header.pt2.entry_point() as u32
     }
 
+    /// Step until the hart halts. Other errors come back as a `CPUError::Crash` saying where
+    /// the hart was and how it got there.
     pub fn run(&mut self) -> CPUResult<CPUStatus> {
         loop {
-            println!("PC is : {:X}", self.get_registers().get_pc());
-            let fetch = self.fetch()?;
-            // println!("Fetch : {:X?}", fetch);
-            let decode = self.decode(fetch)?;
-            println!("Decode : {:X?}", decode);
-            if let Ok(CPUStatus::Continue) = self.execute(decode){
-                println!("{:?}", self.get_registers())
-            } else {
-                return Ok(CPUStatus::Halt)
+            println!("PC is : {}", self.symbolize(self.registers.get_pc()));
+            match self.step() {
+                Ok(CPUStatus::Continue) => println!("{:?}", self.get_registers()),
+                Ok(CPUStatus::Halt) => return Ok(CPUStatus::Halt),
+                Err(error) => return Err(self.crash(error)),
             }
         }
     }
 
+    /// `error` wrapped in a `CPUError::Crash` reporting where the hart stopped
+    pub(crate) fn crash(&self, error: CPUError) -> CPUError {
+        let backtrace = self
+            .backtrace()
+            .iter()
+            .map(|frame| self.symbolize(frame.pc))
+            .collect();
+        CPUError::Crash(Box::new(CrashReport {
+            error,
+            pc: self.symbolize(self.registers.get_pc()),
+            backtrace,
+        }))
+    }
+
     pub fn run_for_steps(&mut self, steps: usize) -> CPUResult<CPUStatus> {
         let mut step_result = Ok(CPUStatus::Continue);
         for _ in 0..steps {
-            // println!("PC is : {:X}", self.get_registers().get_pc());
-            let fetch = self.fetch()?;
-            // println!("Fetch : {:X?}", fetch);
-            let decode = self.decode(fetch)?;
-            // println!("Decode : {:X?}", decode);
-            step_result = self.execute(decode);
+            step_result = self.step();
         }
 
         step_result
@@ -195,19 +1515,853 @@ mod tests {
     fn cpu_fetch() {
         let mut cpu = CPU::new(0, 1024);
 
-        cpu.memory.write_word(0, 0x11335577);
+        cpu.memory.write_word(0, 0x11335577).unwrap();
         assert_eq!(cpu.fetch().unwrap(), 0x11335577);
     }
 
     #[test]
     fn cpu_execute() {
         let mut cpu = CPU::new(0, 1024);
-        cpu.memory.write_word(0, 0x7FF00193);
+        cpu.memory.write_word(0, 0x7FF00193).unwrap();
         let instr_fetch = cpu.fetch().unwrap();
         assert_eq!(instr_fetch, 0x7FF00193);
-        let instr_decode = cpu.decode(instr_fetch).unwrap();
-        assert_eq!(instr_decode, Instruction::ADDI(3, 0, 2047));
-        instr_decode.execute(&mut cpu);
+        assert_eq!(
+            Instruction::decode(instr_fetch).unwrap(),
+            Instruction::ADDI(3, 0, 2047)
+        );
+        cpu.decode(instr_fetch).unwrap().execute(&mut cpu).unwrap();
         assert_eq!(cpu.get_registers()[3], 2047);
     }
+
+    fn load_program(cpu: &mut CPU, program: &[u32]) {
+        for (index, instr) in program.iter().enumerate() {
+            cpu.memory.write_word(index as u32 * 4, *instr).unwrap();
+        }
+    }
+
+    #[test]
+    fn unmapped_load_crashes() {
+        let program = [
+            0x400002B7, // lui t0, 0x40000
+            0x0002A303, // lw t1, 0(t0)
+            0x00000073, // ecall
+        ];
+        let mut cpu = CPU::new(0, 1024);
+        load_program(&mut cpu, &program);
+        match cpu.run() {
+            Err(CPUError::Crash(report)) => {
+                assert_eq!(
+                    report.error,
+                    CPUError::ExecuteError(ExecuteError::MemoryError(MemoryError::UnmappedRegion))
+                );
+                assert_eq!(report.pc.addr, 4);
+            }
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn counters_follow_execution() {
+        use super::super::devices::clint::{Clint, CLINT_BASE, CLINT_SIZE};
+
+        let mut cpu = CPU::new(0, 1024);
+        let clint = Arc::new(Mutex::new(Clint::new(1)));
+        cpu.memory.attach_device(CLINT_BASE, CLINT_SIZE, clint.clone()).unwrap();
+        cpu.clint = Some(clint);
+        load_program(
+            &mut cpu,
+            &[
+                0x00128293, // addi t0, t0, 1
+                0x00128293, // addi t0, t0, 1
+                0xc0002573, // rdcycle a0
+                0xc02025f3, // rdinstret a1
+                0xc0102673, // rdtime a2
+                0x00000073, // ecall
+            ],
+        );
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.get_registers()[10], 2);
+        assert_eq!(cpu.get_registers()[11], 3);
+        // mtime advances once per step, before the instruction executes
+        assert_eq!(cpu.get_registers()[12], 5);
+        assert_eq!(cpu.csrs.read(csr::MINSTRET), 6);
+    }
+
+    #[test]
+    fn user_counters_need_counteren() {
+        let mut cpu = CPU::new(0, 1024);
+        load_program(&mut cpu, &[0xc0002573]); // rdcycle a0
+        cpu.csrs.write(csr::MTVEC, 0x40);
+        cpu.csrs.write(csr::MCOUNTEREN, 1 << csr::COUNTER_CY);
+        cpu.privilege = PrivilegeMode::User;
+        cpu.step().unwrap();
+
+        assert_eq!(cpu.get_registers().get_pc(), 0x40);
+        assert_eq!(cpu.csrs.read(csr::MCAUSE), csr::EXC_ILLEGAL_INSTRUCTION);
+        assert_eq!(cpu.csrs.read(csr::MEPC), 0);
+
+        cpu.csrs.write(csr::SCOUNTEREN, 1 << csr::COUNTER_CY);
+        cpu.privilege = PrivilegeMode::User;
+        cpu.get_registers().set_pc(0);
+        cpu.step().unwrap();
+        assert_eq!(cpu.get_registers().get_pc(), 4);
+        assert_eq!(cpu.get_registers()[10], 1);
+    }
+
+    #[test]
+    fn csr_privilege_is_checked() {
+        let cases = [
+            (PrivilegeMode::User, 0x30551073, false), // csrw mtvec, a0
+            (PrivilegeMode::User, 0x14002573, false), // csrr a0, sscratch
+            (PrivilegeMode::Supervisor, 0x30002573, false), // csrr a0, mstatus
+            (PrivilegeMode::Supervisor, 0x34151073, false), // csrw mepc, a0
+            (PrivilegeMode::Supervisor, 0x14051073, true), // csrw sscratch, a0
+            (PrivilegeMode::Machine, 0xf1402573, true), // csrr a0, mhartid
+            (PrivilegeMode::Machine, 0xf1451073, false), // csrw mhartid, a0
+        ];
+        for &(privilege, instr, allowed) in cases.iter() {
+            let mut cpu = CPU::new(0, 1024);
+            load_program(&mut cpu, &[instr]);
+            cpu.csrs.write(csr::MTVEC, 0x40);
+            cpu.privilege = privilege;
+            cpu.step().unwrap();
+
+            let pc = cpu.get_registers().get_pc();
+            if allowed {
+                assert_eq!(pc, 4, "{:08x} in {:?}", instr, privilege);
+            } else {
+                assert_eq!(pc, 0x40, "{:08x} in {:?}", instr, privilege);
+                assert_eq!(cpu.csrs.read(csr::MCAUSE), csr::EXC_ILLEGAL_INSTRUCTION);
+                assert_eq!(cpu.csrs.read(csr::MTVAL), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn trap_returns_need_privilege() {
+        let cases = [
+            (PrivilegeMode::User, 0x30200073, false), // mret
+            (PrivilegeMode::Supervisor, 0x30200073, false),
+            (PrivilegeMode::Machine, 0x30200073, true),
+            (PrivilegeMode::User, 0x10200073, false), // sret
+            (PrivilegeMode::Supervisor, 0x10200073, true),
+            (PrivilegeMode::Machine, 0x10200073, true),
+        ];
+        for &(privilege, instr, allowed) in cases.iter() {
+            let mut cpu = CPU::new(0, 1024);
+            load_program(&mut cpu, &[instr]);
+            cpu.csrs.write(csr::MTVEC, 0x40);
+            cpu.csrs.write(csr::MEPC, 0x80);
+            cpu.csrs.write(csr::SEPC, 0x80);
+            cpu.privilege = privilege;
+            cpu.step().unwrap();
+
+            let pc = cpu.get_registers().get_pc();
+            if allowed {
+                assert_eq!(pc, 0x80, "{:08x} in {:?}", instr, privilege);
+            } else {
+                assert_eq!(pc, 0x40, "{:08x} in {:?}", instr, privilege);
+                assert_eq!(cpu.csrs.read(csr::MCAUSE), csr::EXC_ILLEGAL_INSTRUCTION);
+                assert_eq!(cpu.csrs.read(csr::MEPC), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn ecall_traps_unless_halting() {
+        for &(privilege, cause) in [
+            (PrivilegeMode::Machine, csr::EXC_ECALL_M),
+            (PrivilegeMode::Supervisor, csr::EXC_ECALL_S),
+            (PrivilegeMode::User, csr::EXC_ECALL_U),
+        ]
+        .iter()
+        {
+            let mut cpu = CPU::new(0, 1024);
+            load_program(&mut cpu, &[0x00000013, 0x00000073]); // nop; ecall
+            cpu.csrs.write(csr::MTVEC, 0x40);
+            cpu.set_halt_on_ecall(false);
+            cpu.privilege = privilege;
+            assert_eq!(cpu.step().unwrap(), CPUStatus::Continue);
+            assert_eq!(cpu.step().unwrap(), CPUStatus::Continue);
+
+            assert_eq!(cpu.get_registers().get_pc(), 0x40);
+            assert_eq!(cpu.csrs.read(csr::MCAUSE), cause);
+            assert_eq!(cpu.csrs.read(csr::MEPC), 4);
+            assert_eq!(cpu.privilege, PrivilegeMode::Machine);
+        }
+
+        let mut cpu = CPU::new(0, 1024);
+        load_program(&mut cpu, &[0x00000073]);
+        assert_eq!(cpu.step().unwrap(), CPUStatus::Halt);
+    }
+
+    #[test]
+    fn hpm_counts_events() {
+        let mut cpu = CPU::new(0, 1024);
+        // Lifted blocks can't count events, the interpreter has to take over
+        cpu.set_ir(true);
+        load_program(
+            &mut cpu,
+            &[
+                0x10000293, // li t0, 0x100
+                0x0052a023, // sw t0, 0(t0)
+                0x0002a303, // lw t1, 0(t0)
+                0x00530463, // beq t1, t0, 0x14
+                0x00000013, // nop
+                0x0080006f, // j 0x1c
+                0x00000013, // nop
+                0x00000073, // ecall
+            ],
+        );
+        let events = [
+            csr::HPM_EVENT_LOAD,
+            csr::HPM_EVENT_STORE,
+            csr::HPM_EVENT_BRANCH_TAKEN,
+            csr::HPM_EVENT_JUMP,
+            csr::HPM_EVENT_ECALL,
+        ];
+        for (index, event) in events.iter().enumerate() {
+            cpu.csrs.write(csr::MHPMEVENT3 + index as u32, *event);
+        }
+        cpu.run().unwrap();
+
+        for index in 0..events.len() as u32 {
+            assert_eq!(cpu.csrs.read(csr::MHPMCOUNTER3 + index), 1);
+        }
+        assert_eq!(cpu.csrs.read(csr::MINSTRET), 6);
+    }
+
+    #[test]
+    fn pipeline_timing() {
+        let program = [
+            0x00300293, // li t0, 3
+            0x10002303, // loop: lw t1, 0x100(zero)
+            0x00130313, // addi t1, t1, 1
+            0x10602023, // sw t1, 0x100(zero)
+            0xfff28293, // addi t0, t0, -1
+            0xfe0298e3, // bnez t0, loop
+            0xb0002573, // csrr a0, mcycle
+            0x00000073, // ecall
+        ];
+        let run = |timing: Option<PipelineConfig>| {
+            let mut cpu = CPU::new(0, 1024);
+            cpu.set_ir(true);
+            cpu.set_timing(timing);
+            load_program(&mut cpu, &program);
+            cpu.run().unwrap();
+            cpu
+        };
+
+        let mut functional = run(None);
+        let mut timed = run(Some(PipelineConfig::default()));
+        assert_eq!(functional.memory.read_word(0x100), Ok(3));
+        assert_eq!(timed.memory.read_word(0x100), Ok(3));
+
+        // 18 instructions, 4 to fill the pipeline, 3 load-use stalls and 2 taken branches
+        let stats = timed.get_timing_stats().unwrap();
+        assert_eq!(stats.instructions, 18);
+        assert_eq!(stats.load_use_stalls, 3);
+        assert_eq!(stats.flush_cycles, 4);
+        assert_eq!(stats.cycles, 18 + 4 + 3 + 4);
+        // mcycle was read before the last two instructions
+        assert_eq!(timed.get_registers()[10] as u64, stats.cycles - 2);
+        assert_eq!(functional.get_registers()[10], 16);
+        assert!(functional.get_timing_stats().is_none());
+    }
+
+    #[test]
+    fn branch_prediction_observes_branches() {
+        use super::super::branch::{PredictorKind, StaticPolicy};
+
+        let program = [
+            0x00500293, // li t0, 5
+            0x010000ef, // loop: call f
+            0xfff28293, // addi t0, t0, -1
+            0xfe029ce3, // bnez t0, loop
+            0x00000073, // ecall
+            0x00008067, // f: ret
+        ];
+        let mut cpu = CPU::new(0, 1024);
+        cpu.set_ir(true);
+        cpu.set_branch_prediction(Some(&BranchConfig {
+            predictors: vec![
+                PredictorKind::Static(StaticPolicy::NotTaken),
+                PredictorKind::Bimodal { entries: 16 },
+            ],
+            return_stack: 4,
+        }));
+        load_program(&mut cpu, &program);
+        cpu.run().unwrap();
+
+        let profiler = cpu.get_branch_profiler().unwrap();
+        let site = &profiler.get_sites()[&0xc];
+        assert_eq!((site.executed, site.taken), (5, 4));
+        assert_eq!(site.mispredictions, vec![4, 2]);
+        let ras = profiler.get_return_stack_stats();
+        assert_eq!((ras.calls, ras.returns, ras.mispredictions), (5, 5, 0));
+    }
+
+    #[test]
+    fn bbv_intervals_can_be_fast_forwarded_to() {
+        let program = [
+            0x00a00293, // li t0, 10
+            0x10002303, // loop: lw t1, 0x100(zero)
+            0x00130313, // addi t1, t1, 1
+            0x10602023, // sw t1, 0x100(zero)
+            0xfff28293, // addi t0, t0, -1
+            0xfe0298e3, // bnez t0, loop
+            0x00000073, // ecall
+        ];
+        let mut profiled = CPU::new(0, 1024);
+        profiled.set_bbv(Some(8));
+        load_program(&mut profiled, &program);
+        profiled.run().unwrap();
+        let bbv = profiled.get_bbv().unwrap();
+        bbv.finish();
+        let intervals = bbv.take_intervals();
+
+        // 52 instructions, the loop is block 1 on the first pass and block 2 after, the ecall
+        // belongs to the last pass
+        assert_eq!(intervals.len(), 7);
+        assert_eq!(intervals[0].to_string(), "T:1:6 :2:2");
+        assert_eq!(intervals[3].to_string(), "T:2:8");
+        assert_eq!(intervals[6].to_string(), "T:2:4");
+        assert_eq!(profiled.get_bbv().unwrap().get_blocks(), &[0x0, 0x4]);
+
+        let mut cpu = CPU::new(0, 1024);
+        cpu.set_ir(true);
+        load_program(&mut cpu, &program);
+        assert_eq!(cpu.fast_forward(intervals[3].start), Ok(CPUStatus::Continue));
+        let snapshot = cpu.snapshot();
+        assert_eq!(snapshot.get_retired(), 24);
+        // The li, four iterations, and the store of the fifth
+        assert_eq!((snapshot.get_pc(), cpu.memory.read_word(0x100)), (0x10, Ok(5)));
+
+        cpu.set_timing(Some(PipelineConfig::default()));
+        cpu.run().unwrap();
+        assert_eq!(cpu.memory.read_word(0x100), Ok(10));
+        cpu.restore(&snapshot).unwrap();
+        assert_eq!(cpu.memory.read_word(0x100), Ok(5));
+        assert_eq!(cpu.fast_forward(1000), Ok(CPUStatus::Halt));
+        assert_eq!(cpu.memory.read_word(0x100), Ok(10));
+    }
+
+    #[test]
+    fn snapshots_keep_interrupt_controllers_and_files() {
+        use super::super::devices::Device;
+
+        let config = MachineConfig {
+            memory_size: 0x1000,
+            uart: false,
+            ..MachineConfig::default()
+        };
+        let mut cpu = CPU::with_config(&config);
+        let clint = cpu.get_clint().unwrap();
+        let plic = cpu.get_plic().unwrap();
+        let line = plic.lock().unwrap().irq_line(3);
+        clint.lock().unwrap().set_mtime(100);
+        cpu.get_memory().write_word(CLINT_BASE + 0x4000, 150).unwrap();
+        cpu.get_memory().write_word(CLINT_BASE + 0x4004, 0).unwrap();
+        cpu.get_memory().write_word(PLIC_BASE + 3 * 4, 1).unwrap();
+        line.raise();
+        plic.lock().unwrap().tick();
+        cpu.get_memory().write_word(config.memory_base, 0x1234).unwrap();
+
+        let path = std::env::temp_dir().join(format!("snapshot-{}.bin", std::process::id()));
+        cpu.snapshot().save(&path).unwrap();
+        let snapshot = Snapshot::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Claim source 3 and move time on
+        cpu.get_memory().write_word(PLIC_BASE + 0x2000, 1 << 3).unwrap();
+        assert_eq!(cpu.get_memory().read_word(PLIC_BASE + 0x20_0004), Ok(3));
+        clint.lock().unwrap().set_mtime(200);
+        cpu.get_memory().write_word(config.memory_base, 0).unwrap();
+
+        cpu.restore(&snapshot).unwrap();
+        assert_eq!(clint.lock().unwrap().get_mtime(), 100);
+        assert_eq!(clint.lock().unwrap().get_mtimecmp(0), 150);
+        assert!(plic.lock().unwrap().is_pending(3));
+        assert_eq!(cpu.get_memory().read_word(PLIC_BASE + 0x2000), Ok(0));
+        assert_eq!(cpu.get_memory().read_word(config.memory_base), Ok(0x1234));
+
+        let mut other = CPU::with_config(&MachineConfig {
+            plic_sources: 0,
+            ..config
+        });
+        assert_eq!(other.restore(&snapshot), Err(CPUError::SnapshotMismatch));
+    }
+
+    #[test]
+    fn memory_trace_records_accesses() {
+        use super::super::trace::TraceFormat;
+
+        let program = [
+            0x10002303, // lw t1, 0x100(zero)
+            0x10601123, // sh t1, 0x102(zero)
+            0x00001083, // lh ra, 0(zero)
+            0x00000073, // ecall
+        ];
+        let path = std::env::temp_dir().join(format!("trace-{}.din", std::process::id()));
+        let mut cpu = CPU::new(0, 1024);
+        cpu.set_ir(true);
+        cpu.set_memory_trace(Some(MemoryTracer::create(&path, TraceFormat::Din).unwrap()));
+        load_program(&mut cpu, &program);
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.get_memory_trace().unwrap().finish().unwrap(), 7);
+        let trace = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            trace,
+            "2 0 4\n0 100 4\n2 4 4\n1 102 2\n2 8 4\n0 0 2\n2 c 4\n"
+        );
+    }
+
+    #[test]
+    fn call_profile_follows_calls() {
+        use super::super::symbols::Symbol;
+
+        let program = [
+            0x00300293, // li t0, 3
+            0x010000ef, // loop: call f
+            0xfff28293, // addi t0, t0, -1
+            0xfe029ce3, // bnez t0, loop
+            0x00000073, // ecall
+            0x00150513, // f: addi a0, a0, 1
+            0x00008067, // ret
+        ];
+        let symbol = |name: &str, addr, size| Symbol {
+            name: name.to_string(),
+            addr,
+            size,
+        };
+        let mut cpu = CPU::new(0, 1024);
+        cpu.set_call_profile(true);
+        cpu.set_symbols(SymbolTable::new(vec![
+            symbol("main", 0x0, 0x14),
+            symbol("f", 0x14, 0x8),
+        ]));
+        load_program(&mut cpu, &program);
+        cpu.run().unwrap();
+
+        let mut out = Vec::new();
+        let profile = cpu.get_call_profile().unwrap();
+        profile.write_callgrind(cpu.get_symbols(), "test", &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(profile.get_instructions(), 17);
+        assert!(out.contains("\nfn=f\n0x14 3 3\n0x18 3 3\n"));
+        assert!(out.contains("\ncfn=f\ncalls=3 0x14\n0x4 6 6\n"));
+    }
+
+    #[test]
+    fn cache_misses_cost_cycles() {
+        let program = [
+            0x00300293, // li t0, 3
+            0x10002303, // loop: lw t1, 0x100(zero)
+            0x10602223, // sw t1, 0x104(zero)
+            0xfff28293, // addi t0, t0, -1
+            0xfe029ae3, // bnez t0, loop
+            0x0000100f, // fence.i
+            0x00000073, // ecall
+        ];
+        let config = CacheConfig {
+            size: 256,
+            associativity: 2,
+            line_size: 16,
+            miss_penalty: 10,
+            ..CacheConfig::default()
+        };
+        let mut cpu = CPU::new(0, 1024);
+        cpu.set_caches(Some(config), Some(config)).unwrap();
+        load_program(&mut cpu, &program);
+        cpu.run().unwrap();
+
+        // The code spans two lines, the second one is fetched again after FENCE.I. The loop
+        // reads and writes the same data line.
+        let icache = cpu.get_icache_stats().unwrap();
+        assert_eq!((icache.reads, icache.misses()), (15, 3));
+        let dcache = cpu.get_dcache_stats().unwrap();
+        assert_eq!((dcache.reads, dcache.writes, dcache.misses()), (3, 3, 1));
+        assert_eq!(cpu.csrs.read(csr::MCYCLE), 15 + 4 * 10);
+        assert_eq!(cpu.csrs.read(csr::MINSTRET), 15);
+
+        let bad = CacheConfig {
+            line_size: 12,
+            ..config
+        };
+        assert_eq!(cpu.set_caches(Some(bad), None), Err(CacheError::InvalidGeometry));
+    }
+
+    #[test]
+    fn timer_interrupt_wakes_wfi() {
+        use super::super::devices::clint::{Clint, CLINT_BASE, CLINT_SIZE};
+        use std::sync::{Arc, Mutex};
+
+        let mut cpu = CPU::new(0, 1024);
+        let clint = Arc::new(Mutex::new(Clint::new(1)));
+        cpu.memory.attach_device(CLINT_BASE, CLINT_SIZE, clint).unwrap();
+        load_program(
+            &mut cpu,
+            &[
+                0x020042b7, // lui t0, 0x2004
+                0x06400313, // li t1, 100
+                0x0062a023, // sw t1, 0(t0)      mtimecmp = 100
+                0x0002a223, // sw zero, 4(t0)
+                0x00000397, // auipc t2, 0
+                0x02038393, // addi t2, t2, 32
+                0x30539073, // csrw mtvec, t2
+                0x08000313, // li t1, 0x80
+                0x30431073, // csrw mie, t1       MTIE
+                0x30046073, // csrsi mstatus, 8   MIE
+                0x10500073, // loop: wfi
+                0xffdff06f, // j loop
+                0x34202573, // handler: csrr a0, mcause
+                0x00000073, // ecall
+            ],
+        );
+
+        assert_eq!(cpu.run_for_steps(11).unwrap(), CPUStatus::Continue);
+        assert!(cpu.is_waiting());
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.get_registers()[10], csr::MCAUSE_INTERRUPT | csr::IRQ_M_TIMER);
+        assert_eq!(cpu.get_csrs().read(csr::MEPC), 0x2C);
+        assert_eq!(cpu.get_csrs().read(csr::MSTATUS) & csr::MSTATUS_MIE, 0);
+        assert_ne!(cpu.get_csrs().read(csr::MSTATUS) & csr::MSTATUS_MPIE, 0);
+    }
+
+    #[test]
+    fn masked_interrupt_not_taken() {
+        let mut cpu = CPU::new(0, 1024);
+        load_program(&mut cpu, &[0x00000013, 0x00000013]);
+        cpu.get_csrs().write(csr::MIE, csr::MIP_MSIP);
+        cpu.get_csrs().write(csr::MTVEC, 0x100);
+        cpu.get_csrs().set_hardware_pending(csr::MIP_MSIP);
+
+        // mstatus.MIE is clear, so execution continues in order
+        cpu.run_for_steps(1).unwrap();
+        assert_eq!(cpu.get_registers().get_pc(), 4);
+    }
+
+    #[test]
+    fn vectored_interrupt_and_mret() {
+        use super::super::devices::clint::{Clint, CLINT_BASE, CLINT_SIZE};
+        use std::sync::{Arc, Mutex};
+
+        let mut cpu = CPU::new(0, 1024);
+        let clint = Arc::new(Mutex::new(Clint::new(1)));
+        cpu.memory.attach_device(CLINT_BASE, CLINT_SIZE, clint.clone()).unwrap();
+        load_program(&mut cpu, &[0x00000013; 16]);
+        cpu.memory.write_word(0x10C, 0x30200073).unwrap(); // mret at the MSI vector
+        cpu.get_csrs().write(csr::MTVEC, 0x100 | 1);
+        cpu.get_csrs().write(csr::MIE, csr::MIP_MSIP);
+        cpu.get_csrs().write(csr::MSTATUS, csr::MSTATUS_MIE);
+        cpu.get_registers().set_pc(0x8);
+
+        clint.lock().unwrap().set_msip(0, true);
+        cpu.run_for_steps(1).unwrap();
+        assert_eq!(cpu.get_registers().get_pc(), 0x10C);
+
+        clint.lock().unwrap().set_msip(0, false);
+        cpu.run_for_steps(1).unwrap();
+        assert_eq!(cpu.get_registers().get_pc(), 0x8);
+        assert_ne!(cpu.get_csrs().read(csr::MSTATUS) & csr::MSTATUS_MIE, 0);
+    }
+
+    #[test]
+    fn delegated_external_interrupt() {
+        use super::super::devices::plic::{Plic, PLIC_BASE, PLIC_SIZE};
+        use std::sync::{Arc, Mutex};
+
+        let mut cpu = CPU::new(0, 1024);
+        let plic = Arc::new(Mutex::new(Plic::new(8, 1)));
+        cpu.memory.attach_device(PLIC_BASE, PLIC_SIZE, plic.clone()).unwrap();
+        load_program(&mut cpu, &[0x00000013; 4]);
+
+        // Source 5 enabled for the hart 0 S-mode context
+        cpu.memory.write_word(PLIC_BASE + 5 * 4, 1).unwrap();
+        cpu.memory.write_word(PLIC_BASE + 0x2080, 1 << 5).unwrap();
+        cpu.get_csrs().write(csr::MIDELEG, csr::MIP_SEIP);
+        cpu.get_csrs().write(csr::MIE, csr::MIP_SEIP);
+        cpu.get_csrs().write(csr::SSTATUS, csr::MSTATUS_SIE);
+        cpu.get_csrs().write(csr::STVEC, 0x200);
+
+        let line = plic.lock().unwrap().irq_line(5);
+        line.raise();
+
+        // Supervisor interrupts are never taken while in machine mode
+        cpu.run_for_steps(1).unwrap();
+        assert_eq!(cpu.get_registers().get_pc(), 4);
+
+        cpu.privilege = PrivilegeMode::Supervisor;
+        cpu.run_for_steps(1).unwrap();
+        assert_eq!(cpu.get_registers().get_pc(), 0x200);
+        assert_eq!(cpu.get_privilege(), PrivilegeMode::Supervisor);
+        assert_eq!(cpu.get_csrs().read(csr::SCAUSE), csr::MCAUSE_INTERRUPT | csr::IRQ_S_EXT);
+        assert_eq!(cpu.get_csrs().read(csr::SEPC), 4);
+        assert_ne!(cpu.get_csrs().read(csr::SSTATUS) & csr::MSTATUS_SPP, 0);
+
+        // Claiming from the S-mode context drops SEIP
+        assert_eq!(cpu.memory.read_word(PLIC_BASE + 0x20_1004).unwrap(), 5);
+        assert_eq!(cpu.memory.pending_interrupts(0), 0);
+    }
+
+    fn clic_cpu() -> CPU {
+        let config = MachineConfig {
+            memory_base: 0,
+            memory_size: 4096,
+            interrupt_mode: InterruptMode::Clic,
+            ..Default::default()
+        };
+        let mut cpu = CPU::with_config(&config);
+        load_program(&mut cpu, &[0x00000013; 0x400]);
+        // 8 level bits, so clicintctl is the level
+        cpu.memory.write_byte(CLIC_BASE, 8 << 1).unwrap();
+        cpu.get_csrs().write(csr::MTVEC, 0x200 | csr::MTVEC_MODE_CLIC);
+        cpu.get_csrs().write(csr::MTVT, 0x800);
+        cpu.get_csrs().write(csr::MSTATUS, csr::MSTATUS_MIE);
+        cpu
+    }
+
+    fn clic_enable(cpu: &mut CPU, id: u32, level: u8, vectored: bool) {
+        let base = CLIC_BASE + 0x1000 + id * 4;
+        cpu.memory.write_byte(base + 1, 1).unwrap();
+        cpu.memory.write_byte(base + 2, vectored as u8).unwrap();
+        cpu.memory.write_byte(base + 3, level).unwrap();
+    }
+
+    #[test]
+    fn clic_vectored_dispatch() {
+        let mut cpu = clic_cpu();
+        clic_enable(&mut cpu, 17, 0x80, true);
+        cpu.memory.write_word(0x800 + 17 * 4, 0x300).unwrap();
+        clic_enable(&mut cpu, 18, 0x80, false);
+
+        cpu.get_clic().unwrap().lock().unwrap().irq_line(17).raise();
+        cpu.run_for_steps(1).unwrap();
+        assert_eq!(cpu.get_registers().get_pc(), 0x300);
+        assert_eq!(cpu.get_csrs().read(csr::MCAUSE), csr::MCAUSE_INTERRUPT | 17);
+        assert_eq!(cpu.get_csrs().get_interrupt_level(), 0x80);
+
+        // Non-vectored interrupts share the mtvec handler
+        let mut cpu = clic_cpu();
+        clic_enable(&mut cpu, 18, 0x80, false);
+        cpu.get_clic().unwrap().lock().unwrap().irq_line(18).raise();
+        cpu.run_for_steps(1).unwrap();
+        assert_eq!(cpu.get_registers().get_pc(), 0x200);
+    }
+
+    #[test]
+    fn clic_preemption_by_level() {
+        let mut cpu = clic_cpu();
+        clic_enable(&mut cpu, 16, 0x80, false);
+        clic_enable(&mut cpu, 17, 0x40, false);
+        clic_enable(&mut cpu, 18, 0xC0, false);
+        let clic = cpu.get_clic().unwrap();
+
+        clic.lock().unwrap().irq_line(16).raise();
+        cpu.run_for_steps(1).unwrap();
+        assert_eq!(cpu.get_csrs().get_interrupt_level(), 0x80);
+        clic.lock().unwrap().irq_line(16).lower();
+
+        // The handler re-enables interrupts, a lower level can't preempt it but a higher one can
+        cpu.get_csrs().write(csr::MSTATUS, csr::MSTATUS_MIE);
+        clic.lock().unwrap().irq_line(17).raise();
+        cpu.run_for_steps(1).unwrap();
+        assert_eq!(cpu.get_registers().get_pc(), 0x204);
+
+        clic.lock().unwrap().irq_line(18).raise();
+        cpu.run_for_steps(1).unwrap();
+        assert_eq!(cpu.get_registers().get_pc(), 0x200);
+        let mcause = cpu.get_csrs().read(csr::MCAUSE);
+        assert_eq!(mcause & csr::MCAUSE_EXCCODE, 18);
+        assert_eq!((mcause & csr::MCAUSE_MPIL) >> csr::MCAUSE_MPIL_SHIFT, 0x80);
+        assert_eq!(cpu.get_csrs().get_interrupt_level(), 0xC0);
+
+        clic.lock().unwrap().irq_line(18).lower();
+        cpu.trap_return();
+        assert_eq!(cpu.get_csrs().get_interrupt_level(), 0x80);
+        assert_eq!(cpu.get_registers().get_pc(), 0x204);
+    }
+
+    #[test]
+    fn clic_threshold() {
+        let mut cpu = clic_cpu();
+        clic_enable(&mut cpu, 16, 0x80, false);
+        cpu.get_csrs().write(csr::MINTTHRESH, 0x80);
+        cpu.get_clic().unwrap().lock().unwrap().irq_line(16).raise();
+        cpu.run_for_steps(1).unwrap();
+        assert_eq!(cpu.get_registers().get_pc(), 4);
+
+        cpu.get_csrs().write(csr::MINTTHRESH, 0x7F);
+        cpu.run_for_steps(1).unwrap();
+        assert_eq!(cpu.get_registers().get_pc(), 0x200);
+    }
+
+    #[test]
+    fn clic_wfi_waits_for_enabled_interrupt() {
+        let mut cpu = clic_cpu();
+        cpu.memory.write_word(0, 0x10500073).unwrap(); // wfi
+        cpu.step().unwrap();
+        assert!(!cpu.waiting);
+
+        clic_enable(&mut cpu, CLIC_FIRST_EXTERNAL, 0xFF, false);
+        cpu.get_registers().set_pc(0);
+        cpu.step().unwrap();
+        assert!(cpu.waiting);
+        cpu.step().unwrap();
+        assert_eq!(cpu.get_registers().get_pc(), 4);
+
+        let line = cpu.get_clic().unwrap().lock().unwrap().irq_line(CLIC_FIRST_EXTERNAL);
+        line.raise();
+        cpu.step().unwrap();
+        assert!(!cpu.waiting);
+        assert_eq!(cpu.get_registers().get_pc(), 0x200);
+    }
+
+    #[test]
+    fn clic_timer_interrupt() {
+        let mut cpu = clic_cpu();
+        clic_enable(&mut cpu, csr::IRQ_M_TIMER, 0xFF, false);
+        cpu.get_clint().unwrap().lock().unwrap().set_mtime(u64::MAX - 1);
+        cpu.memory.write_word(CLINT_BASE + 0x4000, 0).unwrap();
+        cpu.memory.write_word(CLINT_BASE + 0x4004, 0).unwrap();
+        cpu.run_for_steps(1).unwrap();
+        assert_eq!(cpu.get_csrs().read(csr::MCAUSE), csr::MCAUSE_INTERRUPT | csr::IRQ_M_TIMER);
+    }
+
+    #[test]
+    fn virtio_slots_and_interrupts() {
+        use super::super::devices::virtio::block::{DiskImage, DiskMode, VirtioBlock};
+
+        let path = std::env::temp_dir().join(format!("emulator-rs-cpu-{}.img", std::process::id()));
+        std::fs::write(&path, vec![0; 1024]).unwrap();
+        let mut cpu = CPU::with_config(&MachineConfig::default());
+        let open = || VirtioBlock::new(DiskImage::open(&path, DiskMode::ReadOnly).unwrap());
+        cpu.attach_virtio(open()).unwrap();
+        cpu.attach_virtio(open()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let second = VIRTIO_MMIO_BASE + VIRTIO_MMIO_SIZE;
+        assert_eq!(cpu.get_virtio(), &[(VIRTIO_MMIO_BASE, 1), (second, 2)]);
+        assert_eq!(cpu.memory.read_word(second + 0x8).unwrap(), 2);
+        assert_eq!(cpu.memory.read_word(second + 0x100).unwrap(), 2);
+
+        // The rings are left at address 0, which is unmapped, so the notify makes the transport
+        // raise a config change interrupt on PLIC source 2
+        let plic = cpu.get_plic().unwrap();
+        cpu.memory.write_word(second + 0x70, 0x7).unwrap();
+        cpu.memory.write_word(second + 0x30, 0).unwrap();
+        cpu.memory.write_word(second + 0x44, 1).unwrap();
+        cpu.memory.write_word(second + 0x38, 8).unwrap();
+        cpu.memory.write_word(second + 0x50, 0).unwrap();
+        cpu.memory.tick_devices();
+        cpu.memory.tick_devices();
+        assert!(plic.lock().unwrap().is_pending(2));
+    }
+
+    #[test]
+    fn reset_state() {
+        let mut cpu = CPU::with_config(&MachineConfig::default());
+        cpu.registers[5] = 7;
+        cpu.csrs.write(csr::MSTATUS, csr::MSTATUS_MIE);
+        cpu.privilege = PrivilegeMode::User;
+        cpu.waiting = true;
+        cpu.reset();
+
+        assert_eq!(cpu.registers[5], 0);
+        assert_eq!(cpu.registers.get_pc(), boot::DEFAULT_RESET_VECTOR);
+        assert_eq!(cpu.csrs.read(csr::MSTATUS), 0);
+        assert_eq!(cpu.privilege, PrivilegeMode::Machine);
+        assert!(!cpu.waiting);
+    }
+
+    #[test]
+    fn boot_rom_enters_image() {
+        let mut cpu = CPU::with_config(&MachineConfig::default());
+        // addi x5, x0, 1 at the entry point
+        cpu.memory.write_word(0x8000_0000, 0x00100293).unwrap();
+        let dtb = cpu.boot(0x8000_0000, "").unwrap();
+
+        cpu.run_for_steps(5).unwrap();
+        assert_eq!(cpu.registers.get_pc(), 0x8000_0000);
+        assert_eq!(cpu.registers[10], 0);
+        assert_eq!(cpu.registers[11], dtb);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers[5], 1);
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        let program = [
+            0x00000317, // auipc t1, 0
+            0x01832383, // lw t2, 24(t1)
+            0x00732823, // sw t2, 16(t1)
+            0x0000100f, // fence.i
+            0x00100513, // li a0, 1       overwritten with li a0, 2
+            0x00000073, // ecall
+            0x00200513, // li a0, 2
+        ];
+        let nop = 0x00000013;
+        for &fence in [true, false].iter() {
+            for &cached in [true, false].iter() {
+                let mut cpu = CPU::new(0, 1024);
+                cpu.set_block_cache(cached);
+                load_program(&mut cpu, &program);
+                if !fence {
+                    cpu.memory.write_word(12, nop).unwrap();
+                }
+                // Decode the overwritten instruction before the store, without a FENCE.I it is
+                // in the block doing the store
+                cpu.registers.set_pc(if fence { 16 } else { 0 });
+                cpu.step().unwrap();
+                cpu.registers.set_pc(0);
+                assert_eq!(cpu.run().unwrap(), CPUStatus::Halt);
+                assert_eq!(cpu.registers[10], 2, "fence {} cached {}", fence, cached);
+            }
+        }
+    }
+
+    #[test]
+    fn sbi_boots_supervisor() {
+        use super::super::sbi::ResetType;
+
+        let config = MachineConfig {
+            sbi: true,
+            ..MachineConfig::default()
+        };
+        let mut cpu = CPU::with_config(&config);
+        let program = [
+            0x01000893, // li a7, 0x10        base extension
+            0x00300813, // li a6, 3           probe_extension
+            0x54495537, // li a0, 0x54494D45  TIME
+            0xd4550513,
+            0x00000073, // ecall
+            0x00058293, // mv t0, a1
+            0x535258b7, // li a7, 0x53525354  SRST
+            0x35488893,
+            0x00000813, // li a6, 0           system_reset
+            0x00000513, // li a0, 0           shutdown
+            0x00000593, // li a1, 0
+            0x00000073, // ecall
+        ];
+        for (index, instr) in program.iter().enumerate() {
+            cpu.memory.write_word(0x8000_0000 + index as u32 * 4, *instr).unwrap();
+        }
+        let dtb = cpu.boot(0x8000_0000, "").unwrap();
+        assert_eq!(cpu.privilege, PrivilegeMode::Supervisor);
+        assert_eq!(cpu.registers.get_pc(), 0x8000_0000);
+        assert_eq!(cpu.registers[11], dtb);
+        assert_ne!(cpu.csrs.read(csr::MIDELEG) & csr::MIP_STIP, 0);
+        assert!(cpu.csrs.counter_accessible(csr::TIME, PrivilegeMode::Supervisor));
+
+        assert_eq!(cpu.run().unwrap(), CPUStatus::Halt);
+        assert_eq!(cpu.registers[5], 1);
+        assert_eq!(
+            cpu.get_sbi().unwrap().lock().unwrap().get_reset_request(),
+            Some((ResetType::Shutdown, 0))
+        );
+    }
 }
@@ -0,0 +1,582 @@
+use std::io::{self, Read, Write};
+
+use super::snapshot::{get_u32, get_u64, put_u32, put_u64};
+
+// User-level counter shadows, read-only. The high halves are at +0x80.
+pub const CYCLE: u32 = 0xC00;
+pub const TIME: u32 = 0xC01;
+pub const INSTRET: u32 = 0xC02;
+pub const HPMCOUNTER3: u32 = 0xC03;
+pub const HPMCOUNTER31: u32 = 0xC1F;
+pub const CYCLEH: u32 = 0xC80;
+pub const TIMEH: u32 = 0xC81;
+pub const INSTRETH: u32 = 0xC82;
+pub const HPMCOUNTER31H: u32 = 0xC9F;
+
+// Supervisor-level CSR addresses
+pub const SSTATUS: u32 = 0x100;
+pub const SIE: u32 = 0x104;
+pub const STVEC: u32 = 0x105;
+pub const SCOUNTEREN: u32 = 0x106;
+pub const SSCRATCH: u32 = 0x140;
+pub const SEPC: u32 = 0x141;
+pub const SCAUSE: u32 = 0x142;
+pub const STVAL: u32 = 0x143;
+pub const SIP: u32 = 0x144;
+pub const SATP: u32 = 0x180;
+
+// Machine-level CSR addresses
+pub const MSTATUS: u32 = 0x300;
+pub const MISA: u32 = 0x301;
+pub const MEDELEG: u32 = 0x302;
+pub const MIDELEG: u32 = 0x303;
+pub const MIE: u32 = 0x304;
+pub const MTVEC: u32 = 0x305;
+pub const MCOUNTEREN: u32 = 0x306;
+pub const MTVT: u32 = 0x307;
+pub const MCOUNTINHIBIT: u32 = 0x320;
+pub const MHPMEVENT3: u32 = 0x323;
+pub const MHPMEVENT31: u32 = 0x33F;
+pub const MSCRATCH: u32 = 0x340;
+pub const MEPC: u32 = 0x341;
+pub const MCAUSE: u32 = 0x342;
+pub const MTVAL: u32 = 0x343;
+pub const MIP: u32 = 0x344;
+pub const MINTTHRESH: u32 = 0x347;
+pub const MINTSTATUS: u32 = 0xFB1;
+pub const MVENDORID: u32 = 0xF11;
+pub const MARCHID: u32 = 0xF12;
+pub const MIMPID: u32 = 0xF13;
+pub const MHARTID: u32 = 0xF14;
+
+// Machine counters, the high halves are at +0x80. There is no mtime CSR at 0xB01.
+pub const MCYCLE: u32 = 0xB00;
+pub const MINSTRET: u32 = 0xB02;
+pub const MHPMCOUNTER3: u32 = 0xB03;
+pub const MHPMCOUNTER31: u32 = 0xB1F;
+pub const MCYCLEH: u32 = 0xB80;
+pub const MINSTRETH: u32 = 0xB82;
+pub const MHPMCOUNTER31H: u32 = 0xB9F;
+
+// Counter numbers, the bit of each counter in mcounteren, scounteren and mcountinhibit
+pub const COUNTER_CY: u32 = 0;
+pub const COUNTER_TM: u32 = 1;
+pub const COUNTER_IR: u32 = 2;
+
+// Events mhpmcounter3..31 can count, as written to mhpmevent3..31
+pub const HPM_EVENT_NONE: u32 = 0;
+pub const HPM_EVENT_LOAD: u32 = 1;
+pub const HPM_EVENT_STORE: u32 = 2;
+pub const HPM_EVENT_BRANCH_TAKEN: u32 = 3;
+pub const HPM_EVENT_JUMP: u32 = 4;
+pub const HPM_EVENT_ECALL: u32 = 5;
+const HPM_EVENTS: usize = 6;
+
+// mstatus fields
+pub const MSTATUS_SIE: u32 = 1 << 1;
+pub const MSTATUS_MIE: u32 = 1 << 3;
+pub const MSTATUS_SPIE: u32 = 1 << 5;
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+pub const MSTATUS_SPP: u32 = 1 << 8;
+pub const MSTATUS_MPP_SHIFT: u32 = 11;
+pub const MSTATUS_MPP: u32 = 0b11 << MSTATUS_MPP_SHIFT;
+
+// mip / mie bits
+pub const MIP_SSIP: u32 = 1 << 1;
+pub const MIP_MSIP: u32 = 1 << 3;
+pub const MIP_STIP: u32 = 1 << 5;
+pub const MIP_MTIP: u32 = 1 << 7;
+pub const MIP_SEIP: u32 = 1 << 9;
+pub const MIP_MEIP: u32 = 1 << 11;
+
+// Interrupt cause codes, without the interrupt bit set
+pub const IRQ_S_SOFT: u32 = 1;
+pub const IRQ_M_SOFT: u32 = 3;
+pub const IRQ_S_TIMER: u32 = 5;
+pub const IRQ_M_TIMER: u32 = 7;
+pub const IRQ_S_EXT: u32 = 9;
+pub const IRQ_M_EXT: u32 = 11;
+
+// Exception cause codes
+pub const EXC_ILLEGAL_INSTRUCTION: u32 = 2;
+pub const EXC_ECALL_U: u32 = 8;
+pub const EXC_ECALL_S: u32 = 9;
+pub const EXC_ECALL_M: u32 = 11;
+
+pub const MCAUSE_INTERRUPT: u32 = 0x8000_0000;
+
+// CLIC mode fields, mcause keeps the interrupt level that was active before the trap
+pub const MCAUSE_MPIL_SHIFT: u32 = 16;
+pub const MCAUSE_MPIL: u32 = 0xFF << MCAUSE_MPIL_SHIFT;
+pub const MCAUSE_EXCCODE: u32 = 0xFFF;
+pub const MINTSTATUS_MIL_SHIFT: u32 = 24;
+pub const MTVEC_MODE_CLIC: u32 = 0b11;
+
+/// RV32I with the "I" bit set and MXL = 1 (32-bit)
+const MISA_RV32I: u32 = 0x4000_0100;
+
+/// Only the supervisor interrupts can be delegated
+const MIDELEG_MASK: u32 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+/// The mip bits which software can write, the remaining bits are driven by devices
+const MIP_WRITABLE_MASK: u32 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+/// The mip bits which devices can assert. SEIP is both, the value read is the OR of the two.
+const MIP_HARDWARE_MASK: u32 = MIP_MSIP | MIP_MTIP | MIP_MEIP | MIP_SEIP;
+
+/// The mstatus fields visible through sstatus
+const SSTATUS_MASK: u32 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP;
+
+/// Only supervisor software interrupts can be raised by writing sip
+const SIP_WRITABLE_MASK: u32 = MIP_SSIP;
+
+/// time can't be inhibited, its bit in mcountinhibit is always 0
+const MCOUNTINHIBIT_MASK: u32 = !(1 << COUNTER_TM);
+
+/// Whether `addr` is one of the counters, machine or user-level
+pub fn is_counter(addr: u32) -> bool {
+    matches!(addr & 0xFFF, MCYCLE..=MHPMCOUNTER31H | CYCLE..=HPMCOUNTER31H)
+}
+
+/// Whether code running in U-mode can access the CSR at `addr`, bits 9:8 of the address are the
+/// lowest privilege mode which can
+pub fn is_unprivileged(addr: u32) -> bool {
+    (addr >> 8) & 0b11 == PrivilegeMode::User as u32
+}
+
+/// Whether the CSR at `addr` is read-only, which bits 11:10 of the address encode as 0b11
+pub fn is_read_only(addr: u32) -> bool {
+    (addr >> 10) & 0b11 == 0b11
+}
+
+fn set_half(value: u64, high: bool, half: u32) -> u64 {
+    if high {
+        (value & 0xFFFF_FFFF) | (half as u64) << 32
+    } else {
+        (value & !0xFFFF_FFFF) | half as u64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum PrivilegeMode {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl From<u32> for PrivilegeMode {
+    fn from(val: u32) -> PrivilegeMode {
+        match val & 0b11 {
+            0 => PrivilegeMode::User,
+            1 => PrivilegeMode::Supervisor,
+            _ => PrivilegeMode::Machine,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CSRFile {
+    inner: Vec<u32>,
+    /// Pending bits asserted by devices, OR'd into mip on read
+    hardware_pending: u32,
+    /// mcycle, minstret and mhpmcounter3..31 by counter number, 1 is unused
+    counters: [u64; 32],
+    /// mtime as last seen by the hart
+    time: u64,
+    /// The counters counting each event, not inhibited ones included
+    event_counters: [u32; HPM_EVENTS],
+    /// Instructions retired since reset, like minstret but never written or inhibited
+    retired: u64,
+}
+
+// The full 4096 entry CSR space is not useful when printing the CPU
+impl std::fmt::Debug for CSRFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("CSRFile")
+            .field("mstatus", &self.inner[MSTATUS as usize])
+            .field("mie", &self.inner[MIE as usize])
+            .field("mip", &self.read(MIP))
+            .field("mtvec", &self.inner[MTVEC as usize])
+            .field("mepc", &self.inner[MEPC as usize])
+            .field("mcause", &self.inner[MCAUSE as usize])
+            .field("mideleg", &self.inner[MIDELEG as usize])
+            .field("stvec", &self.inner[STVEC as usize])
+            .field("sepc", &self.inner[SEPC as usize])
+            .field("scause", &self.inner[SCAUSE as usize])
+            .field("mcycle", &self.counters[COUNTER_CY as usize])
+            .field("minstret", &self.counters[COUNTER_IR as usize])
+            .finish()
+    }
+}
+
+impl Default for CSRFile {
+    fn default() -> Self {
+        CSRFile::new(0)
+    }
+}
+
+impl CSRFile {
+    pub fn new(hartid: u32) -> Self {
+        let mut inner = vec![0; 4096];
+        inner[MISA as usize] = MISA_RV32I;
+        inner[MHARTID as usize] = hartid;
+        CSRFile {
+            inner,
+            hardware_pending: 0,
+            counters: [0; 32],
+            time: 0,
+            event_counters: [0; HPM_EVENTS],
+            retired: 0,
+        }
+    }
+
+    pub fn read(&self, addr: u32) -> u32 {
+        let addr = addr & 0xFFF;
+        match addr {
+            TIME => self.time as u32,
+            TIMEH => (self.time >> 32) as u32,
+            // Counters other than time, the user-level shadows read the machine counters
+            MCYCLE..=MHPMCOUNTER31 | CYCLE..=HPMCOUNTER31 if addr & 0x1F != COUNTER_TM => {
+                self.counters[(addr & 0x1F) as usize] as u32
+            }
+            MCYCLEH..=MHPMCOUNTER31H | CYCLEH..=HPMCOUNTER31H if addr & 0x1F != COUNTER_TM => {
+                (self.counters[(addr & 0x1F) as usize] >> 32) as u32
+            }
+            MIP => self.inner[MIP as usize] | self.hardware_pending,
+            // The supervisor registers are restricted views of their machine counterparts
+            SSTATUS => self.inner[MSTATUS as usize] & SSTATUS_MASK,
+            SIE => self.inner[MIE as usize] & self.inner[MIDELEG as usize],
+            SIP => self.read(MIP) & self.inner[MIDELEG as usize],
+            _ => self.inner[addr as usize],
+        }
+    }
+
+    pub fn write(&mut self, addr: u32, val: u32) {
+        let addr = addr & 0xFFF;
+        match addr {
+            // Read-only machine information registers
+            MISA | MVENDORID | MARCHID | MIMPID | MHARTID | MINTSTATUS => {}
+            CYCLE..=HPMCOUNTER31H => {}
+            MCYCLE..=MHPMCOUNTER31H if addr & 0x1F != COUNTER_TM => {
+                let counter = &mut self.counters[(addr & 0x1F) as usize];
+                *counter = set_half(*counter, addr >= MCYCLEH, val);
+            }
+            MCOUNTINHIBIT => self.inner[MCOUNTINHIBIT as usize] = val & MCOUNTINHIBIT_MASK,
+            // Unknown events are WARL to no event
+            MHPMEVENT3..=MHPMEVENT31 => {
+                let event = if (val as usize) < HPM_EVENTS {
+                    val
+                } else {
+                    HPM_EVENT_NONE
+                };
+                self.inner[addr as usize] = event;
+                self.update_events();
+            }
+            MINTTHRESH => self.inner[MINTTHRESH as usize] = val & 0xFF,
+            MIDELEG => self.inner[MIDELEG as usize] = val & MIDELEG_MASK,
+            MIP => {
+                let mip = self.inner[MIP as usize];
+                self.inner[MIP as usize] = (mip & !MIP_WRITABLE_MASK) | (val & MIP_WRITABLE_MASK);
+            }
+            SSTATUS => self.write_masked(MSTATUS, val, SSTATUS_MASK),
+            SIE => {
+                let mask = self.inner[MIDELEG as usize];
+                self.write_masked(MIE, val, mask);
+            }
+            SIP => {
+                let mask = self.inner[MIDELEG as usize] & SIP_WRITABLE_MASK;
+                self.write_masked(MIP, val, mask);
+            }
+            _ => self.inner[addr as usize] = val,
+        }
+    }
+
+    fn write_masked(&mut self, addr: u32, val: u32, mask: u32) {
+        let old = self.inner[addr as usize];
+        self.inner[addr as usize] = (old & !mask) | (val & mask);
+    }
+
+    fn update_events(&mut self) {
+        self.event_counters = [0; HPM_EVENTS];
+        for counter in 3..32 {
+            let event = self.inner[(MHPMEVENT3 - 3 + counter) as usize];
+            self.event_counters[event as usize] |= 1 << counter;
+        }
+        self.event_counters[HPM_EVENT_NONE as usize] = 0;
+    }
+
+    /// Counters which are counting, not inhibited through mcountinhibit
+    fn running(&self) -> u32 {
+        !self.inner[MCOUNTINHIBIT as usize]
+    }
+
+    /// Advance mcycle and minstret, unless they are inhibited
+    pub fn advance(&mut self, cycles: u64, instructions: u64) {
+        self.retired += instructions;
+        let running = self.running();
+        if running & (1 << COUNTER_CY) != 0 {
+            let cycle = &mut self.counters[COUNTER_CY as usize];
+            *cycle = cycle.wrapping_add(cycles);
+        }
+        if running & (1 << COUNTER_IR) != 0 {
+            let instret = &mut self.counters[COUNTER_IR as usize];
+            *instret = instret.wrapping_add(instructions);
+        }
+    }
+
+    /// Whether any hpmcounter is counting events, each instruction then has to be looked at
+    pub fn counting_events(&self) -> bool {
+        let running = self.running();
+        self.event_counters
+            .iter()
+            .any(|counters| counters & running != 0)
+    }
+
+    /// Advance the hpmcounters counting `event`
+    pub fn count_event(&mut self, event: u32) {
+        let counters = self.event_counters[event as usize] & self.running();
+        for counter in 3..32 {
+            if counters & (1 << counter) != 0 {
+                self.counters[counter] = self.counters[counter].wrapping_add(1);
+            }
+        }
+    }
+
+    /// Instructions retired since reset, whatever the guest did to minstret
+    pub fn get_retired(&self) -> u64 {
+        self.retired
+    }
+
+    pub(crate) fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        self.inner.iter().try_for_each(|val| put_u32(out, *val))?;
+        put_u32(out, self.hardware_pending)?;
+        self.counters
+            .iter()
+            .try_for_each(|val| put_u64(out, *val))?;
+        put_u64(out, self.time)?;
+        self.event_counters
+            .iter()
+            .try_for_each(|val| put_u32(out, *val))?;
+        put_u64(out, self.retired)
+    }
+
+    pub(crate) fn read_from<R: Read>(input: &mut R) -> io::Result<Self> {
+        let mut csrs = CSRFile::new(0);
+        for val in csrs.inner.iter_mut() {
+            *val = get_u32(input)?;
+        }
+        csrs.hardware_pending = get_u32(input)?;
+        for val in csrs.counters.iter_mut() {
+            *val = get_u64(input)?;
+        }
+        csrs.time = get_u64(input)?;
+        for val in csrs.event_counters.iter_mut() {
+            *val = get_u32(input)?;
+        }
+        csrs.retired = get_u64(input)?;
+        Ok(csrs)
+    }
+
+    pub fn set_time(&mut self, time: u64) {
+        self.time = time;
+    }
+
+    /// Whether a CSR instruction running in `privilege` may access the CSR at `addr`, writing it
+    /// when `write` is set. The privilege mode has to be at least the one encoded in the address,
+    /// read-only CSRs can't be written, and counters have to be enabled for the mode.
+    pub fn accessible(&self, addr: u32, privilege: PrivilegeMode, write: bool) -> bool {
+        let addr = addr & 0xFFF;
+        if (addr >> 8) & 0b11 > privilege as u32 || (write && is_read_only(addr)) {
+            return false;
+        }
+        self.counter_accessible(addr, privilege)
+    }
+
+    /// Whether code running in `privilege` may read the counter at `addr`. The user-level
+    /// counters are enabled for S-mode through mcounteren, and for U-mode through both mcounteren
+    /// and scounteren. Any other CSR is not a counter and passes.
+    pub fn counter_accessible(&self, addr: u32, privilege: PrivilegeMode) -> bool {
+        let addr = addr & 0xFFF;
+        if !matches!(addr, CYCLE..=HPMCOUNTER31H) {
+            return true;
+        }
+        let bit = 1 << (addr & 0x1F);
+        let enabled = match privilege {
+            PrivilegeMode::Machine => return true,
+            PrivilegeMode::Supervisor => self.inner[MCOUNTEREN as usize],
+            PrivilegeMode::User => {
+                self.inner[MCOUNTEREN as usize] & self.inner[SCOUNTEREN as usize]
+            }
+        };
+        enabled & bit != 0
+    }
+
+    pub fn get_hartid(&self) -> u32 {
+        self.inner[MHARTID as usize]
+    }
+
+    /// The CLIC machine interrupt level of the running code, kept in mintstatus.mil
+    pub fn get_interrupt_level(&self) -> u32 {
+        self.inner[MINTSTATUS as usize] >> MINTSTATUS_MIL_SHIFT
+    }
+
+    pub fn set_interrupt_level(&mut self, level: u32) {
+        self.inner[MINTSTATUS as usize] = (level & 0xFF) << MINTSTATUS_MIL_SHIFT;
+    }
+
+    /// Replace the device driven pending bits in mip
+    pub fn set_hardware_pending(&mut self, pending: u32) {
+        self.hardware_pending = pending & MIP_HARDWARE_MASK;
+    }
+
+    /// Interrupts which are both pending and enabled in mie, regardless of the global enable
+    pub fn pending_enabled(&self) -> u32 {
+        self.read(MIP) & self.inner[MIE as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hartid_read_only() {
+        let mut csrs = CSRFile::new(3);
+        csrs.write(MHARTID, 7);
+        assert_eq!(csrs.read(MHARTID), 3);
+    }
+
+    #[test]
+    fn mip_hardware_bits() {
+        let mut csrs = CSRFile::new(0);
+        csrs.write(MIP, MIP_MTIP | MIP_SSIP);
+        assert_eq!(csrs.read(MIP), MIP_SSIP);
+
+        csrs.set_hardware_pending(MIP_MTIP);
+        assert_eq!(csrs.read(MIP), MIP_SSIP | MIP_MTIP);
+    }
+
+    #[test]
+    fn seip_is_software_and_hardware() {
+        let mut csrs = CSRFile::new(0);
+        csrs.write(MIP, MIP_SEIP);
+        csrs.set_hardware_pending(MIP_SEIP);
+        csrs.write(MIP, 0);
+        assert_eq!(csrs.read(MIP), MIP_SEIP);
+        csrs.set_hardware_pending(0);
+        assert_eq!(csrs.read(MIP), 0);
+    }
+
+    #[test]
+    fn supervisor_views() {
+        let mut csrs = CSRFile::new(0);
+        csrs.write(MSTATUS, MSTATUS_MIE | MSTATUS_SIE);
+        assert_eq!(csrs.read(SSTATUS), MSTATUS_SIE);
+        csrs.write(SSTATUS, 0);
+        assert_eq!(csrs.read(MSTATUS), MSTATUS_MIE);
+
+        csrs.write(MIDELEG, MIP_SEIP | MIP_SSIP);
+        csrs.write(SIE, MIP_SEIP | MIP_MEIP);
+        assert_eq!(csrs.read(MIE), MIP_SEIP);
+        csrs.write(SIP, MIP_SSIP);
+        assert_eq!(csrs.read(MIP), MIP_SSIP);
+    }
+
+    #[test]
+    fn mintstatus_read_only() {
+        let mut csrs = CSRFile::new(0);
+        csrs.write(MINTSTATUS, 0xFF00_0000);
+        assert_eq!(csrs.read(MINTSTATUS), 0);
+        csrs.set_interrupt_level(0x80);
+        assert_eq!(csrs.read(MINTSTATUS), 0x8000_0000);
+        assert_eq!(csrs.get_interrupt_level(), 0x80);
+    }
+
+    #[test]
+    fn counter_halves() {
+        let mut csrs = CSRFile::new(0);
+        csrs.write(MCYCLEH, 1);
+        csrs.write(MCYCLE, 0xFFFF_FFFF);
+        csrs.advance(1, 3);
+        assert_eq!(csrs.read(MCYCLE), 0);
+        assert_eq!(csrs.read(MCYCLEH), 2);
+        assert_eq!(csrs.read(CYCLEH), 2);
+        assert_eq!(csrs.read(INSTRET), 3);
+
+        csrs.write(CYCLE, 5);
+        assert_eq!(csrs.read(CYCLE), 0);
+        csrs.set_time(0x1_0000_0002);
+        assert_eq!((csrs.read(TIME), csrs.read(TIMEH)), (2, 1));
+    }
+
+    #[test]
+    fn inhibited_counters_stop() {
+        let mut csrs = CSRFile::new(0);
+        csrs.write(MCOUNTINHIBIT, 0xFFFF_FFFF);
+        assert_eq!(csrs.read(MCOUNTINHIBIT), 0xFFFF_FFFD);
+        csrs.advance(1, 1);
+        assert_eq!((csrs.read(MCYCLE), csrs.read(MINSTRET)), (0, 0));
+    }
+
+    #[test]
+    fn hpm_events() {
+        let mut csrs = CSRFile::new(0);
+        assert!(!csrs.counting_events());
+        csrs.write(MHPMEVENT3, HPM_EVENT_LOAD);
+        csrs.write(MHPMEVENT3 + 1, HPM_EVENT_STORE);
+        csrs.write(MHPMEVENT31, 100);
+        assert_eq!(csrs.read(MHPMEVENT31), HPM_EVENT_NONE);
+        assert!(csrs.counting_events());
+
+        csrs.count_event(HPM_EVENT_LOAD);
+        csrs.count_event(HPM_EVENT_LOAD);
+        csrs.count_event(HPM_EVENT_STORE);
+        assert_eq!(csrs.read(MHPMCOUNTER3), 2);
+        assert_eq!(csrs.read(HPMCOUNTER3 + 1), 1);
+
+        csrs.write(MCOUNTINHIBIT, 0b11000);
+        assert!(!csrs.counting_events());
+    }
+
+    #[test]
+    fn counter_access() {
+        let mut csrs = CSRFile::new(0);
+        csrs.write(MCOUNTEREN, 1 << COUNTER_CY | 1 << COUNTER_TM);
+        csrs.write(SCOUNTEREN, 1 << COUNTER_TM);
+        assert!(csrs.counter_accessible(CYCLE, PrivilegeMode::Supervisor));
+        assert!(csrs.counter_accessible(CYCLEH, PrivilegeMode::Supervisor));
+        assert!(!csrs.counter_accessible(INSTRET, PrivilegeMode::Supervisor));
+        assert!(!csrs.counter_accessible(CYCLE, PrivilegeMode::User));
+        assert!(csrs.counter_accessible(TIME, PrivilegeMode::User));
+        assert!(csrs.counter_accessible(INSTRET, PrivilegeMode::Machine));
+        assert!(csrs.counter_accessible(MSTATUS, PrivilegeMode::User));
+    }
+
+    #[test]
+    fn privilege_and_read_only() {
+        let mut csrs = CSRFile::new(0);
+        assert!(csrs.accessible(MSTATUS, PrivilegeMode::Machine, true));
+        assert!(!csrs.accessible(MSTATUS, PrivilegeMode::Supervisor, false));
+        assert!(!csrs.accessible(MTVEC, PrivilegeMode::User, true));
+        assert!(csrs.accessible(SEPC, PrivilegeMode::Supervisor, true));
+        assert!(!csrs.accessible(SEPC, PrivilegeMode::User, false));
+        assert!(csrs.accessible(MHARTID, PrivilegeMode::Machine, false));
+        assert!(!csrs.accessible(MHARTID, PrivilegeMode::Machine, true));
+        // Hypervisor CSRs don't exist, S-mode can't reach them
+        assert!(!csrs.accessible(0x200, PrivilegeMode::Supervisor, false));
+
+        csrs.write(MCOUNTEREN, 1 << COUNTER_CY);
+        assert!(csrs.accessible(CYCLE, PrivilegeMode::Supervisor, false));
+        assert!(!csrs.accessible(CYCLE, PrivilegeMode::Supervisor, true));
+        assert!(!csrs.accessible(INSTRET, PrivilegeMode::Supervisor, false));
+    }
+
+    #[test]
+    fn pending_enabled() {
+        let mut csrs = CSRFile::new(0);
+        csrs.set_hardware_pending(MIP_MTIP | MIP_MSIP);
+        assert_eq!(csrs.pending_enabled(), 0);
+        csrs.write(MIE, MIP_MTIP);
+        assert_eq!(csrs.pending_enabled(), MIP_MTIP);
+    }
+}
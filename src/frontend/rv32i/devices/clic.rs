@@ -0,0 +1,355 @@
+use super::super::csr::{IRQ_M_EXT, IRQ_M_SOFT, IRQ_M_TIMER, MIP_MEIP, MIP_MSIP, MIP_MTIP};
+use super::super::mem::{Mem, MemoryError, MemoryResult};
+use super::{Device, IrqLevels, IrqLine};
+
+use std::sync::Arc;
+
+pub const CLIC_BASE: u32 = 0x0280_0000;
+pub const CLIC_SIZE: u32 = 0x1_0000;
+
+pub const CLIC_MAX_INTERRUPTS: u32 = 4096;
+
+/// The first interrupt ID available for external inputs, 0-15 are the standard local interrupts
+pub const CLIC_FIRST_EXTERNAL: u32 = 16;
+
+const CLICCFG_OFFSET: u32 = 0x0;
+const CLICINFO_OFFSET: u32 = 0x4;
+const INTERRUPT_OFFSET: u32 = 0x1000;
+
+// Byte offsets within the 4 byte per-interrupt register block
+const INTIP: u32 = 0;
+const INTIE: u32 = 1;
+const INTATTR: u32 = 2;
+const INTCTL: u32 = 3;
+
+const ATTR_SHV: u8 = 1;
+const ATTR_TRIG_SHIFT: u8 = 1;
+const ATTR_TRIG_MASK: u8 = 0b11 << ATTR_TRIG_SHIFT;
+/// Only machine mode is implemented, so the mode field always reads as M
+const ATTR_MODE_MACHINE: u8 = 0b11 << 6;
+
+const CLICCFG_NLBITS_SHIFT: u8 = 1;
+const CLICCFG_NLBITS_MASK: u8 = 0xF << CLICCFG_NLBITS_SHIFT;
+
+const CLIC_VERSION: u32 = 0x09;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    PositiveLevel,
+    PositiveEdge,
+    NegativeLevel,
+    NegativeEdge,
+}
+
+impl Trigger {
+    fn from_attr(attr: u8) -> Trigger {
+        match (attr & ATTR_TRIG_MASK) >> ATTR_TRIG_SHIFT {
+            0b00 => Trigger::PositiveLevel,
+            0b01 => Trigger::PositiveEdge,
+            0b10 => Trigger::NegativeLevel,
+            _ => Trigger::NegativeEdge,
+        }
+    }
+
+    fn is_edge(self) -> bool {
+        self == Trigger::PositiveEdge || self == Trigger::NegativeEdge
+    }
+
+    fn is_negative(self) -> bool {
+        self == Trigger::NegativeLevel || self == Trigger::NegativeEdge
+    }
+}
+
+/// The interrupt the CLIC is presenting to the hart
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClicInterrupt {
+    pub id: u32,
+    pub level: u32,
+    pub priority: u32,
+    /// Selective hardware vectoring, the handler address is read from the mtvt table
+    pub vectored: bool,
+}
+
+/// Core-local interrupt controller for a single hart, using the memory map from the v0.9 draft
+/// specification. Interrupt level, priority, trigger and vectoring are configured per interrupt.
+#[derive(Debug)]
+pub struct Clic {
+    inputs: Arc<IrqLevels>,
+    last_input: Vec<bool>,
+    ip: Vec<bool>,
+    ie: Vec<bool>,
+    attr: Vec<u8>,
+    ctl: Vec<u8>,
+    cfg: u8,
+    /// CLICINTCTLBITS, the number of implemented upper bits in each clicintctl
+    ctl_bits: u32,
+}
+
+impl Clic {
+    pub fn new(num_interrupts: u32, ctl_bits: u32) -> Self {
+        assert!(num_interrupts <= CLIC_MAX_INTERRUPTS, "too many CLIC interrupts");
+        assert!(ctl_bits <= 8, "clicintctl is only 8 bits");
+
+        let count = num_interrupts as usize;
+        let unimplemented = (0xFF >> ctl_bits) as u8;
+        Clic {
+            inputs: IrqLevels::new(count),
+            last_input: vec![false; count],
+            ip: vec![false; count],
+            ie: vec![false; count],
+            attr: vec![0; count],
+            ctl: vec![unimplemented; count],
+            cfg: 0,
+            ctl_bits,
+        }
+    }
+
+    pub fn num_interrupts(&self) -> u32 {
+        self.ip.len() as u32
+    }
+
+    /// Hand out the input line for interrupt `id`
+    pub fn irq_line(&self, id: u32) -> IrqLine {
+        assert!(id < self.num_interrupts(), "invalid CLIC interrupt {}", id);
+        IrqLine::new(self.inputs.clone(), id)
+    }
+
+    /// Whether any interrupt has its clicintie bit set
+    pub fn any_enabled(&self) -> bool {
+        self.ie.iter().any(|&enabled| enabled)
+    }
+
+    pub fn is_pending(&self, id: u32) -> bool {
+        self.ip[id as usize]
+    }
+
+    fn nlbits(&self) -> u32 {
+        let nlbits = ((self.cfg & CLICCFG_NLBITS_MASK) >> CLICCFG_NLBITS_SHIFT) as u32;
+        nlbits.min(8)
+    }
+
+    /// The upper nlbits of clicintctl select the level, the unused lower bits read as ones
+    pub fn get_level(&self, id: u32) -> u32 {
+        let nlbits = self.nlbits();
+        if nlbits == 0 {
+            return 0xFF;
+        }
+        let ctl = self.ctl[id as usize] as u32;
+        (ctl & (0xFF << (8 - nlbits)) & 0xFF) | (0xFF >> nlbits)
+    }
+
+    pub fn get_priority(&self, id: u32) -> u32 {
+        (self.ctl[id as usize] as u32) & (0xFF >> self.nlbits())
+    }
+
+    pub fn get_trigger(&self, id: u32) -> Trigger {
+        Trigger::from_attr(self.attr[id as usize])
+    }
+
+    /// Feed the CLINT style mip bits into the standard local interrupt inputs
+    pub fn set_local_interrupts(&mut self, mip: u32) {
+        let local = [
+            (IRQ_M_SOFT, MIP_MSIP),
+            (IRQ_M_TIMER, MIP_MTIP),
+            (IRQ_M_EXT, MIP_MEIP),
+        ];
+        for (id, bit) in local.iter() {
+            if (*id as usize) < self.inputs.len() {
+                self.inputs.set(*id as usize, mip & bit != 0);
+            }
+        }
+        self.sample();
+    }
+
+    /// Update the pending bits from the input levels according to each interrupt's trigger
+    fn sample(&mut self) {
+        for id in 0..self.ip.len() {
+            let trigger = Trigger::from_attr(self.attr[id]);
+            let level = self.inputs.get(id) != trigger.is_negative();
+            if trigger.is_edge() {
+                if level && !self.last_input[id] {
+                    self.ip[id] = true;
+                }
+            } else {
+                self.ip[id] = level;
+            }
+            self.last_input[id] = level;
+        }
+    }
+
+    /// The pending and enabled interrupt with the highest level, then priority, then ID
+    pub fn highest_pending(&self) -> Option<ClicInterrupt> {
+        let mut best: Option<ClicInterrupt> = None;
+        for id in 0..self.num_interrupts() {
+            if !self.ip[id as usize] || !self.ie[id as usize] {
+                continue;
+            }
+            let candidate = ClicInterrupt {
+                id,
+                level: self.get_level(id),
+                priority: self.get_priority(id),
+                vectored: self.attr[id as usize] & ATTR_SHV != 0,
+            };
+            let better = match best {
+                None => true,
+                Some(current) => {
+                    (candidate.level, candidate.priority, candidate.id)
+                        > (current.level, current.priority, current.id)
+                }
+            };
+            if better {
+                best = Some(candidate);
+            }
+        }
+        best
+    }
+
+    /// Called when the hart takes interrupt `id`, edge triggered interrupts are cleared here
+    pub fn acknowledge(&mut self, id: u32) {
+        if self.get_trigger(id).is_edge() {
+            self.ip[id as usize] = false;
+        }
+    }
+
+    fn clicinfo(&self) -> u32 {
+        (self.ctl_bits << 21) | (CLIC_VERSION << 13) | self.num_interrupts()
+    }
+}
+
+impl Mem for Clic {
+    fn read_byte(&self, addr: u32) -> MemoryResult<u8> {
+        if addr < CLICINFO_OFFSET {
+            Ok(if addr == CLICCFG_OFFSET { self.cfg } else { 0 })
+        } else if addr < CLICINFO_OFFSET + 4 {
+            Ok(((self.clicinfo() >> ((addr - CLICINFO_OFFSET) * 8)) & 0xFF) as u8)
+        } else if addr >= INTERRUPT_OFFSET && addr < INTERRUPT_OFFSET + self.num_interrupts() * 4 {
+            let id = ((addr - INTERRUPT_OFFSET) / 4) as usize;
+            Ok(match (addr - INTERRUPT_OFFSET) % 4 {
+                INTIP => self.ip[id] as u8,
+                INTIE => self.ie[id] as u8,
+                INTATTR => self.attr[id] | ATTR_MODE_MACHINE,
+                _ => self.ctl[id],
+            })
+        } else if addr < CLIC_SIZE {
+            Ok(0)
+        } else {
+            Err(MemoryError::UnmappedRegion)
+        }
+    }
+
+    fn write_byte(&mut self, addr: u32, val: u8) -> MemoryResult<()> {
+        if addr == CLICCFG_OFFSET {
+            self.cfg = val & CLICCFG_NLBITS_MASK;
+        } else if addr >= INTERRUPT_OFFSET && addr < INTERRUPT_OFFSET + self.num_interrupts() * 4 {
+            let id = ((addr - INTERRUPT_OFFSET) / 4) as usize;
+            match (addr - INTERRUPT_OFFSET) % 4 {
+                // Software can only set or clear edge triggered pending bits, level triggered
+                // interrupts follow their input
+                INTIP => {
+                    if Trigger::from_attr(self.attr[id]).is_edge() {
+                        self.ip[id] = val & 1 != 0;
+                    }
+                }
+                INTIE => self.ie[id] = val & 1 != 0,
+                INTATTR => self.attr[id] = val & (ATTR_SHV | ATTR_TRIG_MASK),
+                INTCTL => self.ctl[id] = val | (0xFF >> self.ctl_bits) as u8,
+                _ => unreachable!(),
+            }
+        } else if addr >= CLIC_SIZE {
+            return Err(MemoryError::UnmappedRegion);
+        }
+        Ok(())
+    }
+}
+
+impl Device for Clic {
+    fn tick(&mut self) {
+        self.sample();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn configure(clic: &mut Clic, id: u32, ctl: u8, attr: u8) {
+        let base = INTERRUPT_OFFSET + id * 4;
+        clic.write_byte(base + INTIE, 1).unwrap();
+        clic.write_byte(base + INTATTR, attr).unwrap();
+        clic.write_byte(base + INTCTL, ctl).unwrap();
+    }
+
+    #[test]
+    fn level_and_priority_split() {
+        let mut clic = Clic::new(32, 8);
+        // 2 level bits
+        clic.write_byte(CLICCFG_OFFSET, 2 << CLICCFG_NLBITS_SHIFT).unwrap();
+        configure(&mut clic, 20, 0b1000_0011, 0);
+        assert_eq!(clic.get_level(20), 0b1011_1111);
+        assert_eq!(clic.get_priority(20), 0b0000_0011);
+    }
+
+    #[test]
+    fn unimplemented_ctl_bits_read_as_ones() {
+        let mut clic = Clic::new(32, 3);
+        clic.write_byte(INTERRUPT_OFFSET + 16 * 4 + INTCTL, 0).unwrap();
+        assert_eq!(clic.read_byte(INTERRUPT_OFFSET + 16 * 4 + INTCTL).unwrap(), 0x1F);
+    }
+
+    #[test]
+    fn highest_level_wins() {
+        let mut clic = Clic::new(32, 8);
+        clic.write_byte(CLICCFG_OFFSET, 8 << CLICCFG_NLBITS_SHIFT).unwrap();
+        configure(&mut clic, 16, 0x40, 0);
+        configure(&mut clic, 17, 0x80, ATTR_SHV);
+        clic.irq_line(16).raise();
+        clic.irq_line(17).raise();
+        clic.tick();
+
+        let irq = clic.highest_pending().unwrap();
+        assert_eq!(irq.id, 17);
+        assert_eq!(irq.level, 0x80);
+        assert!(irq.vectored);
+    }
+
+    #[test]
+    fn edge_triggered_latches() {
+        let mut clic = Clic::new(32, 8);
+        configure(&mut clic, 18, 0xFF, 0b01 << ATTR_TRIG_SHIFT);
+        let line = clic.irq_line(18);
+
+        line.raise();
+        clic.tick();
+        line.lower();
+        clic.tick();
+        assert!(clic.is_pending(18));
+
+        clic.acknowledge(18);
+        assert!(!clic.is_pending(18));
+        // The line has to go low and high again for another edge
+        line.raise();
+        clic.tick();
+        clic.acknowledge(18);
+        clic.tick();
+        assert!(!clic.is_pending(18));
+    }
+
+    #[test]
+    fn negative_level() {
+        let mut clic = Clic::new(32, 8);
+        configure(&mut clic, 19, 0xFF, 0b10 << ATTR_TRIG_SHIFT);
+        clic.tick();
+        assert!(clic.is_pending(19));
+        clic.irq_line(19).raise();
+        clic.tick();
+        assert!(!clic.is_pending(19));
+    }
+
+    #[test]
+    fn local_timer_input() {
+        let mut clic = Clic::new(32, 8);
+        configure(&mut clic, IRQ_M_TIMER, 0xFF, 0);
+        clic.set_local_interrupts(MIP_MTIP);
+        assert_eq!(clic.highest_pending().unwrap().id, IRQ_M_TIMER);
+    }
+}
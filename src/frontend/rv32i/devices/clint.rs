@@ -0,0 +1,163 @@
+use super::super::csr::{MIP_MSIP, MIP_MTIP};
+use super::super::mem::{Mem, MemoryError, MemoryResult};
+use super::super::snapshot::{get_u32s, get_u64, get_u64s, invalid, put_u32s, put_u64, put_u64s};
+use super::{register_byte, set_register_byte, Device};
+
+use std::io::{self, Read, Write};
+
+pub const CLINT_BASE: u32 = 0x0200_0000;
+pub const CLINT_SIZE: u32 = 0x1_0000;
+
+/// mtime advances once per step, this is the nominal rate reported to guests
+pub const TIMEBASE_FREQUENCY: u32 = 10_000_000;
+
+const MSIP_OFFSET: u32 = 0x0;
+const MTIMECMP_OFFSET: u32 = 0x4000;
+const MTIME_OFFSET: u32 = 0xBFF8;
+
+/// Core-local interruptor using the SiFive register layout
+#[derive(Debug, Clone)]
+pub struct Clint {
+    msip: Vec<u32>,
+    mtimecmp: Vec<u64>,
+    mtime: u64,
+}
+
+impl Clint {
+    pub fn new(num_harts: u32) -> Self {
+        Clint {
+            msip: vec![0; num_harts as usize],
+            // Nothing should fire until software programs a compare value
+            mtimecmp: vec![u64::MAX; num_harts as usize],
+            mtime: 0,
+        }
+    }
+
+    pub fn get_mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    pub fn set_mtime(&mut self, val: u64) {
+        self.mtime = val;
+    }
+
+    pub fn get_mtimecmp(&self, hartid: u32) -> u64 {
+        self.mtimecmp[hartid as usize]
+    }
+
+    pub fn set_msip(&mut self, hartid: u32, val: bool) {
+        self.msip[hartid as usize] = val as u32;
+    }
+
+    pub fn num_harts(&self) -> u32 {
+        self.msip.len() as u32
+    }
+
+    pub(crate) fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        put_u32s(out, &self.msip)?;
+        put_u64s(out, &self.mtimecmp)?;
+        put_u64(out, self.mtime)
+    }
+
+    pub(crate) fn read_from<R: Read>(input: &mut R) -> io::Result<Self> {
+        let msip = get_u32s(input)?;
+        let mtimecmp = get_u64s(input)?;
+        if msip.len() != mtimecmp.len() {
+            return Err(invalid("CLINT registers for different numbers of harts"));
+        }
+        let mtime = get_u64(input)?;
+        Ok(Clint {
+            msip,
+            mtimecmp,
+            mtime,
+        })
+    }
+}
+
+impl Mem for Clint {
+    fn read_byte(&self, addr: u32) -> MemoryResult<u8> {
+        if addr < MSIP_OFFSET + self.num_harts() * 4 {
+            let hart = (addr - MSIP_OFFSET) / 4;
+            Ok(register_byte(self.msip[hart as usize] as u64, addr % 4))
+        } else if addr >= MTIMECMP_OFFSET && addr < MTIMECMP_OFFSET + self.num_harts() * 8 {
+            let hart = (addr - MTIMECMP_OFFSET) / 8;
+            Ok(register_byte(self.mtimecmp[hart as usize], addr % 8))
+        } else if (MTIME_OFFSET..MTIME_OFFSET + 8).contains(&addr) {
+            Ok(register_byte(self.mtime, addr - MTIME_OFFSET))
+        } else {
+            Err(MemoryError::UnmappedRegion)
+        }
+    }
+
+    fn write_byte(&mut self, addr: u32, val: u8) -> MemoryResult<()> {
+        if addr < MSIP_OFFSET + self.num_harts() * 4 {
+            let hart = ((addr - MSIP_OFFSET) / 4) as usize;
+            // Only bit 0 of msip is implemented
+            if addr % 4 == 0 {
+                self.msip[hart] = (val & 1) as u32;
+            }
+        } else if addr >= MTIMECMP_OFFSET && addr < MTIMECMP_OFFSET + self.num_harts() * 8 {
+            let hart = ((addr - MTIMECMP_OFFSET) / 8) as usize;
+            self.mtimecmp[hart] = set_register_byte(self.mtimecmp[hart], addr % 8, val);
+        } else if (MTIME_OFFSET..MTIME_OFFSET + 8).contains(&addr) {
+            self.mtime = set_register_byte(self.mtime, addr - MTIME_OFFSET, val);
+        } else {
+            return Err(MemoryError::UnmappedRegion);
+        }
+        Ok(())
+    }
+}
+
+impl Device for Clint {
+    fn tick(&mut self) {
+        self.mtime = self.mtime.wrapping_add(1);
+    }
+
+    fn pending_interrupts(&self, hartid: u32) -> u32 {
+        let hart = hartid as usize;
+        if hart >= self.msip.len() {
+            return 0;
+        }
+
+        let mut pending = 0;
+        if self.msip[hart] & 1 != 0 {
+            pending |= MIP_MSIP;
+        }
+        if self.mtime >= self.mtimecmp[hart] {
+            pending |= MIP_MTIP;
+        }
+        pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timer_fires_at_compare() {
+        let mut clint = Clint::new(1);
+        clint.write_word(MTIMECMP_OFFSET, 2).unwrap();
+        clint.write_word(MTIMECMP_OFFSET + 4, 0).unwrap();
+        assert_eq!(clint.pending_interrupts(0), 0);
+        clint.tick();
+        clint.tick();
+        assert_eq!(clint.pending_interrupts(0), MIP_MTIP);
+    }
+
+    #[test]
+    fn software_interrupt() {
+        let mut clint = Clint::new(2);
+        clint.write_word(MSIP_OFFSET + 4, 1).unwrap();
+        assert_eq!(clint.pending_interrupts(0), 0);
+        assert_eq!(clint.pending_interrupts(1), MIP_MSIP);
+    }
+
+    #[test]
+    fn mtime_halves() {
+        let mut clint = Clint::new(1);
+        clint.set_mtime(0x1_2345_6789);
+        assert_eq!(clint.read_word(MTIME_OFFSET).unwrap(), 0x2345_6789);
+        assert_eq!(clint.read_word(MTIME_OFFSET + 4).unwrap(), 0x1);
+    }
+}
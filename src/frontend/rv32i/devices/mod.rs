@@ -0,0 +1,116 @@
+pub mod clic;
+pub mod clint;
+pub mod plic;
+pub mod uart;
+pub mod virtio;
+
+use super::mem::Mem;
+
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A memory mapped peripheral which can be attached to `RAM`.
+///
+/// Addresses passed to the `Mem` functions are relative to the base the device was attached at.
+pub trait Device: Mem + Debug + Send {
+    /// Advance the device by one step of the CPU run loop
+    fn tick(&mut self) {}
+
+    /// Called after `tick` with the guest memory, for devices which perform DMA. Other attached
+    /// devices are not reachable through `memory` during this call.
+    fn process(&mut self, _memory: &mut dyn Mem) {}
+
+    /// The mip bits this device is currently asserting for `hartid`
+    fn pending_interrupts(&self, _hartid: u32) -> u32 {
+        0
+    }
+}
+
+pub type DeviceRef = Arc<Mutex<dyn Device>>;
+
+/// Input levels of an interrupt controller, shared with the `IrqLine`s driving them
+#[derive(Debug)]
+pub(crate) struct IrqLevels {
+    levels: Vec<AtomicBool>,
+    /// How many of `levels` are raised, so a controller can skip sampling while none is
+    raised: AtomicUsize,
+}
+
+impl IrqLevels {
+    pub(crate) fn new(count: usize) -> Arc<Self> {
+        Arc::new(IrqLevels {
+            levels: (0..count).map(|_| AtomicBool::new(false)).collect(),
+            raised: AtomicUsize::new(0),
+        })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub(crate) fn get(&self, line: usize) -> bool {
+        self.levels[line].load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set(&self, line: usize, level: bool) {
+        if self.levels[line].swap(level, Ordering::SeqCst) != level {
+            if level {
+                self.raised.fetch_add(1, Ordering::SeqCst);
+            } else {
+                self.raised.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    pub(crate) fn any_raised(&self) -> bool {
+        self.raised.load(Ordering::SeqCst) != 0
+    }
+}
+
+/// Handle given to a peripheral model so it can drive one input of an interrupt controller.
+///
+/// The controller samples the line level on its next tick, so raising and lowering a line never
+/// needs to lock the controller itself.
+#[derive(Debug, Clone)]
+pub struct IrqLine {
+    levels: Arc<IrqLevels>,
+    source: u32,
+}
+
+impl IrqLine {
+    pub(crate) fn new(levels: Arc<IrqLevels>, source: u32) -> Self {
+        IrqLine { levels, source }
+    }
+
+    pub fn raise(&self) {
+        self.set(true);
+    }
+
+    pub fn lower(&self) {
+        self.set(false);
+    }
+
+    pub fn set(&self, level: bool) {
+        self.levels.set(self.source as usize, level);
+    }
+
+    pub fn is_raised(&self) -> bool {
+        self.levels.get(self.source as usize)
+    }
+
+    pub fn get_source(&self) -> u32 {
+        self.source
+    }
+}
+
+/// Read byte `index` of a little endian register value
+pub(crate) fn register_byte(val: u64, index: u32) -> u8 {
+    ((val >> (index * 8)) & 0xFF) as u8
+}
+
+/// Replace byte `index` of a little endian register value
+pub(crate) fn set_register_byte(reg: u64, index: u32, val: u8) -> u64 {
+    let shift = index * 8;
+    (reg & !(0xFF << shift)) | ((val as u64) << shift)
+}
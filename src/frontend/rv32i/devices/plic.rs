@@ -0,0 +1,516 @@
+use super::super::csr::{PrivilegeMode, MIP_MEIP, MIP_SEIP};
+use super::super::mem::{Mem, MemoryError, MemoryResult};
+use super::super::snapshot::{get_bool, get_u32, get_u32s, invalid, put_bool, put_u32, put_u32s};
+use super::{register_byte, set_register_byte, Device, IrqLevels, IrqLine};
+
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+pub const PLIC_BASE: u32 = 0x0C00_0000;
+pub const PLIC_SIZE: u32 = 0x0400_0000;
+
+/// Source 0 is reserved to mean "no interrupt", so at most 1023 sources can be used
+pub const PLIC_MAX_SOURCES: u32 = 1023;
+pub const PLIC_MAX_CONTEXTS: u32 = 15872;
+
+const PRIORITY_OFFSET: u32 = 0x0;
+const PENDING_OFFSET: u32 = 0x1000;
+const ENABLE_OFFSET: u32 = 0x2000;
+const ENABLE_STRIDE: u32 = 0x80;
+const CONTEXT_OFFSET: u32 = 0x20_0000;
+const CONTEXT_STRIDE: u32 = 0x1000;
+const CLAIM_COMPLETE: u32 = 0x4;
+
+const PRIORITY_MASK: u32 = 0x7;
+
+/// A hart and privilege mode the PLIC delivers interrupts to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlicContext {
+    pub hartid: u32,
+    pub mode: PrivilegeMode,
+}
+
+/// Claim/complete state, this changes on register reads so it lives behind a `RefCell`
+#[derive(Debug, Clone)]
+struct Gateway {
+    pending: Vec<bool>,
+    in_flight: Vec<bool>,
+}
+
+/// Pending and claimed sources and the registers software programmed, see `Plic::state`.
+/// Source levels aren't included, the devices driving them raise them again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlicState {
+    pending: Vec<bool>,
+    claimed: Vec<bool>,
+    priority: Vec<u32>,
+    enable: Vec<Vec<u32>>,
+    threshold: Vec<u32>,
+}
+
+impl PlicState {
+    pub(crate) fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        put_u32(out, self.pending.len() as u32)?;
+        for (pending, claimed) in self.pending.iter().zip(&self.claimed) {
+            put_bool(out, *pending)?;
+            put_bool(out, *claimed)?;
+        }
+        put_u32s(out, &self.priority)?;
+        put_u32(out, self.threshold.len() as u32)?;
+        for (enable, threshold) in self.enable.iter().zip(&self.threshold) {
+            put_u32s(out, enable)?;
+            put_u32(out, *threshold)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_from<R: Read>(input: &mut R) -> io::Result<Self> {
+        let mut pending = Vec::new();
+        let mut claimed = Vec::new();
+        for _ in 0..get_u32(input)? {
+            pending.push(get_bool(input)?);
+            claimed.push(get_bool(input)?);
+        }
+        let priority = get_u32s(input)?;
+        let mut enable = Vec::new();
+        let mut threshold = Vec::new();
+        for _ in 0..get_u32(input)? {
+            enable.push(get_u32s(input)?);
+            threshold.push(get_u32(input)?);
+        }
+        let words = priority.len().div_ceil(32);
+        if priority.len() != pending.len() || enable.iter().any(|enable| enable.len() != words) {
+            return Err(invalid("PLIC registers for different numbers of sources"));
+        }
+        Ok(PlicState {
+            pending,
+            claimed,
+            priority,
+            enable,
+            threshold,
+        })
+    }
+}
+
+/// Platform-level interrupt controller using the SiFive / QEMU virt register layout
+#[derive(Debug)]
+pub struct Plic {
+    contexts: Vec<PlicContext>,
+    levels: Arc<IrqLevels>,
+    gateway: RefCell<Gateway>,
+    priority: Vec<u32>,
+    enable: Vec<Vec<u32>>,
+    threshold: Vec<u32>,
+}
+
+impl Plic {
+    /// A PLIC with an M-mode and S-mode context for each hart, context `2 * hartid` is M-mode and
+    /// `2 * hartid + 1` is S-mode.
+    pub fn new(num_sources: u32, num_harts: u32) -> Self {
+        let contexts = (0..num_harts)
+            .flat_map(|hartid| {
+                vec![
+                    PlicContext {
+                        hartid,
+                        mode: PrivilegeMode::Machine,
+                    },
+                    PlicContext {
+                        hartid,
+                        mode: PrivilegeMode::Supervisor,
+                    },
+                ]
+            })
+            .collect();
+        Plic::with_contexts(num_sources, contexts)
+    }
+
+    pub fn with_contexts(num_sources: u32, contexts: Vec<PlicContext>) -> Self {
+        assert!(num_sources <= PLIC_MAX_SOURCES, "too many PLIC sources");
+        assert!(contexts.len() as u32 <= PLIC_MAX_CONTEXTS, "too many PLIC contexts");
+
+        let lines = num_sources as usize + 1;
+        let words = lines.div_ceil(32);
+        Plic {
+            levels: IrqLevels::new(lines),
+            gateway: RefCell::new(Gateway {
+                pending: vec![false; lines],
+                in_flight: vec![false; lines],
+            }),
+            priority: vec![0; lines],
+            enable: vec![vec![0; words]; contexts.len()],
+            threshold: vec![0; contexts.len()],
+            contexts,
+        }
+    }
+
+    pub fn num_sources(&self) -> u32 {
+        self.priority.len() as u32 - 1
+    }
+
+    pub fn get_contexts(&self) -> &[PlicContext] {
+        &self.contexts
+    }
+
+    /// Hand out the line for `source`, any number of handles to the same source can exist.
+    /// Sources are level triggered, a raised line is latched as pending on the next tick once the
+    /// previous interrupt from the source has been completed.
+    pub fn irq_line(&self, source: u32) -> IrqLine {
+        assert!(
+            source != 0 && source <= self.num_sources(),
+            "invalid PLIC source {}",
+            source
+        );
+        IrqLine::new(self.levels.clone(), source)
+    }
+
+    pub fn is_pending(&self, source: u32) -> bool {
+        self.gateway.borrow().pending[source as usize]
+    }
+
+    pub fn state(&self) -> PlicState {
+        let gateway = self.gateway.borrow();
+        PlicState {
+            pending: gateway.pending.clone(),
+            claimed: gateway.in_flight.clone(),
+            priority: self.priority.clone(),
+            enable: self.enable.clone(),
+            threshold: self.threshold.clone(),
+        }
+    }
+
+    /// Whether `state` comes from a PLIC with the same number of sources and contexts
+    pub fn fits(&self, state: &PlicState) -> bool {
+        state.priority.len() == self.priority.len() && state.threshold.len() == self.threshold.len()
+    }
+
+    /// Go back to `state`, which has to `fit`
+    pub fn set_state(&mut self, state: &PlicState) {
+        assert!(self.fits(state), "PLIC state from another configuration");
+        let gateway = self.gateway.get_mut();
+        gateway.pending.clone_from(&state.pending);
+        gateway.in_flight.clone_from(&state.claimed);
+        self.priority.clone_from(&state.priority);
+        self.enable.clone_from(&state.enable);
+        self.threshold.clone_from(&state.threshold);
+    }
+
+    fn is_enabled(&self, context: usize, source: usize) -> bool {
+        self.enable[context][source / 32] & (1 << (source % 32)) != 0
+    }
+
+    /// The highest priority pending source enabled for `context`, ties go to the lowest ID
+    fn best_pending(&self, context: usize) -> Option<usize> {
+        let gateway = self.gateway.borrow();
+        let mut best: Option<usize> = None;
+        for source in 1..self.priority.len() {
+            if !gateway.pending[source] || !self.is_enabled(context, source) {
+                continue;
+            }
+            if self.priority[source] == 0 {
+                continue;
+            }
+            match best {
+                Some(current) if self.priority[current] >= self.priority[source] => {}
+                _ => best = Some(source),
+            }
+        }
+        best
+    }
+
+    fn claim(&self, context: usize) -> u32 {
+        match self.best_pending(context) {
+            Some(source) => {
+                let mut gateway = self.gateway.borrow_mut();
+                gateway.pending[source] = false;
+                gateway.in_flight[source] = true;
+                source as u32
+            }
+            None => 0,
+        }
+    }
+
+    /// The context whose claim/complete register is at `addr`
+    fn claim_context(&self, addr: u32) -> Option<usize> {
+        let num_contexts = self.contexts.len() as u32;
+        if addr >= CONTEXT_OFFSET
+            && addr < CONTEXT_OFFSET + num_contexts * CONTEXT_STRIDE
+            && (addr - CONTEXT_OFFSET) % CONTEXT_STRIDE == CLAIM_COMPLETE
+        {
+            Some(((addr - CONTEXT_OFFSET) / CONTEXT_STRIDE) as usize)
+        } else {
+            None
+        }
+    }
+
+    fn complete(&mut self, context: usize, source: u32) {
+        let source = source as usize;
+        if source == 0 || source >= self.priority.len() || !self.is_enabled(context, source) {
+            return;
+        }
+        self.gateway.get_mut().in_flight[source] = false;
+    }
+
+    /// Register contents without any side effects
+    fn read_register(&self, addr: u32) -> MemoryResult<u32> {
+        let num_contexts = self.contexts.len() as u32;
+        let words = self.enable.first().map_or(0, |enable| enable.len()) as u32;
+
+        if addr < PENDING_OFFSET {
+            let source = ((addr - PRIORITY_OFFSET) / 4) as usize;
+            Ok(self.priority.get(source).copied().unwrap_or(0))
+        } else if addr < ENABLE_OFFSET {
+            let word = ((addr - PENDING_OFFSET) / 4) as usize;
+            let gateway = self.gateway.borrow();
+            Ok((0..32).fold(0, |bits, bit| {
+                let source = word * 32 + bit;
+                match gateway.pending.get(source) {
+                    Some(true) => bits | (1 << bit),
+                    _ => bits,
+                }
+            }))
+        } else if addr < ENABLE_OFFSET + num_contexts * ENABLE_STRIDE {
+            let context = ((addr - ENABLE_OFFSET) / ENABLE_STRIDE) as usize;
+            let word = (addr - ENABLE_OFFSET) % ENABLE_STRIDE / 4;
+            if word < words {
+                Ok(self.enable[context][word as usize])
+            } else {
+                Ok(0)
+            }
+        } else if addr >= CONTEXT_OFFSET && addr < CONTEXT_OFFSET + num_contexts * CONTEXT_STRIDE {
+            let context = ((addr - CONTEXT_OFFSET) / CONTEXT_STRIDE) as usize;
+            match (addr - CONTEXT_OFFSET) % CONTEXT_STRIDE {
+                0 => Ok(self.threshold[context]),
+                CLAIM_COMPLETE => Ok(self.best_pending(context).unwrap_or(0) as u32),
+                _ => Ok(0),
+            }
+        } else if addr < PLIC_SIZE {
+            Ok(0)
+        } else {
+            Err(MemoryError::UnmappedRegion)
+        }
+    }
+
+    fn write_register(&mut self, addr: u32, val: u32) -> MemoryResult<()> {
+        let num_contexts = self.contexts.len() as u32;
+
+        if addr < PENDING_OFFSET {
+            let source = ((addr - PRIORITY_OFFSET) / 4) as usize;
+            if source != 0 && source < self.priority.len() {
+                self.priority[source] = val & PRIORITY_MASK;
+            }
+        } else if addr < ENABLE_OFFSET {
+            // The pending bits are read-only
+        } else if addr < ENABLE_OFFSET + num_contexts * ENABLE_STRIDE {
+            let context = ((addr - ENABLE_OFFSET) / ENABLE_STRIDE) as usize;
+            let word = ((addr - ENABLE_OFFSET) % ENABLE_STRIDE / 4) as usize;
+            if word < self.enable[context].len() {
+                let mut val = val;
+                // Source 0 does not exist, and neither do sources past the configured count
+                if word == 0 {
+                    val &= !1;
+                }
+                let last = self.priority.len() - word * 32;
+                if last < 32 {
+                    val &= (1 << last) - 1;
+                }
+                self.enable[context][word] = val;
+            }
+        } else if addr >= CONTEXT_OFFSET && addr < CONTEXT_OFFSET + num_contexts * CONTEXT_STRIDE {
+            let context = ((addr - CONTEXT_OFFSET) / CONTEXT_STRIDE) as usize;
+            match (addr - CONTEXT_OFFSET) % CONTEXT_STRIDE {
+                0 => self.threshold[context] = val & PRIORITY_MASK,
+                CLAIM_COMPLETE => self.complete(context, val),
+                _ => {}
+            }
+        } else if addr >= PLIC_SIZE {
+            return Err(MemoryError::UnmappedRegion);
+        }
+        Ok(())
+    }
+}
+
+impl Mem for Plic {
+    // Byte accesses never claim or complete, both take a full word access of the claim/complete
+    // register. Merging a byte into the register would complete whatever source it read as.
+    fn read_byte(&self, addr: u32) -> MemoryResult<u8> {
+        let word = self.read_register(addr & !0b11)?;
+        Ok(register_byte(word as u64, addr & 0b11))
+    }
+
+    fn write_byte(&mut self, addr: u32, val: u8) -> MemoryResult<()> {
+        if self.claim_context(addr & !0b11).is_some() {
+            return Ok(());
+        }
+        let word = self.read_register(addr & !0b11)?;
+        let word = set_register_byte(word as u64, addr & 0b11, val) as u32;
+        self.write_register(addr & !0b11, word)
+    }
+
+    fn read_word(&self, addr: u32) -> MemoryResult<u32> {
+        match self.claim_context(addr) {
+            Some(context) => Ok(self.claim(context)),
+            None => self.read_register(addr),
+        }
+    }
+
+    fn write_word(&mut self, addr: u32, val: u32) -> MemoryResult<()> {
+        self.write_register(addr, val)
+    }
+}
+
+impl Device for Plic {
+    fn tick(&mut self) {
+        if !self.levels.any_raised() {
+            return;
+        }
+        let gateway = self.gateway.get_mut();
+        for source in 1..gateway.pending.len() {
+            if self.levels.get(source) && !gateway.in_flight[source] {
+                gateway.pending[source] = true;
+            }
+        }
+    }
+
+    fn pending_interrupts(&self, hartid: u32) -> u32 {
+        // Checked every step, and most of the time nothing is pending
+        if !self.gateway.borrow().pending.contains(&true) {
+            return 0;
+        }
+        let mut pending = 0;
+        for (context, target) in self.contexts.iter().enumerate() {
+            if target.hartid != hartid {
+                continue;
+            }
+            let deliver = match self.best_pending(context) {
+                Some(source) => self.priority[source] > self.threshold[context],
+                None => false,
+            };
+            if deliver {
+                pending |= match target.mode {
+                    PrivilegeMode::Machine => MIP_MEIP,
+                    _ => MIP_SEIP,
+                };
+            }
+        }
+        pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enable_source(plic: &mut Plic, context: u32, source: u32, priority: u32) {
+        plic.write_word(PRIORITY_OFFSET + source * 4, priority).unwrap();
+        let addr = ENABLE_OFFSET + context * ENABLE_STRIDE + (source / 32) * 4;
+        let enabled = plic.read_word(addr).unwrap();
+        plic.write_word(addr, enabled | (1 << (source % 32))).unwrap();
+    }
+
+    fn claim_addr(context: u32) -> u32 {
+        CONTEXT_OFFSET + context * CONTEXT_STRIDE + CLAIM_COMPLETE
+    }
+
+    #[test]
+    fn claim_complete() {
+        let mut plic = Plic::new(8, 1);
+        let line = plic.irq_line(3);
+        enable_source(&mut plic, 0, 3, 1);
+
+        line.raise();
+        plic.tick();
+        assert!(plic.is_pending(3));
+        assert_eq!(plic.pending_interrupts(0), MIP_MEIP);
+
+        assert_eq!(plic.read_word(claim_addr(0)).unwrap(), 3);
+        assert!(!plic.is_pending(3));
+        assert_eq!(plic.pending_interrupts(0), 0);
+
+        // Still raised but in flight, so it is not pending again until completed
+        plic.tick();
+        assert!(!plic.is_pending(3));
+        plic.write_word(claim_addr(0), 3).unwrap();
+        plic.tick();
+        assert!(plic.is_pending(3));
+
+        line.lower();
+        assert_eq!(plic.read_word(claim_addr(0)).unwrap(), 3);
+        plic.write_word(claim_addr(0), 3).unwrap();
+        plic.tick();
+        assert!(!plic.is_pending(3));
+        assert_eq!(plic.read_word(claim_addr(0)).unwrap(), 0);
+    }
+
+    #[test]
+    fn priority_and_threshold() {
+        let mut plic = Plic::new(40, 1);
+        enable_source(&mut plic, 0, 2, 1);
+        enable_source(&mut plic, 0, 35, 5);
+        plic.irq_line(2).raise();
+        plic.irq_line(35).raise();
+        plic.tick();
+
+        plic.write_word(CONTEXT_OFFSET, 5).unwrap();
+        assert_eq!(plic.pending_interrupts(0), 0);
+        plic.write_word(CONTEXT_OFFSET, 4).unwrap();
+        assert_eq!(plic.pending_interrupts(0), MIP_MEIP);
+
+        assert_eq!(plic.read_word(PENDING_OFFSET + 4).unwrap(), 1 << 3);
+        assert_eq!(plic.read_word(claim_addr(0)).unwrap(), 35);
+        assert_eq!(plic.read_word(claim_addr(0)).unwrap(), 2);
+    }
+
+    #[test]
+    fn supervisor_context() {
+        let mut plic = Plic::new(8, 2);
+        enable_source(&mut plic, 3, 1, 1);
+        plic.irq_line(1).raise();
+        plic.tick();
+
+        assert_eq!(plic.pending_interrupts(0), 0);
+        assert_eq!(plic.pending_interrupts(1), MIP_SEIP);
+    }
+
+    #[test]
+    fn byte_reads_do_not_claim() {
+        let mut plic = Plic::new(8, 1);
+        enable_source(&mut plic, 0, 1, 1);
+        plic.irq_line(1).raise();
+        plic.tick();
+
+        assert_eq!(plic.read_byte(claim_addr(0)).unwrap(), 1);
+        assert!(plic.is_pending(1));
+    }
+
+    #[test]
+    fn sub_word_writes_do_not_complete() {
+        let mut plic = Plic::new(8, 1);
+        enable_source(&mut plic, 0, 1, 1);
+        enable_source(&mut plic, 0, 2, 1);
+        plic.irq_line(1).raise();
+        plic.irq_line(2).raise();
+        plic.tick();
+        assert_eq!(plic.read_word(claim_addr(0)).unwrap(), 1);
+        plic.irq_line(1).lower();
+
+        plic.write_byte(claim_addr(0), 2).unwrap();
+        plic.write_halfword(claim_addr(0), 1).unwrap();
+        plic.irq_line(1).raise();
+        plic.tick();
+        assert!(!plic.is_pending(1));
+
+        plic.write_word(claim_addr(0), 1).unwrap();
+        plic.tick();
+        assert!(plic.is_pending(1));
+    }
+
+    #[test]
+    fn unimplemented_sources_read_zero() {
+        let mut plic = Plic::new(4, 1);
+        plic.write_word(ENABLE_OFFSET, 0xFFFF_FFFF).unwrap();
+        assert_eq!(plic.read_word(ENABLE_OFFSET).unwrap(), 0b11110);
+        plic.write_word(PRIORITY_OFFSET + 20 * 4, 7).unwrap();
+        assert_eq!(plic.read_word(PRIORITY_OFFSET + 20 * 4).unwrap(), 0);
+    }
+}
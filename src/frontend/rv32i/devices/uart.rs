@@ -0,0 +1,229 @@
+use super::super::mem::{Mem, MemoryError, MemoryResult};
+use super::{Device, IrqLine};
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+pub const UART_BASE: u32 = 0x1000_0000;
+pub const UART_SIZE: u32 = 0x100;
+/// PLIC source the UART is wired to, the same as on QEMU's virt machine
+pub const UART_IRQ: u32 = 10;
+/// Input clock advertised to the guest for baud rate calculations
+pub const UART_CLOCK_FREQUENCY: u32 = 3_686_400;
+
+// Register offsets, the divisor latch replaces RBR/THR and IER while LCR.DLAB is set
+const RBR_THR_DLL: u32 = 0;
+const IER_DLM: u32 = 1;
+const IIR_FCR: u32 = 2;
+const LCR: u32 = 3;
+const MCR: u32 = 4;
+const LSR: u32 = 5;
+const MSR: u32 = 6;
+const SCR: u32 = 7;
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_THR_EMPTY: u8 = 1 << 1;
+
+const IIR_NO_INTERRUPT: u8 = 0x01;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_RX_AVAILABLE: u8 = 0x04;
+/// Reported in the top bits of IIR while the FIFOs are enabled
+const IIR_FIFO_ENABLED: u8 = 0xC0;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+const LSR_TRANSMITTER_EMPTY: u8 = 1 << 6;
+
+/// Carrier detect, data set ready and clear to send are always asserted
+const MSR_CONNECTED: u8 = 0xB0;
+
+/// NS16550A compatible UART. Transmission is instant, so the transmitter is always empty.
+#[derive(Debug, Clone, Default)]
+pub struct Uart {
+    /// Reading RBR pops a byte, `Mem` reads only get `&self`
+    rx: RefCell<VecDeque<u8>>,
+    tx: Vec<u8>,
+    /// Also write transmitted bytes to the host's stdout
+    echo: bool,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+    /// The THR empty interrupt is cleared by reading IIR and raised again by the next write
+    thre_pending: Cell<bool>,
+    irq: Option<IrqLine>,
+}
+
+impl Uart {
+    pub fn new() -> Self {
+        Uart::default()
+    }
+
+    /// A UART whose output also goes to the host's stdout
+    pub fn stdout() -> Self {
+        Uart {
+            echo: true,
+            ..Uart::default()
+        }
+    }
+
+    pub fn set_irq_line(&mut self, irq: IrqLine) {
+        self.irq = Some(irq);
+    }
+
+    /// Queue bytes for the guest to receive
+    pub fn push_input(&mut self, data: &[u8]) {
+        self.rx.borrow_mut().extend(data);
+    }
+
+    /// Everything the guest transmitted since the last call
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.tx)
+    }
+
+    fn dlab(&self) -> bool {
+        self.lcr & LCR_DLAB != 0
+    }
+
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_RX_AVAILABLE != 0 && !self.rx.borrow().is_empty() {
+            IIR_RX_AVAILABLE
+        } else if self.ier & IER_THR_EMPTY != 0 && self.thre_pending.get() {
+            IIR_THR_EMPTY
+        } else {
+            IIR_NO_INTERRUPT
+        }
+    }
+
+    fn transmit(&mut self, val: u8) {
+        self.tx.push(val);
+        if self.echo {
+            let mut stdout = io::stdout();
+            let _ = stdout.write_all(&[val]);
+            let _ = stdout.flush();
+        }
+        self.thre_pending.set(true);
+    }
+}
+
+impl Mem for Uart {
+    fn read_byte(&self, addr: u32) -> MemoryResult<u8> {
+        let val = match addr {
+            RBR_THR_DLL if self.dlab() => self.divisor as u8,
+            RBR_THR_DLL => self.rx.borrow_mut().pop_front().unwrap_or(0),
+            IER_DLM if self.dlab() => (self.divisor >> 8) as u8,
+            IER_DLM => self.ier,
+            IIR_FCR => {
+                let id = self.interrupt_id();
+                if id == IIR_THR_EMPTY {
+                    self.thre_pending.set(false);
+                }
+                let fifo = if self.fcr & 1 != 0 {
+                    IIR_FIFO_ENABLED
+                } else {
+                    0
+                };
+                id | fifo
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let ready = if self.rx.borrow().is_empty() {
+                    0
+                } else {
+                    LSR_DATA_READY
+                };
+                ready | LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY
+            }
+            MSR => MSR_CONNECTED,
+            SCR => self.scr,
+            _ if addr < UART_SIZE => 0,
+            _ => return Err(MemoryError::UnmappedRegion),
+        };
+        Ok(val)
+    }
+
+    fn write_byte(&mut self, addr: u32, val: u8) -> MemoryResult<()> {
+        match addr {
+            RBR_THR_DLL if self.dlab() => self.divisor = (self.divisor & 0xFF00) | val as u16,
+            RBR_THR_DLL => self.transmit(val),
+            IER_DLM if self.dlab() => self.divisor = (self.divisor & 0x00FF) | ((val as u16) << 8),
+            IER_DLM => {
+                // Enabling the THR empty interrupt fires it right away, the transmitter is idle
+                if val & IER_THR_EMPTY != 0 && self.ier & IER_THR_EMPTY == 0 {
+                    self.thre_pending.set(true);
+                }
+                self.ier = val & 0x0F;
+            }
+            IIR_FCR => self.fcr = val,
+            LCR => self.lcr = val,
+            MCR => self.mcr = val,
+            SCR => self.scr = val,
+            _ if addr < UART_SIZE => {}
+            _ => return Err(MemoryError::UnmappedRegion),
+        }
+        Ok(())
+    }
+}
+
+impl Device for Uart {
+    fn tick(&mut self) {
+        if let Some(irq) = &self.irq {
+            irq.set(self.interrupt_id() != IIR_NO_INTERRUPT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transmit_and_receive() {
+        let mut uart = Uart::new();
+        for byte in b"ok" {
+            uart.write_byte(RBR_THR_DLL, *byte).unwrap();
+        }
+        assert_eq!(uart.take_output(), b"ok");
+
+        assert_eq!(uart.read_byte(LSR).unwrap() & LSR_DATA_READY, 0);
+        uart.push_input(b"ab");
+        assert_ne!(uart.read_byte(LSR).unwrap() & LSR_DATA_READY, 0);
+        assert_eq!(uart.read_byte(RBR_THR_DLL).unwrap(), b'a');
+        assert_eq!(uart.read_byte(RBR_THR_DLL).unwrap(), b'b');
+        assert_eq!(uart.read_byte(LSR).unwrap() & LSR_DATA_READY, 0);
+    }
+
+    #[test]
+    fn divisor_latch() {
+        let mut uart = Uart::new();
+        uart.write_byte(LCR, LCR_DLAB | 0x3).unwrap();
+        uart.write_byte(RBR_THR_DLL, 0x34).unwrap();
+        uart.write_byte(IER_DLM, 0x12).unwrap();
+        assert_eq!(uart.divisor, 0x1234);
+        uart.write_byte(LCR, 0x3).unwrap();
+        assert_eq!(uart.read_byte(IER_DLM).unwrap(), 0);
+        assert!(uart.take_output().is_empty());
+    }
+
+    #[test]
+    fn interrupt_identification() {
+        let mut uart = Uart::new();
+        assert_eq!(uart.read_byte(IIR_FCR).unwrap(), IIR_NO_INTERRUPT);
+
+        uart.write_byte(IER_DLM, IER_RX_AVAILABLE | IER_THR_EMPTY)
+            .unwrap();
+        uart.push_input(b"x");
+        assert_eq!(uart.read_byte(IIR_FCR).unwrap(), IIR_RX_AVAILABLE);
+        uart.read_byte(RBR_THR_DLL).unwrap();
+
+        // THR empty is reported once, then cleared by the IIR read
+        assert_eq!(uart.read_byte(IIR_FCR).unwrap(), IIR_THR_EMPTY);
+        assert_eq!(uart.read_byte(IIR_FCR).unwrap(), IIR_NO_INTERRUPT);
+    }
+}
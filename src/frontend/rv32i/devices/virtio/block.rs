@@ -0,0 +1,424 @@
+use super::super::super::mem::{Mem, MemoryResult};
+use super::{register_byte, DescriptorChain, VirtioDevice, Virtqueue, VIRTIO_ID_BLOCK};
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub const SECTOR_SIZE: u64 = 512;
+
+// Feature bits
+pub const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+pub const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+// Request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+// Request status codes
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// type, reserved and sector fields at the start of every request
+const REQUEST_HEADER_SIZE: usize = 16;
+/// Length of the serial returned by GET_ID
+const DEVICE_ID_SIZE: usize = 20;
+
+/// How guest writes reach the image file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiskMode {
+    /// The file is never written and the device is offered to the guest as read-only
+    ReadOnly,
+    /// Guest writes are kept in memory on top of the unmodified file and are lost on exit
+    CopyOnWrite,
+    /// Guest writes go straight to the file
+    ReadWrite,
+}
+
+/// A disk image file, read and written in whole sectors
+#[derive(Debug)]
+pub struct DiskImage {
+    file: File,
+    mode: DiskMode,
+    sectors: u64,
+    /// Sectors written by the guest in `CopyOnWrite` mode
+    overlay: HashMap<u64, Vec<u8>>,
+}
+
+impl DiskImage {
+    pub fn open<P: AsRef<Path>>(path: P, mode: DiskMode) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(mode == DiskMode::ReadWrite)
+            .open(path)?;
+        // A trailing partial sector can't be addressed by the guest
+        let sectors = file.metadata()?.len() / SECTOR_SIZE;
+        Ok(DiskImage {
+            file,
+            mode,
+            sectors,
+            overlay: HashMap::new(),
+        })
+    }
+
+    pub fn get_mode(&self) -> DiskMode {
+        self.mode
+    }
+
+    /// Capacity in 512 byte sectors
+    pub fn get_sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn check_range(&self, sector: u64, len: usize) -> io::Result<()> {
+        let count = (len as u64).div_ceil(SECTOR_SIZE);
+        if len as u64 % SECTOR_SIZE != 0
+            || sector
+                .checked_add(count)
+                .map_or(true, |end| end > self.sectors)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "request outside of disk",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fill `buf` from `sector` onwards, `buf` must be a whole number of sectors
+    pub fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.check_range(sector, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(SECTOR_SIZE as usize).enumerate() {
+            let sector = sector + i as u64;
+            if let Some(data) = self.overlay.get(&sector) {
+                chunk.copy_from_slice(data);
+            } else {
+                self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+                self.file.read_exact(chunk)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `buf` from `sector` onwards, `buf` must be a whole number of sectors
+    pub fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> io::Result<()> {
+        self.check_range(sector, buf.len())?;
+        match self.mode {
+            DiskMode::ReadOnly => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "read-only disk",
+                ))
+            }
+            DiskMode::CopyOnWrite => {
+                for (i, chunk) in buf.chunks(SECTOR_SIZE as usize).enumerate() {
+                    self.overlay.insert(sector + i as u64, chunk.to_vec());
+                }
+            }
+            DiskMode::ReadWrite => {
+                self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+                self.file.write_all(buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.mode == DiskMode::ReadWrite {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// virtio-blk device with a single request queue
+#[derive(Debug)]
+pub struct VirtioBlock {
+    disk: DiskImage,
+    id: String,
+}
+
+impl VirtioBlock {
+    pub fn new(disk: DiskImage) -> Self {
+        VirtioBlock {
+            disk,
+            id: String::from("emulator-rs"),
+        }
+    }
+
+    /// Serial number reported to the guest, truncated to 20 bytes
+    pub fn set_id(&mut self, id: &str) {
+        self.id = id.to_string();
+    }
+
+    pub fn get_disk(&mut self) -> &mut DiskImage {
+        &mut self.disk
+    }
+
+    /// Carry out one request, returns the status and the data to place before the status byte
+    fn handle_request(
+        &mut self,
+        chain: &DescriptorChain,
+        memory: &dyn Mem,
+    ) -> MemoryResult<(u8, Vec<u8>)> {
+        let readable = chain.read_all(memory)?;
+        if readable.len() < REQUEST_HEADER_SIZE {
+            return Ok((VIRTIO_BLK_S_IOERR, Vec::new()));
+        }
+        let request_type = u32::from_le_bytes([readable[0], readable[1], readable[2], readable[3]]);
+        let mut sector_bytes = [0; 8];
+        sector_bytes.copy_from_slice(&readable[8..16]);
+        let sector = u64::from_le_bytes(sector_bytes);
+        // The last writable byte of the chain is reserved for the status
+        let data_len = chain.writable_len()? as usize - 1;
+
+        let result = match request_type {
+            VIRTIO_BLK_T_IN => {
+                let mut data = vec![0; data_len];
+                self.disk.read_sectors(sector, &mut data).map(|_| data)
+            }
+            VIRTIO_BLK_T_OUT => self
+                .disk
+                .write_sectors(sector, &readable[REQUEST_HEADER_SIZE..])
+                .map(|_| Vec::new()),
+            VIRTIO_BLK_T_FLUSH => self.disk.flush().map(|_| Vec::new()),
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = self.id.as_bytes().to_vec();
+                id.resize(DEVICE_ID_SIZE, 0);
+                id.truncate(data_len);
+                Ok(id)
+            }
+            _ => return Ok((VIRTIO_BLK_S_UNSUPP, Vec::new())),
+        };
+
+        Ok(match result {
+            Ok(data) => (VIRTIO_BLK_S_OK, data),
+            Err(_) => (VIRTIO_BLK_S_IOERR, Vec::new()),
+        })
+    }
+}
+
+impl VirtioDevice for VirtioBlock {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn device_features(&self) -> u64 {
+        match self.disk.get_mode() {
+            DiskMode::ReadOnly => VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH,
+            _ => VIRTIO_BLK_F_FLUSH,
+        }
+    }
+
+    fn num_queues(&self) -> u32 {
+        1
+    }
+
+    fn read_config(&self, offset: u32) -> u8 {
+        // Only the capacity field is implemented
+        if offset < 8 {
+            register_byte(self.disk.get_sectors(), offset)
+        } else {
+            0
+        }
+    }
+
+    fn process_queue(
+        &mut self,
+        _queue: u32,
+        vq: &mut Virtqueue,
+        memory: &mut dyn Mem,
+    ) -> MemoryResult<bool> {
+        let mut used = false;
+        while let Some(chain) = vq.pop(memory)? {
+            // Without a writable byte there is nowhere to put the status, so the request is dropped
+            let writable = chain.writable_len()?;
+            let written = if writable == 0 {
+                0
+            } else {
+                let (status, mut data) = self.handle_request(&chain, memory)?;
+                // Data that was not produced reads as zero, the status byte is always last
+                data.resize(writable as usize - 1, 0);
+                data.push(status);
+                chain.write_all(memory, &data)?
+            };
+            vq.push_used(memory, chain.head, written)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::super::mem::RAM;
+    use super::super::super::Device;
+    use super::super::tests::{notify, setup, submit, used_elem};
+    use super::super::VirtioMmio;
+    use super::*;
+
+    use std::path::PathBuf;
+
+    /// A four sector image where every byte of sector n is n
+    fn disk_file(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("emulator-rs-{}-{}.img", name, std::process::id()));
+        let mut contents = Vec::new();
+        for sector in 0..4u8 {
+            contents.extend(std::iter::repeat(sector).take(SECTOR_SIZE as usize));
+        }
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn write_header(ram: &mut RAM, addr: u32, request_type: u32, sector: u64) {
+        ram.write_word(addr, request_type).unwrap();
+        ram.write_word(addr + 4, 0).unwrap();
+        ram.write_word(addr + 8, sector as u32).unwrap();
+        ram.write_word(addr + 12, (sector >> 32) as u32).unwrap();
+    }
+
+    fn block(path: &PathBuf, mode: DiskMode) -> (RAM, VirtioMmio<VirtioBlock>) {
+        let mut mmio = VirtioMmio::new(VirtioBlock::new(DiskImage::open(path, mode).unwrap()));
+        setup(&mut mmio);
+        (RAM::new(0, 0x10000), mmio)
+    }
+
+    #[test]
+    fn capacity_config() {
+        let path = disk_file("capacity");
+        let (_, mmio) = block(&path, DiskMode::ReadOnly);
+        assert_eq!(mmio.read_word(0x100).unwrap(), 4);
+        assert_eq!(
+            mmio.read_word(0x10).unwrap() as u64,
+            VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn read_sector() {
+        let path = disk_file("read");
+        let (mut ram, mut mmio) = block(&path, DiskMode::ReadOnly);
+
+        write_header(&mut ram, 0x4000, VIRTIO_BLK_T_IN, 2);
+        ram.write_byte(0x6000, 0xFF).unwrap();
+        submit(
+            &mut ram,
+            0,
+            0,
+            &[(0x4000, 16, false), (0x5000, 512, true), (0x6000, 1, true)],
+        );
+        notify(&mut mmio, 0);
+        mmio.process(&mut ram);
+
+        assert_eq!(used_elem(&ram, 0, 0), (0, 513));
+        assert_eq!(ram.read_byte(0x6000).unwrap(), VIRTIO_BLK_S_OK);
+        assert_eq!(ram.read_byte(0x5000).unwrap(), 2);
+        assert_eq!(ram.read_byte(0x51FF).unwrap(), 2);
+        assert_eq!(
+            mmio.get_interrupt_status(),
+            super::super::INTERRUPT_USED_BUFFER
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn read_only_rejects_writes() {
+        let path = disk_file("ro");
+        let (mut ram, mut mmio) = block(&path, DiskMode::ReadOnly);
+
+        write_header(&mut ram, 0x4000, VIRTIO_BLK_T_OUT, 0);
+        submit(
+            &mut ram,
+            0,
+            0,
+            &[(0x4000, 16, false), (0x5000, 512, false), (0x6000, 1, true)],
+        );
+        notify(&mut mmio, 0);
+        mmio.process(&mut ram);
+        assert_eq!(ram.read_byte(0x6000).unwrap(), VIRTIO_BLK_S_IOERR);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn copy_on_write_overlay() {
+        let path = disk_file("cow");
+        let (mut ram, mut mmio) = block(&path, DiskMode::CopyOnWrite);
+
+        for addr in 0x5000..0x5200 {
+            ram.write_byte(addr, 0xAA).unwrap();
+        }
+        write_header(&mut ram, 0x4000, VIRTIO_BLK_T_OUT, 1);
+        submit(
+            &mut ram,
+            0,
+            0,
+            &[(0x4000, 16, false), (0x5000, 512, false), (0x6000, 1, true)],
+        );
+        notify(&mut mmio, 0);
+        mmio.process(&mut ram);
+        assert_eq!(ram.read_byte(0x6000).unwrap(), VIRTIO_BLK_S_OK);
+        assert_eq!(used_elem(&ram, 0, 0), (0, 1));
+
+        // Reading the sector back sees the overlay, the file does not
+        write_header(&mut ram, 0x4000, VIRTIO_BLK_T_IN, 1);
+        submit(
+            &mut ram,
+            0,
+            3,
+            &[(0x4000, 16, false), (0x7000, 512, true), (0x6000, 1, true)],
+        );
+        notify(&mut mmio, 0);
+        mmio.process(&mut ram);
+        assert_eq!(ram.read_byte(0x7000).unwrap(), 0xAA);
+        assert_eq!(std::fs::read(&path).unwrap()[512], 1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn out_of_range_and_unsupported() {
+        let path = disk_file("range");
+        let (mut ram, mut mmio) = block(&path, DiskMode::ReadOnly);
+
+        write_header(&mut ram, 0x4000, VIRTIO_BLK_T_IN, 4);
+        submit(
+            &mut ram,
+            0,
+            0,
+            &[(0x4000, 16, false), (0x5000, 512, true), (0x6000, 1, true)],
+        );
+        write_header(&mut ram, 0x4100, 0x99, 0);
+        submit(&mut ram, 0, 3, &[(0x4100, 16, false), (0x6001, 1, true)]);
+        notify(&mut mmio, 0);
+        mmio.process(&mut ram);
+        assert_eq!(ram.read_byte(0x6000).unwrap(), VIRTIO_BLK_S_IOERR);
+        assert_eq!(ram.read_byte(0x6001).unwrap(), VIRTIO_BLK_S_UNSUPP);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn get_id() {
+        let path = disk_file("id");
+        let (mut ram, mut mmio) = block(&path, DiskMode::ReadOnly);
+        mmio.get_device().set_id("disk0");
+
+        write_header(&mut ram, 0x4000, VIRTIO_BLK_T_GET_ID, 0);
+        submit(
+            &mut ram,
+            0,
+            0,
+            &[(0x4000, 16, false), (0x5000, 20, true), (0x6000, 1, true)],
+        );
+        notify(&mut mmio, 0);
+        mmio.process(&mut ram);
+        assert_eq!(ram.read_byte(0x6000).unwrap(), VIRTIO_BLK_S_OK);
+        assert_eq!(ram.read_word(0x5000).unwrap(), u32::from_le_bytes(*b"disk"));
+        assert_eq!(ram.read_byte(0x5005).unwrap(), 0);
+        std::fs::remove_file(path).unwrap();
+    }
+}
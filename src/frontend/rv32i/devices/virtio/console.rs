@@ -0,0 +1,212 @@
+use super::super::super::mem::{Mem, MemoryResult};
+use super::{VirtioDevice, Virtqueue, VIRTIO_ID_CONSOLE};
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+// Queues of port 0, multiport is not offered so there are no control queues
+const RECEIVEQ: u32 = 0;
+const TRANSMITQ: u32 = 1;
+
+/// Where the console port is connected on the host side
+#[derive(Debug)]
+enum ConsoleBackend {
+    /// Guest output goes to stdout, stdin is read by a helper thread so the guest never blocks
+    Stdio(Receiver<u8>),
+    /// Guest output is collected for `take_output`, input is only what `push_input` queued
+    Buffer(Vec<u8>),
+}
+
+/// virtio-console device with a single port
+#[derive(Debug)]
+pub struct VirtioConsole {
+    backend: ConsoleBackend,
+    /// Host input waiting for the guest to post receive buffers
+    input: VecDeque<u8>,
+}
+
+impl VirtioConsole {
+    /// A console connected to the host's stdin and stdout
+    pub fn stdio() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 256];
+            let mut stdin = io::stdin();
+            // Stop at EOF, on a read error, or once the console has been dropped
+            while let Ok(len @ 1..) = stdin.read(&mut buf) {
+                if buf[..len].iter().any(|byte| sender.send(*byte).is_err()) {
+                    break;
+                }
+            }
+        });
+        VirtioConsole {
+            backend: ConsoleBackend::Stdio(receiver),
+            input: VecDeque::new(),
+        }
+    }
+
+    /// A console backed by in-memory buffers
+    pub fn buffer() -> Self {
+        VirtioConsole {
+            backend: ConsoleBackend::Buffer(Vec::new()),
+            input: VecDeque::new(),
+        }
+    }
+
+    /// Queue bytes for the guest to receive
+    pub fn push_input(&mut self, data: &[u8]) {
+        self.input.extend(data);
+    }
+
+    /// Everything the guest transmitted since the last call, always empty for a stdio console
+    pub fn take_output(&mut self) -> Vec<u8> {
+        match &mut self.backend {
+            ConsoleBackend::Buffer(output) => std::mem::take(output),
+            ConsoleBackend::Stdio(_) => Vec::new(),
+        }
+    }
+
+    fn poll_host(&mut self) {
+        if let ConsoleBackend::Stdio(receiver) = &self.backend {
+            self.input.extend(receiver.try_iter());
+        }
+    }
+
+    fn transmit(&mut self, data: &[u8]) {
+        match &mut self.backend {
+            ConsoleBackend::Buffer(output) => output.extend_from_slice(data),
+            ConsoleBackend::Stdio(_) => {
+                let mut stdout = io::stdout();
+                // The guest has no way to learn about host write errors
+                let _ = stdout.write_all(data);
+                let _ = stdout.flush();
+            }
+        }
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn device_features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> u32 {
+        2
+    }
+
+    fn process_queue(
+        &mut self,
+        queue: u32,
+        vq: &mut Virtqueue,
+        memory: &mut dyn Mem,
+    ) -> MemoryResult<bool> {
+        let mut used = false;
+        match queue {
+            RECEIVEQ => {
+                self.poll_host();
+                while !self.input.is_empty() {
+                    let chain = match vq.pop(memory)? {
+                        Some(chain) => chain,
+                        None => break,
+                    };
+                    let data: Vec<u8> = self.input.iter().copied().collect();
+                    let written = chain.write_all(memory, &data)?;
+                    self.input.drain(..written as usize);
+                    vq.push_used(memory, chain.head, written)?;
+                    used = true;
+                }
+            }
+            TRANSMITQ => {
+                while let Some(chain) = vq.pop(memory)? {
+                    let data = chain.read_all(memory)?;
+                    self.transmit(&data);
+                    vq.push_used(memory, chain.head, 0)?;
+                    used = true;
+                }
+            }
+            _ => {}
+        }
+        Ok(used)
+    }
+
+    fn wants_poll(&mut self, queue: u32) -> bool {
+        if queue != RECEIVEQ {
+            return false;
+        }
+        self.poll_host();
+        !self.input.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::super::mem::RAM;
+    use super::super::super::Device;
+    use super::super::tests::{notify, setup, submit, used_elem, used_idx};
+    use super::super::VirtioMmio;
+    use super::*;
+
+    fn console() -> (RAM, VirtioMmio<VirtioConsole>) {
+        let mut mmio = VirtioMmio::new(VirtioConsole::buffer());
+        setup(&mut mmio);
+        (RAM::new(0, 0x10000), mmio)
+    }
+
+    #[test]
+    fn transmit() {
+        let (mut ram, mut mmio) = console();
+        for (i, byte) in b"hi\n".iter().enumerate() {
+            ram.write_byte(0x4000 + i as u32, *byte).unwrap();
+        }
+        submit(&mut ram, TRANSMITQ, 0, &[(0x4000, 3, false)]);
+        notify(&mut mmio, TRANSMITQ);
+        mmio.process(&mut ram);
+
+        assert_eq!(mmio.get_device().take_output(), b"hi\n");
+        assert_eq!(used_elem(&ram, TRANSMITQ, 0), (0, 0));
+    }
+
+    #[test]
+    fn receive_without_notify() {
+        let (mut ram, mut mmio) = console();
+        submit(&mut ram, RECEIVEQ, 0, &[(0x5000, 4, true)]);
+        submit(&mut ram, RECEIVEQ, 1, &[(0x5100, 4, true)]);
+        notify(&mut mmio, RECEIVEQ);
+        mmio.process(&mut ram);
+        assert_eq!(used_idx(&ram, RECEIVEQ), 0, "buffers used without input");
+
+        // Input arriving later fills the posted buffers on the next tick
+        mmio.get_device().push_input(b"abcdef");
+        mmio.process(&mut ram);
+        assert_eq!(used_idx(&ram, RECEIVEQ), 2);
+        assert_eq!(used_elem(&ram, RECEIVEQ, 0), (0, 4));
+        assert_eq!(used_elem(&ram, RECEIVEQ, 1), (1, 2));
+        assert_eq!(ram.read_word(0x5000).unwrap(), u32::from_le_bytes(*b"abcd"));
+        assert_eq!(
+            ram.read_halfword(0x5100).unwrap(),
+            u16::from_le_bytes(*b"ef")
+        );
+        assert_eq!(
+            mmio.get_interrupt_status(),
+            super::super::INTERRUPT_USED_BUFFER
+        );
+    }
+
+    #[test]
+    fn input_waits_for_buffers() {
+        let (mut ram, mut mmio) = console();
+        mmio.get_device().push_input(b"xyz");
+        mmio.process(&mut ram);
+        assert_eq!(mmio.get_interrupt_status(), 0);
+
+        submit(&mut ram, RECEIVEQ, 0, &[(0x5000, 16, true)]);
+        mmio.process(&mut ram);
+        assert_eq!(used_elem(&ram, RECEIVEQ, 0), (0, 3));
+    }
+}
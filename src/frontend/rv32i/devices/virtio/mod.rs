@@ -0,0 +1,795 @@
+pub mod block;
+pub mod console;
+pub mod rng;
+
+use super::super::mem::{Mem, MemoryError, MemoryResult};
+use super::{register_byte, Device, IrqLine};
+
+use std::fmt::Debug;
+
+pub const VIRTIO_MMIO_BASE: u32 = 0x1000_1000;
+pub const VIRTIO_MMIO_SIZE: u32 = 0x1000;
+/// Slots available for virtio-mmio transports, laid out back to back from `VIRTIO_MMIO_BASE`
+pub const VIRTIO_MMIO_SLOTS: u32 = 8;
+/// PLIC source of the first slot, CLIC machines use `CLIC_FIRST_EXTERNAL` + slot instead
+pub const VIRTIO_IRQ_BASE: u32 = 1;
+
+// virtio device IDs
+pub const VIRTIO_ID_BLOCK: u32 = 2;
+pub const VIRTIO_ID_CONSOLE: u32 = 3;
+pub const VIRTIO_ID_RNG: u32 = 4;
+
+/// The device follows the virtio 1.x specification, required by the v2 MMIO transport
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Device status bits
+pub const STATUS_ACKNOWLEDGE: u32 = 1;
+pub const STATUS_DRIVER: u32 = 2;
+pub const STATUS_DRIVER_OK: u32 = 4;
+pub const STATUS_FEATURES_OK: u32 = 8;
+pub const STATUS_DEVICE_NEEDS_RESET: u32 = 0x40;
+pub const STATUS_FAILED: u32 = 0x80;
+
+// InterruptStatus bits
+pub const INTERRUPT_USED_BUFFER: u32 = 1;
+pub const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+const MAGIC_VALUE: u32 = 0x7472_6976; // "virt"
+const VERSION: u32 = 2;
+const VENDOR_ID: u32 = 0x554D_4551; // "QEMU", which guest drivers don't treat specially
+
+// Register offsets of the virtio-mmio v2 transport
+const REG_MAGIC_VALUE: u32 = 0x000;
+const REG_VERSION: u32 = 0x004;
+const REG_DEVICE_ID: u32 = 0x008;
+const REG_VENDOR_ID: u32 = 0x00C;
+const REG_DEVICE_FEATURES: u32 = 0x010;
+const REG_DEVICE_FEATURES_SEL: u32 = 0x014;
+const REG_DRIVER_FEATURES: u32 = 0x020;
+const REG_DRIVER_FEATURES_SEL: u32 = 0x024;
+const REG_QUEUE_SEL: u32 = 0x030;
+const REG_QUEUE_NUM_MAX: u32 = 0x034;
+const REG_QUEUE_NUM: u32 = 0x038;
+const REG_QUEUE_READY: u32 = 0x044;
+const REG_QUEUE_NOTIFY: u32 = 0x050;
+const REG_INTERRUPT_STATUS: u32 = 0x060;
+const REG_INTERRUPT_ACK: u32 = 0x064;
+const REG_STATUS: u32 = 0x070;
+const REG_QUEUE_DESC_LOW: u32 = 0x080;
+const REG_QUEUE_DESC_HIGH: u32 = 0x084;
+const REG_QUEUE_DRIVER_LOW: u32 = 0x090;
+const REG_QUEUE_DRIVER_HIGH: u32 = 0x094;
+const REG_QUEUE_DEVICE_LOW: u32 = 0x0A0;
+const REG_QUEUE_DEVICE_HIGH: u32 = 0x0A4;
+const REG_CONFIG_GENERATION: u32 = 0x0FC;
+const REG_CONFIG: u32 = 0x100;
+
+// Split virtqueue descriptor flags
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+const DESCRIPTOR_SIZE: u32 = 16;
+
+/// Most bytes a device reads from or writes into one chain, longer requests are failed instead of
+/// being buffered in host memory
+pub const MAX_CHAIN_LEN: u32 = 16 << 20;
+
+/// `addr + offset` in guest memory, addresses past the end of the address space are unmapped
+fn guest_addr(addr: u32, offset: u32) -> MemoryResult<u32> {
+    addr.checked_add(offset).ok_or(MemoryError::UnmappedRegion)
+}
+
+/// One buffer of a descriptor chain
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Descriptor {
+    pub addr: u32,
+    pub len: u32,
+    /// Set when the buffer is written by the device rather than read
+    pub write: bool,
+}
+
+/// A request taken from the available ring, `head` is handed back through the used ring
+#[derive(Debug, Clone, PartialEq)]
+pub struct DescriptorChain {
+    pub head: u16,
+    pub descriptors: Vec<Descriptor>,
+}
+
+impl DescriptorChain {
+    /// Concatenated contents of the device readable buffers, an error when they are longer than
+    /// `MAX_CHAIN_LEN`
+    pub fn read_all(&self, memory: &dyn Mem) -> MemoryResult<Vec<u8>> {
+        self.total_len(false)?;
+        let mut data = Vec::new();
+        for desc in self.descriptors.iter().filter(|desc| !desc.write) {
+            for offset in 0..desc.len {
+                data.push(memory.read_byte(guest_addr(desc.addr, offset)?)?);
+            }
+        }
+        Ok(data)
+    }
+
+    /// Total size of the device writable buffers, an error when it exceeds `MAX_CHAIN_LEN`
+    pub fn writable_len(&self) -> MemoryResult<u32> {
+        self.total_len(true)
+    }
+
+    fn total_len(&self, write: bool) -> MemoryResult<u32> {
+        let len: u64 = self
+            .descriptors
+            .iter()
+            .filter(|desc| desc.write == write)
+            .map(|desc| desc.len as u64)
+            .sum();
+        if len > MAX_CHAIN_LEN as u64 {
+            return Err(MemoryError::UnmappedRegion);
+        }
+        Ok(len as u32)
+    }
+
+    /// Scatter `data` over the device writable buffers, returns how many bytes fit
+    pub fn write_all(&self, memory: &mut dyn Mem, data: &[u8]) -> MemoryResult<u32> {
+        let mut written = 0;
+        for desc in self.descriptors.iter().filter(|desc| desc.write) {
+            for offset in 0..desc.len {
+                if written == data.len() {
+                    return Ok(written as u32);
+                }
+                memory.write_byte(guest_addr(desc.addr, offset)?, data[written])?;
+                written += 1;
+            }
+        }
+        Ok(written as u32)
+    }
+}
+
+/// Device side state of a split virtqueue, the rings themselves live in guest memory
+#[derive(Debug, Clone, Default)]
+pub struct Virtqueue {
+    size: u16,
+    ready: bool,
+    desc_addr: u64,
+    driver_addr: u64,
+    device_addr: u64,
+    last_avail: u16,
+    used_idx: u16,
+}
+
+impl Virtqueue {
+    pub fn is_ready(&self) -> bool {
+        self.ready && self.size != 0
+    }
+
+    pub fn get_size(&self) -> u16 {
+        self.size
+    }
+
+    /// Point the queue at rings which were set up in guest memory, as a driver would
+    pub fn configure(&mut self, size: u16, desc_addr: u64, driver_addr: u64, device_addr: u64) {
+        self.size = size;
+        self.desc_addr = desc_addr;
+        self.driver_addr = driver_addr;
+        self.device_addr = device_addr;
+        self.ready = true;
+    }
+
+    /// Whether the driver has made buffers available which were not popped yet
+    pub fn has_available(&self, memory: &dyn Mem) -> MemoryResult<bool> {
+        Ok(self.is_ready()
+            && memory.read_halfword(guest_addr(self.driver_addr as u32, 2)?)? != self.last_avail)
+    }
+
+    /// Take the next descriptor chain from the available ring
+    pub fn pop(&mut self, memory: &dyn Mem) -> MemoryResult<Option<DescriptorChain>> {
+        if !self.has_available(memory)? {
+            return Ok(None);
+        }
+
+        let ring_offset = 4 + 2 * (self.last_avail % self.size) as u32;
+        let head = memory.read_halfword(guest_addr(self.driver_addr as u32, ring_offset)?)?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut descriptors = Vec::new();
+        let mut index = head;
+        loop {
+            // A chain can't be longer than the queue, anything else is a loop in the table
+            if index >= self.size || descriptors.len() >= self.size as usize {
+                return Err(MemoryError::UnmappedRegion);
+            }
+            let addr = guest_addr(self.desc_addr as u32, index as u32 * DESCRIPTOR_SIZE)?;
+            guest_addr(addr, DESCRIPTOR_SIZE - 1)?;
+            let flags = memory.read_halfword(addr + 12)?;
+            descriptors.push(Descriptor {
+                addr: memory.read_word(addr)?,
+                len: memory.read_word(addr + 8)?,
+                write: flags & VIRTQ_DESC_F_WRITE != 0,
+            });
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = memory.read_halfword(addr + 14)?;
+        }
+
+        Ok(Some(DescriptorChain { head, descriptors }))
+    }
+
+    /// Return a chain to the driver, `len` is the number of bytes written into it
+    pub fn push_used(&mut self, memory: &mut dyn Mem, head: u16, len: u32) -> MemoryResult<()> {
+        let elem = guest_addr(
+            self.device_addr as u32,
+            4 + 8 * (self.used_idx % self.size) as u32,
+        )?;
+        memory.write_word(elem, head as u32)?;
+        memory.write_word(guest_addr(elem, 4)?, len)?;
+        self.used_idx = self.used_idx.wrapping_add(1);
+        memory.write_halfword(guest_addr(self.device_addr as u32, 2)?, self.used_idx)
+    }
+}
+
+/// Device specific half of a virtio device, the transport handles feature negotiation and the
+/// queue registers
+pub trait VirtioDevice: Debug + Send {
+    fn device_id(&self) -> u32;
+
+    /// Feature bits offered to the driver, `VIRTIO_F_VERSION_1` is added by the transport
+    fn device_features(&self) -> u64;
+
+    fn num_queues(&self) -> u32;
+
+    fn queue_max_size(&self) -> u32 {
+        256
+    }
+
+    fn read_config(&self, _offset: u32) -> u8 {
+        0
+    }
+
+    fn write_config(&mut self, _offset: u32, _val: u8) {}
+
+    /// Handle buffers the driver made available on `queue`, returns true when any were used
+    fn process_queue(
+        &mut self,
+        queue: u32,
+        vq: &mut Virtqueue,
+        memory: &mut dyn Mem,
+    ) -> MemoryResult<bool>;
+
+    /// Whether `queue` should be processed without a notification, for devices which fill
+    /// buffers as host data arrives
+    fn wants_poll(&mut self, _queue: u32) -> bool {
+        false
+    }
+
+    /// Called when the driver resets the device by writing 0 to the status register
+    fn reset(&mut self) {}
+}
+
+/// virtio-mmio version 2 transport around a `VirtioDevice`
+#[derive(Debug)]
+pub struct VirtioMmio<D: VirtioDevice> {
+    device: D,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queues: Vec<Virtqueue>,
+    /// Bitmap of queues written to QueueNotify since they were last processed
+    notified: u32,
+    interrupt_status: u32,
+    status: u32,
+    config_generation: u32,
+    irq: Option<IrqLine>,
+}
+
+impl<D: VirtioDevice> VirtioMmio<D> {
+    pub fn new(device: D) -> Self {
+        let queues = vec![Virtqueue::default(); device.num_queues() as usize];
+        VirtioMmio {
+            device,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queues,
+            notified: 0,
+            interrupt_status: 0,
+            status: 0,
+            config_generation: 0,
+            irq: None,
+        }
+    }
+
+    /// Wire the transport to an interrupt controller input, the line is held while any
+    /// InterruptStatus bit is set
+    pub fn set_irq_line(&mut self, irq: IrqLine) {
+        self.irq = Some(irq);
+    }
+
+    pub fn get_device(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    pub fn get_queue(&mut self, queue: u32) -> &mut Virtqueue {
+        &mut self.queues[queue as usize]
+    }
+
+    pub fn get_status(&self) -> u32 {
+        self.status
+    }
+
+    pub fn get_interrupt_status(&self) -> u32 {
+        self.interrupt_status
+    }
+
+    /// Notify the driver that the config space changed
+    pub fn config_changed(&mut self) {
+        self.config_generation = self.config_generation.wrapping_add(1);
+        self.set_interrupt(INTERRUPT_CONFIG_CHANGE);
+    }
+
+    fn features(&self) -> u64 {
+        self.device.device_features() | VIRTIO_F_VERSION_1
+    }
+
+    fn set_interrupt(&mut self, bits: u32) {
+        self.interrupt_status |= bits;
+        self.update_irq();
+    }
+
+    fn update_irq(&self) {
+        if let Some(irq) = &self.irq {
+            irq.set(self.interrupt_status != 0);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.device.reset();
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+        self.queues
+            .iter_mut()
+            .for_each(|queue| *queue = Virtqueue::default());
+        self.notified = 0;
+        self.interrupt_status = 0;
+        self.status = 0;
+        self.update_irq();
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn read_register(&self, offset: u32) -> u32 {
+        let queue = self.queues.get(self.queue_sel as usize);
+        match offset {
+            REG_MAGIC_VALUE => MAGIC_VALUE,
+            REG_VERSION => VERSION,
+            REG_DEVICE_ID => self.device.device_id(),
+            REG_VENDOR_ID => VENDOR_ID,
+            REG_DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.features() as u32,
+                1 => (self.features() >> 32) as u32,
+                _ => 0,
+            },
+            REG_QUEUE_NUM_MAX => queue.map_or(0, |_| self.device.queue_max_size()),
+            REG_QUEUE_READY => queue.map_or(0, |queue| queue.ready as u32),
+            REG_INTERRUPT_STATUS => self.interrupt_status,
+            REG_STATUS => self.status,
+            REG_CONFIG_GENERATION => self.config_generation,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u32, val: u32) {
+        let max_size = self.device.queue_max_size();
+        match offset {
+            REG_DEVICE_FEATURES_SEL => self.device_features_sel = val,
+            REG_DRIVER_FEATURES => match self.driver_features_sel {
+                0 => self.driver_features = (self.driver_features & !0xFFFF_FFFF) | val as u64,
+                1 => {
+                    self.driver_features =
+                        (self.driver_features & 0xFFFF_FFFF) | ((val as u64) << 32)
+                }
+                _ => {}
+            },
+            REG_DRIVER_FEATURES_SEL => self.driver_features_sel = val,
+            REG_QUEUE_SEL => self.queue_sel = val,
+            REG_QUEUE_NUM => {
+                if let Some(queue) = self.selected_queue() {
+                    queue.size = val.min(max_size) as u16;
+                }
+            }
+            REG_QUEUE_READY => {
+                if let Some(queue) = self.selected_queue() {
+                    queue.ready = val & 1 != 0;
+                }
+            }
+            REG_QUEUE_NOTIFY if val < self.queues.len() as u32 => self.notified |= 1 << val,
+            REG_INTERRUPT_ACK => {
+                self.interrupt_status &= !val;
+                self.update_irq();
+            }
+            REG_STATUS => {
+                if val == 0 {
+                    self.reset();
+                } else {
+                    // Features are accepted only when they are a subset of what was offered
+                    let mut status = val;
+                    if status & STATUS_FEATURES_OK != 0
+                        && self.driver_features & !self.features() != 0
+                    {
+                        status &= !STATUS_FEATURES_OK;
+                    }
+                    self.status = status;
+                }
+            }
+            REG_QUEUE_DESC_LOW..=REG_QUEUE_DEVICE_HIGH => {
+                if let Some(queue) = self.selected_queue() {
+                    let (addr, high) = match offset {
+                        REG_QUEUE_DESC_LOW => (&mut queue.desc_addr, false),
+                        REG_QUEUE_DESC_HIGH => (&mut queue.desc_addr, true),
+                        REG_QUEUE_DRIVER_LOW => (&mut queue.driver_addr, false),
+                        REG_QUEUE_DRIVER_HIGH => (&mut queue.driver_addr, true),
+                        REG_QUEUE_DEVICE_LOW => (&mut queue.device_addr, false),
+                        REG_QUEUE_DEVICE_HIGH => (&mut queue.device_addr, true),
+                        _ => return,
+                    };
+                    *addr = if high {
+                        (*addr & 0xFFFF_FFFF) | ((val as u64) << 32)
+                    } else {
+                        (*addr & !0xFFFF_FFFF) | val as u64
+                    };
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<D: VirtioDevice> Mem for VirtioMmio<D> {
+    fn read_byte(&self, addr: u32) -> MemoryResult<u8> {
+        if addr >= VIRTIO_MMIO_SIZE {
+            return Err(MemoryError::UnmappedRegion);
+        }
+        if addr >= REG_CONFIG {
+            return Ok(self.device.read_config(addr - REG_CONFIG));
+        }
+        Ok(register_byte(
+            self.read_register(addr & !3) as u64,
+            addr % 4,
+        ))
+    }
+
+    fn write_byte(&mut self, addr: u32, val: u8) -> MemoryResult<()> {
+        if addr >= VIRTIO_MMIO_SIZE {
+            return Err(MemoryError::UnmappedRegion);
+        }
+        // The transport registers only take 32-bit writes
+        if addr >= REG_CONFIG {
+            self.device.write_config(addr - REG_CONFIG, val);
+        }
+        Ok(())
+    }
+
+    fn read_word(&self, addr: u32) -> MemoryResult<u32> {
+        if addr < REG_CONFIG {
+            return Ok(self.read_register(addr));
+        }
+        Ok((self.read_byte(addr)? as u32)
+            | (self.read_byte(addr + 1)? as u32) << 8
+            | (self.read_byte(addr + 2)? as u32) << 16
+            | (self.read_byte(addr + 3)? as u32) << 24)
+    }
+
+    fn write_word(&mut self, addr: u32, val: u32) -> MemoryResult<()> {
+        if addr < REG_CONFIG {
+            self.write_register(addr, val);
+            return Ok(());
+        }
+        for index in 0..4 {
+            self.write_byte(addr + index, register_byte(val as u64, index))?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: VirtioDevice> Device for VirtioMmio<D> {
+    fn process(&mut self, memory: &mut dyn Mem) {
+        if self.status & STATUS_DRIVER_OK == 0 || self.status & STATUS_DEVICE_NEEDS_RESET != 0 {
+            return;
+        }
+
+        let mut used = false;
+        for queue in 0..self.queues.len() as u32 {
+            let notified = self.notified & (1 << queue) != 0;
+            if !self.queues[queue as usize].is_ready()
+                || !(notified || self.device.wants_poll(queue))
+            {
+                continue;
+            }
+            self.notified &= !(1 << queue);
+
+            match self
+                .device
+                .process_queue(queue, &mut self.queues[queue as usize], memory)
+            {
+                Ok(queue_used) => used |= queue_used,
+                Err(_) => {
+                    // The rings point at memory we can't reach, or a chain is too long for the
+                    // device to handle, the driver has to start over
+                    self.status |= STATUS_DEVICE_NEEDS_RESET;
+                    self.config_changed();
+                    return;
+                }
+            }
+        }
+
+        if used {
+            self.set_interrupt(INTERRUPT_USED_BUFFER);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::super::super::mem::RAM;
+    use super::*;
+
+    pub const QUEUE_SIZE: u16 = 8;
+    pub const DESC_ADDR: u32 = 0x1000;
+    pub const DRIVER_ADDR: u32 = 0x2000;
+    pub const DEVICE_ADDR: u32 = 0x3000;
+    /// The rings of each queue are placed this far after those of the previous queue
+    const QUEUE_STRIDE: u32 = 0x200;
+
+    /// Bring a transport up the way a guest driver would, with the rings of every queue at fixed
+    /// addresses
+    pub fn setup<D: VirtioDevice>(mmio: &mut VirtioMmio<D>) {
+        mmio.write_word(REG_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER)
+            .unwrap();
+        mmio.write_word(REG_DRIVER_FEATURES_SEL, 1).unwrap();
+        mmio.write_word(REG_DRIVER_FEATURES, 1).unwrap();
+        mmio.write_word(
+            REG_STATUS,
+            STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK,
+        )
+        .unwrap();
+        assert_ne!(mmio.read_word(REG_STATUS).unwrap() & STATUS_FEATURES_OK, 0);
+
+        for queue in 0..mmio.queues.len() as u32 {
+            let offset = queue * QUEUE_STRIDE;
+            mmio.write_word(REG_QUEUE_SEL, queue).unwrap();
+            mmio.write_word(REG_QUEUE_NUM, QUEUE_SIZE as u32).unwrap();
+            mmio.write_word(REG_QUEUE_DESC_LOW, DESC_ADDR + offset)
+                .unwrap();
+            mmio.write_word(REG_QUEUE_DRIVER_LOW, DRIVER_ADDR + offset)
+                .unwrap();
+            mmio.write_word(REG_QUEUE_DEVICE_LOW, DEVICE_ADDR + offset)
+                .unwrap();
+            mmio.write_word(REG_QUEUE_READY, 1).unwrap();
+        }
+        mmio.write_word(
+            REG_STATUS,
+            STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK,
+        )
+        .unwrap();
+    }
+
+    /// Write a chain of `(addr, len, write)` buffers to `queue` starting at descriptor `first`
+    /// and make it available, returns the head index
+    pub fn submit(ram: &mut RAM, queue: u32, first: u16, buffers: &[(u32, u32, bool)]) -> u16 {
+        let offset = queue * QUEUE_STRIDE;
+        for (i, &(addr, len, write)) in buffers.iter().enumerate() {
+            let index = first + i as u16;
+            let desc = DESC_ADDR + offset + index as u32 * DESCRIPTOR_SIZE;
+            let mut flags = if write { VIRTQ_DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            ram.write_word(desc, addr).unwrap();
+            ram.write_word(desc + 4, 0).unwrap();
+            ram.write_word(desc + 8, len).unwrap();
+            ram.write_halfword(desc + 12, flags).unwrap();
+            ram.write_halfword(desc + 14, index + 1).unwrap();
+        }
+
+        let driver = DRIVER_ADDR + offset;
+        let avail_idx = ram.read_halfword(driver + 2).unwrap();
+        ram.write_halfword(driver + 4 + 2 * (avail_idx % QUEUE_SIZE) as u32, first)
+            .unwrap();
+        ram.write_halfword(driver + 2, avail_idx.wrapping_add(1))
+            .unwrap();
+        first
+    }
+
+    /// Number of chains `queue` has returned through its used ring
+    pub fn used_idx(ram: &RAM, queue: u32) -> u16 {
+        ram.read_halfword(DEVICE_ADDR + queue * QUEUE_STRIDE + 2)
+            .unwrap()
+    }
+
+    /// The `(id, len)` used ring entry of `queue` at `index`
+    pub fn used_elem(ram: &RAM, queue: u32, index: u16) -> (u32, u32) {
+        let elem = DEVICE_ADDR + queue * QUEUE_STRIDE + 4 + 8 * (index % QUEUE_SIZE) as u32;
+        (
+            ram.read_word(elem).unwrap(),
+            ram.read_word(elem + 4).unwrap(),
+        )
+    }
+
+    pub fn notify<D: VirtioDevice>(mmio: &mut VirtioMmio<D>, queue: u32) {
+        mmio.write_word(REG_QUEUE_NOTIFY, queue).unwrap();
+    }
+
+    #[derive(Debug, Default)]
+    struct Echo {
+        reset: bool,
+    }
+
+    // Copies the readable part of each chain into its writable part
+    impl VirtioDevice for Echo {
+        fn device_id(&self) -> u32 {
+            0x42
+        }
+
+        fn device_features(&self) -> u64 {
+            1 << 3
+        }
+
+        fn num_queues(&self) -> u32 {
+            1
+        }
+
+        fn process_queue(
+            &mut self,
+            _queue: u32,
+            vq: &mut Virtqueue,
+            memory: &mut dyn Mem,
+        ) -> MemoryResult<bool> {
+            let mut used = false;
+            while let Some(chain) = vq.pop(memory)? {
+                let data = chain.read_all(memory)?;
+                let len = chain.write_all(memory, &data)?;
+                vq.push_used(memory, chain.head, len)?;
+                used = true;
+            }
+            Ok(used)
+        }
+
+        fn reset(&mut self) {
+            self.reset = true;
+        }
+    }
+
+    #[test]
+    fn identification_registers() {
+        let mmio = VirtioMmio::new(Echo::default());
+        assert_eq!(mmio.read_word(REG_MAGIC_VALUE).unwrap(), MAGIC_VALUE);
+        assert_eq!(mmio.read_word(REG_VERSION).unwrap(), 2);
+        assert_eq!(mmio.read_word(REG_DEVICE_ID).unwrap(), 0x42);
+        assert_eq!(mmio.read_word(REG_DEVICE_FEATURES).unwrap(), 1 << 3);
+        assert_eq!(mmio.read_byte(REG_MAGIC_VALUE).unwrap(), b'v');
+    }
+
+    #[test]
+    fn unoffered_features_rejected() {
+        let mut mmio = VirtioMmio::new(Echo::default());
+        mmio.write_word(REG_DRIVER_FEATURES, 1 << 4).unwrap();
+        mmio.write_word(REG_STATUS, STATUS_FEATURES_OK).unwrap();
+        assert_eq!(mmio.read_word(REG_STATUS).unwrap() & STATUS_FEATURES_OK, 0);
+    }
+
+    #[test]
+    fn chain_round_trip_and_interrupt() {
+        let mut ram = RAM::new(0, 0x10000);
+        let mut mmio = VirtioMmio::new(Echo::default());
+        setup(&mut mmio);
+
+        for (i, byte) in b"hello".iter().enumerate() {
+            ram.write_byte(0x4000 + i as u32, *byte).unwrap();
+        }
+        // Split the output over two buffers to exercise scattering
+        let head = submit(
+            &mut ram,
+            0,
+            0,
+            &[(0x4000, 5, false), (0x5000, 2, true), (0x5100, 8, true)],
+        );
+
+        mmio.process(&mut ram);
+        assert_eq!(used_idx(&ram, 0), 0, "processed without notify");
+
+        notify(&mut mmio, 0);
+        mmio.process(&mut ram);
+        assert_eq!(used_idx(&ram, 0), 1);
+        assert_eq!(used_elem(&ram, 0, 0), (head as u32, 5));
+        assert_eq!(
+            ram.read_halfword(0x5000).unwrap(),
+            u16::from_le_bytes(*b"he")
+        );
+        assert_eq!(ram.read_byte(0x5102).unwrap(), b'o');
+        assert_eq!(
+            mmio.read_word(REG_INTERRUPT_STATUS).unwrap(),
+            INTERRUPT_USED_BUFFER
+        );
+
+        mmio.write_word(REG_INTERRUPT_ACK, INTERRUPT_USED_BUFFER)
+            .unwrap();
+        assert_eq!(mmio.get_interrupt_status(), 0);
+    }
+
+    /// Memory which is mapped at every address, reads return zero
+    struct Anywhere;
+
+    impl Mem for Anywhere {
+        fn read_byte(&self, _: u32) -> MemoryResult<u8> {
+            Ok(0)
+        }
+
+        fn write_byte(&mut self, _: u32, _: u8) -> MemoryResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn chain_lengths_and_addresses_are_checked() {
+        let chain = DescriptorChain {
+            head: 0,
+            descriptors: vec![
+                Descriptor {
+                    addr: 0xFFFF_0000,
+                    len: 0xFFFF_FFF0,
+                    write: true,
+                },
+                Descriptor {
+                    addr: 0xFFFF_0000,
+                    len: 0x20,
+                    write: true,
+                },
+            ],
+        };
+        assert_eq!(chain.writable_len(), Err(MemoryError::UnmappedRegion));
+
+        let chain = DescriptorChain {
+            head: 0,
+            descriptors: vec![
+                Descriptor {
+                    addr: 0xFFFF_FFF0,
+                    len: 0x20,
+                    write: false,
+                },
+                Descriptor {
+                    addr: 0xFFFF_FFF0,
+                    len: 0x20,
+                    write: true,
+                },
+            ],
+        };
+        assert_eq!(chain.writable_len(), Ok(0x20));
+        assert_eq!(chain.read_all(&Anywhere), Err(MemoryError::UnmappedRegion));
+        assert_eq!(
+            chain.write_all(&mut Anywhere, &[0; 0x20]),
+            Err(MemoryError::UnmappedRegion)
+        );
+    }
+
+    #[test]
+    fn bad_ring_needs_reset() {
+        let mut ram = RAM::new(0, 0x10000);
+        let mut mmio = VirtioMmio::new(Echo::default());
+        setup(&mut mmio);
+        mmio.write_word(REG_QUEUE_DESC_LOW, 0x8000_0000).unwrap();
+        submit(&mut ram, 0, 0, &[(0x4000, 4, false)]);
+        notify(&mut mmio, 0);
+        mmio.process(&mut ram);
+        assert_ne!(mmio.get_status() & STATUS_DEVICE_NEEDS_RESET, 0);
+        assert_eq!(mmio.get_interrupt_status(), INTERRUPT_CONFIG_CHANGE);
+
+        mmio.write_word(REG_STATUS, 0).unwrap();
+        assert_eq!(mmio.get_status(), 0);
+        assert!(mmio.get_device().reset);
+        assert!(!mmio.get_queue(0).is_ready());
+    }
+}
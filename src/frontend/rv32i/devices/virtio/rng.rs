@@ -0,0 +1,147 @@
+use super::super::super::mem::{Mem, MemoryResult};
+use super::{VirtioDevice, Virtqueue, VIRTIO_ID_RNG};
+
+use std::fs::File;
+use std::io::{self, Read};
+
+/// Seed used by `VirtioRng::default`, so runs are reproducible unless asked otherwise
+pub const DEFAULT_SEED: u64 = 0x5EED_0FE4_A70B_17C3;
+
+/// Where the entropy handed to the guest comes from
+#[derive(Debug)]
+enum EntropySource {
+    /// SplitMix64 generator state
+    Seeded(u64),
+    Host(File),
+}
+
+/// virtio-rng (entropy) device with a single request queue
+#[derive(Debug)]
+pub struct VirtioRng {
+    source: EntropySource,
+}
+
+impl Default for VirtioRng {
+    fn default() -> Self {
+        VirtioRng::new(DEFAULT_SEED)
+    }
+}
+
+impl VirtioRng {
+    /// A deterministic generator, the same seed always produces the same bytes
+    pub fn new(seed: u64) -> Self {
+        VirtioRng {
+            source: EntropySource::Seeded(seed),
+        }
+    }
+
+    /// Real entropy from the host's /dev/urandom
+    pub fn host() -> io::Result<Self> {
+        Ok(VirtioRng {
+            source: EntropySource::Host(File::open("/dev/urandom")?),
+        })
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        match &mut self.source {
+            EntropySource::Seeded(state) => {
+                for chunk in buf.chunks_mut(8) {
+                    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+                    let mut z = *state;
+                    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+                    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+                    z ^= z >> 31;
+                    chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+                }
+                Ok(())
+            }
+            EntropySource::Host(file) => file.read_exact(buf),
+        }
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_RNG
+    }
+
+    fn device_features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> u32 {
+        1
+    }
+
+    fn process_queue(
+        &mut self,
+        _queue: u32,
+        vq: &mut Virtqueue,
+        memory: &mut dyn Mem,
+    ) -> MemoryResult<bool> {
+        let mut used = false;
+        while let Some(chain) = vq.pop(memory)? {
+            let mut data = vec![0; chain.writable_len()? as usize];
+            // Returning the buffer empty tells the driver no entropy was available
+            let written = match self.fill(&mut data) {
+                Ok(()) => chain.write_all(memory, &data)?,
+                Err(_) => 0,
+            };
+            vq.push_used(memory, chain.head, written)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::super::mem::RAM;
+    use super::super::super::Device;
+    use super::super::tests::{notify, setup, submit, used_elem};
+    use super::super::VirtioMmio;
+    use super::*;
+
+    fn request(rng: VirtioRng) -> Vec<u8> {
+        let mut ram = RAM::new(0, 0x10000);
+        let mut mmio = VirtioMmio::new(rng);
+        setup(&mut mmio);
+        submit(&mut ram, 0, 0, &[(0x4000, 5, true), (0x4100, 11, true)]);
+        notify(&mut mmio, 0);
+        mmio.process(&mut ram);
+        assert_eq!(used_elem(&ram, 0, 0), (0, 16));
+
+        let mut data: Vec<u8> = (0x4000..0x4005)
+            .map(|addr| ram.read_byte(addr).unwrap())
+            .collect();
+        data.extend((0x4100..0x410B).map(|addr| ram.read_byte(addr).unwrap()));
+        data
+    }
+
+    #[test]
+    fn seeded_is_reproducible() {
+        let first = request(VirtioRng::default());
+        assert_eq!(first, request(VirtioRng::default()));
+        assert_ne!(first, request(VirtioRng::new(1)));
+        assert_ne!(first, vec![0; 16]);
+    }
+
+    #[test]
+    fn oversized_request_needs_reset() {
+        use super::super::{MAX_CHAIN_LEN, STATUS_DEVICE_NEEDS_RESET};
+
+        let mut ram = RAM::new(0, 0x10000);
+        let mut mmio = VirtioMmio::new(VirtioRng::default());
+        setup(&mut mmio);
+        submit(&mut ram, 0, 0, &[(0x4000, MAX_CHAIN_LEN + 1, true)]);
+        notify(&mut mmio, 0);
+        mmio.process(&mut ram);
+        assert_ne!(mmio.get_status() & STATUS_DEVICE_NEEDS_RESET, 0);
+    }
+
+    #[test]
+    fn host_entropy() {
+        let data = request(VirtioRng::host().unwrap());
+        assert_eq!(data.len(), 16);
+    }
+}
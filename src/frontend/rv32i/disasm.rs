@@ -0,0 +1,248 @@
+//! Disassembly of guest code, annotated with the functions and source lines it belongs to
+
+use super::dwarf::LineTable;
+use super::instructions::Instruction;
+use super::mem::RAM;
+use super::symbols::SymbolTable;
+
+use std::fmt::Write;
+
+const REGISTERS: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+fn reg(index: u32) -> &'static str {
+    REGISTERS[index as usize & 0x1f]
+}
+
+/// The instruction `word` at `pc` in assembler syntax, with ABI register names and the absolute
+/// targets of branches and jumps. Words which don't decode are shown as data.
+pub fn disassemble(word: u32, pc: u32) -> String {
+    use Instruction::*;
+
+    let instr = match Instruction::decode(word) {
+        Ok(instr) => instr,
+        Err(_) => return format!(".word {:#010x}", word),
+    };
+    let imm = |imm: u32| imm as i32;
+    let target = |imm: u32| format!("{:#010x}", pc.wrapping_add(imm));
+    let (name, operands) = match instr {
+        LUI(rd, imm) => ("lui", format!("{}, {:#x}", reg(rd), imm >> 12)),
+        AUIPC(rd, imm) => ("auipc", format!("{}, {:#x}", reg(rd), imm >> 12)),
+        JAL(rd, offset) => ("jal", format!("{}, {}", reg(rd), target(offset))),
+        JALR(rd, rs1, offset) => (
+            "jalr",
+            format!("{}, {}({})", reg(rd), imm(offset), reg(rs1)),
+        ),
+        BEQ(rs1, rs2, offset) => ("beq", branch(rs1, rs2, target(offset))),
+        BNE(rs1, rs2, offset) => ("bne", branch(rs1, rs2, target(offset))),
+        BLT(rs1, rs2, offset) => ("blt", branch(rs1, rs2, target(offset))),
+        BGE(rs1, rs2, offset) => ("bge", branch(rs1, rs2, target(offset))),
+        BLTU(rs1, rs2, offset) => ("bltu", branch(rs1, rs2, target(offset))),
+        BGEU(rs1, rs2, offset) => ("bgeu", branch(rs1, rs2, target(offset))),
+        LB(rd, rs1, offset) => ("lb", memory(rd, rs1, offset)),
+        LH(rd, rs1, offset) => ("lh", memory(rd, rs1, offset)),
+        LW(rd, rs1, offset) => ("lw", memory(rd, rs1, offset)),
+        LBU(rd, rs1, offset) => ("lbu", memory(rd, rs1, offset)),
+        LHU(rd, rs1, offset) => ("lhu", memory(rd, rs1, offset)),
+        SB(rs1, rs2, offset) => ("sb", memory(rs2, rs1, offset)),
+        SH(rs1, rs2, offset) => ("sh", memory(rs2, rs1, offset)),
+        SW(rs1, rs2, offset) => ("sw", memory(rs2, rs1, offset)),
+        ADDI(rd, rs1, value) => ("addi", immediate(rd, rs1, imm(value))),
+        SLTI(rd, rs1, value) => ("slti", immediate(rd, rs1, imm(value))),
+        SLTIU(rd, rs1, value) => ("sltiu", immediate(rd, rs1, imm(value))),
+        XORI(rd, rs1, value) => ("xori", immediate(rd, rs1, imm(value))),
+        ORI(rd, rs1, value) => ("ori", immediate(rd, rs1, imm(value))),
+        ANDI(rd, rs1, value) => ("andi", immediate(rd, rs1, imm(value))),
+        SLLI(rd, rs1, shamt) => ("slli", immediate(rd, rs1, shamt as i32)),
+        SRLI(rd, rs1, shamt) => ("srli", immediate(rd, rs1, shamt as i32)),
+        SRAI(rd, rs1, shamt) => ("srai", immediate(rd, rs1, shamt as i32)),
+        ADD(rd, rs1, rs2) => ("add", registers(rd, rs1, rs2)),
+        SUB(rd, rs1, rs2) => ("sub", registers(rd, rs1, rs2)),
+        SLL(rd, rs1, rs2) => ("sll", registers(rd, rs1, rs2)),
+        SLT(rd, rs1, rs2) => ("slt", registers(rd, rs1, rs2)),
+        SLTU(rd, rs1, rs2) => ("sltu", registers(rd, rs1, rs2)),
+        XOR(rd, rs1, rs2) => ("xor", registers(rd, rs1, rs2)),
+        SRL(rd, rs1, rs2) => ("srl", registers(rd, rs1, rs2)),
+        SRA(rd, rs1, rs2) => ("sra", registers(rd, rs1, rs2)),
+        OR(rd, rs1, rs2) => ("or", registers(rd, rs1, rs2)),
+        AND(rd, rs1, rs2) => ("and", registers(rd, rs1, rs2)),
+        FENCE(succ, pred) => ("fence", format!("{}, {}", fence_set(pred), fence_set(succ))),
+        FENCE_I => ("fence.i", String::new()),
+        ECALL => ("ecall", String::new()),
+        EBREAK => ("ebreak", String::new()),
+        SRET => ("sret", String::new()),
+        MRET => ("mret", String::new()),
+        WFI => ("wfi", String::new()),
+        CSRRW(rd, rs1, csr) => ("csrrw", csr_register(rd, csr, rs1)),
+        CSRRS(rd, rs1, csr) => ("csrrs", csr_register(rd, csr, rs1)),
+        CSRRC(rd, rs1, csr) => ("csrrc", csr_register(rd, csr, rs1)),
+        CSRRWI(rd, uimm, csr) => ("csrrwi", format!("{}, {:#x}, {}", reg(rd), csr, uimm)),
+        CSRRSI(rd, uimm, csr) => ("csrrsi", format!("{}, {:#x}, {}", reg(rd), csr, uimm)),
+        CSRRCI(rd, uimm, csr) => ("csrrci", format!("{}, {:#x}, {}", reg(rd), csr, uimm)),
+    };
+    if operands.is_empty() {
+        name.to_string()
+    } else {
+        format!("{} {}", name, operands)
+    }
+}
+
+fn branch(rs1: u32, rs2: u32, target: String) -> String {
+    format!("{}, {}, {}", reg(rs1), reg(rs2), target)
+}
+
+fn memory(value: u32, base: u32, offset: u32) -> String {
+    format!("{}, {}({})", reg(value), offset as i32, reg(base))
+}
+
+fn immediate(rd: u32, rs1: u32, imm: i32) -> String {
+    format!("{}, {}, {}", reg(rd), reg(rs1), imm)
+}
+
+fn registers(rd: u32, rs1: u32, rs2: u32) -> String {
+    format!("{}, {}, {}", reg(rd), reg(rs1), reg(rs2))
+}
+
+fn csr_register(rd: u32, csr: u32, rs1: u32) -> String {
+    format!("{}, {:#x}, {}", reg(rd), csr, reg(rs1))
+}
+
+fn fence_set(bits: u32) -> String {
+    let set: String = "iorw"
+        .chars()
+        .zip([8, 4, 2, 1].iter())
+        .filter(|(_, &bit)| bits & bit != 0)
+        .map(|(name, _)| name)
+        .collect();
+    if set.is_empty() {
+        "0".to_string()
+    } else {
+        set
+    }
+}
+
+/// `count` instructions from `start` in the style of `objdump -dl`: a `<function>:` header where
+/// each function starts and the source line before the instructions it compiled to. Branch and
+/// jump targets are followed by the function they land in. Stops early at anything but RAM.
+pub fn listing(
+    memory: &RAM,
+    symbols: &SymbolTable,
+    lines: &LineTable,
+    start: u32,
+    count: u32,
+) -> String {
+    let mut out = String::new();
+    let mut function = None;
+    let mut line = None;
+    for index in 0..count {
+        let pc = start.wrapping_add(index * 4);
+        let word = match memory.peek_word(pc) {
+            Ok(word) => word,
+            Err(_) => break,
+        };
+
+        let symbol = symbols.lookup(pc);
+        if symbol.map(|symbol| symbol.addr) != function {
+            function = symbol.map(|symbol| symbol.addr);
+            if let Some(symbol) = symbol {
+                let _ = writeln!(out, "\n{:08x} <{}>:", symbol.addr, symbol.name);
+            }
+        }
+        let location = lines.lookup(pc);
+        if location != line {
+            if let Some(location) = &location {
+                let _ = writeln!(out, "{}:{}", location.file, location.line);
+            }
+        }
+        line = location;
+
+        let _ = write!(out, "{:08x}:  {:08x}  {}", pc, word, disassemble(word, pc));
+        if let Some(target) = target(word, pc) {
+            if let Some(symbol) = symbols.lookup(target) {
+                let _ = write!(out, " <{}+{:#x}>", symbol.name, target - symbol.addr);
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Where a branch or direct jump goes
+fn target(word: u32, pc: u32) -> Option<u32> {
+    use Instruction::*;
+
+    match Instruction::decode(word).ok()? {
+        JAL(_, offset)
+        | BEQ(_, _, offset)
+        | BNE(_, _, offset)
+        | BLT(_, _, offset)
+        | BGE(_, _, offset)
+        | BLTU(_, _, offset)
+        | BGEU(_, _, offset) => Some(pc.wrapping_add(offset)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mem::Mem;
+    use super::super::symbols::Symbol;
+    use super::*;
+
+    #[test]
+    fn instructions() {
+        let pc = 0x8000_0010;
+        let cases = [
+            (0x8000_4137, "lui sp, 0x80004"),
+            (0xff01_0113, "addi sp, sp, -16"),
+            (0x0011_2623, "sw ra, 12(sp)"),
+            (0xff44_2703, "lw a4, -12(s0)"),
+            (0xfef5_94e3, "bne a1, a5, 0x7ffffff8"),
+            (0x0100_00ef, "jal ra, 0x80000020"),
+            (0x0000_8067, "jalr zero, 0(ra)"),
+            (0x4010_d093, "srai ra, ra, 1"),
+            (0x3420_2f73, "csrrs t5, 0x342, zero"),
+            (0x0ff0_000f, "fence iorw, iorw"),
+            (0x0000_0073, "ecall"),
+            (0x0000_0000, ".word 0x00000000"),
+        ];
+        for &(word, text) in cases.iter() {
+            assert_eq!(disassemble(word, pc), text);
+        }
+    }
+
+    #[test]
+    fn listing_is_annotated() {
+        let mut memory = RAM::new(0x8000_0000, 0x10);
+        for (index, word) in [0x0080_00ef, 0x0000_0073, 0x0000_8067].iter().enumerate() {
+            memory
+                .write_word(0x8000_0000 + index as u32 * 4, *word)
+                .unwrap();
+        }
+        let symbols = SymbolTable::new(vec![
+            Symbol {
+                name: "_start".to_string(),
+                addr: 0x8000_0000,
+                size: 8,
+            },
+            Symbol {
+                name: "f".to_string(),
+                addr: 0x8000_0008,
+                size: 4,
+            },
+        ]);
+
+        assert_eq!(
+            listing(&memory, &symbols, &LineTable::default(), 0x8000_0000, 8),
+            "\n80000000 <_start>:\n\
+             80000000:  008000ef  jal ra, 0x80000008 <f+0x0>\n\
+             80000004:  00000073  ecall\n\
+             \n80000008 <f>:\n\
+             80000008:  00008067  jalr zero, 0(ra)\n\
+             8000000c:  00000000  .word 0x00000000\n"
+        );
+    }
+}
@@ -0,0 +1,256 @@
+//! DWARF debug information of a guest binary, for mapping addresses back to functions and
+//! source lines
+
+use super::symbols::Symbol;
+
+use gimli::{AttributeValue, EndianSlice, LittleEndian, Unit};
+use xmas_elf::ElfFile;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+type Slice<'a> = EndianSlice<'a, LittleEndian>;
+type Dwarf<'a> = gimli::Dwarf<Slice<'a>>;
+
+/// The DWARF sections of `elf`, empty when it has none
+fn load<'a>(elf: &ElfFile<'a>) -> Result<Dwarf<'a>, gimli::Error> {
+    gimli::Dwarf::load(|id: gimli::SectionId| {
+        let data = elf
+            .find_section_by_name(id.name())
+            .map(|sect| sect.raw_data(elf))
+            .unwrap_or(&[]);
+        Ok(EndianSlice::new(data, LittleEndian))
+    })
+}
+
+fn string(dwarf: &Dwarf, unit: &Unit<Slice>, attr: AttributeValue<Slice>) -> String {
+    dwarf
+        .attr_string(unit, attr)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Functions described by `.debug_info`, named with the namespaces and types they are declared
+/// in, e.g. `crate::module::function`. Functions with several address ranges get a symbol for
+/// each.
+pub fn functions(elf: &ElfFile) -> Result<Vec<Symbol>, gimli::Error> {
+    let dwarf = load(elf)?;
+    let mut functions = Vec::new();
+
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        // Names of the scopes enclosing the entry, by depth. Only namespaces and types have one.
+        let mut scopes: Vec<Option<String>> = Vec::new();
+        let mut depth = 0;
+        let mut entries = unit.entries();
+        while let Some((delta, entry)) = entries.next_dfs()? {
+            depth += delta;
+            scopes.truncate(depth.max(0) as usize);
+            let name = entry
+                .attr_value(gimli::DW_AT_name)?
+                .map(|name| string(&dwarf, &unit, name));
+
+            match entry.tag() {
+                gimli::DW_TAG_namespace
+                | gimli::DW_TAG_structure_type
+                | gimli::DW_TAG_class_type
+                | gimli::DW_TAG_union_type
+                | gimli::DW_TAG_enumeration_type => {
+                    scopes.push(name);
+                    continue;
+                }
+                gimli::DW_TAG_subprogram => {
+                    if let Some(name) = &name {
+                        let mut path: Vec<&str> = scopes.iter().flatten().map(|s| &**s).collect();
+                        path.push(name);
+                        let name = path.join("::");
+                        let mut ranges = dwarf.die_ranges(&unit, entry)?;
+                        while let Some(range) = ranges.next()? {
+                            // The linker leaves the functions it discarded at 0
+                            if range.begin == 0 || range.end <= range.begin {
+                                continue;
+                            }
+                            functions.push(Symbol {
+                                name: name.clone(),
+                                addr: range.begin as u32,
+                                size: (range.end - range.begin) as u32,
+                            });
+                        }
+                    }
+                }
+                _ => {}
+            }
+            scopes.push(None);
+        }
+    }
+    Ok(functions)
+}
+
+/// A source line and the addresses of its instructions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineRow {
+    pub addr: u32,
+    /// Address after the last instruction of the row
+    pub end: u32,
+    /// Index into `LineTable::get_files`
+    pub file: usize,
+    pub line: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location<'a> {
+    pub file: &'a str,
+    pub line: u32,
+}
+
+/// The `.debug_line` line number programs of a binary, by address
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LineTable {
+    files: Vec<String>,
+    rows: Vec<LineRow>,
+}
+
+impl LineTable {
+    /// Lines of every compilation unit of `elf`. Paths are joined to the compilation directory.
+    pub fn from_elf(elf: &ElfFile) -> Result<Self, gimli::Error> {
+        let dwarf = load(elf)?;
+        let mut table = LineTable::default();
+        let mut file_indices: HashMap<String, usize> = HashMap::new();
+
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let program = match &unit.line_program {
+                Some(program) => program.clone(),
+                None => continue,
+            };
+            let comp_dir = unit
+                .comp_dir
+                .map(|dir| dir.to_string_lossy().into_owned())
+                .unwrap_or_default();
+
+            // A row covers the addresses up to the next one of its sequence
+            let mut previous: Option<(u32, usize, u32)> = None;
+            let mut rows = program.rows();
+            while let Some((header, row)) = rows.next_row()? {
+                let addr = row.address() as u32;
+                if let Some((start, file, line)) = previous.take() {
+                    if addr > start {
+                        table.rows.push(LineRow {
+                            addr: start,
+                            end: addr,
+                            file,
+                            line,
+                        });
+                    }
+                }
+                let line = match row.line() {
+                    Some(line) if !row.end_sequence() => line.get() as u32,
+                    _ => continue,
+                };
+                let entry = match row.file(header) {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                let mut path = PathBuf::from(&comp_dir);
+                if let Some(dir) = entry.directory(header) {
+                    path.push(string(&dwarf, &unit, dir));
+                }
+                path.push(string(&dwarf, &unit, entry.path_name()));
+                let path = path.to_string_lossy().into_owned();
+
+                let files = &mut table.files;
+                let file = *file_indices.entry(path).or_insert_with_key(|path| {
+                    files.push(path.clone());
+                    files.len() - 1
+                });
+                previous = Some((addr, file, line));
+            }
+        }
+        table.rows.sort_by_key(|row| row.addr);
+        Ok(table)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Paths of the source files, in the order they were found
+    pub fn get_files(&self) -> &[String] {
+        &self.files
+    }
+
+    /// Rows by address
+    pub fn get_rows(&self) -> &[LineRow] {
+        &self.rows
+    }
+
+    /// The source line the instruction at `addr` came from
+    pub fn lookup(&self, addr: u32) -> Option<Location<'_>> {
+        let index = self.rows.partition_point(|row| row.addr <= addr);
+        let row = self.rows.get(index.checked_sub(1)?)?;
+        if addr < row.end {
+            Some(Location {
+                file: &self.files[row.file],
+                line: row.line,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_of_test_program() {
+        let elf = std::fs::read("tests/debug/program").unwrap();
+        let lines = LineTable::from_elf(&ElfFile::new(&elf).unwrap()).unwrap();
+
+        assert_eq!(lines.get_files().len(), 1);
+        let location = lines.lookup(0x8000_0008).unwrap();
+        assert!(location.file.ends_with("program.S"));
+        assert_eq!(location.line, 17);
+        // `call` is two instructions on one line
+        assert_eq!(lines.lookup(0x8000_000c).unwrap().line, 17);
+        assert_eq!(lines.lookup(0x8000_002c).unwrap().line, 31);
+        assert_eq!(lines.lookup(0x8000_0030), None);
+        assert_eq!(lines.lookup(0x7fff_fffc), None);
+    }
+
+    #[test]
+    fn functions_of_test_program() {
+        let elf = std::fs::read("tests/debug/crash").unwrap();
+        let functions = functions(&ElfFile::new(&elf).unwrap()).unwrap();
+
+        let sum = functions
+            .iter()
+            .find(|function| function.name == "crash::checks::sum")
+            .unwrap();
+        assert_eq!((sum.addr, sum.size), (0x8000_0010, 0x50));
+        assert!(functions
+            .iter()
+            .any(|function| function.name == "crash::main"));
+        // Only code that made it into the binary, not inlined functions or the unused panic
+        // handler
+        assert!(functions
+            .iter()
+            .all(|function| function.addr >= 0x8000_0000));
+        assert!(!functions
+            .iter()
+            .any(|function| function.name.contains("black_box")));
+    }
+
+    #[test]
+    fn binaries_without_dwarf() {
+        let elf = std::fs::read("tests/rv32i-compliance/jal").unwrap();
+        let elf = ElfFile::new(&elf).unwrap();
+        let lines = LineTable::from_elf(&elf).unwrap();
+        assert!(lines.is_empty());
+        assert_eq!(lines.lookup(0x8000_0040), None);
+        assert!(functions(&elf).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,620 @@
+use super::cpu::CPU;
+use super::csr;
+use super::devices::clic::{CLIC_BASE, CLIC_SIZE};
+use super::devices::clint::{CLINT_BASE, CLINT_SIZE, TIMEBASE_FREQUENCY};
+use super::devices::plic::{PLIC_BASE, PLIC_SIZE};
+use super::devices::uart::{UART_BASE, UART_CLOCK_FREQUENCY, UART_IRQ, UART_SIZE};
+use super::devices::virtio::VIRTIO_MMIO_SIZE;
+use super::machine::InterruptMode;
+
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+/// A single terminating entry, no memory is reserved
+const FDT_RSVMAP_SIZE: usize = 16;
+
+// Structure block tokens
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FdtError {
+    BadMagic,
+    Truncated,
+    BadToken(u32),
+    BadString,
+}
+
+pub type FdtResult<T> = Result<T, FdtError>;
+
+/// Builds a flattened device tree blob, nodes are written depth first
+#[derive(Debug, Default)]
+pub struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    depth: usize,
+}
+
+impl FdtWriter {
+    pub fn new() -> Self {
+        FdtWriter::default()
+    }
+
+    fn push_u32(&mut self, val: u32) {
+        self.structure.extend_from_slice(&val.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while self.structure.len() % 4 != 0 {
+            self.structure.push(0);
+        }
+    }
+
+    /// Offset of `name` in the strings block, names are shared between properties
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        for existing in self.strings.split(|byte| *byte == 0) {
+            if existing == name.as_bytes() && offset < self.strings.len() {
+                return offset as u32;
+            }
+            offset += existing.len() + 1;
+        }
+        let offset = self.strings.len();
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        offset as u32
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "end_node without begin_node");
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let name_offset = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(name_offset);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    pub fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, val: u32) {
+        self.property(name, &val.to_be_bytes());
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    pub fn property_string(&mut self, name: &str, val: &str) {
+        self.property_strings(name, &[val]);
+    }
+
+    pub fn property_strings(&mut self, name: &str, vals: &[&str]) {
+        let mut value = Vec::new();
+        for val in vals {
+            value.extend_from_slice(val.as_bytes());
+            value.push(0);
+        }
+        self.property(name, &value);
+    }
+
+    /// Assemble the blob, every node must have been closed
+    pub fn finish(mut self, boot_cpuid: u32) -> Vec<u8> {
+        assert_eq!(self.depth, 0, "unclosed FDT node");
+        self.push_u32(FDT_END);
+
+        let rsvmap_offset = FDT_HEADER_SIZE;
+        let struct_offset = rsvmap_offset + FDT_RSVMAP_SIZE;
+        let strings_offset = struct_offset + self.structure.len();
+        let total_size = strings_offset + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            struct_offset as u32,
+            strings_offset as u32,
+            rsvmap_offset as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            boot_cpuid,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+
+        let mut blob = Vec::with_capacity(total_size);
+        header
+            .iter()
+            .for_each(|field| blob.extend_from_slice(&field.to_be_bytes()));
+        blob.extend_from_slice(&[0; FDT_RSVMAP_SIZE]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+/// A node of a parsed device tree
+#[derive(Debug, Clone, PartialEq)]
+pub struct FdtNode {
+    pub name: String,
+    pub properties: Vec<(String, Vec<u8>)>,
+    pub children: Vec<FdtNode>,
+}
+
+impl FdtNode {
+    pub fn get_property(&self, name: &str) -> Option<&[u8]> {
+        self.properties
+            .iter()
+            .find(|(prop, _)| prop == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    pub fn get_u32(&self, name: &str) -> Option<u32> {
+        self.get_cells(name)?.first().copied()
+    }
+
+    pub fn get_cells(&self, name: &str) -> Option<Vec<u32>> {
+        let value = self.get_property(name)?;
+        Some(
+            value
+                .chunks_exact(4)
+                .map(|cell| u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]))
+                .collect(),
+        )
+    }
+
+    /// The strings of a string or string list property
+    pub fn get_strings(&self, name: &str) -> Option<Vec<String>> {
+        let value = self.get_property(name)?;
+        let value = value.strip_suffix(&[0]).unwrap_or(value);
+        Some(
+            value
+                .split(|byte| *byte == 0)
+                .map(|string| String::from_utf8_lossy(string).into_owned())
+                .collect(),
+        )
+    }
+
+    pub fn get_child(&self, name: &str) -> Option<&FdtNode> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    /// Look a node up by an absolute path such as `/soc/serial@10000000`
+    pub fn find(&self, path: &str) -> Option<&FdtNode> {
+        path.split('/')
+            .filter(|component| !component.is_empty())
+            .try_fold(self, |node, component| node.get_child(component))
+    }
+}
+
+fn read_be_u32(blob: &[u8], offset: usize) -> FdtResult<u32> {
+    let bytes = blob.get(offset..offset + 4).ok_or(FdtError::Truncated)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_cstring(blob: &[u8], offset: usize) -> FdtResult<String> {
+    let bytes = blob.get(offset..).ok_or(FdtError::Truncated)?;
+    let len = bytes
+        .iter()
+        .position(|byte| *byte == 0)
+        .ok_or(FdtError::Truncated)?;
+    String::from_utf8(bytes[..len].to_vec()).map_err(|_| FdtError::BadString)
+}
+
+/// Parse a blob back into its node tree, returning the root node
+pub fn parse(blob: &[u8]) -> FdtResult<FdtNode> {
+    if read_be_u32(blob, 0)? != FDT_MAGIC {
+        return Err(FdtError::BadMagic);
+    }
+    let struct_offset = read_be_u32(blob, 8)? as usize;
+    let strings_offset = read_be_u32(blob, 12)? as usize;
+
+    let mut stack: Vec<FdtNode> = Vec::new();
+    let mut offset = struct_offset;
+    loop {
+        let token = read_be_u32(blob, offset)?;
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = read_cstring(blob, offset)?;
+                offset = (offset + name.len() + 1 + 3) & !3;
+                stack.push(FdtNode {
+                    name,
+                    properties: Vec::new(),
+                    children: Vec::new(),
+                });
+            }
+            FDT_END_NODE => {
+                let node = stack.pop().ok_or(FdtError::BadToken(token))?;
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(node),
+                    // The root node has to be followed by FDT_END
+                    None => {
+                        return match read_be_u32(blob, offset)? {
+                            FDT_END => Ok(node),
+                            other => Err(FdtError::BadToken(other)),
+                        }
+                    }
+                }
+            }
+            FDT_PROP => {
+                let len = read_be_u32(blob, offset)? as usize;
+                let name = read_cstring(
+                    blob,
+                    strings_offset + read_be_u32(blob, offset + 4)? as usize,
+                )?;
+                let value = blob
+                    .get(offset + 8..offset + 8 + len)
+                    .ok_or(FdtError::Truncated)?
+                    .to_vec();
+                offset = (offset + 8 + len + 3) & !3;
+                stack
+                    .last_mut()
+                    .ok_or(FdtError::BadToken(token))?
+                    .properties
+                    .push((name, value));
+            }
+            FDT_NOP => {}
+            _ => return Err(FdtError::BadToken(token)),
+        }
+    }
+}
+
+/// `riscv,isa` string for the extensions reported in misa
+fn isa_string(misa: u32) -> String {
+    let mut isa = String::from("rv32");
+    for (bit, letter) in ('a'..='z').enumerate() {
+        if misa & (1 << bit) != 0 {
+            isa.push(letter);
+        }
+    }
+    isa + "_zicsr_zifencei"
+}
+
+fn reg_cells(base: u32, size: u32) -> [u32; 4] {
+    // Two address and two size cells, like QEMU's virt machine
+    [0, base, 0, size]
+}
+
+/// Describe the machine `cpu` is part of as a device tree: memory segments, the harts, interrupt
+/// controllers, UART and virtio transports
+pub fn generate(cpu: &CPU, bootargs: &str) -> Vec<u8> {
+    let hartid = cpu.get_hartid();
+    let harts = 0..cpu.get_num_harts();
+    // The interrupt controller of hart N has phandle N + 1, the platform controller comes after
+    let intc_phandle = |hart: u32| hart + 1;
+    let controller_phandle = cpu.get_num_harts() + 1;
+
+    let mut fdt = FdtWriter::new();
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "riscv-virtio");
+    fdt.property_string("model", "emulator-rs,rv32i");
+
+    fdt.begin_node("chosen");
+    fdt.property_string("bootargs", bootargs);
+    if cpu.get_uart().is_some() {
+        fdt.property_string("stdout-path", &format!("/soc/serial@{:x}", UART_BASE));
+    }
+    if let Some((start, end)) = cpu.get_initrd() {
+        // 64-bit values, like QEMU writes them
+        fdt.property_cells("linux,initrd-start", &[0, start]);
+        fdt.property_cells("linux,initrd-end", &[0, end]);
+    }
+    fdt.end_node();
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", TIMEBASE_FREQUENCY);
+    let isa = isa_string(cpu.read_csr(csr::MISA));
+    for hart in harts.clone() {
+        fdt.begin_node(&format!("cpu@{:x}", hart));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", hart);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", &isa);
+        fdt.property_string("mmu-type", "riscv,none");
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", intc_phandle(hart));
+        fdt.end_node();
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    for (base, size) in cpu.get_memory_segments() {
+        fdt.begin_node(&format!("memory@{:x}", base));
+        fdt.property_string("device_type", "memory");
+        fdt.property_cells("reg", &reg_cells(base, size));
+        fdt.end_node();
+    }
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_empty("ranges");
+
+    if cpu.get_clint().is_some() {
+        fdt.begin_node(&format!("clint@{:x}", CLINT_BASE));
+        fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+        fdt.property_cells("reg", &reg_cells(CLINT_BASE, CLINT_SIZE));
+        let interrupts: Vec<u32> = harts
+            .clone()
+            .flat_map(|hart| {
+                vec![
+                    intc_phandle(hart),
+                    csr::IRQ_M_SOFT,
+                    intc_phandle(hart),
+                    csr::IRQ_M_TIMER,
+                ]
+            })
+            .collect();
+        fdt.property_cells("interrupts-extended", &interrupts);
+        fdt.end_node();
+    }
+
+    let has_controller = match cpu.get_interrupt_mode() {
+        InterruptMode::Clint => match cpu.get_plic() {
+            Some(plic) => {
+                let plic = plic.lock().unwrap();
+                fdt.begin_node(&format!("plic@{:x}", PLIC_BASE));
+                fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+                fdt.property_cells("reg", &reg_cells(PLIC_BASE, PLIC_SIZE));
+                fdt.property_u32("#address-cells", 0);
+                fdt.property_u32("#interrupt-cells", 1);
+                fdt.property_empty("interrupt-controller");
+                let contexts: Vec<u32> = plic
+                    .get_contexts()
+                    .iter()
+                    .flat_map(|context| {
+                        let irq = match context.mode {
+                            csr::PrivilegeMode::Machine => csr::IRQ_M_EXT,
+                            _ => csr::IRQ_S_EXT,
+                        };
+                        vec![intc_phandle(context.hartid), irq]
+                    })
+                    .collect();
+                fdt.property_cells("interrupts-extended", &contexts);
+                fdt.property_u32("riscv,ndev", plic.num_sources());
+                fdt.property_u32("phandle", controller_phandle);
+                fdt.end_node();
+                true
+            }
+            None => false,
+        },
+        InterruptMode::Clic => match cpu.get_clic() {
+            Some(clic) => {
+                let clic = clic.lock().unwrap();
+                fdt.begin_node(&format!("interrupt-controller@{:x}", CLIC_BASE));
+                fdt.property_string("compatible", "riscv,clic0");
+                fdt.property_cells("reg", &reg_cells(CLIC_BASE, CLIC_SIZE));
+                fdt.property_u32("#interrupt-cells", 1);
+                fdt.property_empty("interrupt-controller");
+                fdt.property_cells("interrupts-extended", &[intc_phandle(0), csr::IRQ_M_EXT]);
+                fdt.property_u32("riscv,num-sources", clic.num_interrupts());
+                fdt.property_u32("phandle", controller_phandle);
+                fdt.end_node();
+                true
+            }
+            None => false,
+        },
+    };
+
+    if cpu.get_uart().is_some() {
+        fdt.begin_node(&format!("serial@{:x}", UART_BASE));
+        fdt.property_string("compatible", "ns16550a");
+        fdt.property_cells("reg", &reg_cells(UART_BASE, UART_SIZE));
+        fdt.property_u32("clock-frequency", UART_CLOCK_FREQUENCY);
+        if has_controller && cpu.get_interrupt_mode() == InterruptMode::Clint {
+            fdt.property_u32("interrupts", UART_IRQ);
+            fdt.property_u32("interrupt-parent", controller_phandle);
+        }
+        fdt.end_node();
+    }
+
+    for &(base, irq) in cpu.get_virtio() {
+        fdt.begin_node(&format!("virtio_mmio@{:x}", base));
+        fdt.property_string("compatible", "virtio,mmio");
+        fdt.property_cells("reg", &reg_cells(base, VIRTIO_MMIO_SIZE));
+        if has_controller {
+            fdt.property_u32("interrupts", irq);
+            fdt.property_u32("interrupt-parent", controller_phandle);
+        }
+        fdt.end_node();
+    }
+
+    fdt.end_node();
+    fdt.end_node();
+    fdt.finish(hartid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::devices::virtio::rng::VirtioRng;
+    use super::super::devices::virtio::VIRTIO_MMIO_BASE;
+    use super::super::machine::MachineConfig;
+    use super::*;
+
+    #[test]
+    fn writer_round_trip() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 1);
+        fdt.begin_node("child@10");
+        fdt.property_strings("compatible", &["a", "bc"]);
+        fdt.property_empty("flag");
+        fdt.property_u32("#address-cells", 2);
+        fdt.end_node();
+        fdt.end_node();
+        let blob = fdt.finish(0);
+
+        assert_eq!(read_be_u32(&blob, 4).unwrap() as usize, blob.len());
+        let root = parse(&blob).unwrap();
+        assert_eq!(root.get_u32("#address-cells"), Some(1));
+        let child = root.find("/child@10").unwrap();
+        assert_eq!(child.get_strings("compatible").unwrap(), vec!["a", "bc"]);
+        assert_eq!(child.get_property("flag"), Some(&[][..]));
+        assert_eq!(child.get_u32("#address-cells"), Some(2));
+
+        // Property names are stored once
+        let strings_size = read_be_u32(&blob, 32).unwrap() as usize;
+        assert_eq!(strings_size, "#address-cells\0compatible\0flag\0".len());
+    }
+
+    #[test]
+    fn rejects_bad_blobs() {
+        assert_eq!(parse(&[0; 64]), Err(FdtError::BadMagic));
+        let mut blob = FdtWriter::new();
+        blob.begin_node("");
+        blob.end_node();
+        let blob = blob.finish(0);
+        assert_eq!(parse(&blob[..blob.len() - 8]), Err(FdtError::Truncated));
+    }
+
+    #[test]
+    fn machine_tree() {
+        let mut cpu = CPU::with_config(&MachineConfig::default());
+        cpu.attach_virtio(VirtioRng::default()).unwrap();
+        let root = parse(&generate(&cpu, "console=ttyS0")).unwrap();
+
+        assert_eq!(
+            root.find("/chosen")
+                .unwrap()
+                .get_strings("bootargs")
+                .unwrap(),
+            vec!["console=ttyS0"]
+        );
+        let cpu0 = root.find("/cpus/cpu@0").unwrap();
+        assert_eq!(
+            cpu0.get_strings("riscv,isa").unwrap(),
+            vec!["rv32i_zicsr_zifencei"]
+        );
+        assert_eq!(cpu0.get_u32("reg"), Some(0));
+        let intc = cpu0.get_child("interrupt-controller").unwrap();
+        assert!(intc.get_property("interrupt-controller").is_some());
+
+        let memory = root.find("/memory@80000000").unwrap();
+        assert_eq!(
+            memory.get_cells("reg").unwrap(),
+            vec![0, 0x8000_0000, 0, 0x0100_0000]
+        );
+
+        let clint = root.find("/soc/clint@2000000").unwrap();
+        assert_eq!(
+            clint.get_cells("interrupts-extended").unwrap(),
+            vec![1, 3, 1, 7]
+        );
+        let plic = root.find("/soc/plic@c000000").unwrap();
+        assert_eq!(
+            plic.get_cells("interrupts-extended").unwrap(),
+            vec![1, 11, 1, 9]
+        );
+        assert_eq!(plic.get_u32("riscv,ndev"), Some(32));
+        let phandle = plic.get_u32("phandle").unwrap();
+
+        let uart = root.find("/soc/serial@10000000").unwrap();
+        assert_eq!(uart.get_strings("compatible").unwrap(), vec!["ns16550a"]);
+        assert_eq!(uart.get_u32("interrupts"), Some(UART_IRQ));
+        assert_eq!(uart.get_u32("interrupt-parent"), Some(phandle));
+        assert_eq!(
+            root.find("/chosen")
+                .unwrap()
+                .get_strings("stdout-path")
+                .unwrap(),
+            vec!["/soc/serial@10000000"]
+        );
+
+        let virtio = root
+            .find(&format!("/soc/virtio_mmio@{:x}", VIRTIO_MMIO_BASE))
+            .unwrap();
+        assert_eq!(virtio.get_u32("interrupts"), Some(1));
+        assert_eq!(virtio.get_cells("reg").unwrap()[1], VIRTIO_MMIO_BASE);
+    }
+
+    #[test]
+    fn smp_machine_tree() {
+        let cpu = CPU::with_config(&MachineConfig {
+            harts: 2,
+            ..MachineConfig::default()
+        });
+        let root = parse(&generate(&cpu, "")).unwrap();
+        let cpu1 = root.find("/cpus/cpu@1").unwrap();
+        assert_eq!(cpu1.get_u32("reg"), Some(1));
+        let intc = cpu1.get_child("interrupt-controller").unwrap();
+        assert_eq!(intc.get_u32("phandle"), Some(2));
+
+        let clint = root.find("/soc/clint@2000000").unwrap();
+        assert_eq!(
+            clint.get_cells("interrupts-extended").unwrap(),
+            vec![1, 3, 1, 7, 2, 3, 2, 7]
+        );
+        let plic = root.find("/soc/plic@c000000").unwrap();
+        assert_eq!(
+            plic.get_cells("interrupts-extended").unwrap(),
+            vec![1, 11, 1, 9, 2, 11, 2, 9]
+        );
+        assert_eq!(plic.get_u32("phandle"), Some(3));
+    }
+
+    #[test]
+    fn clic_machine_tree() {
+        let config = MachineConfig {
+            interrupt_mode: InterruptMode::Clic,
+            ..MachineConfig::default()
+        };
+        let cpu = CPU::with_config(&config);
+        let root = parse(&generate(&cpu, "")).unwrap();
+        let soc = root.find("/soc").unwrap();
+        assert!(soc.get_child("plic@c000000").is_none());
+        let clic = soc.get_child("interrupt-controller@2800000").unwrap();
+        assert_eq!(clic.get_u32("riscv,num-sources"), Some(64));
+    }
+
+    #[test]
+    fn loaded_into_memory() {
+        use super::super::mem::Mem;
+
+        let mut cpu = CPU::with_config(&MachineConfig::default());
+        let addr = cpu.load_fdt("quiet").unwrap();
+        assert_eq!(addr % 8, 0);
+
+        let size = cpu.get_memory().read_word(addr + 4).unwrap().swap_bytes();
+        assert!(addr + size <= 0x8100_0000);
+        let blob: Vec<u8> = (addr..addr + size)
+            .map(|byte| cpu.get_memory().read_byte(byte).unwrap())
+            .collect();
+        let root = parse(&blob).unwrap();
+        assert_eq!(
+            root.find("/chosen")
+                .unwrap()
+                .get_strings("bootargs")
+                .unwrap(),
+            vec!["quiet"]
+        );
+    }
+}
@@ -1,4 +1,3 @@
-use super::mem::Mem;
 use super::mem::MemoryError;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -15,6 +14,7 @@ pub enum DecodeError {
 
 type DecodeResult<T> = Result<T, DecodeError>;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ExecuteStatus {
     ECALL,
@@ -24,7 +24,6 @@ pub enum ExecuteStatus {
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ExecuteError {
-    InvalidExecutionInstruction,
     MisalignedAddress,
     MemoryError(MemoryError),
 }
@@ -35,8 +34,9 @@ impl From<MemoryError> for ExecuteError {
     }
 }
 
-type ExecuteResult<T> = Result<T, ExecuteError>;
+pub type ExecuteResult<T> = Result<T, ExecuteError>;
 
+#[allow(clippy::upper_case_acronyms, non_camel_case_types)]
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Instruction {
     LUI(u32, u32),
@@ -80,6 +80,9 @@ pub enum Instruction {
     FENCE_I,
     ECALL,
     EBREAK,
+    SRET,
+    MRET,
+    WFI,
     CSRRW(u32, u32, u32),
     CSRRS(u32, u32, u32),
     CSRRC(u32, u32, u32),
@@ -103,11 +106,11 @@ impl Instruction {
         let rd = (instr >> 7) & 0x1F;
         let funct3 = (instr >> 12) & 0x7;
         let rs1 = (instr >> 15) & 0x1F;
-        let mut imm = (instr >> 20);
+        let mut imm = instr >> 20;
 
         // This is our sign extension shifting
         if (imm & 0x800) != 0 {
-            imm = imm | 0xFFFF_F000;
+            imm |= 0xFFFF_F000;
         }
 
         (rd, funct3, rs1, imm)
@@ -121,7 +124,7 @@ impl Instruction {
 
         // This is our sign extension shifting
         if (imm & 0x800) != 0 {
-            imm = imm | 0xFFFF_F000;
+            imm |= 0xFFFF_F000;
         }
 
         (funct3, rs1, rs2, imm)
@@ -138,7 +141,7 @@ impl Instruction {
 
         // This is our sign extension shifting
         if (imm & 0x1000) != 0 {
-            imm = imm | 0xFFFF_E000;
+            imm |= 0xFFFF_E000;
         }
 
         (funct3, rs1, rs2, imm)
@@ -157,12 +160,12 @@ impl Instruction {
         let rd = (instr >> 7) & 0x1F;
         let mut imm = (instr >> 20) & 0x7FE
             | (instr >> 9) & 0x800
-            | (instr >> 0) & 0xF_F000
+            | instr & 0xF_F000
             | (instr >> 10) & 0x10_0000;
 
         // This is our sign extension shifting
         if (imm & 0x10_000) != 0 {
-            imm = imm | 0xFFE0_0000;
+            imm |= 0xFFE0_0000;
         }
 
         (rd, imm)
@@ -247,7 +250,7 @@ impl Instruction {
                     0b101 => {
                         // These instructions we need funct7 in order to determine which instruction it is
                         // Double extraction is a waste here, we could make it a special implementation to slightly speed it up
-                        let (rd, funct3, rs1, rs2, funct7) = Instruction::extract_rtype(instr);
+                        let (rd, _, rs1, rs2, funct7) = Instruction::extract_rtype(instr);
                         match funct7 {
                             0b0000000 => Ok(Instruction::SRLI(rd, rs1, rs2)),
                             0b0100000 => Ok(Instruction::SRAI(rd, rs1, rs2)),
@@ -294,759 +297,27 @@ impl Instruction {
             0b1110011 => {
                 let (rd, funct3, rs1, imm) = Instruction::extract_itype(instr);
                 match funct3 {
-                    0b000 => match imm {
-                        0b0 => Ok(Instruction::ECALL),
-                        0b1 => Ok(Instruction::EBREAK),
+                    0b000 => match imm & 0xFFF {
+                        0x000 => Ok(Instruction::ECALL),
+                        0x001 => Ok(Instruction::EBREAK),
+                        0x102 => Ok(Instruction::SRET),
+                        0x302 => Ok(Instruction::MRET),
+                        0x105 => Ok(Instruction::WFI),
                         _ => Err(DecodeError::ITypeExtract(instr, opcode)),
                     },
-                    0b001 => Ok(Instruction::CSRRW(rd, rs1, imm)),
-                    0b010 => Ok(Instruction::CSRRS(rd, rs1, imm)),
-                    0b011 => Ok(Instruction::CSRRC(rd, rs1, imm)),
-                    0b101 => Ok(Instruction::CSRRWI(rd, rs1, imm)),
-                    0b110 => Ok(Instruction::CSRRSI(rd, rs1, imm)),
-                    0b111 => Ok(Instruction::CSRRCI(rd, rs1, imm)),
+                    // The CSR address is unsigned, so drop the sign extension from the I-type extract
+                    0b001 => Ok(Instruction::CSRRW(rd, rs1, imm & 0xFFF)),
+                    0b010 => Ok(Instruction::CSRRS(rd, rs1, imm & 0xFFF)),
+                    0b011 => Ok(Instruction::CSRRC(rd, rs1, imm & 0xFFF)),
+                    0b101 => Ok(Instruction::CSRRWI(rd, rs1, imm & 0xFFF)),
+                    0b110 => Ok(Instruction::CSRRSI(rd, rs1, imm & 0xFFF)),
+                    0b111 => Ok(Instruction::CSRRCI(rd, rs1, imm & 0xFFF)),
                     _ => Err(DecodeError::ITypeExtract(instr, opcode)),
                 }
             }
             _ => Err(DecodeError::InvalidInstruction(instr, opcode)),
         }
     }
-
-    fn lui(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::LUI(rd, imm) = self {
-            if rd != 0 {
-                cpu.get_registers()[rd as usize] = imm;
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn auipc(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::AUIPC(rd, imm) = self {
-            if rd != 0 {
-                let (value, overflow) = cpu.get_registers().get_pc().overflowing_add(imm);
-                cpu.get_registers()[rd as usize] = value;
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn jal(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::JAL(rd, imm) = self {
-            if rd != 0 {
-                cpu.get_registers()[rd as usize] = cpu.get_registers().get_pc() + 4;
-            }
-
-            if imm % 4 != 0 {
-                return Err(ExecuteError::MisalignedAddress);
-            }
-
-            cpu.get_registers().add_to_pc(imm);
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        // TODO : This instruction should return a misasigned exception if the address % 4 != 0
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn jalr(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::JALR(rd, rs1, imm) = self {
-            if rd != 0 {
-                cpu.get_registers()[rd as usize] = cpu.get_registers().get_pc() + 4;
-            }
-            let (mut address, overflow) = cpu.get_registers()[rs1 as usize].overflowing_add(imm);
-            address = address & 0xFFFF_FFFE;
-
-            if address % 4 != 0 {
-                return Err(ExecuteError::MisalignedAddress);
-            }
-
-            cpu.get_registers().set_pc(address);
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        // TODO : This instruction should return a misasigned exception if the address % 4 != 0
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn beq(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::BEQ(rs1, rs2, imm) = self {
-            if cpu.get_registers()[rs1 as usize] == cpu.get_registers()[rs2 as usize] {
-                cpu.get_registers().add_to_pc(imm)
-            } else {
-                cpu.get_registers().increment_pc();
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn bne(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::BNE(rs1, rs2, imm) = self {
-            if cpu.get_registers()[rs1 as usize] != cpu.get_registers()[rs2 as usize] {
-                cpu.get_registers().add_to_pc(imm)
-            } else {
-                cpu.get_registers().increment_pc();
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn blt(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::BLT(rs1, rs2, imm) = self {
-            if (cpu.get_registers()[rs1 as usize] as i32)
-                < (cpu.get_registers()[rs2 as usize] as i32)
-            {
-                cpu.get_registers().add_to_pc(imm)
-            } else {
-                cpu.get_registers().increment_pc();
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn bltu(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::BLTU(rs1, rs2, imm) = self {
-            if cpu.get_registers()[rs1 as usize] < cpu.get_registers()[rs2 as usize] {
-                cpu.get_registers().add_to_pc(imm)
-            } else {
-                cpu.get_registers().increment_pc();
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn bge(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::BGE(rs1, rs2, imm) = self {
-            if (cpu.get_registers()[rs1 as usize] as i32)
-                >= (cpu.get_registers()[rs2 as usize] as i32)
-            {
-                cpu.get_registers().add_to_pc(imm)
-            } else {
-                cpu.get_registers().increment_pc();
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn bgeu(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::BGEU(rs1, rs2, imm) = self {
-            if cpu.get_registers()[rs1 as usize] >= cpu.get_registers()[rs2 as usize] {
-                cpu.get_registers().add_to_pc(imm)
-            } else {
-                cpu.get_registers().increment_pc();
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn lb(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::LB(rd, rs1, imm) = self {
-            let (address, overflow) = cpu.get_registers()[rs1 as usize].overflowing_add(imm);
-            let mut val: u32 = cpu.get_memory().read_byte(address)? as u32;
-            if rd != 0 {
-                if val & 0x80 != 0 {
-                    val = (val as u32) | 0xFFFF_FF00;
-                }
-                cpu.get_registers()[rd as usize] = val;
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn lbu(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::LBU(rd, rs1, imm) = self {
-            let (address, overflow) = cpu.get_registers()[rs1 as usize].overflowing_add(imm);
-            let mut val: u32 = cpu.get_memory().read_byte(address)? as u32;
-            if rd != 0 {
-                val = val & 0x0000_00FF;
-                cpu.get_registers()[rd as usize] = val;
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn lh(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::LH(rd, rs1, imm) = self {
-            let (address, overflow) = cpu.get_registers()[rs1 as usize].overflowing_add(imm);
-            let mut val: u32 = cpu.get_memory().read_halfword(address)? as u32;
-            if rd != 0 {
-                if val & 0x8000 != 0 {
-                    val = (val as u32) | 0xFFFF_0000;
-                }
-                cpu.get_registers()[rd as usize] = val;
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn lhu(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::LHU(rd, rs1, imm) = self {
-            let (address, overflow) = cpu.get_registers()[rs1 as usize].overflowing_add(imm);
-            let mut val: u32 = cpu.get_memory().read_halfword(address)? as u32;
-            if rd != 0 {
-                val = val & 0x0000_FFFF;
-                cpu.get_registers()[rd as usize] = val;
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn lw(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::LW(rd, rs1, imm) = self {
-            let (address, overflow) = cpu.get_registers()[rs1 as usize].overflowing_add(imm);
-            let val: u32 = cpu.get_memory().read_word(address)? as u32;
-            if rd != 0 {
-                cpu.get_registers()[rd as usize] = val;
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn sb(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::SB(rs1, rs2, imm) = self {
-            let (address, overflow) = cpu.get_registers()[rs1 as usize].overflowing_add(imm);
-            let val: u8 = (cpu.get_registers()[rs2 as usize] as u8) & 0xFF;
-            cpu.get_memory().write_byte(address, val)?;
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        println!("{:?}", cpu);
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn sh(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::SH(rs1, rs2, imm) = self {
-            let (address, overflow) = cpu.get_registers()[rs1 as usize].overflowing_add(imm);
-            let val: u16 = (cpu.get_registers()[rs2 as usize] as u16) & 0xFFFF;
-            cpu.get_memory().write_halfword(address, val)?;
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-    fn sw(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::SW(rs1, rs2, imm) = self {
-            let (address, overflow) = cpu.get_registers()[rs1 as usize].overflowing_add(imm);
-            let val: u32 = cpu.get_registers()[rs2 as usize] & 0xFFFF_FFFF;
-            cpu.get_memory().write_word(address, val)?;
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn addi(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::ADDI(rd, rs1, imm) = self {
-            if rd != 0 {
-                let (value, _) = cpu.get_registers()[rs1 as usize].overflowing_add(imm);
-                cpu.get_registers()[rd as usize] = value;
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn slti(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::SLTI(rd, rs1, imm) = self {
-            if rd != 0 {
-                if (cpu.get_registers()[rs1 as usize] as i32) < (imm as i32) {
-                    cpu.get_registers()[rd as usize] = 1
-                } else {
-                    cpu.get_registers()[rd as usize] = 0
-                }
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn sltiu(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::SLTIU(rd, rs1, imm) = self {
-            if rd != 0 {
-                if cpu.get_registers()[rs1 as usize] < imm {
-                    cpu.get_registers()[rd as usize] = 1
-                } else {
-                    cpu.get_registers()[rd as usize] = 0
-                }
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn xori(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::XORI(rd, rs1, imm) = self {
-            if rd != 0 {
-                cpu.get_registers()[rd as usize] = cpu.get_registers()[rs1 as usize] ^ imm;
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn ori(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::ORI(rd, rs1, imm) = self {
-            if rd != 0 {
-                cpu.get_registers()[rd as usize] = cpu.get_registers()[rs1 as usize] | imm;
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn andi(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::ANDI(rd, rs1, imm) = self {
-            if rd != 0 {
-                cpu.get_registers()[rd as usize] = cpu.get_registers()[rs1 as usize] & imm;
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn slli(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::SLLI(rd, rs1, imm) = self {
-            if rd != 0 {
-                cpu.get_registers()[rd as usize] = cpu.get_registers()[rs1 as usize] << imm;
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn srli(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::SRLI(rd, rs1, imm) = self {
-            if rd != 0 {
-                cpu.get_registers()[rd as usize] = cpu.get_registers()[rs1 as usize] >> imm;
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn srai(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::SRAI(rd, rs1, imm) = self {
-            if rd != 0 {
-                cpu.get_registers()[rd as usize] =
-                    ((cpu.get_registers()[rs1 as usize] as i32) >> (imm as i32)) as u32;
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn add(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::ADD(rd, rs1, rs2) = self {
-            if rd != 0 {
-                let (value, overflow) = cpu.get_registers()[rs1 as usize]
-                    .overflowing_add(cpu.get_registers()[rs2 as usize]);
-                cpu.get_registers()[rd as usize] = value;
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn sub(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::SUB(rd, rs1, rs2) = self {
-            if rd != 0 {
-                let (value, overflow) = cpu.get_registers()[rs1 as usize]
-                    .overflowing_sub(cpu.get_registers()[rs2 as usize]);
-                cpu.get_registers()[rd as usize] = value;
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn sll(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::SLL(rd, rs1, rs2) = self {
-            if rd != 0 {
-                cpu.get_registers()[rd as usize] =
-                    cpu.get_registers()[rs1 as usize] << (cpu.get_registers()[rs2 as usize] & 0x1F);
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn slt(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::SLT(rd, rs1, rs2) = self {
-            if rd != 0 {
-                if (cpu.get_registers()[rs1 as usize] as i32)
-                    < (cpu.get_registers()[rs2 as usize] as i32)
-                {
-                    cpu.get_registers()[rd as usize] = 1;
-                } else {
-                    cpu.get_registers()[rd as usize] = 0;
-                }
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn sltu(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::SLTU(rd, rs1, rs2) = self {
-            if rd != 0 {
-                if cpu.get_registers()[rs1 as usize] < cpu.get_registers()[rs2 as usize] {
-                    cpu.get_registers()[rd as usize] = 1;
-                } else {
-                    cpu.get_registers()[rd as usize] = 0;
-                }
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn xor(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::XOR(rd, rs1, rs2) = self {
-            if rd != 0 {
-                cpu.get_registers()[rd as usize] =
-                    cpu.get_registers()[rs1 as usize] ^ cpu.get_registers()[rs2 as usize];
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn srl(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::SRL(rd, rs1, rs2) = self {
-            if rd != 0 {
-                cpu.get_registers()[rd as usize] =
-                    cpu.get_registers()[rs1 as usize] >> (cpu.get_registers()[rs2 as usize] & 0x1F);
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn sra(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::SRA(rd, rs1, rs2) = self {
-            if rd != 0 {
-                cpu.get_registers()[rd as usize] = ((cpu.get_registers()[rs1 as usize] as i32)
-                    >> ((cpu.get_registers()[rs2 as usize] & 0x1F) as i32))
-                    as u32;
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn or(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::OR(rd, rs1, rs2) = self {
-            if rd != 0 {
-                cpu.get_registers()[rd as usize] =
-                    cpu.get_registers()[rs1 as usize] | cpu.get_registers()[rs2 as usize];
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn and(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::AND(rd, rs1, rs2) = self {
-            if rd != 0 {
-                cpu.get_registers()[rd as usize] =
-                    cpu.get_registers()[rs1 as usize] & cpu.get_registers()[rs2 as usize];
-            }
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn fence(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::FENCE(succ, pred) = self {
-            //TODO
-            println!("FENCE");
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn fence_i(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::FENCE_I = self {
-            //TODO
-            println!("FENCE.I");
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn ecall(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::ECALL = self {
-            //TODO
-            println!("ECALL");
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::ECALL)
-    }
-
-    fn ebreak(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::EBREAK = self {
-            //TODO
-            println!("EBREAK");
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::EBREAK)
-    }
-
-    fn csrrw(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::CSRRW(rd, rs1, csr) = self {
-            //TODO
-            println!("CSRRW");
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn csrrs(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::CSRRS(rd, rs1, csr) = self {
-            //TODO
-            println!("CSRRS");
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn csrrc(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::CSRRC(rd, rs1, csr) = self {
-            //TODO
-            println!("CSRRC");
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn csrrwi(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::CSRRWI(rd, rs1, csr) = self {
-            //TODO
-            println!("CSRRWI");
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn csrrsi(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::CSRRSI(rd, rs1, csr) = self {
-            //TODO
-            println!("CSRRSI");
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-
-    fn csrrci(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        if let &Instruction::CSRRCI(rd, rs1, csr) = self {
-            //TODO
-            println!("CSRRCI");
-        } else {
-            return Err(ExecuteError::InvalidExecutionInstruction);
-        }
-
-        cpu.get_registers().increment_pc();
-        Ok(ExecuteStatus::CONTINUE)
-    }
-}
-
-use super::cpu::CPU;
-pub trait Executable {
-    fn execute(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus>;
-}
-
-impl Executable for Instruction {
-    fn execute(&self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
-        match self {
-            &Instruction::LUI(_, _) => self.lui(cpu),
-            &Instruction::AUIPC(_, _) => self.auipc(cpu),
-            &Instruction::JAL(_, _) => self.jal(cpu),
-            &Instruction::JALR(_, _, _) => self.jalr(cpu),
-            &Instruction::BEQ(_, _, _) => self.beq(cpu),
-            &Instruction::BNE(_, _, _) => self.bne(cpu),
-            &Instruction::BLT(_, _, _) => self.blt(cpu),
-            &Instruction::BGE(_, _, _) => self.bge(cpu),
-            &Instruction::BLTU(_, _, _) => self.bltu(cpu),
-            &Instruction::BGEU(_, _, _) => self.bgeu(cpu),
-            &Instruction::LB(_, _, _) => self.lb(cpu),
-            &Instruction::LH(_, _, _) => self.lh(cpu),
-            &Instruction::LW(_, _, _) => self.lw(cpu),
-            &Instruction::LBU(_, _, _) => self.lbu(cpu),
-            &Instruction::LHU(_, _, _) => self.lhu(cpu),
-            &Instruction::SB(_, _, _) => self.sb(cpu),
-            &Instruction::SH(_, _, _) => self.sh(cpu),
-            &Instruction::SW(_, _, _) => self.sw(cpu),
-            &Instruction::ADDI(_, _, _) => self.addi(cpu),
-            &Instruction::SLTI(_, _, _) => self.slti(cpu),
-            &Instruction::SLTIU(_, _, _) => self.sltiu(cpu),
-            &Instruction::XORI(_, _, _) => self.xori(cpu),
-            &Instruction::ORI(_, _, _) => self.ori(cpu),
-            &Instruction::ANDI(_, _, _) => self.andi(cpu),
-            &Instruction::SLLI(_, _, _) => self.slli(cpu),
-            &Instruction::SRLI(_, _, _) => self.srli(cpu),
-            &Instruction::SRAI(_, _, _) => self.srai(cpu),
-            &Instruction::ADD(_, _, _) => self.add(cpu),
-            &Instruction::SUB(_, _, _) => self.sub(cpu),
-            &Instruction::SLL(_, _, _) => self.sll(cpu),
-            &Instruction::SLT(_, _, _) => self.slt(cpu),
-            &Instruction::SLTU(_, _, _) => self.sltu(cpu),
-            &Instruction::XOR(_, _, _) => self.xor(cpu),
-            &Instruction::SRL(_, _, _) => self.srl(cpu),
-            &Instruction::SRA(_, _, _) => self.sra(cpu),
-            &Instruction::OR(_, _, _) => self.or(cpu),
-            &Instruction::AND(_, _, _) => self.and(cpu),
-            &Instruction::FENCE(_, _) => self.fence(cpu),
-            &Instruction::FENCE_I => self.fence_i(cpu),
-            &Instruction::ECALL => self.ecall(cpu),
-            &Instruction::EBREAK => self.ebreak(cpu),
-            &Instruction::CSRRW(_, _, _) => self.csrrw(cpu),
-            &Instruction::CSRRS(_, _, _) => self.csrrs(cpu),
-            &Instruction::CSRRC(_, _, _) => self.csrrc(cpu),
-            &Instruction::CSRRWI(_, _, _) => self.csrrwi(cpu),
-            &Instruction::CSRRSI(_, _, _) => self.csrrsi(cpu),
-            &Instruction::CSRRCI(_, _, _) => self.csrrci(cpu),
-            _ => Err(ExecuteError::InvalidExecutionInstruction),
-        }
-    }
 }
 
 #[cfg(test)]
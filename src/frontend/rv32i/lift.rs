@@ -0,0 +1,709 @@
+//! Lifting RV32I code into the architecture-neutral IR in `crate::ir`. Every instruction lifts to
+//! operations on the register file, memory and CSRs, except MRET, SRET and WFI, which change the
+//! privilege or run state of the hart, accesses to the counters and to privileged CSRs, which
+//! depend on the mode the hart is in. These are left to the interpreter.
+
+use super::cpu::CPU;
+use super::csr;
+use super::instructions::{ExecuteError, ExecuteResult, Instruction};
+use super::mem::{Mem, PAGE_SHIFT, RAM};
+use crate::backend::interpreter::{self, Outcome};
+use crate::ir::{BinOp, Block, Builder, Exit, Guest, Reg, Value, Width};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Longest run of instructions lifted into one block
+const MAX_BLOCK_LEN: usize = 64;
+
+/// New value of a CSR, computed from the old one
+#[derive(Clone, Copy)]
+enum CsrWrite {
+    Replace(Value),
+    Set(Value),
+    Clear(Value),
+}
+
+/// Lifts the instructions of one block, remembering the value each register was last given so
+/// later instructions use it instead of reading the register again
+struct Lifter {
+    builder: Builder,
+    regs: [Option<Value>; 32],
+}
+
+impl Lifter {
+    fn new() -> Self {
+        Lifter {
+            builder: Builder::new(),
+            regs: [None; 32],
+        }
+    }
+
+    fn read(&mut self, reg: u32) -> Value {
+        if let Some(value) = self.regs[reg as usize] {
+            return value;
+        }
+        let value = if reg == 0 {
+            self.builder.constant(0)
+        } else {
+            self.builder.read_reg(reg as Reg)
+        };
+        self.regs[reg as usize] = Some(value);
+        value
+    }
+
+    /// Write rd, unless it is x0
+    fn write(&mut self, reg: u32, value: Value) {
+        if reg != 0 {
+            self.builder.write_reg(reg as Reg, value);
+            self.regs[reg as usize] = Some(value);
+        }
+    }
+
+    fn binary_imm(&mut self, op: BinOp, rd: u32, rs1: u32, imm: u32) {
+        if rd != 0 {
+            let lhs = self.read(rs1);
+            let rhs = self.builder.constant(imm);
+            let value = self.builder.binary(op, lhs, rhs);
+            self.write(rd, value);
+        }
+    }
+
+    fn binary(&mut self, op: BinOp, rd: u32, rs1: u32, rs2: u32) {
+        if rd != 0 {
+            let lhs = self.read(rs1);
+            let rhs = self.read(rs2);
+            let value = self.builder.binary(op, lhs, rhs);
+            self.write(rd, value);
+        }
+    }
+
+    fn address(&mut self, rs1: u32, imm: u32) -> Value {
+        let base = self.read(rs1);
+        if imm == 0 {
+            return base;
+        }
+        let offset = self.builder.constant(imm);
+        self.builder.binary(BinOp::Add, base, offset)
+    }
+
+    /// Loads access memory even when rd is x0, the access can fault
+    fn load(&mut self, rd: u32, rs1: u32, imm: u32, width: Width, signed: bool) {
+        let addr = self.address(rs1, imm);
+        let value = self.builder.load(addr, width, signed);
+        self.write(rd, value);
+    }
+
+    fn store(&mut self, rs1: u32, rs2: u32, imm: u32, width: Width) {
+        let addr = self.address(rs1, imm);
+        let value = self.read(rs2);
+        self.builder.store(addr, value, width);
+    }
+
+    fn branch(&mut self, op: BinOp, pc: u32, rs1: u32, rs2: u32, imm: u32) -> Exit {
+        let lhs = self.read(rs1);
+        let rhs = self.read(rs2);
+        Exit::Branch {
+            cond: self.builder.binary(op, lhs, rhs),
+            taken: pc.wrapping_add(imm),
+            not_taken: pc.wrapping_add(4),
+        }
+    }
+
+    /// Like the interpreter, rd is written before the target is checked
+    fn jump(&mut self, pc: u32, rd: u32, target: Value) -> Exit {
+        let link = self.builder.constant(pc.wrapping_add(4));
+        self.write(rd, link);
+        self.builder.check_align(target, 4);
+        Exit::Jump(target)
+    }
+
+    /// Continue with the next instruction in a new block
+    fn next(&mut self, pc: u32) -> Exit {
+        Exit::Jump(self.builder.constant(pc.wrapping_add(4)))
+    }
+
+    /// The CSR is always read, and only written when `write` is given, following the rs1 = x0 /
+    /// uimm = 0 rules
+    fn csr(&mut self, pc: u32, rd: u32, csr: u32, write: Option<CsrWrite>) -> Exit {
+        let old = self.builder.read_sys(csr as u16);
+        if let Some(write) = write {
+            let value = match write {
+                CsrWrite::Replace(value) => value,
+                CsrWrite::Set(bits) => self.builder.binary(BinOp::Or, old, bits),
+                CsrWrite::Clear(bits) => {
+                    let ones = self.builder.constant(0xFFFF_FFFF);
+                    let mask = self.builder.binary(BinOp::Xor, bits, ones);
+                    self.builder.binary(BinOp::And, old, mask)
+                }
+            };
+            self.builder.write_sys(csr as u16, value);
+        }
+        self.write(rd, old);
+        // A CSR write can enable an interrupt, which has to be taken before the next instruction
+        self.next(pc)
+    }
+
+    /// Lift `instr` at `pc`. Returns the block's exit if the instruction ends it, and `Err` with
+    /// nothing emitted when it can't be lifted.
+    fn instruction(&mut self, pc: u32, instr: Instruction) -> Result<Option<Exit>, ()> {
+        use Instruction::*;
+
+        if let MRET | SRET | WFI = instr {
+            return Err(());
+        }
+        // Counters advance with instructions retired, which a block only reports at its end.
+        // Whether a privileged CSR can be accessed depends on the mode the block runs in.
+        if let CSRRW(_, _, csr) | CSRRS(_, _, csr) | CSRRC(_, _, csr) | CSRRWI(_, _, csr)
+        | CSRRSI(_, _, csr) | CSRRCI(_, _, csr) = instr
+        {
+            if csr::is_counter(csr) || !csr::is_unprivileged(csr) {
+                return Err(());
+            }
+        }
+        self.builder.guest(pc);
+        let exit = match instr {
+            LUI(rd, imm) => {
+                if rd != 0 {
+                    let value = self.builder.constant(imm);
+                    self.write(rd, value);
+                }
+                None
+            }
+            AUIPC(rd, imm) => {
+                if rd != 0 {
+                    let value = self.builder.constant(pc.wrapping_add(imm));
+                    self.write(rd, value);
+                }
+                None
+            }
+            JAL(rd, imm) => {
+                let target = self.builder.constant(pc.wrapping_add(imm));
+                Some(self.jump(pc, rd, target))
+            }
+            JALR(rd, rs1, imm) => {
+                let sum = self.address(rs1, imm);
+                let mask = self.builder.constant(0xFFFF_FFFE);
+                let target = self.builder.binary(BinOp::And, sum, mask);
+                Some(self.jump(pc, rd, target))
+            }
+            BEQ(rs1, rs2, imm) => Some(self.branch(BinOp::Eq, pc, rs1, rs2, imm)),
+            BNE(rs1, rs2, imm) => Some(self.branch(BinOp::Ne, pc, rs1, rs2, imm)),
+            BLT(rs1, rs2, imm) => Some(self.branch(BinOp::Lt, pc, rs1, rs2, imm)),
+            BGE(rs1, rs2, imm) => Some(self.branch(BinOp::Ge, pc, rs1, rs2, imm)),
+            BLTU(rs1, rs2, imm) => Some(self.branch(BinOp::Ltu, pc, rs1, rs2, imm)),
+            BGEU(rs1, rs2, imm) => Some(self.branch(BinOp::Geu, pc, rs1, rs2, imm)),
+            LB(rd, rs1, imm) => {
+                self.load(rd, rs1, imm, Width::Byte, true);
+                None
+            }
+            LH(rd, rs1, imm) => {
+                self.load(rd, rs1, imm, Width::Half, true);
+                None
+            }
+            LW(rd, rs1, imm) => {
+                self.load(rd, rs1, imm, Width::Word, false);
+                None
+            }
+            LBU(rd, rs1, imm) => {
+                self.load(rd, rs1, imm, Width::Byte, false);
+                None
+            }
+            LHU(rd, rs1, imm) => {
+                self.load(rd, rs1, imm, Width::Half, false);
+                None
+            }
+            SB(rs1, rs2, imm) => {
+                self.store(rs1, rs2, imm, Width::Byte);
+                None
+            }
+            SH(rs1, rs2, imm) => {
+                self.store(rs1, rs2, imm, Width::Half);
+                None
+            }
+            SW(rs1, rs2, imm) => {
+                self.store(rs1, rs2, imm, Width::Word);
+                None
+            }
+            ADDI(rd, rs1, imm) => {
+                self.binary_imm(BinOp::Add, rd, rs1, imm);
+                None
+            }
+            SLTI(rd, rs1, imm) => {
+                self.binary_imm(BinOp::Lt, rd, rs1, imm);
+                None
+            }
+            SLTIU(rd, rs1, imm) => {
+                self.binary_imm(BinOp::Ltu, rd, rs1, imm);
+                None
+            }
+            XORI(rd, rs1, imm) => {
+                self.binary_imm(BinOp::Xor, rd, rs1, imm);
+                None
+            }
+            ORI(rd, rs1, imm) => {
+                self.binary_imm(BinOp::Or, rd, rs1, imm);
+                None
+            }
+            ANDI(rd, rs1, imm) => {
+                self.binary_imm(BinOp::And, rd, rs1, imm);
+                None
+            }
+            SLLI(rd, rs1, shamt) => {
+                self.binary_imm(BinOp::Shl, rd, rs1, shamt);
+                None
+            }
+            SRLI(rd, rs1, shamt) => {
+                self.binary_imm(BinOp::Shr, rd, rs1, shamt);
+                None
+            }
+            SRAI(rd, rs1, shamt) => {
+                self.binary_imm(BinOp::Sar, rd, rs1, shamt);
+                None
+            }
+            ADD(rd, rs1, rs2) => {
+                self.binary(BinOp::Add, rd, rs1, rs2);
+                None
+            }
+            SUB(rd, rs1, rs2) => {
+                self.binary(BinOp::Sub, rd, rs1, rs2);
+                None
+            }
+            SLL(rd, rs1, rs2) => {
+                self.binary(BinOp::Shl, rd, rs1, rs2);
+                None
+            }
+            SLT(rd, rs1, rs2) => {
+                self.binary(BinOp::Lt, rd, rs1, rs2);
+                None
+            }
+            SLTU(rd, rs1, rs2) => {
+                self.binary(BinOp::Ltu, rd, rs1, rs2);
+                None
+            }
+            XOR(rd, rs1, rs2) => {
+                self.binary(BinOp::Xor, rd, rs1, rs2);
+                None
+            }
+            SRL(rd, rs1, rs2) => {
+                self.binary(BinOp::Shr, rd, rs1, rs2);
+                None
+            }
+            SRA(rd, rs1, rs2) => {
+                self.binary(BinOp::Sar, rd, rs1, rs2);
+                None
+            }
+            OR(rd, rs1, rs2) => {
+                self.binary(BinOp::Or, rd, rs1, rs2);
+                None
+            }
+            AND(rd, rs1, rs2) => {
+                self.binary(BinOp::And, rd, rs1, rs2);
+                None
+            }
+            // Memory accesses are performed in program order, so there is nothing to order here
+            FENCE(_, _) => None,
+            FENCE_I => {
+                self.builder.flush_code();
+                Some(self.next(pc))
+            }
+            ECALL => Some(Exit::Syscall {
+                next: pc.wrapping_add(4),
+            }),
+            EBREAK => Some(Exit::Breakpoint {
+                next: pc.wrapping_add(4),
+            }),
+            CSRRW(rd, rs1, csr) => {
+                let value = self.read(rs1);
+                Some(self.csr(pc, rd, csr, Some(CsrWrite::Replace(value))))
+            }
+            CSRRS(rd, rs1, csr) => {
+                let bits = self.read(rs1);
+                let write = Some(CsrWrite::Set(bits)).filter(|_| rs1 != 0);
+                Some(self.csr(pc, rd, csr, write))
+            }
+            CSRRC(rd, rs1, csr) => {
+                let bits = self.read(rs1);
+                let write = Some(CsrWrite::Clear(bits)).filter(|_| rs1 != 0);
+                Some(self.csr(pc, rd, csr, write))
+            }
+            CSRRWI(rd, uimm, csr) => {
+                let value = self.builder.constant(uimm);
+                Some(self.csr(pc, rd, csr, Some(CsrWrite::Replace(value))))
+            }
+            CSRRSI(rd, uimm, csr) => {
+                let bits = self.builder.constant(uimm);
+                let write = Some(CsrWrite::Set(bits)).filter(|_| uimm != 0);
+                Some(self.csr(pc, rd, csr, write))
+            }
+            CSRRCI(rd, uimm, csr) => {
+                let bits = self.builder.constant(uimm);
+                let write = Some(CsrWrite::Clear(bits)).filter(|_| uimm != 0);
+                Some(self.csr(pc, rd, csr, write))
+            }
+            MRET | SRET | WFI => unreachable!(),
+        };
+        Ok(exit)
+    }
+}
+
+/// Lift the straight-line code at `start` up to the first control transfer, the end of its page
+/// or RAM segment, or an instruction which can't be lifted or doesn't decode. Returns `None` when
+/// not even the first instruction can be lifted. Code is read with `RAM::peek_word`, so lifting
+/// never reads devices.
+pub fn lift_block(memory: &RAM, start: u32) -> Option<Block> {
+    let mut lifter = Lifter::new();
+    let mut addr = start;
+    for _ in 0..MAX_BLOCK_LEN {
+        let instr = memory
+            .peek_word(addr)
+            .ok()
+            .and_then(|word| Instruction::decode(word).ok());
+        let exit = match instr.map(|instr| lifter.instruction(addr, instr)) {
+            Some(Ok(exit)) => exit,
+            // The interpreter executes or reports the instruction once execution reaches it
+            _ if lifter.builder.is_empty() => return None,
+            _ => return Some(lifter.builder.finish(start, Exit::Unlifted(addr))),
+        };
+        if let Some(exit) = exit {
+            return Some(lifter.builder.finish(start, exit));
+        }
+        addr = addr.wrapping_add(4);
+        if addr >> PAGE_SHIFT != start >> PAGE_SHIFT {
+            break;
+        }
+    }
+    let next = lifter.builder.constant(addr);
+    Some(lifter.builder.finish(start, Exit::Jump(next)))
+}
+
+impl From<crate::ir::Misaligned> for ExecuteError {
+    fn from(_: crate::ir::Misaligned) -> ExecuteError {
+        ExecuteError::MisalignedAddress
+    }
+}
+
+/// A hart as seen by code running on the IR, interpreted or recompiled
+pub struct Hart<'a> {
+    cpu: &'a mut CPU,
+    /// `RAM::get_code_generation` when code was last checked for writes
+    generation: u64,
+}
+
+impl<'a> Hart<'a> {
+    pub fn new(cpu: &'a mut CPU) -> Self {
+        let generation = cpu.get_memory().get_code_generation();
+        Hart { cpu, generation }
+    }
+}
+
+impl Guest for Hart<'_> {
+    type Error = ExecuteError;
+
+    fn read_reg(&mut self, reg: Reg) -> u32 {
+        self.cpu.get_registers()[reg as usize]
+    }
+
+    fn write_reg(&mut self, reg: Reg, value: u32) {
+        if reg != 0 {
+            self.cpu.get_registers()[reg as usize] = value;
+        }
+    }
+
+    fn read_sys(&mut self, reg: u16) -> u32 {
+        self.cpu.get_csrs().read(reg as u32)
+    }
+
+    fn write_sys(&mut self, reg: u16, value: u32) {
+        self.cpu.get_csrs().write(reg as u32, value);
+    }
+
+    fn load(&mut self, addr: u32, width: Width) -> ExecuteResult<u32> {
+        let memory = self.cpu.get_memory();
+        let value = match width {
+            Width::Byte => memory.read_byte(addr)? as u32,
+            Width::Half => memory.read_halfword(addr)? as u32,
+            Width::Word => memory.read_word(addr)?,
+        };
+        Ok(value)
+    }
+
+    fn store(&mut self, addr: u32, value: u32, width: Width) -> ExecuteResult<()> {
+        let memory = self.cpu.get_memory();
+        match width {
+            Width::Byte => memory.write_byte(addr, value as u8)?,
+            Width::Half => memory.write_halfword(addr, value as u16)?,
+            Width::Word => memory.write_word(addr, value)?,
+        }
+        Ok(())
+    }
+
+    fn code_modified(&mut self) -> bool {
+        let generation = self.cpu.get_memory().get_code_generation();
+        std::mem::replace(&mut self.generation, generation) != generation
+    }
+
+    fn flush_code(&mut self) {
+        self.cpu.flush_block_cache();
+    }
+
+    fn set_pc(&mut self, pc: u32) {
+        self.cpu.get_registers().set_pc(pc);
+    }
+}
+
+/// Run a lifted block on the IR interpreter
+pub fn run_block(cpu: &mut CPU, block: &Block) -> ExecuteResult<Outcome> {
+    interpreter::run(block, &mut Hart::new(cpu))
+}
+
+#[derive(Debug)]
+struct LiftedBlock {
+    /// `None` when nothing at the address can be lifted
+    block: Option<Arc<Block>>,
+    page: u32,
+    /// Version of the code page when the block was lifted
+    version: u64,
+}
+
+/// Lifted blocks keyed by their start address, dropped when their page is written like the blocks
+/// of `BlockCache`
+#[derive(Debug, Clone, Default)]
+pub struct LiftCache {
+    blocks: HashMap<u32, Arc<LiftedBlock>>,
+    /// `RAM::get_code_generation` when the blocks were last checked against their pages
+    generation: u64,
+}
+
+impl LiftCache {
+    pub fn new() -> Self {
+        LiftCache::default()
+    }
+
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+    }
+
+    fn invalidate_stale(&mut self, memory: &RAM) {
+        self.blocks
+            .retain(|_, block| memory.get_code_page_version(block.page) == Some(block.version));
+        self.generation = memory.get_code_generation();
+    }
+
+    /// The lifted block at `pc`. Only code in RAM is lifted.
+    pub fn fetch(&mut self, pc: u32, memory: &mut RAM) -> Option<Arc<Block>> {
+        if memory.get_code_generation() != self.generation {
+            self.invalidate_stale(memory);
+        }
+        if let Some(lifted) = self.blocks.get(&pc) {
+            return lifted.block.clone();
+        }
+        if !memory.is_mapped(pc) {
+            return None;
+        }
+        let page = pc >> PAGE_SHIFT;
+        let lifted = LiftedBlock {
+            version: memory.watch_code_page(page),
+            block: lift_block(memory, pc).map(Arc::new),
+            page,
+        };
+        let block = lifted.block.clone();
+        self.blocks.insert(pc, Arc::new(lifted));
+        block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::threaded::ThreadedOp;
+    use super::*;
+    use crate::backend::interpreter::Event;
+
+    const ADDI_T0_1: u32 = 0x00128293; // addi t0, t0, 1
+
+    fn lift_one(instr: u32) -> Block {
+        let mut memory = RAM::new(0, 0x1000);
+        memory.write_word(0x100, instr).unwrap();
+        memory.write_word(0x104, 0x30200073).unwrap(); // mret
+        lift_block(&memory, 0x100).unwrap()
+    }
+
+    #[test]
+    fn registers_are_read_once_per_block() {
+        let mut memory = RAM::new(0, 0x1000);
+        // addi t0, t0, 1; addi t0, t0, 1; add t1, t0, t0
+        for (index, instr) in [ADDI_T0_1, ADDI_T0_1, 0x00528333].iter().enumerate() {
+            memory.write_word(index as u32 * 4, *instr).unwrap();
+        }
+        let block = lift_block(&memory, 0).unwrap();
+        let reads = block
+            .ops
+            .iter()
+            .filter(|op| matches!(op, crate::ir::Op::ReadReg { .. }))
+            .count();
+        assert_eq!(reads, 1);
+    }
+
+    #[test]
+    fn blocks_stop_before_unliftable_instructions() {
+        let block = lift_one(ADDI_T0_1);
+        assert_eq!(block.exit, Exit::Unlifted(0x104));
+
+        let mut memory = RAM::new(0, 0x1000);
+        memory.write_word(0, 0x10500073).unwrap(); // wfi
+        assert!(lift_block(&memory, 0).is_none());
+        // Undecodable
+        assert!(lift_block(&memory, 4).is_none());
+    }
+
+    #[test]
+    fn blocks_stop_at_segment_end() {
+        let mut memory = RAM::new(0, 8);
+        memory.write_word(0, ADDI_T0_1).unwrap();
+        memory.write_word(4, ADDI_T0_1).unwrap();
+        let block = lift_block(&memory, 0).unwrap();
+        assert_eq!(block.exit, Exit::Unlifted(8));
+    }
+
+    #[test]
+    fn print_lifted_block() {
+        // lw a1, 8(a0)
+        assert_eq!(
+            lift_one(0x00852583).to_string(),
+            "block 0x00000100:\n\
+             0x00000100:\n    \
+             %0 = reg r10\n    \
+             %1 = const 0x8\n    \
+             %2 = add %0, %1\n    \
+             %3 = load.u32 [%2]\n    \
+             r11 = %3\n    \
+             unlifted 0x00000104\n"
+        );
+    }
+
+    #[test]
+    fn ecall_is_a_syscall() {
+        let mut cpu = CPU::new(0, 0x1000);
+        cpu.get_memory().write_word(0, ADDI_T0_1).unwrap();
+        cpu.get_memory().write_word(4, 0x00000073).unwrap();
+        let block = lift_block(cpu.get_memory(), 0).unwrap();
+        let outcome = run_block(&mut cpu, &block).unwrap();
+
+        assert_eq!(outcome.retired, 2);
+        assert_eq!(outcome.event, Event::Syscall);
+        assert_eq!(cpu.get_registers().get_pc(), 8);
+    }
+
+    #[test]
+    fn privileged_csrs_are_unlifted() {
+        let mut cpu = CPU::new(0, 0x1000);
+        // csrrw x3, mscratch, x1
+        cpu.get_memory().write_word(0, 0x340091F3).unwrap();
+        assert!(lift_block(cpu.get_memory(), 0).is_none());
+    }
+
+    /// Lifted code must leave the hart exactly as the threaded interpreter does, faults included
+    #[test]
+    fn matches_interpreter() {
+        let instructions = [
+            0x7FF00193, // addi x3, x0, 2047
+            0xFFF0A193, // slti x3, x1, -1
+            0xFFF0B193, // sltiu x3, x1, -1
+            0x0FF0C193, // xori x3, x1, 0xFF
+            0x0FF0E193, // ori x3, x1, 0xFF
+            0x0FF0F193, // andi x3, x1, 0xFF
+            0x01F09193, // slli x3, x1, 31
+            0x01F0D193, // srli x3, x1, 31
+            0x41F0D193, // srai x3, x1, 31
+            0x002081B3, // add x3, x1, x2
+            0x402081B3, // sub x3, x1, x2
+            0x002091B3, // sll x3, x1, x2
+            0x0020A1B3, // slt x3, x1, x2
+            0x0020B1B3, // sltu x3, x1, x2
+            0x0020C1B3, // xor x3, x1, x2
+            0x0020D1B3, // srl x3, x1, x2
+            0x4020D1B3, // sra x3, x1, x2
+            0x0020E1B3, // or x3, x1, x2
+            0x0020F1B3, // and x3, x1, x2
+            0x00208033, // add x0, x1, x2
+            0x123451B7, // lui x3, 0x12345
+            0x12345197, // auipc x3, 0x12345
+            0x008001EF, // jal x3, 8
+            0x002000EF, // jal x1, 2 (misaligned)
+            0x004080E7, // jalr x1, 4(x1)
+            0x001101E7, // jalr x3, 1(x2)
+            0x00208463, // beq x1, x2, 8
+            0x00209463, // bne x1, x2, 8
+            0x0020C463, // blt x1, x2, 8
+            0x0020D463, // bge x1, x2, 8
+            0x0020E463, // bltu x1, x2, 8
+            0x0020F463, // bgeu x1, x2, 8
+            0x00010183, // lb x3, 0(x2)
+            0x00011183, // lh x3, 0(x2)
+            0x00012183, // lw x3, 0(x2)
+            0x00014183, // lbu x3, 0(x2)
+            0x00015183, // lhu x3, 0(x2)
+            0x00010003, // lb x0, 0(x2)
+            0x00110023, // sb x1, 0(x2)
+            0x00111023, // sh x1, 0(x2)
+            0x00112023, // sw x1, 0(x2)
+            0x0000000F, // fence
+            0x0000100F, // fence.i
+            0x00100073, // ebreak
+        ];
+        let states = [
+            (0, 0, 0x800),
+            (5, 0x80, 0x801),
+            (0xFFFF_FFF0, 0x7FFF_FFFF, 0x1000),
+            (0x8000_0000, 0x8000_0000, 0x7FE),
+        ];
+        for &word in instructions.iter() {
+            for &(x1, x2, x3) in states.iter() {
+                let mut cpu = CPU::new(0, 0x1000);
+                cpu.get_memory().write_word(0x100, word).unwrap();
+                cpu.get_memory().write_word(0x800, 0x8899AABB).unwrap();
+                cpu.get_registers().set_pc(0x100);
+                cpu.get_registers()[1] = x1;
+                cpu.get_registers()[2] = x2;
+                cpu.get_registers()[3] = x3;
+                cpu.get_csrs().write(0x340, 0x0F0F);
+                let mut expected = cpu.clone();
+
+                let op = ThreadedOp::decode(word).unwrap();
+                let expected_result = op.execute(&mut expected).map(|_| ());
+                let block = lift_block(cpu.get_memory(), 0x100).unwrap();
+                let result = run_block(&mut cpu, &block).map(|_| ());
+
+                let context = format!("{:08X} with x1={:X}, x2={:X}\n{}", word, x1, x2, block);
+                assert_eq!(result, expected_result, "{}", context);
+                for reg in 0..32 {
+                    assert_eq!(
+                        cpu.get_registers()[reg],
+                        expected.get_registers()[reg],
+                        "x{}: {}",
+                        reg,
+                        context
+                    );
+                }
+                assert_eq!(
+                    cpu.get_registers().get_pc(),
+                    expected.get_registers().get_pc(),
+                    "pc: {}",
+                    context
+                );
+                assert_eq!(
+                    cpu.get_csrs().read(0x340),
+                    expected.get_csrs().read(0x340),
+                    "mscratch: {}",
+                    context
+                );
+                assert_eq!(
+                    cpu.get_memory().read_word(0x800),
+                    expected.get_memory().read_word(0x800),
+                    "memory: {}",
+                    context
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use super::cpu::{CPUError, CPU};
+use super::mem::{Mem, MemoryError};
+
+use std::convert::TryInto;
+
+const HEADER_SIZE: usize = 64;
+const MAGIC_OFFSET: usize = 48;
+const MAGIC2_OFFSET: usize = 56;
+/// Deprecated since header version 0.2, still accepted
+const MAGIC: &[u8; 8] = b"RISCV\0\0\0";
+const MAGIC2: &[u8; 4] = b"RSC\x05";
+
+/// The initramfs starts on the first page after the kernel
+const INITRD_ALIGN: u32 = 0x1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinuxError {
+    BadMagic,
+    Truncated,
+    /// The kernel, initramfs and device tree don't fit in RAM without overlapping
+    DoesNotFit,
+    CPUError(CPUError),
+}
+
+impl From<CPUError> for LinuxError {
+    fn from(err: CPUError) -> LinuxError {
+        LinuxError::CPUError(err)
+    }
+}
+
+impl From<MemoryError> for LinuxError {
+    fn from(err: MemoryError) -> LinuxError {
+        LinuxError::CPUError(CPUError::MemoryError(err))
+    }
+}
+
+pub type LinuxResult<T> = Result<T, LinuxError>;
+
+/// A RISC-V Linux kernel `Image`, as described in Documentation/riscv/boot-image-header.rst
+#[derive(Debug, Clone, Copy)]
+pub struct KernelImage<'a> {
+    data: &'a [u8],
+    /// Where the image wants to be placed, relative to the start of RAM
+    text_offset: u32,
+    /// Size of the image in memory, including bss
+    image_size: u32,
+}
+
+impl<'a> KernelImage<'a> {
+    pub fn parse(data: &'a [u8]) -> LinuxResult<Self> {
+        if data.len() < HEADER_SIZE {
+            return Err(LinuxError::Truncated);
+        }
+        if &data[MAGIC2_OFFSET..MAGIC2_OFFSET + 4] != MAGIC2
+            && &data[MAGIC_OFFSET..MAGIC_OFFSET + 8] != MAGIC
+        {
+            return Err(LinuxError::BadMagic);
+        }
+
+        let field =
+            |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+        let text_offset = field(8).try_into().map_err(|_| LinuxError::DoesNotFit)?;
+        let image_size: u32 = field(16).try_into().map_err(|_| LinuxError::DoesNotFit)?;
+        // Old images leave image_size at 0, the file is all there is then
+        let image_size = image_size.max(data.len() as u32);
+        Ok(KernelImage {
+            data,
+            text_offset,
+            image_size,
+        })
+    }
+
+    pub fn get_text_offset(&self) -> u32 {
+        self.text_offset
+    }
+
+    pub fn get_image_size(&self) -> u32 {
+        self.image_size
+    }
+}
+
+/// Where `boot` put everything
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinuxBoot {
+    pub entry: u32,
+    pub dtb: u32,
+    pub initrd: Option<(u32, u32)>,
+}
+
+fn write_bytes(cpu: &mut CPU, addr: u32, data: &[u8]) -> LinuxResult<()> {
+    let memory = cpu.get_memory();
+    for (offset, byte) in data.iter().enumerate() {
+        memory.write_byte(addr + offset as u32, *byte)?;
+    }
+    Ok(())
+}
+
+/// Load `kernel` at `text_offset` into the first RAM segment and `initrd` on the page after it,
+/// returning the kernel entry point. The initramfs location is recorded for the device tree.
+pub fn load(cpu: &mut CPU, kernel: &KernelImage, initrd: Option<&[u8]>) -> LinuxResult<u32> {
+    let (base, size) = *cpu
+        .get_memory_segments()
+        .first()
+        .ok_or(LinuxError::DoesNotFit)?;
+    let ram_end = base as u64 + size as u64;
+
+    let entry = base
+        .checked_add(kernel.text_offset)
+        .ok_or(LinuxError::DoesNotFit)?;
+    let kernel_end = entry as u64 + kernel.image_size as u64;
+    if kernel_end > ram_end {
+        return Err(LinuxError::DoesNotFit);
+    }
+    write_bytes(cpu, entry, kernel.data)?;
+
+    let initrd = match initrd {
+        Some(data) => {
+            let align = INITRD_ALIGN as u64;
+            let start = kernel_end.div_ceil(align) * align;
+            let end = start + data.len() as u64;
+            if end > ram_end {
+                return Err(LinuxError::DoesNotFit);
+            }
+            write_bytes(cpu, start as u32, data)?;
+            Some((start as u32, end as u32))
+        }
+        None => None,
+    };
+    cpu.set_initrd(initrd);
+    Ok(entry)
+}
+
+/// Boot a Linux kernel: load the kernel and initramfs, generate the device tree with `bootargs`
+/// and start the hart with a0 = hartid and a1 = DTB. With the built-in SBI the kernel is entered
+/// in S-mode, otherwise the boot ROM jumps to it in M-mode, which suits NOMMU kernels.
+/// No real kernel gets through this yet: RV32 Linux needs the M and A extensions and Sv32.
+pub fn boot(
+    cpu: &mut CPU,
+    kernel: &[u8],
+    initrd: Option<&[u8]>,
+    bootargs: &str,
+) -> LinuxResult<LinuxBoot> {
+    let image = KernelImage::parse(kernel)?;
+    let entry = load(cpu, &image, initrd)?;
+    let dtb = cpu.boot(entry, bootargs)?;
+
+    // The device tree goes at the end of RAM, it must not have landed on the kernel or initramfs
+    let loaded_end = match cpu.get_initrd() {
+        Some((_, end)) => end,
+        None => entry + image.image_size,
+    };
+    if dtb < loaded_end {
+        return Err(LinuxError::DoesNotFit);
+    }
+    Ok(LinuxBoot {
+        entry,
+        dtb,
+        initrd: cpu.get_initrd(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::csr::PrivilegeMode;
+    use super::super::fdt;
+    use super::super::machine::MachineConfig;
+    use super::*;
+
+    fn image(text_offset: u64, image_size: u64) -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE + 8];
+        data[8..16].copy_from_slice(&text_offset.to_le_bytes());
+        data[16..24].copy_from_slice(&image_size.to_le_bytes());
+        data[MAGIC2_OFFSET..MAGIC2_OFFSET + 4].copy_from_slice(MAGIC2);
+        data
+    }
+
+    #[test]
+    fn parse_header() {
+        let data = image(0x40_0000, 0x2000);
+        let kernel = KernelImage::parse(&data).unwrap();
+        assert_eq!(kernel.get_text_offset(), 0x40_0000);
+        assert_eq!(kernel.get_image_size(), 0x2000);
+
+        assert_eq!(
+            KernelImage::parse(&data[..32]).unwrap_err(),
+            LinuxError::Truncated
+        );
+        assert_eq!(
+            KernelImage::parse(&[0; HEADER_SIZE]).unwrap_err(),
+            LinuxError::BadMagic
+        );
+    }
+
+    #[test]
+    fn initrd_in_device_tree() {
+        let mut cpu = CPU::with_config(&MachineConfig {
+            memory_size: 0x10_0000,
+            ..MachineConfig::default()
+        });
+        let boot = boot(
+            &mut cpu,
+            &image(0x1000, 0x1800),
+            Some(b"07070"),
+            "console=ttyS0",
+        )
+        .unwrap();
+        assert_eq!(boot.entry, 0x8000_1000);
+        assert_eq!(boot.initrd, Some((0x8000_3000, 0x8000_3005)));
+        assert_eq!(cpu.get_memory().read_byte(0x8000_3000).unwrap(), b'0');
+
+        let mut blob = vec![0; 0x10_0000 - (boot.dtb - 0x8000_0000) as usize];
+        for (offset, byte) in blob.iter_mut().enumerate() {
+            *byte = cpu
+                .get_memory()
+                .read_byte(boot.dtb + offset as u32)
+                .unwrap();
+        }
+        let tree = fdt::parse(&blob).unwrap();
+        let chosen = tree.find("/chosen").unwrap();
+        assert_eq!(
+            chosen.get_cells("linux,initrd-start"),
+            Some(vec![0, 0x8000_3000])
+        );
+        assert_eq!(
+            chosen.get_cells("linux,initrd-end"),
+            Some(vec![0, 0x8000_3005])
+        );
+    }
+
+    #[test]
+    fn enters_supervisor_with_hartid_and_dtb() {
+        let mut cpu = CPU::with_config(&MachineConfig {
+            memory_size: 0x10_0000,
+            sbi: true,
+            ..MachineConfig::default()
+        });
+        let boot = boot(&mut cpu, &image(0x1000, 0x1800), None, "").unwrap();
+        assert_eq!(cpu.get_privilege(), PrivilegeMode::Supervisor);
+        assert_eq!(cpu.get_registers().get_pc(), boot.entry);
+        assert_eq!(cpu.get_registers()[10], 0);
+        assert_eq!(cpu.get_registers()[11], boot.dtb);
+        assert_eq!(boot.initrd, None);
+    }
+
+    #[test]
+    fn kernel_must_fit() {
+        let mut cpu = CPU::with_config(&MachineConfig {
+            memory_size: 0x10_0000,
+            ..MachineConfig::default()
+        });
+        let err = boot(&mut cpu, &image(0x40_0000, 0x1000), None, "").unwrap_err();
+        assert_eq!(err, LinuxError::DoesNotFit);
+    }
+}
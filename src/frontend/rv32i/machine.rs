@@ -0,0 +1,571 @@
+use super::boot::DEFAULT_RESET_VECTOR;
+use super::branch::BranchConfig;
+use super::cache::CacheConfig;
+use super::cpu::{CPUResult, CPUStatus, CPU};
+use super::mem::RAM;
+use super::timing::PipelineConfig;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// How interrupts are delivered to the hart
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InterruptMode {
+    /// mip/mie based interrupts from a CLINT, with a PLIC for external interrupts
+    Clint,
+    /// Per-interrupt level, priority and vectoring from a CLIC, as used by microcontroller profiles
+    Clic,
+}
+
+/// Description of the emulated platform, used by `CPU::with_config`
+#[derive(Debug, Clone)]
+pub struct MachineConfig {
+    /// Number of harts, numbered from 0. Only `Machine` runs more than one.
+    pub harts: u32,
+    pub memory_base: u32,
+    pub memory_size: u32,
+    /// Address harts start at after reset, where the boot ROM is placed
+    pub reset_vector: u32,
+    pub interrupt_mode: InterruptMode,
+    /// Number of PLIC interrupt sources, no PLIC is attached when this is 0 or in CLIC mode
+    pub plic_sources: u32,
+    /// Number of CLIC interrupts, including the 16 local interrupts
+    pub clic_interrupts: u32,
+    /// CLICINTCTLBITS, how many bits of each clicintctl register are implemented
+    pub clic_ctl_bits: u32,
+    /// Attach an NS16550A UART at `UART_BASE`
+    pub uart: bool,
+    /// Handle S-mode ecalls with the built-in SBI, so kernels can be started without firmware
+    pub sbi: bool,
+    /// Stop the hart on an M-mode ecall, as the test programs expect. Otherwise it traps like any
+    /// other ecall, with cause 11.
+    pub halt_on_ecall: bool,
+    /// Execute from a cache of decoded basic blocks instead of decoding every instruction
+    pub block_cache: bool,
+    /// Lift basic blocks to the architecture-neutral IR and execute them on the IR interpreter
+    pub ir: bool,
+    /// Translate hot code to host code, on x86-64 hosts. Ignored elsewhere.
+    pub jit: bool,
+    /// Times an address runs in the interpreter before the JIT compiles the code there
+    pub jit_threshold: u32,
+    /// Estimate cycles with a model of an in-order pipeline with these latencies. Compiled and
+    /// lifted code isn't used while it is enabled.
+    pub timing: Option<PipelineConfig>,
+    /// Model an L1 instruction cache with this geometry. Like timing, it keeps compiled and
+    /// lifted code from being used.
+    pub icache: Option<CacheConfig>,
+    /// Model an L1 data cache with this geometry
+    pub dcache: Option<CacheConfig>,
+    /// Show every branch and jump to these branch predictors and return-address stack
+    pub branch_prediction: Option<BranchConfig>,
+    /// Collect basic block vectors for SimPoint in intervals of this many instructions
+    pub bbv_interval: Option<u64>,
+    /// Profile calls and the cost of every instruction, see `CPU::get_call_profile`
+    pub call_profile: bool,
+    /// Record the code executed, see `CPU::get_coverage`
+    pub coverage: bool,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        MachineConfig {
+            harts: 1,
+            memory_base: 0x8000_0000,
+            memory_size: 0x0100_0000,
+            reset_vector: DEFAULT_RESET_VECTOR,
+            interrupt_mode: InterruptMode::Clint,
+            plic_sources: 32,
+            clic_interrupts: 64,
+            clic_ctl_bits: 8,
+            uart: true,
+            sbi: false,
+            halt_on_ecall: true,
+            block_cache: true,
+            ir: false,
+            jit: false,
+            jit_threshold: DEFAULT_JIT_THRESHOLD,
+            timing: None,
+            icache: None,
+            dcache: None,
+            branch_prediction: None,
+            bbv_interval: None,
+            call_profile: false,
+            coverage: false,
+        }
+    }
+}
+
+/// Times code at an address runs in the interpreter before the JIT compiles it
+pub const DEFAULT_JIT_THRESHOLD: u32 = 50;
+
+/// Instructions a hart runs before the scheduler moves on to the next one
+pub const DEFAULT_QUANTUM: usize = 100;
+
+/// Several harts sharing one memory and set of devices. The harts take turns: the scheduler lends
+/// the memory to each running hart in hartid order for a quantum of instructions. The devices
+/// advance once per step of a quantum after every round, as if the harts had run side by side.
+#[derive(Debug)]
+pub struct Machine {
+    harts: Vec<CPU>,
+    /// Set once a hart halts, through ECALL, SBI or an execution error
+    halted: Vec<bool>,
+    memory: RAM,
+    quantum: usize,
+}
+
+impl Machine {
+    /// Build the machine described by `config`, with `config.harts` harts
+    pub fn new(config: &MachineConfig) -> Self {
+        let mut boot_hart = CPU::with_config(config);
+        let memory = std::mem::take(boot_hart.get_memory());
+        let mut harts: Vec<CPU> = (1..config.harts.max(1))
+            .map(|hartid| boot_hart.new_hart(hartid))
+            .collect();
+        harts.insert(0, boot_hart);
+        for hart in &mut harts {
+            hart.set_ticks_devices(false);
+        }
+
+        Machine {
+            halted: vec![false; harts.len()],
+            harts,
+            memory,
+            quantum: DEFAULT_QUANTUM,
+        }
+    }
+
+    pub fn num_harts(&self) -> u32 {
+        self.harts.len() as u32
+    }
+
+    pub fn get_quantum(&self) -> usize {
+        self.quantum
+    }
+
+    pub fn set_quantum(&mut self, quantum: usize) {
+        self.quantum = quantum.max(1);
+    }
+
+    pub fn get_memory(&mut self) -> &mut RAM {
+        &mut self.memory
+    }
+
+    /// Registers and CSRs of a hart. The shared memory is not attached, use `with_hart` for
+    /// anything which accesses memory.
+    pub fn get_hart(&mut self, hartid: u32) -> &mut CPU {
+        &mut self.harts[hartid as usize]
+    }
+
+    pub fn is_halted(&self, hartid: u32) -> bool {
+        self.halted[hartid as usize]
+    }
+
+    /// Run `f` on a hart with the shared memory attached to it
+    pub fn with_hart<R>(&mut self, hartid: u32, f: impl FnOnce(&mut CPU) -> R) -> R {
+        let hart = &mut self.harts[hartid as usize];
+        std::mem::swap(&mut self.memory, hart.get_memory());
+        let result = f(hart);
+        std::mem::swap(&mut self.memory, hart.get_memory());
+        result
+    }
+
+    /// `CPU::boot` for every hart: hart 0 loads the device tree and boot ROM, then the other harts
+    /// are reset. Without the built-in SBI they all start `entry` with their own hartid in a0.
+    /// With it only hart 0 starts, the others stay stopped until hart 0 starts them through HSM.
+    pub fn boot(&mut self, entry: u32, bootargs: &str) -> CPUResult<u32> {
+        let dtb = self.with_hart(0, |hart| hart.boot(entry, bootargs))?;
+        for hart in &mut self.harts[1..] {
+            hart.reset();
+        }
+        self.halted.iter_mut().for_each(|halted| *halted = false);
+        Ok(dtb)
+    }
+
+    /// Run `quantum` instructions on `hart` with `memory` attached, returns true if the hart halted
+    fn run_quantum(hart: &mut CPU, memory: &mut RAM, quantum: usize) -> CPUResult<bool> {
+        std::mem::swap(memory, hart.get_memory());
+        let mut result = Ok(false);
+        for _ in 0..quantum {
+            match hart.step() {
+                Ok(CPUStatus::Continue) => {}
+                Ok(CPUStatus::Halt) => {
+                    result = Ok(true);
+                    break;
+                }
+                // Reported like `CPU::run` does, while the hart still has the memory attached
+                Err(err) => {
+                    result = Err(hart.crash(err));
+                    break;
+                }
+            }
+        }
+        std::mem::swap(memory, hart.get_memory());
+        result
+    }
+
+    /// Advance the devices for the quantum the harts ran in a round
+    fn tick_devices(memory: &mut RAM, quantum: usize) {
+        for _ in 0..quantum {
+            memory.tick_devices();
+        }
+    }
+
+    /// One scheduling round, every running hart executes a quantum. Returns false once all harts
+    /// have halted.
+    pub fn step(&mut self) -> CPUResult<bool> {
+        for (hart, halted) in self.harts.iter_mut().zip(self.halted.iter_mut()) {
+            if !*halted {
+                *halted = Machine::run_quantum(hart, &mut self.memory, self.quantum)?;
+            }
+        }
+        Machine::tick_devices(&mut self.memory, self.quantum);
+        Ok(self.halted.iter().any(|halted| !halted))
+    }
+
+    /// Run until every hart has halted
+    pub fn run(&mut self) -> CPUResult<()> {
+        while self.step()? {}
+        Ok(())
+    }
+
+    /// Run every hart on its own host thread until all of them have halted, or one fails and the
+    /// others stop after their quantum. The memory is still lent out a quantum at a time, so the
+    /// harts don't run in parallel and this is no faster than `run`. The order the harts get the
+    /// memory in is up to the host, so runs are not reproducible. The devices follow the hart
+    /// which has run the most quanta.
+    pub fn run_threaded(&mut self) -> CPUResult<()> {
+        let memory = Mutex::new((std::mem::take(&mut self.memory), 0));
+        let failed = AtomicBool::new(false);
+        let quantum = self.quantum;
+        let results: Vec<CPUResult<()>> = thread::scope(|scope| {
+            let threads: Vec<_> = self
+                .harts
+                .iter_mut()
+                .zip(self.halted.iter_mut())
+                .map(|(hart, halted)| {
+                    let memory = &memory;
+                    let failed = &failed;
+                    scope.spawn(move || {
+                        let mut rounds = 0;
+                        while !*halted && !failed.load(Ordering::SeqCst) {
+                            let mut shared = memory.lock().unwrap();
+                            let (memory, time) = &mut *shared;
+                            *halted = match Machine::run_quantum(hart, memory, quantum) {
+                                Ok(halted) => halted,
+                                Err(err) => {
+                                    // Harts waiting on this one would otherwise never halt
+                                    failed.store(true, Ordering::SeqCst);
+                                    return Err(err);
+                                }
+                            };
+                            rounds += 1;
+                            if rounds > *time {
+                                *time = rounds;
+                                Machine::tick_devices(memory, quantum);
+                            }
+                            drop(shared);
+                            thread::yield_now();
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .collect()
+        });
+        self.memory = memory.into_inner().unwrap().0;
+        results.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::csr;
+    use super::super::mem::Mem;
+    use super::super::sbi::{ResetType, HSM_STOPPED};
+    use super::*;
+
+    use std::sync::Arc;
+
+    /// Hart 1 waits in WFI for a software interrupt, which hart 0 sends through the CLINT once
+    /// hart 1 has set the flag at 0x80001000
+    const IPI_PROGRAM: [u32; 24] = [
+        0xf1402573, // csrr a0, mhartid
+        0x02051063, // bnez a0, secondary
+        0x800012b7, // lui t0, 0x80001
+        0x0002a303, // 1: lw t1, 0(t0)
+        0xfe030ee3, // beqz t1, 1b
+        0x020003b7, // lui t2, 0x2000
+        0x00100e13, // li t3, 1
+        0x01c3a223, // sw t3, 4(t2)       msip[1] = 1
+        0x00000073, // ecall
+        0x00000297, // secondary: auipc t0, 0
+        0x02c28293, // addi t0, t0, 44
+        0x30529073, // csrw mtvec, t0
+        0x00800293, // li t0, 8
+        0x30429073, // csrw mie, t0       MSIE
+        0x30046073, // csrsi mstatus, 8   MIE
+        0x800012b7, // lui t0, 0x80001
+        0x00100313, // li t1, 1
+        0x0062a023, // sw t1, 0(t0)
+        0x10500073, // 2: wfi
+        0xffdff06f, // j 2b
+        0x342025f3, // handler: csrr a1, mcause
+        0x020003b7, // lui t2, 0x2000
+        0x0003a223, // sw zero, 4(t2)     msip[1] = 0
+        0x00000073, // ecall
+    ];
+
+    fn ipi_machine(quantum: usize) -> Machine {
+        let mut machine = Machine::new(&MachineConfig {
+            harts: 2,
+            memory_size: 0x2000,
+            ..MachineConfig::default()
+        });
+        machine.set_quantum(quantum);
+        for (index, instr) in IPI_PROGRAM.iter().enumerate() {
+            machine
+                .get_memory()
+                .write_word(0x8000_0000 + index as u32 * 4, *instr)
+                .unwrap();
+        }
+        machine.boot(0x8000_0000, "").unwrap();
+        machine
+    }
+
+    fn assert_ipi_delivered(machine: &mut Machine) {
+        assert!(machine.is_halted(0) && machine.is_halted(1));
+        assert_eq!(machine.get_hart(0).get_registers()[10], 0);
+        assert_eq!(machine.get_hart(1).get_registers()[10], 1);
+        assert_eq!(
+            machine.get_hart(1).get_registers()[11],
+            csr::MCAUSE_INTERRUPT | csr::IRQ_M_SOFT
+        );
+        assert_eq!(machine.get_memory().read_word(0x8000_1000).unwrap(), 1);
+    }
+
+    #[test]
+    fn harts_share_memory_and_devices() {
+        let mut machine = ipi_machine(DEFAULT_QUANTUM);
+        assert_eq!(machine.num_harts(), 2);
+        assert_eq!(machine.get_hart(1).get_hartid(), 1);
+        assert!(machine.get_hart(1).get_memory().get_segments().is_empty());
+
+        machine.with_hart(1, |hart| {
+            assert_eq!(
+                hart.get_memory().read_word(0x8000_0000).unwrap(),
+                IPI_PROGRAM[0]
+            );
+        });
+        let clint = machine.get_hart(0).get_clint().unwrap();
+        assert!(Arc::ptr_eq(
+            &clint,
+            &machine.get_hart(1).get_clint().unwrap()
+        ));
+    }
+
+    #[test]
+    fn round_robin_ipi() {
+        for &quantum in [1, 3, DEFAULT_QUANTUM].iter() {
+            let mut machine = ipi_machine(quantum);
+            machine.run().unwrap();
+            assert_ipi_delivered(&mut machine);
+        }
+    }
+
+    #[test]
+    fn round_robin_is_deterministic() {
+        let mut first = ipi_machine(5);
+        let mut second = ipi_machine(5);
+        for _ in 0..20 {
+            first.step().unwrap();
+            second.step().unwrap();
+            for hartid in 0..2 {
+                let registers = first.get_hart(hartid).get_registers();
+                let (values, pc) = (**registers, registers.get_pc());
+                let registers = second.get_hart(hartid).get_registers();
+                assert_eq!((values, pc), (**registers, registers.get_pc()));
+            }
+        }
+    }
+
+    #[test]
+    fn devices_tick_once_per_round() {
+        let mut machine = Machine::new(&MachineConfig {
+            harts: 3,
+            memory_size: 0x1000,
+            ..MachineConfig::default()
+        });
+        machine.set_quantum(10);
+        // j .
+        machine.get_memory().write_word(0x8000_0000, 0x0000006f).unwrap();
+        machine.boot(0x8000_0000, "").unwrap();
+        machine.step().unwrap();
+        machine.step().unwrap();
+
+        let clint = machine.get_hart(0).get_clint().unwrap();
+        assert_eq!(clint.lock().unwrap().get_mtime(), 20);
+    }
+
+    /// Hart 0 starts hart 1 through HSM and sends it an IPI through the SBI, hart 1 records the
+    /// supervisor software interrupt and stops itself, then hart 0 shuts the system down
+    const HSM_PROGRAM: [u32; 52] = [
+        0x004858b7, // lui a7, 1157          HSM
+        0x34d88893, // addi a7, a7, 845
+        0x00200813, // li a6, 2              hart_get_status
+        0x00100513, // li a0, 1
+        0x00000073, // ecall
+        0x00058413, // mv s0, a1             s0 = status of hart 1
+        0x004858b7, // lui a7, 1157
+        0x34d88893, // addi a7, a7, 845
+        0x00000813, // li a6, 0              hart_start(1, secondary, 0x1234)
+        0x00100513, // li a0, 1
+        0x00000597, // auipc a1, 0
+        0x05858593, // addi a1, a1, 88
+        0x00001637, // lui a2, 1
+        0x23460613, // addi a2, a2, 564
+        0x00000073, // ecall
+        0x800012b7, // lui t0, 524289
+        0x0002a303, // lw t1, 0(t0)          1: wait for hart 1
+        0xfe030ee3, // beqz t1, 0x40
+        0x007358b7, // lui a7, 1845          IPI
+        0x04988893, // addi a7, a7, 73
+        0x00000813, // li a6, 0
+        0x00200513, // li a0, 2              send_ipi to hart 1
+        0x00000593, // li a1, 0
+        0x00000073, // ecall
+        0x0042a303, // lw t1, 4(t0)          2: wait for the handler
+        0xfe030ee3, // beqz t1, 0x60
+        0x535258b7, // lui a7, 341285        SRST
+        0x35488893, // addi a7, a7, 852
+        0x00000813, // li a6, 0
+        0x00000513, // li a0, 0              shutdown
+        0x00000593, // li a1, 0
+        0x00000073, // ecall
+        0x00050413, // mv s0, a0             secondary:
+        0x00058493, // mv s1, a1
+        0x00000297, // auipc t0, 0
+        0x02828293, // addi t0, t0, 40
+        0x10529073, // csrw stvec, t0
+        0x10416073, // csrsi sie, 2
+        0x10016073, // csrsi sstatus, 2
+        0x800012b7, // lui t0, 524289
+        0x00100313, // li t1, 1
+        0x0062a023, // sw t1, 0(t0)
+        0x10500073, // wfi
+        0xffdff06f, // j 0xa8
+        0x14202973, // csrr s2, scause       handler:
+        0x800012b7, // lui t0, 524289
+        0x00100313, // li t1, 1
+        0x0062a223, // sw t1, 4(t0)
+        0x004858b7, // lui a7, 1157
+        0x34d88893, // addi a7, a7, 845
+        0x00100813, // li a6, 1              hart_stop
+        0x00000073, // ecall
+    ];
+
+    #[test]
+    fn unmapped_load_crashes() {
+        use super::super::cpu::CPUError;
+        use super::super::instructions::ExecuteError;
+        use super::super::mem::MemoryError;
+
+        let mut machine = Machine::new(&MachineConfig {
+            memory_size: 0x1000,
+            ..MachineConfig::default()
+        });
+        let program = [
+            0x400002B7, // lui t0, 0x40000
+            0x0002A303, // lw t1, 0(t0)
+        ];
+        for (index, instr) in program.iter().enumerate() {
+            machine
+                .get_memory()
+                .write_word(0x8000_0000 + index as u32 * 4, *instr)
+                .unwrap();
+        }
+        machine.boot(0x8000_0000, "").unwrap();
+        match machine.run() {
+            Err(CPUError::Crash(report)) => {
+                assert_eq!(
+                    report.error,
+                    CPUError::ExecuteError(ExecuteError::MemoryError(MemoryError::UnmappedRegion))
+                );
+                assert_eq!(report.pc.addr, 0x8000_0004);
+            }
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn sbi_starts_and_signals_harts() {
+        let mut machine = Machine::new(&MachineConfig {
+            harts: 2,
+            memory_size: 0x2000,
+            sbi: true,
+            ..MachineConfig::default()
+        });
+        machine.set_quantum(7);
+        for (index, instr) in HSM_PROGRAM.iter().enumerate() {
+            machine
+                .get_memory()
+                .write_word(0x8000_0000 + index as u32 * 4, *instr)
+                .unwrap();
+        }
+        machine.boot(0x8000_0000, "").unwrap();
+        let sbi = machine.get_hart(0).get_sbi().unwrap();
+        assert!(Arc::ptr_eq(&sbi, &machine.get_hart(1).get_sbi().unwrap()));
+        assert_eq!(sbi.lock().unwrap().get_hart_status(1), HSM_STOPPED);
+
+        machine.run().unwrap();
+        assert_eq!(machine.get_hart(0).get_registers()[8], HSM_STOPPED);
+        let secondary = machine.get_hart(1).get_registers();
+        assert_eq!(secondary[8], 1);
+        assert_eq!(secondary[9], 0x1234);
+        assert_eq!(secondary[18], csr::MCAUSE_INTERRUPT | csr::IRQ_S_SOFT);
+        let sbi = sbi.lock().unwrap();
+        assert_eq!(sbi.get_hart_status(1), HSM_STOPPED);
+        assert_eq!(sbi.get_reset_request(), Some((ResetType::Shutdown, 0)));
+    }
+
+    #[test]
+    fn threaded_ipi() {
+        let mut machine = ipi_machine(10);
+        machine.run_threaded().unwrap();
+        assert_ipi_delivered(&mut machine);
+    }
+
+    #[test]
+    fn threaded_failure_stops_every_hart() {
+        use super::super::cpu::CPUError;
+
+        let mut machine = Machine::new(&MachineConfig {
+            harts: 2,
+            memory_size: 0x1000,
+            ..MachineConfig::default()
+        });
+        // Hart 1 waits for an interrupt nothing will send
+        let program = [
+            0xf1402573, // csrr a0, mhartid
+            0x00051663, // bnez a0, 1f
+            0x400002B7, // lui t0, 0x40000
+            0x0002A303, // lw t1, 0(t0)
+            0x10500073, // 1: wfi
+            0xFFDFF06F, // j 1b
+        ];
+        for (index, instr) in program.iter().enumerate() {
+            machine
+                .get_memory()
+                .write_word(0x8000_0000 + index as u32 * 4, *instr)
+                .unwrap();
+        }
+        machine.boot(0x8000_0000, "").unwrap();
+        match machine.run_threaded() {
+            Err(CPUError::Crash(report)) => assert_eq!(report.pc.addr, 0x8000_000C),
+            result => panic!("{:?}", result),
+        }
+    }
+}
@@ -1,13 +1,15 @@
-use std::ops::{Deref, DerefMut};
+use std::cell::Cell;
 use std::collections::HashMap;
 
+use super::devices::DeviceRef;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MemoryError {
     UnmappedRegion,
     AlreadyMappedRegion,
 }
 
-type MemoryResult<T> = Result<T, MemoryError>;
+pub type MemoryResult<T> = Result<T, MemoryError>;
 
 pub trait Mem {
     fn read_byte(&self, addr: u32) -> MemoryResult<u8>;
@@ -39,9 +41,35 @@ pub trait Mem {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Writes are tracked at this granularity for invalidating decoded code
+pub const PAGE_SHIFT: u32 = 12;
+
+/// Raw host view of a RAM segment, for generated code accessing guest memory directly. The
+/// pointers stay valid until the segments or watched code pages of the `RAM` change.
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentView {
+    pub base: u32,
+    pub size: u32,
+    pub data: *mut u8,
+    /// One byte for each page the segment touches, starting with the page of `base`, which is
+    /// non-zero when the page is a watched code page. Stores there must go through `Mem`.
+    pub code_pages: *const u8,
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct RAM {
     mapping: HashMap<(u32, u32), Vec<u8>>,
+    devices: Vec<((u32, u32), DeviceRef)>,
+    /// Pages harts have decoded code from, with a version bumped by every write to the page
+    code_pages: HashMap<u32, u64>,
+    /// Bumped by every write to any of `code_pages`
+    code_generation: u64,
+    /// `code_pages` as flags for each page of a segment, keyed by the segment
+    code_page_flags: HashMap<(u32, u32), Vec<u8>>,
+    /// mip bits the devices assert for each hart, collected while they are ticked
+    interrupts: Vec<u32>,
+    /// Cleared by any device access, which can change what the devices assert
+    interrupts_valid: Cell<bool>,
     // pub inner: Vec<u8>,
 }
 
@@ -51,6 +79,12 @@ impl RAM {
         hm.insert((base, size), vec![0; size as usize]);
         RAM {
             mapping: hm,
+            devices: Vec::new(),
+            code_pages: HashMap::new(),
+            code_generation: 0,
+            code_page_flags: HashMap::new(),
+            interrupts: Vec::new(),
+            interrupts_valid: Cell::new(false),
             // inner: vec![0; size as usize],
         }
     }
@@ -65,6 +99,219 @@ impl RAM {
         self.mapping.insert((base, size), vec![0; size as usize]);
         Ok(())
     }
+
+    /// Map a device into the address space, accesses in `base..base + size` are forwarded to it
+    pub fn attach_device(&mut self, base: u32, size: u32, device: DeviceRef) -> MemoryResult<()> {
+        for entry in self.mapping.keys().chain(self.devices.iter().map(|(key, _)| key)) {
+            if base < (entry.0 + entry.1) && entry.0 < (base + size) {
+                return Err(MemoryError::AlreadyMappedRegion)
+            }
+        }
+        self.devices.push(((base, size), device));
+        self.invalidate_interrupts();
+        Ok(())
+    }
+
+    /// The device mapped at `addr` and the offset into it. Looking one up means it is about to be
+    /// accessed, so the interrupts it asserts may change.
+    fn device_at(&self, addr: u32) -> Option<(u32, &DeviceRef)> {
+        let (key, device) = self
+            .devices
+            .iter()
+            .find(|(key, _)| addr >= key.0 && addr < (key.0 + key.1))?;
+        self.invalidate_interrupts();
+        Some((addr - key.0, device))
+    }
+
+    /// Advance every attached device by one step, collecting the interrupts they assert while
+    /// each one is locked anyway
+    pub fn tick_devices(&mut self) {
+        // Devices get access to the rest of the address space for DMA while they are processed.
+        // The device list is moved out for that time, so DMA can't recurse into a device.
+        let devices = std::mem::take(&mut self.devices);
+        let mut interrupts = std::mem::take(&mut self.interrupts);
+        interrupts.iter_mut().for_each(|lines| *lines = 0);
+        for (_, device) in devices.iter() {
+            let mut device = device.lock().unwrap();
+            device.tick();
+            device.process(self);
+            for (hartid, lines) in interrupts.iter_mut().enumerate() {
+                *lines |= device.pending_interrupts(hartid as u32);
+            }
+        }
+        self.devices = devices;
+        self.interrupts = interrupts;
+        self.interrupts_valid.set(true);
+    }
+
+    /// Devices were changed without going through the bus, such as by restoring their state, so
+    /// the interrupts they assert have to be looked at again
+    pub fn invalidate_interrupts(&self) {
+        self.interrupts_valid.set(false);
+    }
+
+    pub fn is_mapped(&self, addr: u32) -> bool {
+        self.mapping
+            .keys()
+            .any(|entry| addr >= entry.0 && addr < (entry.0 + entry.1))
+    }
+
+    /// Read a word from the RAM segments only. Device registers are never touched, reading them
+    /// can have side effects, so this is how guest memory is looked at from outside the guest.
+    pub fn peek_word(&self, addr: u32) -> MemoryResult<u32> {
+        let mut bytes = [0; 4];
+        for (offset, byte) in bytes.iter_mut().enumerate() {
+            let addr = addr.wrapping_add(offset as u32);
+            *byte = self
+                .mapping
+                .iter()
+                .find(|(key, _)| addr >= key.0 && addr - key.0 < key.1)
+                .map(|(key, data)| data[(addr - key.0) as usize])
+                .ok_or(MemoryError::UnmappedRegion)?;
+        }
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /// (base, size) of every RAM segment, in address order
+    pub fn get_segments(&self) -> Vec<(u32, u32)> {
+        let mut segments: Vec<(u32, u32)> = self.mapping.keys().copied().collect();
+        segments.sort_unstable();
+        segments
+    }
+
+    /// Contents of every RAM segment, by base address in address order
+    pub fn get_contents(&self) -> Vec<(u32, Vec<u8>)> {
+        let mut contents: Vec<(u32, Vec<u8>)> = self
+            .mapping
+            .iter()
+            .map(|(key, data)| (key.0, data.clone()))
+            .collect();
+        contents.sort_unstable_by_key(|(base, _)| *base);
+        contents
+    }
+
+    /// Overwrite RAM segments with `contents` from `get_contents`. Every watched code page counts
+    /// as written.
+    pub fn set_contents(&mut self, contents: &[(u32, Vec<u8>)]) -> MemoryResult<()> {
+        let fits = |(base, data): &(u32, Vec<u8>)| {
+            self.mapping.contains_key(&(*base, data.len() as u32))
+        };
+        if !contents.iter().all(fits) {
+            return Err(MemoryError::UnmappedRegion);
+        }
+        for (base, data) in contents {
+            let key = (*base, data.len() as u32);
+            self.mapping.get_mut(&key).unwrap().copy_from_slice(data);
+        }
+        for version in self.code_pages.values_mut() {
+            *version += 1;
+        }
+        self.code_generation += 1;
+        Ok(())
+    }
+
+    /// Start tracking writes to the code page `page`, returning its current version
+    pub fn watch_code_page(&mut self, page: u32) -> u64 {
+        let addr = page << PAGE_SHIFT;
+        if let Some(key) = self.segment_key(addr) {
+            let flags = self
+                .code_page_flags
+                .entry(key)
+                .or_insert_with(|| RAM::segment_page_flags(key));
+            flags[(page - (key.0 >> PAGE_SHIFT)) as usize] = 1;
+        }
+        *self.code_pages.entry(page).or_insert(0)
+    }
+
+    /// The `len` bytes at `addr` when they all lie in one RAM segment
+    fn ram_bytes(&self, addr: u32, len: usize) -> Option<&[u8]> {
+        let (key, data) = self
+            .mapping
+            .iter()
+            .find(|(key, _)| addr >= key.0 && addr - key.0 < key.1)?;
+        let offset = (addr - key.0) as usize;
+        data.get(offset..offset + len)
+    }
+
+    fn ram_bytes_mut(&mut self, addr: u32, len: usize) -> Option<&mut [u8]> {
+        let (key, data) = self
+            .mapping
+            .iter_mut()
+            .find(|(key, _)| addr >= key.0 && addr - key.0 < key.1)?;
+        let offset = (addr - key.0) as usize;
+        data.get_mut(offset..offset + len)
+    }
+
+    /// Bump the versions of the watched code pages `len` bytes at `addr` were written to
+    fn code_written(&mut self, addr: u32, len: u32) {
+        if self.code_pages.is_empty() {
+            return;
+        }
+        let last = addr.wrapping_add(len - 1);
+        for page in (addr >> PAGE_SHIFT)..=(last >> PAGE_SHIFT) {
+            if let Some(version) = self.code_pages.get_mut(&page) {
+                *version += 1;
+                self.code_generation += 1;
+            }
+        }
+    }
+
+    fn segment_key(&self, addr: u32) -> Option<(u32, u32)> {
+        self.mapping
+            .keys()
+            .find(|entry| addr >= entry.0 && addr < (entry.0 + entry.1))
+            .copied()
+    }
+
+    fn segment_page_flags(key: (u32, u32)) -> Vec<u8> {
+        let first = key.0 >> PAGE_SHIFT;
+        let last = (key.0 as u64 + key.1 as u64 - 1) as u32 >> PAGE_SHIFT;
+        vec![0; (last - first + 1) as usize]
+    }
+
+    /// Host view of the segment containing `addr`
+    pub fn segment_view(&mut self, addr: u32) -> Option<SegmentView> {
+        let key = self.segment_key(addr)?;
+        let code_pages = self
+            .code_page_flags
+            .entry(key)
+            .or_insert_with(|| RAM::segment_page_flags(key))
+            .as_ptr();
+        Some(SegmentView {
+            base: key.0,
+            size: key.1,
+            data: self.mapping.get_mut(&key).unwrap().as_mut_ptr(),
+            code_pages,
+        })
+    }
+
+    pub fn get_code_page_version(&self, page: u32) -> Option<u64> {
+        self.code_pages.get(&page).copied()
+    }
+
+    /// Changes whenever a watched code page is written, so decoded code can be checked cheaply
+    pub fn get_code_generation(&self) -> u64 {
+        self.code_generation
+    }
+
+    /// Combined mip bits asserted by all attached devices for `hartid`. These are remembered from
+    /// the last tick until a device is accessed.
+    pub fn pending_interrupts(&mut self, hartid: u32) -> u32 {
+        let hart = hartid as usize;
+        if hart >= self.interrupts.len() {
+            self.interrupts.resize(hart + 1, 0);
+            self.invalidate_interrupts();
+        }
+        if !self.interrupts_valid.get() {
+            for (hartid, lines) in self.interrupts.iter_mut().enumerate() {
+                *lines = self.devices.iter().fold(0, |pending, (_, device)| {
+                    pending | device.lock().unwrap().pending_interrupts(hartid as u32)
+                });
+            }
+            self.interrupts_valid.set(true);
+        }
+        self.interrupts[hart]
+    }
 }
 
 // impl Deref for RAM {
@@ -89,27 +336,108 @@ impl Mem for RAM {
                 return Ok(self.mapping.get(entry).unwrap()[(addr - entry.0) as usize])
             }
         }
+        if let Some((offset, device)) = self.device_at(addr) {
+            return device.lock().unwrap().read_byte(offset);
+        }
         Err(MemoryError::UnmappedRegion)
         // self.inner[addr as usize]
     }
 
     // TODO We need to implement memory protections here so we can't read everything
     fn write_byte(&mut self, addr: u32, val: u8) -> MemoryResult<()> {
+        if let Some((offset, device)) = self.device_at(addr) {
+            return device.lock().unwrap().write_byte(offset, val);
+        }
 
         self.mapping.iter_mut().for_each(|(key, value)| {
             if addr >= key.0 && addr < (key.0 + key.1) {
                 value[(addr - key.0) as usize] = val;
             }
         });
+        if !self.code_pages.is_empty() {
+            if let Some(version) = self.code_pages.get_mut(&(addr >> PAGE_SHIFT)) {
+                *version += 1;
+                self.code_generation += 1;
+            }
+        }
         Ok(())
         // self.inner[addr as usize] = val;
     }
+
+    // Accesses inside one RAM segment are copied whole. Device registers can have side effects on
+    // access, so wider accesses are forwarded whole too instead of being split into bytes.
+    fn read_halfword(&self, addr: u32) -> MemoryResult<u16> {
+        if let Some(bytes) = self.ram_bytes(addr, 2) {
+            return Ok(u16::from_le_bytes([bytes[0], bytes[1]]));
+        }
+        if let Some((offset, device)) = self.device_at(addr) {
+            return device.lock().unwrap().read_halfword(offset);
+        }
+        Ok(self.read_byte(addr)? as u16 | (self.read_byte(addr + 1)? as u16) << 8)
+    }
+
+    fn write_halfword(&mut self, addr: u32, val: u16) -> MemoryResult<()> {
+        if let Some(bytes) = self.ram_bytes_mut(addr, 2) {
+            bytes.copy_from_slice(&val.to_le_bytes());
+            self.code_written(addr, 2);
+            return Ok(());
+        }
+        if let Some((offset, device)) = self.device_at(addr) {
+            return device.lock().unwrap().write_halfword(offset, val);
+        }
+        self.write_byte(addr, (val & 0xFF) as u8)?;
+        self.write_byte(addr + 1, ((val >> 8) & 0xFF) as u8)?;
+        Ok(())
+    }
+
+    fn read_word(&self, addr: u32) -> MemoryResult<u32> {
+        if let Some(bytes) = self.ram_bytes(addr, 4) {
+            return Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+        }
+        if let Some((offset, device)) = self.device_at(addr) {
+            return device.lock().unwrap().read_word(offset);
+        }
+        Ok((self.read_byte(addr)? as u32)
+            | (self.read_byte(addr + 1)? as u32) << 8
+            | (self.read_byte(addr + 2)? as u32) << 16
+            | (self.read_byte(addr + 3)? as u32) << 24)
+    }
+
+    fn write_word(&mut self, addr: u32, val: u32) -> MemoryResult<()> {
+        if let Some(bytes) = self.ram_bytes_mut(addr, 4) {
+            bytes.copy_from_slice(&val.to_le_bytes());
+            self.code_written(addr, 4);
+            return Ok(());
+        }
+        if let Some((offset, device)) = self.device_at(addr) {
+            return device.lock().unwrap().write_word(offset, val);
+        }
+        self.write_byte(addr, (val & 0xFF) as u8)?;
+        self.write_byte(addr + 1, ((val >> 8) & 0xFF) as u8)?;
+        self.write_byte(addr + 2, ((val >> 16) & 0xFF) as u8)?;
+        self.write_byte(addr + 3, ((val >> 24) & 0xFF) as u8)?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn segment_view_flags_code_pages() {
+        let mut ram = RAM::new(0x1000, 0x3000);
+        ram.write_word(0x1004, 0x11335577).unwrap();
+        ram.watch_code_page(2);
+
+        let view = ram.segment_view(0x2FFF).unwrap();
+        assert_eq!((view.base, view.size), (0x1000, 0x3000));
+        let flags = unsafe { std::slice::from_raw_parts(view.code_pages, 3) };
+        assert_eq!(flags, &[0, 1, 0]);
+        assert_eq!(unsafe { *view.data.add(4) }, 0x77);
+        assert!(ram.segment_view(0x4000).is_none());
+    }
+
     #[test]
     fn ram_new() {
         let ram = RAM::new(0, 1024);
@@ -125,28 +453,122 @@ mod tests {
     #[test]
     fn write_read_byte() {
         let mut ram = RAM::new(0, 1024);
-        ram.write_byte(527, 83);
+        ram.write_byte(527, 83).unwrap();
         assert_eq!(ram.read_byte(527).unwrap(), 83);
     }
 
     #[test]
     fn write_read_halfword() {
         let mut ram = RAM::new(0, 1024);
-        ram.write_halfword(527, 0x1234);
+        ram.write_halfword(527, 0x1234).unwrap();
         assert_eq!(ram.read_halfword(527).unwrap(), 0x1234);
     }
 
     #[test]
     fn write_read_word() {
         let mut ram = RAM::new(0, 1024);
-        ram.write_word(527, 0x12345678);
+        ram.write_word(527, 0x12345678).unwrap();
         assert_eq!(ram.read_word(527).unwrap(), 0x12345678);
     }
 
     #[test]
     #[should_panic]
     fn read_past_memory() {
-        let mut ram = RAM::new(0, 1024);
+        let ram = RAM::new(0, 1024);
         assert_eq!(ram.read_byte(2048).unwrap(), 0);
     }
+
+    #[test]
+    fn device_forwarding() {
+        use super::super::devices::clint::{Clint, CLINT_BASE, CLINT_SIZE};
+        use std::sync::{Arc, Mutex};
+
+        let mut ram = RAM::new(0, 1024);
+        let clint = Arc::new(Mutex::new(Clint::new(1)));
+        ram.attach_device(CLINT_BASE, CLINT_SIZE, clint.clone()).unwrap();
+        ram.write_word(CLINT_BASE + 0xBFF8, 0x1234).unwrap();
+        assert_eq!(clint.lock().unwrap().get_mtime(), 0x1234);
+        assert_eq!(ram.read_word(CLINT_BASE + 0xBFF8).unwrap(), 0x1234);
+    }
+
+    #[test]
+    fn words_across_segments_and_code_pages() {
+        let mut ram = RAM::new(0, 0x1000);
+        ram.add_segment(0x1000, 0x1000).unwrap();
+        let versions = (ram.watch_code_page(0), ram.watch_code_page(1));
+        ram.write_word(0xFFE, 0x1234_5678).unwrap();
+        assert_eq!(ram.read_word(0xFFE).unwrap(), 0x1234_5678);
+        assert_ne!(ram.get_code_page_version(0), Some(versions.0));
+        assert_ne!(ram.get_code_page_version(1), Some(versions.1));
+    }
+
+    #[test]
+    fn device_accesses_refresh_interrupts() {
+        use super::super::csr::MIP_MSIP;
+        use super::super::devices::clint::{Clint, CLINT_BASE, CLINT_SIZE};
+        use std::sync::{Arc, Mutex};
+
+        let mut ram = RAM::new(0, 1024);
+        ram.attach_device(CLINT_BASE, CLINT_SIZE, Arc::new(Mutex::new(Clint::new(1))))
+            .unwrap();
+        ram.tick_devices();
+        assert_eq!(ram.pending_interrupts(0), 0);
+        // Seen right away, not only after the next tick
+        ram.write_word(CLINT_BASE, 1).unwrap();
+        assert_eq!(ram.pending_interrupts(0), MIP_MSIP);
+    }
+
+    #[test]
+    fn peeking_skips_devices() {
+        use super::super::devices::plic::{Plic, PLIC_BASE, PLIC_SIZE};
+        use super::super::devices::Device;
+        use std::sync::{Arc, Mutex};
+
+        let mut ram = RAM::new(0, 1024);
+        let plic = Arc::new(Mutex::new(Plic::new(1, 1)));
+        ram.attach_device(PLIC_BASE, PLIC_SIZE, plic.clone()).unwrap();
+        ram.write_word(PLIC_BASE + 4, 1).unwrap();
+        ram.write_word(PLIC_BASE + 0x2000, 1 << 1).unwrap();
+        plic.lock().unwrap().irq_line(1).raise();
+        plic.lock().unwrap().tick();
+        ram.write_word(0x10, 0x1234_5678).unwrap();
+
+        assert_eq!(ram.peek_word(0x10), Ok(0x1234_5678));
+        assert_eq!(ram.peek_word(1022), Err(MemoryError::UnmappedRegion));
+        // Reading the claim register would claim the interrupt
+        assert_eq!(
+            ram.peek_word(PLIC_BASE + 0x20_0004),
+            Err(MemoryError::UnmappedRegion)
+        );
+        assert!(plic.lock().unwrap().is_pending(1));
+    }
+
+    #[test]
+    fn attach_overlapping_device() {
+        use super::super::devices::clint::Clint;
+        use std::sync::{Arc, Mutex};
+
+        let mut ram = RAM::new(0, 1024);
+        let clint = Arc::new(Mutex::new(Clint::new(1)));
+        assert_eq!(
+            ram.attach_device(512, 0x1000, clint),
+            Err(MemoryError::AlreadyMappedRegion)
+        );
+    }
+
+    #[test]
+    fn restore_contents() {
+        let mut ram = RAM::new(0, 0x2000);
+        ram.write_word(0x1000, 0x13).unwrap();
+        let version = ram.watch_code_page(1);
+        let contents = ram.get_contents();
+
+        ram.write_word(0x1000, 0x73).unwrap();
+        ram.set_contents(&contents).unwrap();
+        assert_eq!(ram.read_word(0x1000), Ok(0x13));
+        assert!(ram.get_code_page_version(1) > Some(version));
+
+        let mut other = RAM::new(0, 0x1000);
+        assert_eq!(other.set_contents(&contents), Err(MemoryError::UnmappedRegion));
+    }
 }
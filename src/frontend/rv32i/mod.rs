@@ -1,7 +1,29 @@
+pub mod bbv;
+mod block_cache;
+pub mod boot;
+pub mod branch;
+pub mod cache;
+pub mod coverage;
 pub mod cpu;
-mod instructions;
-mod mem;
+pub mod csr;
+pub mod devices;
+pub mod disasm;
+pub mod dwarf;
+pub mod fdt;
+pub(crate) mod instructions;
+pub mod lift;
+pub mod linux;
+pub mod machine;
+pub mod mem;
+pub mod profile;
+pub mod recompile;
 mod registers;
+pub mod sbi;
+pub mod snapshot;
+pub mod symbols;
+mod threaded;
+pub mod timing;
+pub mod trace;
+pub mod unwind;
 
-use cpu::*;
 pub use instructions::DecodeError;
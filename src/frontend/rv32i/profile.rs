@@ -0,0 +1,282 @@
+//! Function-level profiles in the callgrind format, for KCachegrind. Every executed instruction
+//! is charged to its address, and a shadow call stack built from the link register conventions
+//! of JAL and JALR charges what each call cost, callees included, to its call site. Addresses
+//! are attributed to functions with a `SymbolTable` only when the profile is written.
+
+use super::branch::is_link;
+use super::instructions::Instruction;
+use super::symbols::SymbolTable;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+/// Instructions and cycles
+type Cost = [u64; 2];
+
+/// Calls deeper than this push the outermost frames out
+const MAX_CALL_DEPTH: usize = 4096;
+
+fn add(total: &mut Cost, cost: Cost) {
+    total[0] += cost[0];
+    total[1] += cost[1];
+}
+
+fn since(now: Cost, then: Cost) -> Cost {
+    [now[0] - then[0], now[1] - then[1]]
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct CallCost {
+    calls: u64,
+    /// Cost of the callee and everything it called
+    inclusive: Cost,
+}
+
+/// Cost of each address and of each call made, of one function
+type FunctionCosts = (BTreeMap<u32, Cost>, BTreeMap<(u32, u32), CallCost>);
+
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    site: u32,
+    target: u32,
+    return_address: u32,
+    /// Totals when the call was made
+    entry: Cost,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CallProfiler {
+    /// Cost of the instructions at each address
+    costs: HashMap<u32, Cost>,
+    /// By (call site, call target)
+    calls: HashMap<(u32, u32), CallCost>,
+    stack: Vec<Frame>,
+    total: Cost,
+}
+
+impl CallProfiler {
+    pub fn new() -> Self {
+        CallProfiler::default()
+    }
+
+    /// Instructions profiled so far
+    pub fn get_instructions(&self) -> u64 {
+        self.total[0]
+    }
+
+    /// Calls not returned from yet
+    pub fn get_call_depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Charge `instr`, which executed at `pc` in `cycles` and continued at `next_pc`
+    pub fn observe(&mut self, instr: Instruction, pc: u32, next_pc: u32, cycles: u64) {
+        let cost = [1, cycles];
+        add(self.costs.entry(pc).or_default(), cost);
+        add(&mut self.total, cost);
+
+        match instr {
+            Instruction::JAL(rd, imm) if is_link(rd) && next_pc == pc.wrapping_add(imm) => {
+                self.call(pc, next_pc)
+            }
+            Instruction::JALR(rd, rs1, _) => {
+                // A return to one link register while calling through the other is both
+                if is_link(rs1) && (!is_link(rd) || rd != rs1) {
+                    self.ret(next_pc);
+                }
+                if is_link(rd) {
+                    self.call(pc, next_pc);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn call(&mut self, site: u32, target: u32) {
+        if self.stack.len() == MAX_CALL_DEPTH {
+            self.stack.remove(0);
+        }
+        self.stack.push(Frame {
+            site,
+            target,
+            return_address: site.wrapping_add(4),
+            entry: self.total,
+        });
+    }
+
+    /// Pop the call returning to `target`, and any calls it left without returning. Returns which
+    /// don't match any call, e.g. after a context switch, are ignored.
+    fn ret(&mut self, target: u32) {
+        let depth = match self
+            .stack
+            .iter()
+            .rposition(|frame| frame.return_address == target)
+        {
+            Some(depth) => depth,
+            None => return,
+        };
+        for frame in self.stack.split_off(depth) {
+            let call = self.calls.entry((frame.site, frame.target)).or_default();
+            call.calls += 1;
+            add(&mut call.inclusive, since(self.total, frame.entry));
+        }
+    }
+
+    /// Every call, those still running included, by (call site, call target)
+    fn all_calls(&self) -> HashMap<(u32, u32), CallCost> {
+        let mut calls = self.calls.clone();
+        for frame in &self.stack {
+            let call = calls.entry((frame.site, frame.target)).or_default();
+            call.calls += 1;
+            add(&mut call.inclusive, since(self.total, frame.entry));
+        }
+        calls
+    }
+
+    /// Write the profile in the callgrind format, with instructions and cycles as events.
+    /// Addresses outside of `symbols` are grouped by address.
+    pub fn write_callgrind<W: Write>(
+        &self,
+        symbols: &SymbolTable,
+        command: &str,
+        out: &mut W,
+    ) -> io::Result<()> {
+        let function = |addr: u32| match symbols.lookup(addr) {
+            Some(symbol) => symbol.name.clone(),
+            None => format!("{:#010x}", addr),
+        };
+
+        // Cost lines and calls of each function, in address order
+        let mut functions: BTreeMap<String, FunctionCosts> = BTreeMap::new();
+        for (&pc, &cost) in &self.costs {
+            functions
+                .entry(function(pc))
+                .or_default()
+                .0
+                .insert(pc, cost);
+        }
+        for (key, call) in self.all_calls() {
+            functions
+                .entry(function(key.0))
+                .or_default()
+                .1
+                .insert(key, call);
+        }
+
+        writeln!(out, "# callgrind format")?;
+        writeln!(out, "version: 1")?;
+        writeln!(out, "creator: emulator-rs")?;
+        writeln!(out, "cmd: {}", command)?;
+        writeln!(out, "positions: instr")?;
+        writeln!(out, "events: Ir Cycles")?;
+        writeln!(out, "summary: {} {}", self.total[0], self.total[1])?;
+        for (name, (costs, calls)) in functions {
+            writeln!(out, "\nfn={}", name)?;
+            for (pc, cost) in costs {
+                writeln!(out, "{:#x} {} {}", pc, cost[0], cost[1])?;
+            }
+            for ((site, target), call) in calls {
+                writeln!(out, "cfn={}", function(target))?;
+                writeln!(out, "calls={} {:#x}", call.calls, target)?;
+                writeln!(
+                    out,
+                    "{:#x} {} {}",
+                    site, call.inclusive[0], call.inclusive[1]
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::symbols::Symbol;
+    use super::*;
+
+    const CALL: Instruction = Instruction::JAL(1, 0x100);
+    const RET: Instruction = Instruction::JALR(0, 1, 0);
+    const NOP: Instruction = Instruction::ADDI(0, 0, 0);
+
+    fn symbols() -> SymbolTable {
+        let symbol = |name: &str, addr| Symbol {
+            name: name.to_string(),
+            addr,
+            size: 0x100,
+        };
+        SymbolTable::new(vec![
+            symbol("main", 0x0),
+            symbol("f", 0x100),
+            symbol("g", 0x200),
+        ])
+    }
+
+    fn callgrind(profiler: &CallProfiler) -> String {
+        let mut out = Vec::new();
+        profiler
+            .write_callgrind(&symbols(), "test", &mut out)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn calls_are_charged_to_call_sites() {
+        let mut profiler = CallProfiler::new();
+        for _ in 0..2 {
+            profiler.observe(CALL, 0x0, 0x100, 1);
+            profiler.observe(NOP, 0x100, 0x104, 3);
+            profiler.observe(CALL, 0x104, 0x204, 1);
+            profiler.observe(RET, 0x204, 0x108, 2);
+            profiler.observe(RET, 0x108, 0x4, 2);
+        }
+        profiler.observe(NOP, 0x4, 0x8, 1);
+
+        assert_eq!(profiler.get_call_depth(), 0);
+        assert_eq!(
+            callgrind(&profiler),
+            "# callgrind format\n\
+             version: 1\n\
+             creator: emulator-rs\n\
+             cmd: test\n\
+             positions: instr\n\
+             events: Ir Cycles\n\
+             summary: 11 19\n\
+             \n\
+             fn=f\n\
+             0x100 2 6\n\
+             0x104 2 2\n\
+             0x108 2 4\n\
+             cfn=g\n\
+             calls=2 0x204\n\
+             0x104 2 4\n\
+             \n\
+             fn=g\n\
+             0x204 2 4\n\
+             \n\
+             fn=main\n\
+             0x0 2 2\n\
+             0x4 1 1\n\
+             cfn=f\n\
+             calls=2 0x100\n\
+             0x0 8 16\n"
+        );
+    }
+
+    #[test]
+    fn unfinished_calls_are_included() {
+        let mut profiler = CallProfiler::new();
+        profiler.observe(CALL, 0x0, 0x100, 1);
+        profiler.observe(CALL, 0x100, 0x200, 1);
+        profiler.observe(NOP, 0x200, 0x204, 1);
+        // Returns straight to main, e.g. from a longjmp
+        profiler.observe(RET, 0x204, 0x4, 1);
+        profiler.observe(Instruction::JAL(1, 0x2fc), 0x4, 0x300, 1);
+        profiler.observe(NOP, 0x300, 0x304, 1);
+
+        assert_eq!(profiler.get_call_depth(), 1);
+        let profile = callgrind(&profiler);
+        assert!(profile.contains("cfn=g\ncalls=1 0x200\n0x100 2 2\n"));
+        assert!(profile.contains("cfn=f\ncalls=1 0x100\n0x0 3 3\n"));
+        assert!(profile.contains("cfn=0x00000300\ncalls=1 0x300\n0x4 1 1\n"));
+    }
+}
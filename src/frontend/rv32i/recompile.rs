@@ -0,0 +1,272 @@
+//! Ahead-of-time recompilation of RV32I ELF binaries to Rust source. Code is discovered
+//! recursively from the entry point and the symbols in executable sections, following every
+//! branch and jump with a known target and the return address of every call. Each block found
+//! becomes a function run by `backend::rust`'s `dispatch` on a `lift::Hart`. Indirect jumps end a
+//! block, where they land is looked up again at run time, and code the recompiler never saw is
+//! left to the interpreter.
+
+use super::cpu::{CPUResult, CPU};
+use super::instructions::Instruction;
+use super::lift;
+use super::mem::{Mem, MemoryError, PAGE_SHIFT, RAM};
+use crate::backend::interpreter::Outcome;
+use crate::backend::rust;
+use crate::ir::{Block, Exit, Op};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::ops::Range;
+use xmas_elf::sections::{SectionData, ShType, SHF_ALLOC, SHF_EXECINSTR};
+use xmas_elf::symbol_table::Entry;
+use xmas_elf::ElfFile;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecompileError {
+    /// The file isn't an ELF file xmas-elf can read
+    Elf(&'static str),
+    /// Sections overlap or don't fit in the address space
+    MemoryError(MemoryError),
+    /// No code could be lifted at the entry point
+    NoCode,
+}
+
+impl From<&'static str> for RecompileError {
+    fn from(err: &'static str) -> RecompileError {
+        RecompileError::Elf(err)
+    }
+}
+
+impl From<MemoryError> for RecompileError {
+    fn from(err: MemoryError) -> RecompileError {
+        RecompileError::MemoryError(err)
+    }
+}
+
+pub type RecompileResult<T> = Result<T, RecompileError>;
+
+/// The code of a recompiled binary, as exported by the generated module in `RECOMPILED`
+#[derive(Debug, Clone, Copy)]
+pub struct Recompiled {
+    /// Start addresses of the recompiled blocks
+    pub blocks: &'static [u32],
+    /// Run the block at the given pc, `None` when none starts there
+    pub run: fn(&mut CPU, u32) -> Option<CPUResult<Outcome>>,
+}
+
+/// `Recompiled` code loaded into a hart, with the versions of the code pages it was compiled
+/// from. Blocks on a page written since are stale and left to the interpreter.
+#[derive(Debug, Clone)]
+pub(crate) struct LoadedCode {
+    code: Recompiled,
+    pages: HashMap<u32, u64>,
+}
+
+impl LoadedCode {
+    pub(crate) fn new(code: Recompiled, memory: &mut RAM) -> Self {
+        let pages = code
+            .blocks
+            .iter()
+            .map(|start| start >> PAGE_SHIFT)
+            .map(|page| (page, memory.watch_code_page(page)))
+            .collect();
+        LoadedCode { code, pages }
+    }
+
+    pub(crate) fn run(&self, cpu: &mut CPU, pc: u32) -> Option<CPUResult<Outcome>> {
+        let page = pc >> PAGE_SHIFT;
+        let version = *self.pages.get(&page)?;
+        if cpu.get_memory().get_code_page_version(page) != Some(version) {
+            return None;
+        }
+        (self.code.run)(cpu, pc)
+    }
+}
+
+/// The binary's allocated sections in memory, and where its code is
+struct Image {
+    memory: RAM,
+    code: Vec<Range<u32>>,
+    entry: u32,
+    /// Symbols defined in code
+    symbols: Vec<u32>,
+}
+
+impl Image {
+    fn load(elf: &[u8]) -> RecompileResult<Self> {
+        let elf = ElfFile::new(elf)?;
+        let mut image = Image {
+            memory: RAM::default(),
+            code: Vec::new(),
+            entry: elf.header.pt2.entry_point() as u32,
+            symbols: Vec::new(),
+        };
+
+        for sect in elf.section_iter() {
+            let (addr, size) = (sect.address() as u32, sect.size() as u32);
+            if sect.get_type() == Ok(ShType::ProgBits) && sect.flags() & SHF_ALLOC != 0 {
+                image.memory.add_segment(addr, size)?;
+                for (offset, byte) in sect.raw_data(&elf).iter().enumerate() {
+                    image.memory.write_byte(addr + offset as u32, *byte)?;
+                }
+                if sect.flags() & SHF_EXECINSTR != 0 {
+                    image.code.push(addr..addr + size);
+                }
+            }
+        }
+
+        // xmas-elf doesn't parse every kind of section, so only symbol tables are looked at
+        let symtabs = elf
+            .section_iter()
+            .filter(|sect| sect.get_type() == Ok(ShType::SymTab));
+        for sect in symtabs {
+            if let Ok(SectionData::SymbolTable32(entries)) = sect.get_data(&elf) {
+                image
+                    .symbols
+                    .extend(entries.iter().map(|entry| entry.value() as u32));
+            }
+        }
+        let code = image.code.clone();
+        image
+            .symbols
+            .retain(|addr| code.iter().any(|range| range.contains(addr)));
+        Ok(image)
+    }
+
+    fn is_code(&self, addr: u32) -> bool {
+        addr % 4 == 0 && self.code.iter().any(|range| range.contains(&addr))
+    }
+
+    /// Where execution continues after `block` without knowing register values
+    fn successors(&self, block: &Block) -> Vec<u32> {
+        let mut next = block.successors();
+        let last = block.ops.iter().rev().find_map(|op| match *op {
+            Op::Guest(addr) => Some(addr),
+            _ => None,
+        });
+        match block.exit {
+            // Calls come back to the instruction after them
+            Exit::Jump(_) => {
+                let instr = last
+                    .and_then(|addr| self.memory.read_word(addr).ok())
+                    .and_then(|word| Instruction::decode(word).ok());
+                match instr {
+                    Some(Instruction::JAL(rd, _)) | Some(Instruction::JALR(rd, _, _))
+                        if rd != 0 =>
+                    {
+                        next.extend(last.map(|addr| addr + 4))
+                    }
+                    _ => {}
+                }
+            }
+            // MRET and SRET go wherever the trap came from, WFI continues after itself
+            Exit::Unlifted(addr) => next.push(addr + 4),
+            _ => {}
+        }
+        next
+    }
+
+    /// Lift all blocks reachable from the entry point and symbols, by start address
+    fn discover(&self) -> BTreeMap<u32, Block> {
+        let mut blocks = BTreeMap::new();
+        let mut pending = vec![self.entry];
+        pending.extend(&self.symbols);
+        while let Some(addr) = pending.pop() {
+            if blocks.contains_key(&addr) || !self.is_code(addr) {
+                continue;
+            }
+            // The interpreter runs anything which doesn't lift at run time
+            if let Some(block) = lift::lift_block(&self.memory, addr) {
+                pending.extend(self.successors(&block));
+                blocks.insert(addr, block);
+            }
+        }
+        blocks
+    }
+}
+
+/// Rust source for the code of an ELF binary. The generated module reaches this crate as
+/// `crate_name` and exports `RECOMPILED`, for `CPU::set_recompiled`.
+pub fn recompile(elf: &[u8], crate_name: &str) -> RecompileResult<String> {
+    let image = Image::load(elf)?;
+    let blocks = image.discover();
+    if !blocks.contains_key(&image.entry) {
+        return Err(RecompileError::NoCode);
+    }
+    let blocks: Vec<Block> = blocks.into_values().collect();
+
+    let uses = [
+        format!("{}::frontend::rv32i::cpu::{{CPUResult, CPU}}", crate_name),
+        format!("{}::frontend::rv32i::lift::Hart", crate_name),
+        format!("{}::frontend::rv32i::recompile::Recompiled", crate_name),
+    ];
+    let mut out = String::from("// Generated by rv32-recompile, do not edit\n\n");
+    out.push_str(&rust::emit(&blocks, crate_name, &uses));
+    write!(
+        out,
+        "\npub const ENTRY: u32 = {:#010x};\n\n\
+         pub const RECOMPILED: Recompiled = Recompiled {{\n    \
+         blocks: BLOCKS,\n    \
+         run,\n\
+         }};\n\n\
+         pub fn run(cpu: &mut CPU, pc: u32) -> Option<CPUResult<Outcome>> {{\n    \
+         dispatch(&mut Hart::new(cpu), pc).map(|result| result.map_err(Into::into))\n\
+         }}\n",
+        image.entry
+    )
+    .unwrap();
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(code: &[u32]) -> Image {
+        let end = 0x1000 + code.len() as u32 * 4;
+        let mut memory = RAM::new(0x1000, 0x1000);
+        for (index, word) in code.iter().enumerate() {
+            memory.write_word(0x1000 + index as u32 * 4, *word).unwrap();
+        }
+        Image {
+            memory,
+            code: std::iter::once(0x1000..end).collect(),
+            entry: 0x1000,
+            symbols: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn calls_and_branches_are_followed() {
+        let image = image(&[
+            0x008000ef, // jal ra, 0x1008
+            0x00100073, // ebreak
+            0x00050463, // beqz a0, 0x1010
+            0x00150513, // addi a0, a0, 1
+            0x00008067, // ret
+        ]);
+        let blocks = image.discover();
+
+        assert_eq!(
+            blocks.keys().copied().collect::<Vec<_>>(),
+            vec![0x1000, 0x1004, 0x1008, 0x100c, 0x1010]
+        );
+    }
+
+    #[test]
+    fn indirect_jumps_stop_discovery() {
+        let image = image(&[
+            0x00050067, // jr a0
+            0x00000013, // nop
+        ]);
+
+        assert_eq!(image.discover().len(), 1);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(matches!(
+            recompile(b"not an elf file", "emulator_rs"),
+            Err(RecompileError::Elf(_))
+        ));
+    }
+}
@@ -1,5 +1,8 @@
+use std::io::{self, Read, Write};
 use std::ops::{Deref, DerefMut};
 
+use super::snapshot::{get_u32, put_u32};
+
 #[derive(Debug, Copy, Clone, Default)]
 pub struct RV32Registers {
     inner: [u32; 32],
@@ -64,7 +67,7 @@ impl RV32Registers {
 
     // This might have a signedness issue when we are adding
     pub fn add_to_pc(&mut self, val: u32) {
-        let (value, overflow) = self.pc.overflowing_add(val);
+        let (value, _) = self.pc.overflowing_add(val);
         self.pc = value;
     }
 
@@ -75,6 +78,20 @@ impl RV32Registers {
     pub fn increment_pc(&mut self) {
         self.pc += 4;
     }
+
+    pub(crate) fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        self.inner.iter().try_for_each(|reg| put_u32(out, *reg))?;
+        put_u32(out, self.pc)
+    }
+
+    pub(crate) fn read_from<R: Read>(input: &mut R) -> io::Result<Self> {
+        let mut registers = RV32Registers::new();
+        for reg in registers.inner.iter_mut() {
+            *reg = get_u32(input)?;
+        }
+        registers.pc = get_u32(input)?;
+        Ok(registers)
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,569 @@
+use super::cpu::{CPUStatus, CPU};
+use super::csr;
+use super::mem::Mem;
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+// Extension IDs
+pub const EXT_BASE: u32 = 0x10;
+pub const EXT_TIME: u32 = 0x5449_4D45;
+pub const EXT_IPI: u32 = 0x0073_5049;
+pub const EXT_RFENCE: u32 = 0x5246_4E43;
+pub const EXT_HSM: u32 = 0x0048_534D;
+pub const EXT_SRST: u32 = 0x5352_5354;
+
+// Legacy extensions, each one is a single function returning only a0
+const LEGACY_SET_TIMER: u32 = 0x00;
+const LEGACY_CONSOLE_PUTCHAR: u32 = 0x01;
+const LEGACY_CONSOLE_GETCHAR: u32 = 0x02;
+const LEGACY_CLEAR_IPI: u32 = 0x03;
+const LEGACY_SEND_IPI: u32 = 0x04;
+const LEGACY_REMOTE_FENCE_I: u32 = 0x05;
+const LEGACY_REMOTE_SFENCE_VMA_ASID: u32 = 0x07;
+const LEGACY_SHUTDOWN: u32 = 0x08;
+
+// Standard error codes
+pub const SBI_SUCCESS: i32 = 0;
+pub const SBI_ERR_FAILED: i32 = -1;
+pub const SBI_ERR_NOT_SUPPORTED: i32 = -2;
+pub const SBI_ERR_INVALID_PARAM: i32 = -3;
+pub const SBI_ERR_ALREADY_AVAILABLE: i32 = -6;
+
+/// Version 1.0 of the SBI specification
+const SPEC_VERSION: u32 = 1 << 24;
+/// Implementation ID reported by the base extension, outside of the range allocated so far
+const IMPL_ID: u32 = 0x454D_5253;
+const IMPL_VERSION: u32 = 1;
+
+// HSM hart states
+pub const HSM_STARTED: u32 = 0;
+pub const HSM_STOPPED: u32 = 1;
+pub const HSM_START_PENDING: u32 = 2;
+const HSM_SUSPEND_RETENTIVE: u32 = 0;
+
+/// A hart mask base of all ones selects every hart
+const HART_MASK_ALL: u32 = u32::MAX;
+
+/// Reset requested by the guest through the SRST extension or the legacy shutdown call
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResetType {
+    Shutdown,
+    ColdReboot,
+    WarmReboot,
+}
+
+/// Result of one SBI call, a0 carries the error and a1 the value
+struct SbiRet {
+    error: i32,
+    value: u32,
+}
+
+impl SbiRet {
+    fn success(value: u32) -> Self {
+        SbiRet {
+            error: SBI_SUCCESS,
+            value,
+        }
+    }
+
+    fn error(error: i32) -> Self {
+        SbiRet { error, value: 0 }
+    }
+}
+
+/// What the SBI keeps for each hart
+#[derive(Debug, Clone, Copy, Default)]
+struct HartState {
+    /// HSM state, `HSM_STARTED`, `HSM_STOPPED` or `HSM_START_PENDING`
+    status: u32,
+    /// Start address and opaque value of a pending hart_start
+    start: (u32, u32),
+    /// Deadline set through the TIME extension, STIP is raised once mtime reaches it
+    timer: Option<u64>,
+    /// An IPI was sent and SSIP is still to be raised
+    ipi: bool,
+    /// A remote FENCE.I was requested
+    fence_i: bool,
+}
+
+/// What a hart has to do for the SBI before its next instruction, see `Sbi::poll`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HartEvents {
+    /// The timer deadline passed, raise STIP
+    pub timer: bool,
+    /// Raise SSIP
+    pub ipi: bool,
+    /// Drop decoded and compiled code
+    pub fence_i: bool,
+    /// Enter S-mode at the address with a1 set to the opaque value, started through HSM
+    pub start: Option<(u32, u32)>,
+    /// Stopped through HSM, the hart idles until it is started
+    pub stopped: bool,
+    /// A system reset was requested, or every hart is stopped and none can be started again
+    pub halt: bool,
+}
+
+/// Supervisor binary interface implemented directly in the emulator, standing in for the
+/// machine mode firmware when a kernel is started in S-mode. One `Sbi` serves every hart of the
+/// machine, the harts share it and poll it for what other harts asked of them.
+#[derive(Debug, Clone)]
+pub struct Sbi {
+    /// Indexed by hartid
+    harts: Vec<HartState>,
+    console: Vec<u8>,
+    /// Also write console output to the host's stdout
+    echo: bool,
+    input: VecDeque<u8>,
+    reset: Option<(ResetType, u32)>,
+}
+
+impl Sbi {
+    /// An SBI for `num_harts` harts, all of them started
+    pub fn new(num_harts: u32) -> Self {
+        Sbi {
+            harts: vec![HartState::default(); num_harts.max(1) as usize],
+            console: Vec::new(),
+            echo: false,
+            input: VecDeque::new(),
+            reset: None,
+        }
+    }
+
+    /// An SBI whose console output also goes to the host's stdout
+    pub fn stdout(num_harts: u32) -> Self {
+        Sbi {
+            echo: true,
+            ..Sbi::new(num_harts)
+        }
+    }
+
+    /// Start over with `hartid` running and every other hart stopped, waiting for hart_start
+    pub fn boot(&mut self, hartid: u32) {
+        for (id, hart) in self.harts.iter_mut().enumerate() {
+            *hart = HartState {
+                status: if id as u32 == hartid {
+                    HSM_STARTED
+                } else {
+                    HSM_STOPPED
+                },
+                ..HartState::default()
+            };
+        }
+        self.reset = None;
+    }
+
+    /// Queue bytes for the legacy console_getchar call
+    pub fn push_input(&mut self, data: &[u8]) {
+        self.input.extend(data);
+    }
+
+    /// Everything written through the legacy console since the last call
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.console)
+    }
+
+    /// The reset type and reason of the last system reset request, if there was one
+    pub fn get_reset_request(&self) -> Option<(ResetType, u32)> {
+        self.reset
+    }
+
+    pub fn get_timer(&self, hartid: u32) -> Option<u64> {
+        self.harts[hartid as usize].timer
+    }
+
+    /// HSM state of a hart, `HSM_STARTED`, `HSM_STOPPED` or `HSM_START_PENDING`
+    pub fn get_hart_status(&self, hartid: u32) -> u32 {
+        self.harts[hartid as usize].status
+    }
+
+    /// Collect what `hartid` has to do before its next instruction. The timer is checked against
+    /// `mtime` and disarmed once its deadline passed, so STIP only needs to be raised once. A
+    /// pending start is taken, the hart counts as started from here.
+    pub fn poll(&mut self, hartid: u32, mtime: Option<u64>) -> HartEvents {
+        let halt = self.reset.is_some() || self.harts.iter().all(|hart| hart.status == HSM_STOPPED);
+        let hart = &mut self.harts[hartid as usize];
+        let start = if hart.status == HSM_START_PENDING {
+            hart.status = HSM_STARTED;
+            Some(hart.start)
+        } else {
+            None
+        };
+        let timer = match (hart.timer, mtime) {
+            (Some(deadline), Some(mtime)) if mtime >= deadline => {
+                hart.timer = None;
+                true
+            }
+            _ => false,
+        };
+        HartEvents {
+            timer,
+            ipi: std::mem::take(&mut hart.ipi),
+            fence_i: std::mem::take(&mut hart.fence_i),
+            start,
+            stopped: hart.status == HSM_STOPPED,
+            halt,
+        }
+    }
+
+    /// Handle an ecall from S-mode on `cpu`. The extension is in a7, the function in a6 and the
+    /// arguments in a0-a5. Returns `Halt` when the guest reset the system.
+    pub fn call(&mut self, cpu: &mut CPU) -> CPUStatus {
+        let regs = *cpu.get_registers();
+        let (eid, fid) = (regs[17], regs[16]);
+        let args = [regs[10], regs[11], regs[12], regs[13], regs[14], regs[15]];
+
+        if eid <= LEGACY_SHUTDOWN {
+            let (ret, status) = self.legacy_call(cpu, eid, &args);
+            cpu.get_registers()[10] = ret as u32;
+            return status;
+        }
+
+        let (ret, status) = match eid {
+            EXT_BASE => (self.base(cpu, fid, &args), CPUStatus::Continue),
+            EXT_TIME => (self.time(cpu, fid, &args), CPUStatus::Continue),
+            EXT_IPI => (self.ipi(fid, &args), CPUStatus::Continue),
+            EXT_RFENCE => (self.rfence(fid, &args), CPUStatus::Continue),
+            EXT_HSM => self.hsm(cpu, fid, &args),
+            EXT_SRST => self.srst(fid, &args),
+            _ => (SbiRet::error(SBI_ERR_NOT_SUPPORTED), CPUStatus::Continue),
+        };
+        cpu.get_registers()[10] = ret.error as u32;
+        cpu.get_registers()[11] = ret.value;
+        status
+    }
+
+    fn is_supported(eid: u32) -> bool {
+        matches!(
+            eid,
+            EXT_BASE | EXT_TIME | EXT_IPI | EXT_RFENCE | EXT_HSM | EXT_SRST | LEGACY_SET_TIMER
+                ..=LEGACY_SHUTDOWN
+        )
+    }
+
+    fn base(&mut self, cpu: &mut CPU, fid: u32, args: &[u32; 6]) -> SbiRet {
+        match fid {
+            0 => SbiRet::success(SPEC_VERSION),
+            1 => SbiRet::success(IMPL_ID),
+            2 => SbiRet::success(IMPL_VERSION),
+            3 => SbiRet::success(Sbi::is_supported(args[0]) as u32),
+            4 => SbiRet::success(cpu.read_csr(csr::MVENDORID)),
+            5 => SbiRet::success(cpu.read_csr(csr::MARCHID)),
+            6 => SbiRet::success(cpu.read_csr(csr::MIMPID)),
+            _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED),
+        }
+    }
+
+    fn set_timer(&mut self, cpu: &mut CPU, deadline: u64) {
+        self.harts[cpu.get_hartid() as usize].timer = Some(deadline);
+        let mip = cpu.read_csr(csr::MIP);
+        cpu.get_csrs().write(csr::MIP, mip & !csr::MIP_STIP);
+    }
+
+    fn time(&mut self, cpu: &mut CPU, fid: u32, args: &[u32; 6]) -> SbiRet {
+        match fid {
+            // RV32 passes the 64-bit deadline in a0 (low) and a1 (high)
+            0 => {
+                self.set_timer(cpu, args[0] as u64 | (args[1] as u64) << 32);
+                SbiRet::success(0)
+            }
+            _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED),
+        }
+    }
+
+    /// The harts a hart mask selects, or `None` if it names harts which don't exist
+    fn selected_harts(&self, hart_mask: u32, hart_mask_base: u32) -> Option<Vec<usize>> {
+        if hart_mask_base == HART_MASK_ALL {
+            return Some((0..self.harts.len()).collect());
+        }
+        (0..32)
+            .filter(|bit| hart_mask & (1 << bit) != 0)
+            .map(|bit| {
+                let hartid = hart_mask_base.checked_add(bit)? as usize;
+                Some(hartid).filter(|&hartid| hartid < self.harts.len())
+            })
+            .collect()
+    }
+
+    /// Set the IPI or remote FENCE.I flag of every hart selected by a hart mask, the harts act on
+    /// it when they next poll
+    fn signal(&mut self, hart_mask: u32, hart_mask_base: u32, fence_i: bool) -> SbiRet {
+        let selected = match self.selected_harts(hart_mask, hart_mask_base) {
+            Some(selected) => selected,
+            None => return SbiRet::error(SBI_ERR_INVALID_PARAM),
+        };
+        for hartid in selected {
+            let hart = &mut self.harts[hartid];
+            if fence_i {
+                hart.fence_i = true;
+            } else {
+                hart.ipi = true;
+            }
+        }
+        SbiRet::success(0)
+    }
+
+    fn ipi(&mut self, fid: u32, args: &[u32; 6]) -> SbiRet {
+        match fid {
+            0 => self.signal(args[0], args[1], false),
+            _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED),
+        }
+    }
+
+    fn rfence(&mut self, fid: u32, args: &[u32; 6]) -> SbiRet {
+        match fid {
+            0 => self.signal(args[0], args[1], true),
+            // There are no TLBs to flush, SFENCE.VMA is a no-op already
+            1 | 2 => match self.selected_harts(args[0], args[1]) {
+                Some(_) => SbiRet::success(0),
+                None => SbiRet::error(SBI_ERR_INVALID_PARAM),
+            },
+            // The hypervisor fences need the H extension
+            _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED),
+        }
+    }
+
+    fn hsm(&mut self, cpu: &mut CPU, fid: u32, args: &[u32; 6]) -> (SbiRet, CPUStatus) {
+        let target = self.harts.get_mut(args[0] as usize);
+        let ret = match (fid, target) {
+            // hart_start, the hart enters S-mode at args[1] with a1 = args[2] when it next polls
+            (0, Some(hart)) if hart.status == HSM_STOPPED => {
+                hart.status = HSM_START_PENDING;
+                hart.start = (args[1], args[2]);
+                SbiRet::success(0)
+            }
+            (0, Some(_)) => SbiRet::error(SBI_ERR_ALREADY_AVAILABLE),
+            (0, None) => SbiRet::error(SBI_ERR_INVALID_PARAM),
+            // hart_stop, the hart idles from its next step on
+            (1, _) => {
+                self.harts[cpu.get_hartid() as usize].status = HSM_STOPPED;
+                SbiRet::success(0)
+            }
+            // hart_get_status
+            (2, Some(hart)) => SbiRet::success(hart.status),
+            (2, None) => SbiRet::error(SBI_ERR_INVALID_PARAM),
+            // hart_suspend, only the retentive default suspend is supported
+            (3, _) if args[0] == HSM_SUSPEND_RETENTIVE => {
+                cpu.wait_for_interrupt();
+                SbiRet::success(0)
+            }
+            _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED),
+        };
+        (ret, CPUStatus::Continue)
+    }
+
+    fn srst(&mut self, fid: u32, args: &[u32; 6]) -> (SbiRet, CPUStatus) {
+        if fid != 0 {
+            return (SbiRet::error(SBI_ERR_NOT_SUPPORTED), CPUStatus::Continue);
+        }
+        let reset_type = match args[0] {
+            0 => ResetType::Shutdown,
+            1 => ResetType::ColdReboot,
+            2 => ResetType::WarmReboot,
+            _ => return (SbiRet::error(SBI_ERR_INVALID_PARAM), CPUStatus::Continue),
+        };
+        // Reasons between system failure and the SBI implementation specific ones are reserved,
+        // the vendor specific ones follow those
+        if args[1] > 1 && args[1] < 0xE000_0000 {
+            return (SbiRet::error(SBI_ERR_INVALID_PARAM), CPUStatus::Continue);
+        }
+        // Rebooting is left to whoever runs the emulator, the request is recorded for them
+        self.reset = Some((reset_type, args[1]));
+        (SbiRet::success(0), CPUStatus::Halt)
+    }
+
+    fn legacy_call(&mut self, cpu: &mut CPU, eid: u32, args: &[u32; 6]) -> (i32, CPUStatus) {
+        match eid {
+            LEGACY_SET_TIMER => {
+                self.set_timer(cpu, args[0] as u64 | (args[1] as u64) << 32);
+                (SBI_SUCCESS, CPUStatus::Continue)
+            }
+            LEGACY_CONSOLE_PUTCHAR => {
+                let byte = args[0] as u8;
+                self.console.push(byte);
+                if self.echo {
+                    let mut stdout = io::stdout();
+                    let _ = stdout.write_all(&[byte]);
+                    let _ = stdout.flush();
+                }
+                (SBI_SUCCESS, CPUStatus::Continue)
+            }
+            LEGACY_CONSOLE_GETCHAR => match self.input.pop_front() {
+                Some(byte) => (byte as i32, CPUStatus::Continue),
+                None => (SBI_ERR_FAILED, CPUStatus::Continue),
+            },
+            LEGACY_CLEAR_IPI => {
+                self.harts[cpu.get_hartid() as usize].ipi = false;
+                let mip = cpu.read_csr(csr::MIP);
+                cpu.get_csrs().write(csr::MIP, mip & !csr::MIP_SSIP);
+                (SBI_SUCCESS, CPUStatus::Continue)
+            }
+            // send_ipi and the remote fences pass a pointer to the hart mask, null meaning all harts
+            LEGACY_SEND_IPI..=LEGACY_REMOTE_SFENCE_VMA_ASID => {
+                let mask = if args[0] == 0 {
+                    Some(u32::MAX)
+                } else {
+                    cpu.get_memory().read_word(args[0]).ok()
+                };
+                let mask = match mask {
+                    // Bits of harts which don't exist are ignored
+                    Some(mask) => {
+                        mask & (u32::MAX >> 32u32.saturating_sub(self.harts.len() as u32))
+                    }
+                    None => return (SBI_ERR_INVALID_PARAM, CPUStatus::Continue),
+                };
+                match eid {
+                    LEGACY_SEND_IPI => self.signal(mask, 0, false),
+                    LEGACY_REMOTE_FENCE_I => self.signal(mask, 0, true),
+                    _ => SbiRet::success(0),
+                };
+                (SBI_SUCCESS, CPUStatus::Continue)
+            }
+            LEGACY_SHUTDOWN => {
+                self.reset = Some((ResetType::Shutdown, 0));
+                (SBI_SUCCESS, CPUStatus::Halt)
+            }
+            _ => (SBI_ERR_NOT_SUPPORTED, CPUStatus::Continue),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ecall(sbi: &mut Sbi, cpu: &mut CPU, eid: u32, fid: u32, args: &[u32]) -> CPUStatus {
+        let regs = cpu.get_registers();
+        regs[17] = eid;
+        regs[16] = fid;
+        for (index, arg) in args.iter().enumerate() {
+            regs[10 + index] = *arg;
+        }
+        sbi.call(cpu)
+    }
+
+    #[test]
+    fn base_extension() {
+        let (mut sbi, mut cpu) = (Sbi::new(1), CPU::new(0, 1024));
+        ecall(&mut sbi, &mut cpu, EXT_BASE, 0, &[]);
+        assert_eq!(cpu.get_registers()[10], SBI_SUCCESS as u32);
+        assert_eq!(cpu.get_registers()[11], SPEC_VERSION);
+
+        ecall(&mut sbi, &mut cpu, EXT_BASE, 3, &[EXT_SRST]);
+        assert_eq!(cpu.get_registers()[11], 1);
+        ecall(&mut sbi, &mut cpu, EXT_BASE, 3, &[0x4442_4E43]);
+        assert_eq!(cpu.get_registers()[11], 0);
+
+        ecall(&mut sbi, &mut cpu, 0x0A00_0000, 0, &[]);
+        assert_eq!(cpu.get_registers()[10], SBI_ERR_NOT_SUPPORTED as u32);
+    }
+
+    #[test]
+    fn timer_and_ipi() {
+        let (mut sbi, mut cpu) = (Sbi::new(1), CPU::new(0, 1024));
+        cpu.get_csrs().write(csr::MIP, csr::MIP_STIP);
+        ecall(&mut sbi, &mut cpu, EXT_TIME, 0, &[0x10, 0x1]);
+        assert_eq!(sbi.get_timer(0), Some(0x1_0000_0010));
+        assert_eq!(cpu.read_csr(csr::MIP) & csr::MIP_STIP, 0);
+        assert!(!sbi.poll(0, Some(0x1_0000_000F)).timer);
+        assert!(sbi.poll(0, Some(0x1_0000_0010)).timer);
+        assert!(!sbi.poll(0, Some(0x1_0000_0011)).timer);
+
+        ecall(&mut sbi, &mut cpu, EXT_IPI, 0, &[0b10, 0]);
+        assert_eq!(cpu.get_registers()[10], SBI_ERR_INVALID_PARAM as u32);
+        ecall(&mut sbi, &mut cpu, EXT_IPI, 0, &[0b1, 0]);
+        assert!(sbi.poll(0, None).ipi);
+        assert!(!sbi.poll(0, None).ipi);
+        ecall(&mut sbi, &mut cpu, EXT_IPI, 0, &[0b1, 0]);
+        ecall(&mut sbi, &mut cpu, LEGACY_CLEAR_IPI, 0, &[]);
+        assert!(!sbi.poll(0, None).ipi);
+    }
+
+    #[test]
+    fn ipis_and_fences_reach_other_harts() {
+        let (mut sbi, mut cpu) = (Sbi::new(3), CPU::new(0, 1024));
+        ecall(&mut sbi, &mut cpu, EXT_IPI, 0, &[0b11, 1]);
+        assert_eq!(cpu.get_registers()[10], SBI_SUCCESS as u32);
+        ecall(&mut sbi, &mut cpu, EXT_IPI, 0, &[0b100, 1]);
+        assert_eq!(cpu.get_registers()[10], SBI_ERR_INVALID_PARAM as u32);
+        ecall(&mut sbi, &mut cpu, EXT_RFENCE, 0, &[0, HART_MASK_ALL]);
+
+        let events: Vec<HartEvents> = (0..3).map(|hartid| sbi.poll(hartid, None)).collect();
+        assert_eq!(
+            events.iter().map(|e| e.ipi).collect::<Vec<_>>(),
+            [false, true, true]
+        );
+        assert!(events.iter().all(|e| e.fence_i));
+    }
+
+    #[test]
+    fn hart_state_management() {
+        let (mut sbi, mut cpu) = (Sbi::new(2), CPU::new(0, 1024));
+        sbi.boot(0);
+        ecall(&mut sbi, &mut cpu, EXT_HSM, 2, &[1]);
+        assert_eq!(cpu.get_registers()[11], HSM_STOPPED);
+        ecall(&mut sbi, &mut cpu, EXT_HSM, 2, &[2]);
+        assert_eq!(cpu.get_registers()[10], SBI_ERR_INVALID_PARAM as u32);
+        assert!(sbi.poll(1, None).stopped);
+
+        ecall(&mut sbi, &mut cpu, EXT_HSM, 0, &[1, 0x8000_0000, 7]);
+        assert_eq!(cpu.get_registers()[10], SBI_SUCCESS as u32);
+        assert_eq!(sbi.get_hart_status(1), HSM_START_PENDING);
+        ecall(&mut sbi, &mut cpu, EXT_HSM, 0, &[1, 0x8000_0000, 7]);
+        assert_eq!(cpu.get_registers()[10], SBI_ERR_ALREADY_AVAILABLE as u32);
+        let events = sbi.poll(1, None);
+        assert_eq!(events.start, Some((0x8000_0000, 7)));
+        assert!(!events.stopped);
+        assert_eq!(sbi.get_hart_status(1), HSM_STARTED);
+
+        // Once the last running hart stops nothing can start the others again
+        ecall(&mut sbi, &mut cpu, EXT_HSM, 1, &[]);
+        assert!(!sbi.poll(0, None).halt);
+        sbi.harts[1].status = HSM_STOPPED;
+        assert!(sbi.poll(0, None).halt);
+    }
+
+    #[test]
+    fn legacy_console() {
+        let (mut sbi, mut cpu) = (Sbi::new(1), CPU::new(0, 1024));
+        for byte in b"ok" {
+            ecall(
+                &mut sbi,
+                &mut cpu,
+                LEGACY_CONSOLE_PUTCHAR,
+                0,
+                &[*byte as u32],
+            );
+        }
+        assert_eq!(sbi.take_output(), b"ok");
+
+        sbi.push_input(b"x");
+        ecall(&mut sbi, &mut cpu, LEGACY_CONSOLE_GETCHAR, 0, &[]);
+        assert_eq!(cpu.get_registers()[10], b'x' as u32);
+        ecall(&mut sbi, &mut cpu, LEGACY_CONSOLE_GETCHAR, 0, &[]);
+        assert_eq!(cpu.get_registers()[10], SBI_ERR_FAILED as u32);
+    }
+
+    #[test]
+    fn system_reset_halts() {
+        let (mut sbi, mut cpu) = (Sbi::new(1), CPU::new(0, 1024));
+        let status = ecall(&mut sbi, &mut cpu, EXT_SRST, 0, &[3, 0]);
+        assert_eq!(status, CPUStatus::Continue);
+        assert_eq!(cpu.get_registers()[10], SBI_ERR_INVALID_PARAM as u32);
+
+        let status = ecall(&mut sbi, &mut cpu, EXT_SRST, 0, &[0, 2]);
+        assert_eq!(status, CPUStatus::Continue);
+        assert_eq!(cpu.get_registers()[10], SBI_ERR_INVALID_PARAM as u32);
+
+        let status = ecall(&mut sbi, &mut cpu, EXT_SRST, 0, &[1, 1]);
+        assert_eq!(status, CPUStatus::Halt);
+        assert_eq!(sbi.get_reset_request(), Some((ResetType::ColdReboot, 1)));
+
+        for &reason in [0xE000_0000, 0xEFFF_FFFF, 0xF000_0000].iter() {
+            let (mut sbi, mut cpu) = (Sbi::new(1), CPU::new(0, 1024));
+            let status = ecall(&mut sbi, &mut cpu, EXT_SRST, 0, &[0, reason]);
+            assert_eq!(status, CPUStatus::Halt, "{:#x}", reason);
+            assert_eq!(sbi.get_reset_request(), Some((ResetType::Shutdown, reason)));
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! Hart snapshots, see `CPU::snapshot`. A snapshot can be written to a file and read back, in a
+//! little-endian format private to this crate: a magic number and version, then the hart state,
+//! RAM segments, CLINT and PLIC, with every variable-length part prefixed by its length.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use super::csr::{CSRFile, PrivilegeMode};
+use super::devices::clint::Clint;
+use super::devices::plic::PlicState;
+use super::registers::RV32Registers;
+
+const MAGIC: &[u8; 8] = b"RV32SNAP";
+const VERSION: u32 = 1;
+
+/// Registers, CSRs, RAM contents and interrupt controller state of a hart, see `CPU::snapshot`
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub(crate) registers: RV32Registers,
+    pub(crate) csrs: CSRFile,
+    pub(crate) privilege: PrivilegeMode,
+    pub(crate) waiting: bool,
+    pub(crate) memory: Vec<(u32, Vec<u8>)>,
+    pub(crate) clint: Option<Clint>,
+    pub(crate) plic: Option<PlicState>,
+}
+
+impl Snapshot {
+    pub fn get_pc(&self) -> u32 {
+        self.registers.get_pc()
+    }
+
+    /// Instructions retired since reset when the snapshot was taken
+    pub fn get_retired(&self) -> u64 {
+        self.csrs.get_retired()
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        put_u32(out, VERSION)?;
+        self.registers.write_to(out)?;
+        self.csrs.write_to(out)?;
+        put_u32(out, self.privilege as u32)?;
+        put_bool(out, self.waiting)?;
+        put_u32(out, self.memory.len() as u32)?;
+        for (base, data) in &self.memory {
+            put_u32(out, *base)?;
+            put_bytes(out, data)?;
+        }
+        put_bool(out, self.clint.is_some())?;
+        if let Some(clint) = &self.clint {
+            clint.write_to(out)?;
+        }
+        put_bool(out, self.plic.is_some())?;
+        if let Some(plic) = &self.plic {
+            plic.write_to(out)?;
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(input: &mut R) -> io::Result<Snapshot> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC || get_u32(input)? != VERSION {
+            return Err(invalid("not a snapshot"));
+        }
+        let registers = RV32Registers::read_from(input)?;
+        let csrs = CSRFile::read_from(input)?;
+        let privilege = match get_u32(input)? {
+            0 => PrivilegeMode::User,
+            1 => PrivilegeMode::Supervisor,
+            3 => PrivilegeMode::Machine,
+            _ => return Err(invalid("invalid privilege mode")),
+        };
+        let waiting = get_bool(input)?;
+        let mut memory = Vec::new();
+        for _ in 0..get_u32(input)? {
+            let base = get_u32(input)?;
+            memory.push((base, get_bytes(input)?));
+        }
+        let clint = if get_bool(input)? {
+            Some(Clint::read_from(input)?)
+        } else {
+            None
+        };
+        let plic = if get_bool(input)? {
+            Some(PlicState::read_from(input)?)
+        } else {
+            None
+        };
+        Ok(Snapshot {
+            registers,
+            csrs,
+            privilege,
+            waiting,
+            memory,
+            clint,
+            plic,
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write(&mut out)?;
+        out.flush()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Snapshot> {
+        Snapshot::read(&mut BufReader::new(File::open(path)?))
+    }
+}
+
+pub(crate) fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub(crate) fn put_u32<W: Write>(out: &mut W, val: u32) -> io::Result<()> {
+    out.write_all(&val.to_le_bytes())
+}
+
+pub(crate) fn put_u64<W: Write>(out: &mut W, val: u64) -> io::Result<()> {
+    out.write_all(&val.to_le_bytes())
+}
+
+pub(crate) fn put_bool<W: Write>(out: &mut W, val: bool) -> io::Result<()> {
+    out.write_all(&[val as u8])
+}
+
+pub(crate) fn put_bytes<W: Write>(out: &mut W, data: &[u8]) -> io::Result<()> {
+    put_u32(out, data.len() as u32)?;
+    out.write_all(data)
+}
+
+/// A length-prefixed list of words
+pub(crate) fn put_u32s<W: Write>(out: &mut W, vals: &[u32]) -> io::Result<()> {
+    put_u32(out, vals.len() as u32)?;
+    vals.iter().try_for_each(|val| put_u32(out, *val))
+}
+
+pub(crate) fn put_u64s<W: Write>(out: &mut W, vals: &[u64]) -> io::Result<()> {
+    put_u32(out, vals.len() as u32)?;
+    vals.iter().try_for_each(|val| put_u64(out, *val))
+}
+
+pub(crate) fn get_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub(crate) fn get_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub(crate) fn get_bool<R: Read>(input: &mut R) -> io::Result<bool> {
+    match get_u8(input)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(invalid("invalid flag")),
+    }
+}
+
+fn get_u8<R: Read>(input: &mut R) -> io::Result<u8> {
+    let mut byte = [0];
+    input.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+/// Read bytes as written by `put_bytes`. The buffer grows as data arrives, so a corrupt length
+/// can't make it allocate more than the input holds.
+pub(crate) fn get_bytes<R: Read>(input: &mut R) -> io::Result<Vec<u8>> {
+    let len = get_u32(input)? as u64;
+    let mut data = Vec::new();
+    input.take(len).read_to_end(&mut data)?;
+    if data.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(data)
+}
+
+pub(crate) fn get_u32s<R: Read>(input: &mut R) -> io::Result<Vec<u32>> {
+    let len = get_u32(input)?;
+    (0..len).map(|_| get_u32(input)).collect()
+}
+
+pub(crate) fn get_u64s<R: Read>(input: &mut R) -> io::Result<Vec<u64>> {
+    let len = get_u32(input)?;
+    (0..len).map(|_| get_u64(input)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_or_foreign_input_is_rejected() {
+        let snapshot = Snapshot {
+            registers: RV32Registers::new(),
+            csrs: CSRFile::new(0),
+            privilege: PrivilegeMode::Supervisor,
+            waiting: true,
+            memory: vec![(0x8000_0000, vec![1, 2, 3])],
+            clint: Some(Clint::new(2)),
+            plic: None,
+        };
+        let mut bytes = Vec::new();
+        snapshot.write(&mut bytes).unwrap();
+
+        let read = Snapshot::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read.privilege, PrivilegeMode::Supervisor);
+        assert_eq!(read.memory, snapshot.memory);
+        assert_eq!(read.clint.unwrap().get_mtimecmp(1), u64::MAX);
+
+        let truncated = &bytes[..bytes.len() - 1];
+        let error = Snapshot::read(&mut &truncated[..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        let error = Snapshot::read(&mut &b"RV32SNAQ\x01\0\0\0"[..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,225 @@
+//! Code symbols of a guest binary, for naming the functions addresses belong to
+
+use super::dwarf::{self, LineTable};
+
+use std::fmt;
+use xmas_elf::sections::{SectionData, ShType, SHF_EXECINSTR, SHN_LORESERVE};
+use xmas_elf::symbol_table::{Entry, Type};
+use xmas_elf::ElfFile;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u32,
+    /// 0 when unknown, the symbol then reaches up to the next one
+    pub size: u32,
+}
+
+impl Symbol {
+    pub fn contains(&self, addr: u32) -> bool {
+        addr >= self.addr && (self.size == 0 || addr - self.addr < self.size)
+    }
+}
+
+/// Symbols by address
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    /// Where several symbols share an address, the first one is kept
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|symbol| symbol.addr);
+        symbols.dedup_by_key(|symbol| symbol.addr);
+        SymbolTable { symbols }
+    }
+
+    /// Functions from the DWARF debug information of `elf`, then functions and labels in
+    /// executable sections from its symbol table. Earlier ones win at the same address.
+    pub fn from_elf(elf: &ElfFile) -> Self {
+        // Unreadable debug information leaves the symbol table
+        let mut found: Vec<(u8, Symbol)> = dwarf::functions(elf)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|symbol| (0, symbol))
+            .collect();
+        // xmas-elf doesn't parse every kind of section, so only symbol tables are looked at
+        let symtabs = elf
+            .section_iter()
+            .filter(|sect| sect.get_type() == Ok(ShType::SymTab));
+        for sect in symtabs {
+            let entries = match sect.get_data(elf) {
+                Ok(SectionData::SymbolTable32(entries)) => entries,
+                _ => continue,
+            };
+            for entry in entries {
+                let rank = match entry.get_type() {
+                    Ok(Type::Func) => 1,
+                    Ok(Type::NoType) => 2,
+                    _ => continue,
+                };
+                // Undefined, absolute and common symbols have no section
+                let shndx = entry.shndx();
+                let in_code = (shndx != 0 && shndx < SHN_LORESERVE)
+                    && elf
+                        .section_header(shndx)
+                        .map(|header| header.flags() & SHF_EXECINSTR != 0)
+                        .unwrap_or(false);
+                let name = entry.get_name(elf).unwrap_or("");
+                // Local labels and the mapping symbols marking code and data aren't functions
+                if !in_code || name.is_empty() || name.starts_with(".L") || name.starts_with('$') {
+                    continue;
+                }
+                found.push((
+                    rank,
+                    Symbol {
+                        name: name.to_string(),
+                        addr: entry.value() as u32,
+                        size: entry.size() as u32,
+                    },
+                ));
+            }
+        }
+        found.sort_by_key(|(rank, symbol)| (symbol.addr, *rank));
+        SymbolTable::new(found.into_iter().map(|(_, symbol)| symbol).collect())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    /// The symbol `addr` is in
+    pub fn lookup(&self, addr: u32) -> Option<&Symbol> {
+        let index = self.symbols.partition_point(|symbol| symbol.addr <= addr);
+        let symbol = self.symbols.get(index.checked_sub(1)?)?;
+        if symbol.contains(addr) {
+            Some(symbol)
+        } else {
+            None
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    /// The function `addr` is in and the source line it came from, as far as these symbols and
+    /// `lines` tell
+    pub fn symbolize(&self, lines: &LineTable, addr: u32) -> Symbolized {
+        Symbolized {
+            addr,
+            function: self
+                .lookup(addr)
+                .map(|symbol| (symbol.name.clone(), addr - symbol.addr)),
+            line: lines
+                .lookup(addr)
+                .map(|location| (location.file.to_string(), location.line)),
+        }
+    }
+}
+
+/// An address with the function it is in and the source line it came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbolized {
+    pub addr: u32,
+    /// Function name and offset into it
+    pub function: Option<(String, u32)>,
+    /// Source file and line
+    pub line: Option<(String, u32)>,
+}
+
+impl fmt::Display for Symbolized {
+    /// Like `0x80000014 <main+0x4> at src/main.c:12`, leaving out what isn't known
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#010x}", self.addr)?;
+        if let Some((name, offset)) = &self.function {
+            write!(f, " <{}+{:#x}>", name, offset)?;
+        }
+        if let Some((file, line)) = &self.line {
+            write!(f, " at {}:{}", file, line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(name: &str, addr: u32, size: u32) -> Symbol {
+        Symbol {
+            name: name.to_string(),
+            addr,
+            size,
+        }
+    }
+
+    #[test]
+    fn lookup_by_address() {
+        let symbols = SymbolTable::new(vec![
+            symbol("main", 0x100, 0x20),
+            symbol("_start", 0x80, 0),
+            symbol("helper", 0x200, 0x10),
+            symbol("alias", 0x200, 0x10),
+        ]);
+
+        assert_eq!(symbols.lookup(0x7c), None);
+        assert_eq!(symbols.lookup(0xfc).unwrap().name, "_start");
+        assert_eq!(symbols.lookup(0x11c).unwrap().name, "main");
+        assert_eq!(symbols.lookup(0x120), None);
+        assert_eq!(symbols.lookup(0x204).unwrap().name, "helper");
+        assert_eq!(symbols.get("helper").unwrap().addr, 0x200);
+        assert!(symbols.get("alias").is_none());
+    }
+
+    #[test]
+    fn symbols_of_compliance_binary() {
+        let elf = std::fs::read("tests/rv32i-compliance/jal").unwrap();
+        let symbols = SymbolTable::from_elf(&ElfFile::new(&elf).unwrap());
+
+        let start = symbols.get("_start").unwrap();
+        assert_eq!(symbols.lookup(start.addr), Some(start));
+        assert!(symbols.iter().all(|symbol| !symbol.name.starts_with('$')));
+        assert_eq!(
+            symbols
+                .symbolize(&LineTable::default(), start.addr)
+                .to_string(),
+            format!("{:#010x} <_start+0x0>", start.addr)
+        );
+    }
+
+    #[test]
+    fn symbolize_with_debug_info() {
+        let elf = std::fs::read("tests/debug/crash").unwrap();
+        let elf = ElfFile::new(&elf).unwrap();
+        let symbols = SymbolTable::from_elf(&elf);
+        let lines = LineTable::from_elf(&elf).unwrap();
+
+        // DWARF names win over the mangled symbol table names
+        let fault = symbols.get("crash::checks::fault").unwrap();
+        assert_eq!(fault.addr, 0x8000_0060);
+        let symbolized = symbols.symbolize(&lines, 0x8000_0084);
+        assert_eq!(
+            symbolized.function,
+            Some(("crash::checks::fault".to_string(), 0x24))
+        );
+        let (file, line) = symbolized.line.clone().unwrap();
+        assert!(file.ends_with("tests/debug/crash.rs"));
+        assert_eq!(line, 42);
+        assert_eq!(
+            symbolized.to_string(),
+            format!("0x80000084 <crash::checks::fault+0x24> at {}:42", file)
+        );
+        // Symbol table entries fill in where there is no debug information
+        assert_eq!(symbols.lookup(0x8000_0004).unwrap().name, "_start");
+        assert_eq!(
+            symbols.symbolize(&lines, 0x7000_0000).to_string(),
+            "0x70000000"
+        );
+    }
+}
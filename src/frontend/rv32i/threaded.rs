@@ -0,0 +1,739 @@
+use super::cpu::CPU;
+use super::instructions::{DecodeError, ExecuteError, ExecuteResult, ExecuteStatus, Instruction};
+use super::mem::Mem;
+
+// Operands are extracted once at decode time. Immediates are already sign-extended and register
+// numbers are ready to index with, so handlers never look at the instruction word again.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RType {
+    pub rd: u8,
+    pub rs1: u8,
+    pub rs2: u8,
+}
+
+/// Register-immediate operations, loads and JALR. Shifts keep the shift amount in `imm`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IType {
+    pub rd: u8,
+    pub rs1: u8,
+    pub imm: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SType {
+    pub rs1: u8,
+    pub rs2: u8,
+    pub imm: u32,
+}
+
+/// Conditional branches, `imm` is the pc-relative target offset
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BType {
+    pub rs1: u8,
+    pub rs2: u8,
+    pub imm: u32,
+}
+
+/// LUI and AUIPC, `imm` already has the low 12 bits cleared
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UType {
+    pub rd: u8,
+    pub imm: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JType {
+    pub rd: u8,
+    pub imm: u32,
+}
+
+/// `src` is the source register for CSRRW/CSRRS/CSRRC and the zero-extended immediate for the
+/// I variants
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CsrType {
+    pub rd: u8,
+    pub src: u8,
+    pub csr: u16,
+}
+
+pub type Handler<T> = fn(&mut CPU, T) -> ExecuteResult<ExecuteStatus>;
+
+/// A decoded instruction: the function implementing it and its operands. Executing one is a jump
+/// on the operand format followed by an indirect call. These handlers are the only
+/// implementation of the instructions.
+#[derive(Debug, Clone, Copy)]
+pub enum ThreadedOp {
+    R(Handler<RType>, RType),
+    I(Handler<IType>, IType),
+    S(Handler<SType>, SType),
+    B(Handler<BType>, BType),
+    U(Handler<UType>, UType),
+    J(Handler<JType>, JType),
+    Csr(Handler<CsrType>, CsrType),
+    System(Handler<()>),
+}
+
+impl ThreadedOp {
+    pub fn decode(instr: u32) -> Result<Self, DecodeError> {
+        Ok(Instruction::decode(instr)?.into())
+    }
+
+    #[inline]
+    pub fn execute(self, cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
+        match self {
+            ThreadedOp::R(handler, operands) => handler(cpu, operands),
+            ThreadedOp::I(handler, operands) => handler(cpu, operands),
+            ThreadedOp::S(handler, operands) => handler(cpu, operands),
+            ThreadedOp::B(handler, operands) => handler(cpu, operands),
+            ThreadedOp::U(handler, operands) => handler(cpu, operands),
+            ThreadedOp::J(handler, operands) => handler(cpu, operands),
+            ThreadedOp::Csr(handler, operands) => handler(cpu, operands),
+            ThreadedOp::System(handler) => handler(cpu, ()),
+        }
+    }
+}
+
+/// An instruction and its handler, as cached by `BlockCache`
+#[derive(Debug, Clone, Copy)]
+pub struct Decoded {
+    pub instr: Instruction,
+    pub op: ThreadedOp,
+}
+
+impl Decoded {
+    pub fn decode(instr: u32) -> Result<Self, DecodeError> {
+        let instr = Instruction::decode(instr)?;
+        Ok(Decoded {
+            instr,
+            op: instr.into(),
+        })
+    }
+}
+
+fn r(handler: Handler<RType>, rd: u32, rs1: u32, rs2: u32) -> ThreadedOp {
+    // Writes to x0 are dropped, so an ALU operation targeting it does nothing at all
+    if rd == 0 {
+        return ThreadedOp::System(nop);
+    }
+    let operands = RType {
+        rd: rd as u8,
+        rs1: rs1 as u8,
+        rs2: rs2 as u8,
+    };
+    ThreadedOp::R(handler, operands)
+}
+
+fn i(handler: Handler<IType>, rd: u32, rs1: u32, imm: u32) -> ThreadedOp {
+    if rd == 0 {
+        return ThreadedOp::System(nop);
+    }
+    i_keep(handler, rd, rs1, imm)
+}
+
+/// An I-type operation with side effects besides writing rd, kept even when rd is x0
+fn i_keep(handler: Handler<IType>, rd: u32, rs1: u32, imm: u32) -> ThreadedOp {
+    let operands = IType {
+        rd: rd as u8,
+        rs1: rs1 as u8,
+        imm,
+    };
+    ThreadedOp::I(handler, operands)
+}
+
+fn s(handler: Handler<SType>, rs1: u32, rs2: u32, imm: u32) -> ThreadedOp {
+    let operands = SType {
+        rs1: rs1 as u8,
+        rs2: rs2 as u8,
+        imm,
+    };
+    ThreadedOp::S(handler, operands)
+}
+
+fn b(handler: Handler<BType>, rs1: u32, rs2: u32, imm: u32) -> ThreadedOp {
+    let operands = BType {
+        rs1: rs1 as u8,
+        rs2: rs2 as u8,
+        imm,
+    };
+    ThreadedOp::B(handler, operands)
+}
+
+fn u(handler: Handler<UType>, rd: u32, imm: u32) -> ThreadedOp {
+    if rd == 0 {
+        return ThreadedOp::System(nop);
+    }
+    ThreadedOp::U(handler, UType { rd: rd as u8, imm })
+}
+
+fn csr(handler: Handler<CsrType>, rd: u32, src: u32, csr: u32) -> ThreadedOp {
+    let operands = CsrType {
+        rd: rd as u8,
+        src: src as u8,
+        csr: csr as u16,
+    };
+    ThreadedOp::Csr(handler, operands)
+}
+
+impl From<Instruction> for ThreadedOp {
+    fn from(instr: Instruction) -> ThreadedOp {
+        match instr {
+            Instruction::LUI(rd, imm) => u(lui, rd, imm),
+            Instruction::AUIPC(rd, imm) => u(auipc, rd, imm),
+            Instruction::JAL(rd, imm) => ThreadedOp::J(jal, JType { rd: rd as u8, imm }),
+            Instruction::JALR(rd, rs1, imm) => i_keep(jalr, rd, rs1, imm),
+            Instruction::BEQ(rs1, rs2, imm) => b(beq, rs1, rs2, imm),
+            Instruction::BNE(rs1, rs2, imm) => b(bne, rs1, rs2, imm),
+            Instruction::BLT(rs1, rs2, imm) => b(blt, rs1, rs2, imm),
+            Instruction::BGE(rs1, rs2, imm) => b(bge, rs1, rs2, imm),
+            Instruction::BLTU(rs1, rs2, imm) => b(bltu, rs1, rs2, imm),
+            Instruction::BGEU(rs1, rs2, imm) => b(bgeu, rs1, rs2, imm),
+            // Loads still have to access memory when rd is x0, the access can fault
+            Instruction::LB(rd, rs1, imm) => i_keep(lb, rd, rs1, imm),
+            Instruction::LH(rd, rs1, imm) => i_keep(lh, rd, rs1, imm),
+            Instruction::LW(rd, rs1, imm) => i_keep(lw, rd, rs1, imm),
+            Instruction::LBU(rd, rs1, imm) => i_keep(lbu, rd, rs1, imm),
+            Instruction::LHU(rd, rs1, imm) => i_keep(lhu, rd, rs1, imm),
+            Instruction::SB(rs1, rs2, imm) => s(sb, rs1, rs2, imm),
+            Instruction::SH(rs1, rs2, imm) => s(sh, rs1, rs2, imm),
+            Instruction::SW(rs1, rs2, imm) => s(sw, rs1, rs2, imm),
+            Instruction::ADDI(rd, rs1, imm) => i(addi, rd, rs1, imm),
+            Instruction::SLTI(rd, rs1, imm) => i(slti, rd, rs1, imm),
+            Instruction::SLTIU(rd, rs1, imm) => i(sltiu, rd, rs1, imm),
+            Instruction::XORI(rd, rs1, imm) => i(xori, rd, rs1, imm),
+            Instruction::ORI(rd, rs1, imm) => i(ori, rd, rs1, imm),
+            Instruction::ANDI(rd, rs1, imm) => i(andi, rd, rs1, imm),
+            Instruction::SLLI(rd, rs1, shamt) => i(slli, rd, rs1, shamt),
+            Instruction::SRLI(rd, rs1, shamt) => i(srli, rd, rs1, shamt),
+            Instruction::SRAI(rd, rs1, shamt) => i(srai, rd, rs1, shamt),
+            Instruction::ADD(rd, rs1, rs2) => r(add, rd, rs1, rs2),
+            Instruction::SUB(rd, rs1, rs2) => r(sub, rd, rs1, rs2),
+            Instruction::SLL(rd, rs1, rs2) => r(sll, rd, rs1, rs2),
+            Instruction::SLT(rd, rs1, rs2) => r(slt, rd, rs1, rs2),
+            Instruction::SLTU(rd, rs1, rs2) => r(sltu, rd, rs1, rs2),
+            Instruction::XOR(rd, rs1, rs2) => r(xor, rd, rs1, rs2),
+            Instruction::SRL(rd, rs1, rs2) => r(srl, rd, rs1, rs2),
+            Instruction::SRA(rd, rs1, rs2) => r(sra, rd, rs1, rs2),
+            Instruction::OR(rd, rs1, rs2) => r(or, rd, rs1, rs2),
+            Instruction::AND(rd, rs1, rs2) => r(and, rd, rs1, rs2),
+            // Memory accesses are performed in program order, so there is nothing to order here
+            Instruction::FENCE(_, _) => ThreadedOp::System(nop),
+            Instruction::FENCE_I => ThreadedOp::System(fence_i),
+            Instruction::ECALL => ThreadedOp::System(ecall),
+            Instruction::EBREAK => ThreadedOp::System(ebreak),
+            Instruction::SRET => ThreadedOp::System(sret),
+            Instruction::MRET => ThreadedOp::System(mret),
+            Instruction::WFI => ThreadedOp::System(wfi),
+            Instruction::CSRRW(rd, rs1, addr) => csr(csrrw, rd, rs1, addr),
+            Instruction::CSRRS(rd, rs1, addr) => csr(csrrs, rd, rs1, addr),
+            Instruction::CSRRC(rd, rs1, addr) => csr(csrrc, rd, rs1, addr),
+            Instruction::CSRRWI(rd, uimm, addr) => csr(csrrwi, rd, uimm, addr),
+            Instruction::CSRRSI(rd, uimm, addr) => csr(csrrsi, rd, uimm, addr),
+            Instruction::CSRRCI(rd, uimm, addr) => csr(csrrci, rd, uimm, addr),
+        }
+    }
+}
+
+#[inline(always)]
+fn reg(cpu: &mut CPU, index: u8) -> u32 {
+    cpu.get_registers()[index as usize]
+}
+
+/// Write rd, unless it is x0
+#[inline(always)]
+fn set_reg(cpu: &mut CPU, index: u8, value: u32) {
+    if index != 0 {
+        cpu.get_registers()[index as usize] = value;
+    }
+}
+
+#[inline(always)]
+fn next(cpu: &mut CPU) -> ExecuteResult<ExecuteStatus> {
+    cpu.get_registers().increment_pc();
+    Ok(ExecuteStatus::CONTINUE)
+}
+
+#[inline(always)]
+fn branch(cpu: &mut CPU, taken: bool, offset: u32) -> ExecuteResult<ExecuteStatus> {
+    if taken {
+        cpu.get_registers().add_to_pc(offset);
+    } else {
+        cpu.get_registers().increment_pc();
+    }
+    Ok(ExecuteStatus::CONTINUE)
+}
+
+fn nop(cpu: &mut CPU, _: ()) -> ExecuteResult<ExecuteStatus> {
+    next(cpu)
+}
+
+fn lui(cpu: &mut CPU, op: UType) -> ExecuteResult<ExecuteStatus> {
+    set_reg(cpu, op.rd, op.imm);
+    next(cpu)
+}
+
+fn auipc(cpu: &mut CPU, op: UType) -> ExecuteResult<ExecuteStatus> {
+    let value = cpu.get_registers().get_pc().wrapping_add(op.imm);
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn jal(cpu: &mut CPU, op: JType) -> ExecuteResult<ExecuteStatus> {
+    let pc = cpu.get_registers().get_pc();
+    set_reg(cpu, op.rd, pc + 4);
+    if op.imm % 4 != 0 {
+        return Err(ExecuteError::MisalignedAddress);
+    }
+    cpu.get_registers().add_to_pc(op.imm);
+    Ok(ExecuteStatus::CONTINUE)
+}
+
+fn jalr(cpu: &mut CPU, op: IType) -> ExecuteResult<ExecuteStatus> {
+    // The target is computed before rd is written, rd and rs1 can be the same register
+    let target = reg(cpu, op.rs1).wrapping_add(op.imm) & 0xFFFF_FFFE;
+    let pc = cpu.get_registers().get_pc();
+    set_reg(cpu, op.rd, pc + 4);
+    if target % 4 != 0 {
+        return Err(ExecuteError::MisalignedAddress);
+    }
+    cpu.get_registers().set_pc(target);
+    Ok(ExecuteStatus::CONTINUE)
+}
+
+fn beq(cpu: &mut CPU, op: BType) -> ExecuteResult<ExecuteStatus> {
+    let taken = reg(cpu, op.rs1) == reg(cpu, op.rs2);
+    branch(cpu, taken, op.imm)
+}
+
+fn bne(cpu: &mut CPU, op: BType) -> ExecuteResult<ExecuteStatus> {
+    let taken = reg(cpu, op.rs1) != reg(cpu, op.rs2);
+    branch(cpu, taken, op.imm)
+}
+
+fn blt(cpu: &mut CPU, op: BType) -> ExecuteResult<ExecuteStatus> {
+    let taken = (reg(cpu, op.rs1) as i32) < (reg(cpu, op.rs2) as i32);
+    branch(cpu, taken, op.imm)
+}
+
+fn bge(cpu: &mut CPU, op: BType) -> ExecuteResult<ExecuteStatus> {
+    let taken = (reg(cpu, op.rs1) as i32) >= (reg(cpu, op.rs2) as i32);
+    branch(cpu, taken, op.imm)
+}
+
+fn bltu(cpu: &mut CPU, op: BType) -> ExecuteResult<ExecuteStatus> {
+    let taken = reg(cpu, op.rs1) < reg(cpu, op.rs2);
+    branch(cpu, taken, op.imm)
+}
+
+fn bgeu(cpu: &mut CPU, op: BType) -> ExecuteResult<ExecuteStatus> {
+    let taken = reg(cpu, op.rs1) >= reg(cpu, op.rs2);
+    branch(cpu, taken, op.imm)
+}
+
+fn lb(cpu: &mut CPU, op: IType) -> ExecuteResult<ExecuteStatus> {
+    let address = reg(cpu, op.rs1).wrapping_add(op.imm);
+    let value = cpu.data_memory().read_byte(address)? as i8 as u32;
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn lh(cpu: &mut CPU, op: IType) -> ExecuteResult<ExecuteStatus> {
+    let address = reg(cpu, op.rs1).wrapping_add(op.imm);
+    let value = cpu.data_memory().read_halfword(address)? as i16 as u32;
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn lw(cpu: &mut CPU, op: IType) -> ExecuteResult<ExecuteStatus> {
+    let address = reg(cpu, op.rs1).wrapping_add(op.imm);
+    let value = cpu.data_memory().read_word(address)?;
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn lbu(cpu: &mut CPU, op: IType) -> ExecuteResult<ExecuteStatus> {
+    let address = reg(cpu, op.rs1).wrapping_add(op.imm);
+    let value = cpu.data_memory().read_byte(address)? as u32;
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn lhu(cpu: &mut CPU, op: IType) -> ExecuteResult<ExecuteStatus> {
+    let address = reg(cpu, op.rs1).wrapping_add(op.imm);
+    let value = cpu.data_memory().read_halfword(address)? as u32;
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn sb(cpu: &mut CPU, op: SType) -> ExecuteResult<ExecuteStatus> {
+    let address = reg(cpu, op.rs1).wrapping_add(op.imm);
+    let value = reg(cpu, op.rs2) as u8;
+    cpu.data_memory().write_byte(address, value)?;
+    next(cpu)
+}
+
+fn sh(cpu: &mut CPU, op: SType) -> ExecuteResult<ExecuteStatus> {
+    let address = reg(cpu, op.rs1).wrapping_add(op.imm);
+    let value = reg(cpu, op.rs2) as u16;
+    cpu.data_memory().write_halfword(address, value)?;
+    next(cpu)
+}
+
+fn sw(cpu: &mut CPU, op: SType) -> ExecuteResult<ExecuteStatus> {
+    let address = reg(cpu, op.rs1).wrapping_add(op.imm);
+    let value = reg(cpu, op.rs2);
+    cpu.data_memory().write_word(address, value)?;
+    next(cpu)
+}
+
+fn addi(cpu: &mut CPU, op: IType) -> ExecuteResult<ExecuteStatus> {
+    let value = reg(cpu, op.rs1).wrapping_add(op.imm);
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn slti(cpu: &mut CPU, op: IType) -> ExecuteResult<ExecuteStatus> {
+    let value = ((reg(cpu, op.rs1) as i32) < (op.imm as i32)) as u32;
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn sltiu(cpu: &mut CPU, op: IType) -> ExecuteResult<ExecuteStatus> {
+    let value = (reg(cpu, op.rs1) < op.imm) as u32;
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn xori(cpu: &mut CPU, op: IType) -> ExecuteResult<ExecuteStatus> {
+    let value = reg(cpu, op.rs1) ^ op.imm;
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn ori(cpu: &mut CPU, op: IType) -> ExecuteResult<ExecuteStatus> {
+    let value = reg(cpu, op.rs1) | op.imm;
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn andi(cpu: &mut CPU, op: IType) -> ExecuteResult<ExecuteStatus> {
+    let value = reg(cpu, op.rs1) & op.imm;
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn slli(cpu: &mut CPU, op: IType) -> ExecuteResult<ExecuteStatus> {
+    let value = reg(cpu, op.rs1) << op.imm;
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn srli(cpu: &mut CPU, op: IType) -> ExecuteResult<ExecuteStatus> {
+    let value = reg(cpu, op.rs1) >> op.imm;
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn srai(cpu: &mut CPU, op: IType) -> ExecuteResult<ExecuteStatus> {
+    let value = ((reg(cpu, op.rs1) as i32) >> op.imm) as u32;
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn add(cpu: &mut CPU, op: RType) -> ExecuteResult<ExecuteStatus> {
+    let value = reg(cpu, op.rs1).wrapping_add(reg(cpu, op.rs2));
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn sub(cpu: &mut CPU, op: RType) -> ExecuteResult<ExecuteStatus> {
+    let value = reg(cpu, op.rs1).wrapping_sub(reg(cpu, op.rs2));
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn sll(cpu: &mut CPU, op: RType) -> ExecuteResult<ExecuteStatus> {
+    let value = reg(cpu, op.rs1) << (reg(cpu, op.rs2) & 0x1F);
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn slt(cpu: &mut CPU, op: RType) -> ExecuteResult<ExecuteStatus> {
+    let value = ((reg(cpu, op.rs1) as i32) < (reg(cpu, op.rs2) as i32)) as u32;
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn sltu(cpu: &mut CPU, op: RType) -> ExecuteResult<ExecuteStatus> {
+    let value = (reg(cpu, op.rs1) < reg(cpu, op.rs2)) as u32;
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn xor(cpu: &mut CPU, op: RType) -> ExecuteResult<ExecuteStatus> {
+    let value = reg(cpu, op.rs1) ^ reg(cpu, op.rs2);
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn srl(cpu: &mut CPU, op: RType) -> ExecuteResult<ExecuteStatus> {
+    let value = reg(cpu, op.rs1) >> (reg(cpu, op.rs2) & 0x1F);
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn sra(cpu: &mut CPU, op: RType) -> ExecuteResult<ExecuteStatus> {
+    let value = ((reg(cpu, op.rs1) as i32) >> (reg(cpu, op.rs2) & 0x1F)) as u32;
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn or(cpu: &mut CPU, op: RType) -> ExecuteResult<ExecuteStatus> {
+    let value = reg(cpu, op.rs1) | reg(cpu, op.rs2);
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn and(cpu: &mut CPU, op: RType) -> ExecuteResult<ExecuteStatus> {
+    let value = reg(cpu, op.rs1) & reg(cpu, op.rs2);
+    set_reg(cpu, op.rd, value);
+    next(cpu)
+}
+
+fn fence_i(cpu: &mut CPU, _: ()) -> ExecuteResult<ExecuteStatus> {
+    cpu.flush_block_cache();
+    next(cpu)
+}
+
+fn ecall(cpu: &mut CPU, _: ()) -> ExecuteResult<ExecuteStatus> {
+    cpu.get_registers().increment_pc();
+    Ok(ExecuteStatus::ECALL)
+}
+
+fn ebreak(cpu: &mut CPU, _: ()) -> ExecuteResult<ExecuteStatus> {
+    cpu.get_registers().increment_pc();
+    Ok(ExecuteStatus::EBREAK)
+}
+
+fn sret(cpu: &mut CPU, _: ()) -> ExecuteResult<ExecuteStatus> {
+    cpu.supervisor_trap_return();
+    Ok(ExecuteStatus::CONTINUE)
+}
+
+fn mret(cpu: &mut CPU, _: ()) -> ExecuteResult<ExecuteStatus> {
+    cpu.trap_return();
+    Ok(ExecuteStatus::CONTINUE)
+}
+
+fn wfi(cpu: &mut CPU, _: ()) -> ExecuteResult<ExecuteStatus> {
+    cpu.wait_for_interrupt();
+    next(cpu)
+}
+
+/// Shared body of the CSR instructions. CSRRS/CSRRC with x0 and the I variants with a zero
+/// immediate only read the CSR.
+#[inline(always)]
+fn csr_access<F>(
+    cpu: &mut CPU,
+    op: CsrType,
+    should_write: bool,
+    write: F,
+) -> ExecuteResult<ExecuteStatus>
+where
+    F: Fn(u32) -> u32,
+{
+    let csr = op.csr as u32;
+    let old = match cpu.access_csr(csr, should_write) {
+        Some(old) => old,
+        None => {
+            cpu.illegal_instruction();
+            return Ok(ExecuteStatus::CONTINUE);
+        }
+    };
+    if should_write {
+        cpu.get_csrs().write(csr, write(old));
+    }
+    set_reg(cpu, op.rd, old);
+    next(cpu)
+}
+
+fn csrrw(cpu: &mut CPU, op: CsrType) -> ExecuteResult<ExecuteStatus> {
+    let value = reg(cpu, op.src);
+    csr_access(cpu, op, true, |_| value)
+}
+
+fn csrrs(cpu: &mut CPU, op: CsrType) -> ExecuteResult<ExecuteStatus> {
+    let value = reg(cpu, op.src);
+    csr_access(cpu, op, op.src != 0, |old| old | value)
+}
+
+fn csrrc(cpu: &mut CPU, op: CsrType) -> ExecuteResult<ExecuteStatus> {
+    let value = reg(cpu, op.src);
+    csr_access(cpu, op, op.src != 0, |old| old & !value)
+}
+
+fn csrrwi(cpu: &mut CPU, op: CsrType) -> ExecuteResult<ExecuteStatus> {
+    let uimm = op.src as u32;
+    csr_access(cpu, op, true, |_| uimm)
+}
+
+fn csrrsi(cpu: &mut CPU, op: CsrType) -> ExecuteResult<ExecuteStatus> {
+    let uimm = op.src as u32;
+    csr_access(cpu, op, uimm != 0, |old| old | uimm)
+}
+
+fn csrrci(cpu: &mut CPU, op: CsrType) -> ExecuteResult<ExecuteStatus> {
+    let uimm = op.src as u32;
+    csr_access(cpu, op, uimm != 0, |old| old & !uimm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operands_are_sign_extended() {
+        // addi x3, x0, -1
+        match ThreadedOp::decode(0xFFF00193).unwrap() {
+            ThreadedOp::I(_, operands) => assert_eq!(
+                operands,
+                IType {
+                    rd: 3,
+                    rs1: 0,
+                    imm: 0xFFFF_FFFF
+                }
+            ),
+            op => panic!("{:?}", op),
+        }
+        // beq x1, x2, -4
+        match ThreadedOp::decode(0xFE208EE3).unwrap() {
+            ThreadedOp::B(_, operands) => assert_eq!(operands.imm, (-4i32) as u32),
+            op => panic!("{:?}", op),
+        }
+    }
+
+    #[test]
+    fn writes_to_x0_become_nops() {
+        // add x0, x1, x2
+        assert!(matches!(
+            ThreadedOp::decode(0x00208033).unwrap(),
+            ThreadedOp::System(_)
+        ));
+        // lw x0, 0(x1) still accesses memory
+        assert!(matches!(
+            ThreadedOp::decode(0x0000A003).unwrap(),
+            ThreadedOp::I(..)
+        ));
+    }
+
+    #[test]
+    fn jalr_reads_rs1_before_writing_rd() {
+        let mut cpu = CPU::new(0, 0x1000);
+        cpu.get_registers().set_pc(0x100);
+        cpu.get_registers()[1] = 0x200;
+        // jalr ra, 0(ra)
+        ThreadedOp::decode(0x000080E7)
+            .unwrap()
+            .execute(&mut cpu)
+            .unwrap();
+        assert_eq!(cpu.get_registers().get_pc(), 0x200);
+        assert_eq!(cpu.get_registers()[1], 0x104);
+    }
+
+    /// A hart with ra, sp, gp and t1 set, a word to load at 0x200 and mscratch written
+    fn hart() -> CPU {
+        let mut cpu = CPU::new(0, 0x1000);
+        cpu.get_registers().set_pc(0x100);
+        cpu.get_registers()[1] = 0xFFFF_FFF0;
+        cpu.get_registers()[2] = 7;
+        cpu.get_registers()[3] = 0x204;
+        cpu.get_registers()[6] = 0x200;
+        cpu.get_memory().write_word(0x200, 0x8081_F2F3).unwrap();
+        cpu.get_csrs().write(0x340, 0x55);
+        cpu
+    }
+
+    #[test]
+    fn handlers() {
+        // (instruction, register written, its value, next pc)
+        let cases: [(u32, usize, u32, u32); 37] = [
+            (0x123452B7, 5, 0x1234_5000, 0x104), // lui t0, 0x12345
+            (0x00001317, 6, 0x1100, 0x104),      // auipc t1, 1
+            (0x008000EF, 1, 0x104, 0x108),       // jal ra, 8
+            (0x004300E7, 1, 0x104, 0x204),       // jalr ra, 4(t1)
+            (0xFE208EE3, 1, 0xFFFF_FFF0, 0x104), // beq ra, sp, -4
+            (0x00209463, 1, 0xFFFF_FFF0, 0x108), // bne ra, sp, 8
+            (0x0020C463, 1, 0xFFFF_FFF0, 0x108), // blt ra, sp, 8
+            (0x0020D463, 1, 0xFFFF_FFF0, 0x104), // bge ra, sp, 8
+            (0x0020E463, 1, 0xFFFF_FFF0, 0x104), // bltu ra, sp, 8
+            (0x0020F463, 1, 0xFFFF_FFF0, 0x108), // bgeu ra, sp, 8
+            (0xFFC18283, 5, 0xFFFF_FFF3, 0x104), // lb t0, -4(gp)
+            (0xFFC19283, 5, 0xFFFF_F2F3, 0x104), // lh t0, -4(gp)
+            (0xFFC1A283, 5, 0x8081_F2F3, 0x104), // lw t0, -4(gp)
+            (0xFFC1C283, 5, 0xF3, 0x104),        // lbu t0, -4(gp)
+            (0xFFC1D283, 5, 0xF2F3, 0x104),      // lhu t0, -4(gp)
+            (0x80008293, 5, 0xFFFF_F7F0, 0x104), // addi t0, ra, -2048
+            (0xFFF0A293, 5, 1, 0x104),           // slti t0, ra, -1
+            (0xFFF0B293, 5, 1, 0x104),           // sltiu t0, ra, -1
+            (0xFFF0C293, 5, 0xF, 0x104),         // xori t0, ra, -1
+            (0x0F00E293, 5, 0xFFFF_FFF0, 0x104), // ori t0, ra, 0xF0
+            (0x0F00F293, 5, 0xF0, 0x104),        // andi t0, ra, 0xF0
+            (0x01F09293, 5, 0, 0x104),           // slli t0, ra, 31
+            (0x0040D293, 5, 0x0FFF_FFFF, 0x104), // srli t0, ra, 4
+            (0x4040D293, 5, 0xFFFF_FFFF, 0x104), // srai t0, ra, 4
+            (0x002082B3, 5, 0xFFFF_FFF7, 0x104), // add t0, ra, sp
+            (0x402082B3, 5, 0xFFFF_FFE9, 0x104), // sub t0, ra, sp
+            (0x002092B3, 5, 0xFFFF_F800, 0x104), // sll t0, ra, sp
+            (0x0020A2B3, 5, 1, 0x104),           // slt t0, ra, sp
+            (0x0020B2B3, 5, 0, 0x104),           // sltu t0, ra, sp
+            (0x0020C2B3, 5, 0xFFFF_FFF7, 0x104), // xor t0, ra, sp
+            (0x0020D2B3, 5, 0x01FF_FFFF, 0x104), // srl t0, ra, sp
+            (0x4020D2B3, 5, 0xFFFF_FFFF, 0x104), // sra t0, ra, sp
+            (0x0020E2B3, 5, 0xFFFF_FFF7, 0x104), // or t0, ra, sp
+            (0x0020F2B3, 5, 0, 0x104),           // and t0, ra, sp
+            (0x00208033, 0, 0, 0x104),           // add zero, ra, sp
+            (0x0FF0000F, 0, 0, 0x104),           // fence
+            (0x000280E7, 1, 0x104, 0),           // jalr ra, 0(t0)
+        ];
+        for &(word, rd, value, pc) in cases.iter() {
+            let mut cpu = hart();
+            let result = ThreadedOp::decode(word).unwrap().execute(&mut cpu);
+            assert_eq!(result, Ok(ExecuteStatus::CONTINUE), "{:08X}", word);
+            assert_eq!(cpu.get_registers()[rd], value, "{:08X}", word);
+            assert_eq!(cpu.get_registers().get_pc(), pc, "{:08X}", word);
+        }
+    }
+
+    #[test]
+    fn store_handlers() {
+        let cases = [
+            (0xFE218E23, 0x8081_F207), // sb sp, -4(gp)
+            (0xFE219E23, 0x8081_0007), // sh sp, -4(gp)
+            (0xFE21AE23, 7),           // sw sp, -4(gp)
+        ];
+        for &(word, stored) in cases.iter() {
+            let mut cpu = hart();
+            ThreadedOp::decode(word).unwrap().execute(&mut cpu).unwrap();
+            assert_eq!(cpu.get_memory().read_word(0x200), Ok(stored), "{:08X}", word);
+        }
+    }
+
+    #[test]
+    fn csr_handlers() {
+        // (instruction, t0, mscratch)
+        let cases = [
+            (0x34009073, 0, 0xFFFF_FFF0), // csrw mscratch, ra
+            (0x340022F3, 0x55, 0x55),     // csrr t0, mscratch
+            (0x3400B2F3, 0x55, 0x5),      // csrrc t0, mscratch, ra
+            (0x3401D2F3, 0x55, 3),        // csrrwi t0, mscratch, 3
+            (0x340262F3, 0x55, 0x55),     // csrrsi t0, mscratch, 4
+            (0x3401F2F3, 0x55, 0x54),     // csrrci t0, mscratch, 3
+        ];
+        for &(word, t0, mscratch) in cases.iter() {
+            let mut cpu = hart();
+            ThreadedOp::decode(word).unwrap().execute(&mut cpu).unwrap();
+            assert_eq!(cpu.get_registers()[5], t0, "{:08X}", word);
+            assert_eq!(cpu.read_csr(0x340), mscratch, "{:08X}", word);
+        }
+    }
+}
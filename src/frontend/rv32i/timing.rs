@@ -0,0 +1,322 @@
+//! Cycle-approximate timing of a five-stage in-order pipeline (IF, ID, EX, MEM, WB) with full
+//! forwarding and static not-taken branch prediction. Executed instructions are fed to the model
+//! one at a time. It charges each one the cycles of its instruction class, plus bubbles for
+//! load-use hazards and for instructions fetched past a control transfer and then flushed.
+
+use super::instructions::Instruction;
+
+use std::fmt;
+
+/// Latencies and penalties of a core variant. All values are in cycles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PipelineConfig {
+    /// Pipeline depth, the first instruction completes after this many cycles
+    pub stages: u32,
+    /// Register-register and register-immediate arithmetic, LUI and AUIPC
+    pub alu: u32,
+    pub load: u32,
+    pub store: u32,
+    /// Conditional branches, taken or not
+    pub branch: u32,
+    /// JAL and JALR
+    pub jump: u32,
+    /// CSR accesses, fences, ECALL, EBREAK, xRET and WFI
+    pub system: u32,
+    /// Bubbles when an instruction needs the result of the load right before it
+    pub load_use_penalty: u32,
+    /// Instructions flushed after a taken branch, which resolves in EX
+    pub branch_penalty: u32,
+    /// Instructions flushed after JAL, whose target is known in ID
+    pub jal_penalty: u32,
+    /// Instructions flushed after JALR, which needs rs1 and resolves in EX
+    pub jalr_penalty: u32,
+    /// Instructions flushed when a trap or xRET redirects fetch
+    pub trap_penalty: u32,
+}
+
+impl Default for PipelineConfig {
+    /// The classic five-stage pipeline, every instruction issuing in a single cycle
+    fn default() -> Self {
+        PipelineConfig {
+            stages: 5,
+            alu: 1,
+            load: 1,
+            store: 1,
+            branch: 1,
+            jump: 1,
+            system: 1,
+            load_use_penalty: 1,
+            branch_penalty: 2,
+            jal_penalty: 1,
+            jalr_penalty: 2,
+            trap_penalty: 4,
+        }
+    }
+}
+
+/// What the pipeline spent its cycles on
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PipelineStats {
+    pub instructions: u64,
+    pub cycles: u64,
+    /// Bubbles inserted for load-use hazards
+    pub load_use_stalls: u64,
+    /// Cycles lost to flushed instructions after control transfers and traps
+    pub flush_cycles: u64,
+    /// Cycles the hart was stalled in WFI or entering an interrupt handler
+    pub idle_cycles: u64,
+    /// Cycles spent waiting for cache misses to be served
+    pub memory_stall_cycles: u64,
+}
+
+impl PipelineStats {
+    /// Cycles per instruction, 0 before anything executed
+    pub fn cpi(&self) -> f64 {
+        if self.instructions == 0 {
+            return 0.0;
+        }
+        self.cycles as f64 / self.instructions as f64
+    }
+}
+
+impl fmt::Display for PipelineStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} cycles, {} instructions, CPI {:.3} ({} load-use stalls, {} flush cycles, {} idle \
+             cycles, {} memory stall cycles)",
+            self.cycles,
+            self.instructions,
+            self.cpi(),
+            self.load_use_stalls,
+            self.flush_cycles,
+            self.idle_cycles,
+            self.memory_stall_cycles
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Class {
+    Alu,
+    Load,
+    Store,
+    Branch,
+    Jal,
+    Jalr,
+    System,
+}
+
+/// Instruction class and the registers it reads
+fn classify(instr: Instruction) -> (Class, [u32; 2]) {
+    use Instruction::*;
+
+    match instr {
+        LUI(..) | AUIPC(..) => (Class::Alu, [0, 0]),
+        JAL(..) => (Class::Jal, [0, 0]),
+        JALR(_, rs1, _) => (Class::Jalr, [rs1, 0]),
+        BEQ(rs1, rs2, _)
+        | BNE(rs1, rs2, _)
+        | BLT(rs1, rs2, _)
+        | BGE(rs1, rs2, _)
+        | BLTU(rs1, rs2, _)
+        | BGEU(rs1, rs2, _) => (Class::Branch, [rs1, rs2]),
+        LB(_, rs1, _) | LH(_, rs1, _) | LW(_, rs1, _) | LBU(_, rs1, _) | LHU(_, rs1, _) => {
+            (Class::Load, [rs1, 0])
+        }
+        SB(rs1, rs2, _) | SH(rs1, rs2, _) | SW(rs1, rs2, _) => (Class::Store, [rs1, rs2]),
+        ADDI(_, rs1, _)
+        | SLTI(_, rs1, _)
+        | SLTIU(_, rs1, _)
+        | XORI(_, rs1, _)
+        | ORI(_, rs1, _)
+        | ANDI(_, rs1, _)
+        | SLLI(_, rs1, _)
+        | SRLI(_, rs1, _)
+        | SRAI(_, rs1, _) => (Class::Alu, [rs1, 0]),
+        ADD(_, rs1, rs2)
+        | SUB(_, rs1, rs2)
+        | SLL(_, rs1, rs2)
+        | SLT(_, rs1, rs2)
+        | SLTU(_, rs1, rs2)
+        | XOR(_, rs1, rs2)
+        | SRL(_, rs1, rs2)
+        | SRA(_, rs1, rs2)
+        | OR(_, rs1, rs2)
+        | AND(_, rs1, rs2) => (Class::Alu, [rs1, rs2]),
+        CSRRW(_, rs1, _) | CSRRS(_, rs1, _) | CSRRC(_, rs1, _) => (Class::System, [rs1, 0]),
+        FENCE(..) | FENCE_I | ECALL | EBREAK | SRET | MRET | WFI | CSRRWI(..) | CSRRSI(..)
+        | CSRRCI(..) => (Class::System, [0, 0]),
+    }
+}
+
+/// Register written by a load, the only results which aren't forwarded in time
+fn load_destination(instr: Instruction) -> Option<u32> {
+    use Instruction::*;
+
+    match instr {
+        LB(rd, ..) | LH(rd, ..) | LW(rd, ..) | LBU(rd, ..) | LHU(rd, ..) if rd != 0 => Some(rd),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    config: PipelineConfig,
+    /// Destination of the load executed last, if the previous instruction was one
+    pending_load: Option<u32>,
+    stats: PipelineStats,
+}
+
+impl Pipeline {
+    pub fn new(config: PipelineConfig) -> Self {
+        Pipeline {
+            config,
+            pending_load: None,
+            stats: PipelineStats::default(),
+        }
+    }
+
+    pub fn get_config(&self) -> PipelineConfig {
+        self.config
+    }
+
+    pub fn get_stats(&self) -> PipelineStats {
+        self.stats
+    }
+
+    /// Account for `instr`, which executed at `pc` and continued at `next_pc`. Returns the cycles
+    /// it cost.
+    pub fn execute(&mut self, instr: Instruction, pc: u32, next_pc: u32) -> u64 {
+        let config = &self.config;
+        let (class, sources) = classify(instr);
+        let mut cycles = match class {
+            Class::Alu => config.alu,
+            Class::Load => config.load,
+            Class::Store => config.store,
+            Class::Branch => config.branch,
+            Class::Jal | Class::Jalr => config.jump,
+            Class::System => config.system,
+        } as u64;
+        // The pipeline fills behind the first instruction
+        if self.stats.instructions == 0 {
+            cycles += config.stages.saturating_sub(1) as u64;
+        }
+
+        if let Some(rd) = self.pending_load {
+            if sources.contains(&rd) {
+                cycles += config.load_use_penalty as u64;
+                self.stats.load_use_stalls += config.load_use_penalty as u64;
+            }
+        }
+
+        let flush = if next_pc == pc.wrapping_add(4) {
+            0
+        } else {
+            match class {
+                Class::Branch => config.branch_penalty,
+                Class::Jal => config.jal_penalty,
+                Class::Jalr => config.jalr_penalty,
+                _ => config.trap_penalty,
+            }
+        } as u64;
+        cycles += flush;
+        self.stats.flush_cycles += flush;
+
+        self.pending_load = load_destination(instr);
+        self.stats.instructions += 1;
+        self.stats.cycles += cycles;
+        cycles
+    }
+
+    /// A cycle without an instruction executing, the pipeline drains
+    pub fn idle(&mut self) {
+        self.pending_load = None;
+        self.stats.cycles += 1;
+        self.stats.idle_cycles += 1;
+    }
+
+    /// The last instruction waited `cycles` for memory, e.g. for cache misses
+    pub fn stall(&mut self, cycles: u64) {
+        self.stats.cycles += cycles;
+        self.stats.memory_stall_cycles += cycles;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(pipeline: &mut Pipeline, program: &[(u32, u32)]) {
+        for &(word, next_pc) in program {
+            let instr = Instruction::decode(word).unwrap();
+            pipeline.execute(instr, 0x100, next_pc);
+        }
+    }
+
+    #[test]
+    fn straight_line_code_fills_the_pipeline() {
+        let mut pipeline = Pipeline::new(PipelineConfig::default());
+        run(
+            &mut pipeline,
+            &[
+                (0x00128293, 0x104), // addi t0, t0, 1
+                (0x00128293, 0x104),
+                (0x00128293, 0x104),
+            ],
+        );
+
+        let stats = pipeline.get_stats();
+        assert_eq!(stats.cycles, 4 + 3);
+        assert_eq!(stats.instructions, 3);
+    }
+
+    #[test]
+    fn load_use_stalls() {
+        let mut pipeline = Pipeline::new(PipelineConfig::default());
+        run(
+            &mut pipeline,
+            &[
+                (0x0002a303, 0x104), // lw t1, 0(t0)
+                (0x00130313, 0x104), // addi t1, t1, 1
+                (0x0002a303, 0x104), // lw t1, 0(t0)
+                (0x00128293, 0x104), // addi t0, t0, 1
+            ],
+        );
+
+        assert_eq!(pipeline.get_stats().load_use_stalls, 1);
+        assert_eq!(pipeline.get_stats().cycles, 4 + 4 + 1);
+    }
+
+    #[test]
+    fn control_transfers_flush() {
+        let mut pipeline = Pipeline::new(PipelineConfig::default());
+        run(
+            &mut pipeline,
+            &[
+                (0x00530463, 0x104), // beq t1, t0, 8, not taken
+                (0x00530463, 0x108), // taken
+                (0x0080006f, 0x108), // j 8
+                (0x00008067, 0x200), // ret
+                (0x30200073, 0x200), // mret
+            ],
+        );
+
+        let stats = pipeline.get_stats();
+        assert_eq!(stats.flush_cycles, 2 + 1 + 2 + 4);
+        assert_eq!(stats.cycles, 4 + 5 + 9);
+    }
+
+    #[test]
+    fn latencies_are_configurable() {
+        let mut pipeline = Pipeline::new(PipelineConfig {
+            stages: 3,
+            load: 3,
+            ..PipelineConfig::default()
+        });
+        run(&mut pipeline, &[(0x0002a303, 0x104), (0x0002a303, 0x104)]);
+
+        assert_eq!(pipeline.get_stats().cycles, 2 + 3 + 3);
+        assert_eq!(pipeline.get_stats().cpi(), 4.0);
+    }
+}
@@ -0,0 +1,257 @@
+//! Memory reference traces for replaying the guest's accesses in cache simulators. Every
+//! instruction fetch, load and store the hart makes is written out with its address and size, as
+//! Dinero IV extended din text or a compact binary format.
+
+use super::mem::{Mem, MemoryResult};
+
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    /// One `label address size` line per access, addresses and sizes in hex. Dinero IV reads it
+    /// with `-informat D`.
+    Din,
+    /// 6 bytes per access: the din label, the size, and the address in little endian
+    Binary,
+}
+
+/// Kind of access, numbered like din labels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceKind {
+    Read = 0,
+    Write = 1,
+    Fetch = 2,
+}
+
+/// An access as it reached memory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryAccess {
+    pub kind: TraceKind,
+    pub addr: u32,
+    pub size: u32,
+}
+
+/// `Mem` for a hart's loads and stores. Accesses are forwarded to `memory` whole, and every one
+/// that completes is appended to `accesses` with the address and width it was made with.
+pub struct TracingMem<'a, M: Mem> {
+    memory: &'a mut M,
+    accesses: Option<&'a RefCell<Vec<MemoryAccess>>>,
+}
+
+impl<'a, M: Mem> TracingMem<'a, M> {
+    /// Forward to `memory`, noting accesses only when there is somewhere to note them
+    pub fn new(memory: &'a mut M, accesses: Option<&'a RefCell<Vec<MemoryAccess>>>) -> Self {
+        TracingMem { memory, accesses }
+    }
+
+    fn note<T>(&self, result: MemoryResult<T>, kind: TraceKind, addr: u32, size: u32) -> MemoryResult<T> {
+        if let (Ok(_), Some(accesses)) = (&result, self.accesses) {
+            accesses.borrow_mut().push(MemoryAccess { kind, addr, size });
+        }
+        result
+    }
+}
+
+impl<'a, M: Mem> Mem for TracingMem<'a, M> {
+    fn read_byte(&self, addr: u32) -> MemoryResult<u8> {
+        self.note(self.memory.read_byte(addr), TraceKind::Read, addr, 1)
+    }
+
+    fn write_byte(&mut self, addr: u32, val: u8) -> MemoryResult<()> {
+        let result = self.memory.write_byte(addr, val);
+        self.note(result, TraceKind::Write, addr, 1)
+    }
+
+    fn read_halfword(&self, addr: u32) -> MemoryResult<u16> {
+        self.note(self.memory.read_halfword(addr), TraceKind::Read, addr, 2)
+    }
+
+    fn write_halfword(&mut self, addr: u32, val: u16) -> MemoryResult<()> {
+        let result = self.memory.write_halfword(addr, val);
+        self.note(result, TraceKind::Write, addr, 2)
+    }
+
+    fn read_word(&self, addr: u32) -> MemoryResult<u32> {
+        self.note(self.memory.read_word(addr), TraceKind::Read, addr, 4)
+    }
+
+    fn write_word(&mut self, addr: u32, val: u32) -> MemoryResult<()> {
+        let result = self.memory.write_word(addr, val);
+        self.note(result, TraceKind::Write, addr, 4)
+    }
+}
+
+struct Sink {
+    out: Box<dyn Write + Send>,
+    records: u64,
+    /// The first write error, nothing is written after it
+    error: Option<io::Error>,
+}
+
+/// Writes the accesses of every hart holding a clone of it to the same output
+#[derive(Clone)]
+pub struct MemoryTracer {
+    format: TraceFormat,
+    sink: Arc<Mutex<Sink>>,
+}
+
+impl fmt::Debug for MemoryTracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MemoryTracer")
+            .field("format", &self.format)
+            .field("records", &self.get_records())
+            .finish()
+    }
+}
+
+impl MemoryTracer {
+    pub fn new<W: Write + Send + 'static>(out: W, format: TraceFormat) -> Self {
+        MemoryTracer {
+            format,
+            sink: Arc::new(Mutex::new(Sink {
+                out: Box::new(out),
+                records: 0,
+                error: None,
+            })),
+        }
+    }
+
+    /// Trace to a new file at `path`
+    pub fn create<P: AsRef<Path>>(path: P, format: TraceFormat) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(MemoryTracer::new(BufWriter::new(file), format))
+    }
+
+    pub fn get_format(&self) -> TraceFormat {
+        self.format
+    }
+
+    /// Accesses recorded so far
+    pub fn get_records(&self) -> u64 {
+        self.sink.lock().unwrap().records
+    }
+
+    pub fn record(&self, kind: TraceKind, addr: u32, size: u32) {
+        let mut sink = self.sink.lock().unwrap();
+        if sink.error.is_some() {
+            return;
+        }
+        let result = match self.format {
+            TraceFormat::Din => writeln!(sink.out, "{} {:x} {:x}", kind as u8, addr, size),
+            TraceFormat::Binary => {
+                let mut record = [kind as u8, size as u8, 0, 0, 0, 0];
+                record[2..].copy_from_slice(&addr.to_le_bytes());
+                sink.out.write_all(&record)
+            }
+        };
+        match result {
+            Ok(()) => sink.records += 1,
+            Err(err) => sink.error = Some(err),
+        }
+    }
+
+    /// Flush the output, returning the number of accesses written or the first error
+    pub fn finish(&self) -> io::Result<u64> {
+        let mut sink = self.sink.lock().unwrap();
+        if let Some(err) = sink.error.take() {
+            return Err(err);
+        }
+        sink.out.flush()?;
+        Ok(sink.records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `Write` the test can still read after handing it to the tracer
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace(format: TraceFormat) -> Vec<u8> {
+        let out = Shared::default();
+        let tracer = MemoryTracer::new(out.clone(), format);
+        tracer.record(TraceKind::Fetch, 0x8000_0000, 4);
+        tracer.record(TraceKind::Read, 0x100, 2);
+        tracer.clone().record(TraceKind::Write, 0x1f, 1);
+        assert_eq!(tracer.finish().unwrap(), 3);
+        let bytes = out.0.lock().unwrap().clone();
+        bytes
+    }
+
+    #[test]
+    fn din_format() {
+        assert_eq!(
+            String::from_utf8(trace(TraceFormat::Din)).unwrap(),
+            "2 80000000 4\n0 100 2\n1 1f 1\n"
+        );
+    }
+
+    #[test]
+    fn binary_format() {
+        assert_eq!(
+            trace(TraceFormat::Binary),
+            vec![2, 4, 0, 0, 0, 0x80, 0, 2, 0, 1, 0, 0, 1, 1, 0x1f, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn tracing_mem_notes_completed_accesses() {
+        use super::super::mem::{MemoryError, RAM};
+
+        let mut ram = RAM::new(0x100, 0x10);
+        let accesses = RefCell::new(Vec::new());
+        let mut memory = TracingMem::new(&mut ram, Some(&accesses));
+        memory.write_halfword(0x102, 0xBEEF).unwrap();
+        assert_eq!(memory.read_byte(0x103), Ok(0xBE));
+        assert_eq!(memory.read_word(0x200), Err(MemoryError::UnmappedRegion));
+        let access = |kind, addr, size| MemoryAccess { kind, addr, size };
+        assert_eq!(
+            accesses.into_inner(),
+            vec![
+                access(TraceKind::Write, 0x102, 2),
+                access(TraceKind::Read, 0x103, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn write_errors_are_reported() {
+        struct Full;
+
+        impl Write for Full {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::WriteZero.into())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let tracer = MemoryTracer::new(Full, TraceFormat::Binary);
+        tracer.record(TraceKind::Read, 0, 4);
+        tracer.record(TraceKind::Read, 4, 4);
+        assert_eq!(tracer.get_records(), 0);
+        assert_eq!(
+            tracer.finish().unwrap_err().kind(),
+            io::ErrorKind::WriteZero
+        );
+    }
+}
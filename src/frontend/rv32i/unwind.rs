@@ -0,0 +1,234 @@
+//! Guest call stacks. Frames are unwound with the call frame information of the binary, from
+//! `.eh_frame` or `.debug_frame`, and where there is none by following the frame pointer.
+
+use super::mem::RAM;
+
+use gimli::{
+    BaseAddresses, CfaRule, DebugFrame, EhFrame, EndianSlice, LittleEndian, RegisterRule,
+    UnwindContext, UnwindSection,
+};
+use xmas_elf::ElfFile;
+
+type Slice<'a> = EndianSlice<'a, LittleEndian>;
+
+/// Deeper stacks are cut off, which also ends unwinding that goes in circles
+pub const MAX_FRAMES: usize = 256;
+
+const RA: usize = 1;
+const SP: usize = 2;
+const FP: usize = 8;
+
+/// Registers of a frame
+type Registers = [u32; 32];
+
+/// How a frame was found
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unwound {
+    /// The frame that was running
+    Top,
+    /// From call frame information
+    Cfi,
+    /// From the frame record the frame pointer points to
+    FramePointer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    /// Where the frame is executing. Callers are at the instruction that made the call.
+    pub pc: u32,
+    /// Stack pointer of the frame
+    pub sp: u32,
+    pub unwound: Unwound,
+}
+
+/// The call frame information sections of a binary
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Unwinder {
+    /// Address and contents of `.eh_frame`
+    eh_frame: Option<(u32, Vec<u8>)>,
+    debug_frame: Option<Vec<u8>>,
+}
+
+impl Unwinder {
+    pub fn from_elf(elf: &ElfFile) -> Self {
+        let section = |name| elf.find_section_by_name(name);
+        Unwinder {
+            eh_frame: section(".eh_frame")
+                .map(|sect| (sect.address() as u32, sect.raw_data(elf).to_vec())),
+            debug_frame: section(".debug_frame").map(|sect| sect.raw_data(elf).to_vec()),
+        }
+    }
+
+    /// Whether there is any call frame information, otherwise only frame pointers are followed
+    pub fn has_cfi(&self) -> bool {
+        self.eh_frame.is_some() || self.debug_frame.is_some()
+    }
+
+    /// The frames of the call stack, innermost first, starting from `pc` with `registers`.
+    /// Unwinding stops at the outermost frame, which has no return address, or where neither call
+    /// frame information nor a frame pointer leads further up the stack. Stack slots are only
+    /// read from RAM, never from devices.
+    pub fn backtrace(&self, memory: &RAM, pc: u32, registers: &Registers) -> Vec<Frame> {
+        let mut frames = vec![Frame {
+            pc,
+            sp: registers[SP],
+            unwound: Unwound::Top,
+        }];
+        let mut registers = *registers;
+        let mut pc = pc;
+        while frames.len() < MAX_FRAMES {
+            let (caller, unwound) = match self.unwind_cfi(memory, pc, &registers) {
+                Some(caller) => (caller, Unwound::Cfi),
+                None => match unwind_frame_pointer(memory, &registers) {
+                    Some(caller) => (caller, Unwound::FramePointer),
+                    None => break,
+                },
+            };
+            let return_address = caller[RA];
+            // Stacks grow down, so a caller's frame is above. Only the running function can be a
+            // leaf without a frame of its own.
+            let leaf = frames.len() == 1 && caller[SP] == registers[SP];
+            if return_address == 0 || (caller[SP] <= registers[SP] && !leaf) {
+                break;
+            }
+            registers = caller;
+            pc = return_address.wrapping_sub(4);
+            frames.push(Frame {
+                pc,
+                sp: registers[SP],
+                unwound,
+            });
+        }
+        frames
+    }
+
+    /// Registers of the caller of the frame at `pc`, with its return address in ra
+    fn unwind_cfi(&self, memory: &RAM, pc: u32, registers: &Registers) -> Option<Registers> {
+        if let Some((addr, data)) = &self.eh_frame {
+            let mut section = EhFrame::new(data, LittleEndian);
+            section.set_address_size(4);
+            let bases = BaseAddresses::default().set_eh_frame(*addr as u64);
+            if let Some(caller) = unwind_section(&section, &bases, memory, pc, registers) {
+                return Some(caller);
+            }
+        }
+        if let Some(data) = &self.debug_frame {
+            let mut section = DebugFrame::new(data, LittleEndian);
+            section.set_address_size(4);
+            return unwind_section(&section, &BaseAddresses::default(), memory, pc, registers);
+        }
+        None
+    }
+}
+
+fn unwind_section<'a, S: UnwindSection<Slice<'a>>>(
+    section: &S,
+    bases: &BaseAddresses,
+    memory: &RAM,
+    pc: u32,
+    registers: &Registers,
+) -> Option<Registers> {
+    let fde = section
+        .fde_for_address(bases, pc as u64, S::cie_from_offset)
+        .ok()?;
+    let mut context = UnwindContext::new();
+    let row = fde
+        .unwind_info_for_address(section, bases, &mut context, pc as u64)
+        .ok()?;
+
+    let cfa = match row.cfa() {
+        CfaRule::RegisterAndOffset { register, offset } => {
+            let base = *registers.get(register.0 as usize)?;
+            base.wrapping_add(*offset as u32)
+        }
+        CfaRule::Expression(_) => return None,
+    };
+    // Registers without a rule keep their value
+    let mut caller = *registers;
+    let return_register = fde.cie().return_address_register().0 as usize;
+    for (register, rule) in row.registers() {
+        let value = match rule {
+            RegisterRule::Undefined => {
+                if register.0 as usize == return_register {
+                    // The outermost frame
+                    return None;
+                }
+                continue;
+            }
+            RegisterRule::SameValue => continue,
+            RegisterRule::Offset(offset) => {
+                memory.peek_word(cfa.wrapping_add(*offset as u32)).ok()?
+            }
+            RegisterRule::ValOffset(offset) => cfa.wrapping_add(*offset as u32),
+            RegisterRule::Register(from) => *registers.get(from.0 as usize)?,
+            RegisterRule::Constant(value) => *value as u32,
+            _ => return None,
+        };
+        if let Some(slot) = caller.get_mut(register.0 as usize) {
+            *slot = value;
+        }
+    }
+    caller[RA] = *caller.get(return_register)?;
+    caller[SP] = cfa;
+    Some(caller)
+}
+
+/// Registers of the caller from the frame record below the frame pointer: the return address at
+/// fp - 4 and the caller's frame pointer at fp - 8, as GCC and LLVM lay them out. The caller's
+/// stack pointer is the frame pointer.
+fn unwind_frame_pointer(memory: &RAM, registers: &Registers) -> Option<Registers> {
+    let fp = registers[FP];
+    if fp == 0 || fp % 4 != 0 {
+        return None;
+    }
+    let mut caller = *registers;
+    caller[RA] = memory.peek_word(fp.wrapping_sub(4)).ok()?;
+    caller[FP] = memory.peek_word(fp.wrapping_sub(8)).ok()?;
+    caller[SP] = fp;
+    Some(caller)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mem::Mem;
+    use super::*;
+
+    #[test]
+    fn frame_pointer_chain() {
+        let mut memory = RAM::new(0x8000_0000, 0x100);
+        // main's frame record, at the top of the stack, then f's below it
+        memory.write_word(0x8000_00fc, 0x8000_0010).unwrap();
+        memory.write_word(0x8000_00f8, 0).unwrap();
+        memory.write_word(0x8000_00ec, 0x8000_0048).unwrap();
+        memory.write_word(0x8000_00e8, 0x8000_0100).unwrap();
+
+        let mut registers = [0; 32];
+        registers[SP] = 0x8000_00e0;
+        registers[FP] = 0x8000_00f0;
+        let frames = Unwinder::default().backtrace(&memory, 0x8000_0080, &registers);
+
+        let pcs: Vec<u32> = frames.iter().map(|frame| frame.pc).collect();
+        assert_eq!(pcs, vec![0x8000_0080, 0x8000_0044, 0x8000_000c]);
+        assert_eq!(frames[1].sp, 0x8000_00f0);
+        assert_eq!(frames[2].unwound, Unwound::FramePointer);
+    }
+
+    #[test]
+    fn garbage_frame_pointers_stop_unwinding() {
+        let mut memory = RAM::new(0x8000_0000, 0x100);
+        // A frame record pointing back down the stack
+        memory.write_word(0x8000_00fc, 0x8000_0010).unwrap();
+        memory.write_word(0x8000_00f8, 0x8000_0080).unwrap();
+        memory.write_word(0x8000_007c, 0x8000_0020).unwrap();
+
+        let mut registers = [0; 32];
+        registers[SP] = 0x8000_00e0;
+        registers[FP] = 0x8000_0100;
+        let frames = Unwinder::default().backtrace(&memory, 0x8000_0080, &registers);
+        assert_eq!(frames.len(), 2);
+
+        registers[FP] = 0x8000_0102;
+        let frames = Unwinder::default().backtrace(&memory, 0x8000_0080, &registers);
+        assert_eq!(frames.len(), 1);
+    }
+}
@@ -0,0 +1,147 @@
+use super::{BinOp, Block, Exit, Op, Value, Width};
+
+use std::fmt;
+
+// Blocks print one operation per line, guest instruction markers unindented:
+//
+// block 0x80000000:
+// 0x80000000:
+//     %0 = reg r5
+//     %1 = const 0x1
+//     %2 = add %0, %1
+//     r5 = %2
+//     %3 = const 0x80000004
+//     jump %3
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "%{}", self.0)
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            BinOp::Add => "add",
+            BinOp::Sub => "sub",
+            BinOp::And => "and",
+            BinOp::Or => "or",
+            BinOp::Xor => "xor",
+            BinOp::Shl => "shl",
+            BinOp::Shr => "shr",
+            BinOp::Sar => "sar",
+            BinOp::Eq => "eq",
+            BinOp::Ne => "ne",
+            BinOp::Lt => "lt",
+            BinOp::Ltu => "ltu",
+            BinOp::Ge => "ge",
+            BinOp::Geu => "geu",
+        };
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for Width {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.bytes() * 8)
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Op::Guest(addr) => write!(f, "{:#010x}:", addr),
+            Op::Const { dst, value } => write!(f, "    {} = const {:#x}", dst, value),
+            Op::ReadReg { dst, reg } => write!(f, "    {} = reg r{}", dst, reg),
+            Op::WriteReg { reg, src } => write!(f, "    r{} = {}", reg, src),
+            Op::ReadSys { dst, reg } => write!(f, "    {} = sys {:#x}", dst, reg),
+            Op::WriteSys { reg, src } => write!(f, "    sys {:#x} = {}", reg, src),
+            Op::Binary { dst, op, lhs, rhs } => write!(f, "    {} = {} {}, {}", dst, op, lhs, rhs),
+            Op::Load {
+                dst,
+                addr,
+                width,
+                signed,
+            } => {
+                let kind = if signed { "s" } else { "u" };
+                write!(f, "    {} = load.{}{} [{}]", dst, kind, width, addr)
+            }
+            Op::Store { addr, src, width } => write!(f, "    store.{} [{}] = {}", width, addr, src),
+            Op::CheckAlign { addr, align } => write!(f, "    check_align {}, {}", addr, align),
+            Op::FlushCode => write!(f, "    flush_code"),
+        }
+    }
+}
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Exit::Jump(target) => write!(f, "    jump {}", target),
+            Exit::Branch {
+                cond,
+                taken,
+                not_taken,
+            } => write!(
+                f,
+                "    branch {}, {:#010x}, {:#010x}",
+                cond, taken, not_taken
+            ),
+            Exit::Syscall { next } => write!(f, "    syscall, {:#010x}", next),
+            Exit::Breakpoint { next } => write!(f, "    breakpoint, {:#010x}", next),
+            Exit::Unlifted(addr) => write!(f, "    unlifted {:#010x}", addr),
+        }
+    }
+}
+
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "block {:#010x}:", self.start)?;
+        for op in &self.ops {
+            writeln!(f, "{}", op)?;
+        }
+        writeln!(f, "{}", self.exit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Builder;
+    use super::*;
+
+    #[test]
+    fn print_block() {
+        let mut builder = Builder::new();
+        builder.guest(0x8000_0000);
+        let base = builder.read_reg(10);
+        let offset = builder.constant(8);
+        let addr = builder.binary(BinOp::Add, base, offset);
+        let value = builder.load(addr, Width::Half, true);
+        builder.write_reg(11, value);
+        builder.guest(0x8000_0004);
+        let zero = builder.constant(0);
+        let cond = builder.binary(BinOp::Ne, value, zero);
+        let block = builder.finish(
+            0x8000_0000,
+            Exit::Branch {
+                cond,
+                taken: 0x8000_0010,
+                not_taken: 0x8000_0008,
+            },
+        );
+
+        assert_eq!(
+            block.to_string(),
+            "block 0x80000000:\n\
+             0x80000000:\n    \
+             %0 = reg r10\n    \
+             %1 = const 0x8\n    \
+             %2 = add %0, %1\n    \
+             %3 = load.s16 [%2]\n    \
+             r11 = %3\n\
+             0x80000004:\n    \
+             %4 = const 0x0\n    \
+             %5 = ne %3, %4\n    \
+             branch %5, 0x80000010, 0x80000008\n"
+        );
+    }
+}
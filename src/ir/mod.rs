@@ -0,0 +1,341 @@
+//! Architecture-neutral intermediate representation. Frontends lift guest code into `Block`s of
+//! SSA-style operations: every `Value` is defined exactly once, by a constant, a register or
+//! memory read or an arithmetic operation, and guest state only changes through explicit
+//! register writes, stores and the block's `Exit`. Analyses and backends only have to understand
+//! these few operations instead of every instruction of every ISA.
+
+mod display;
+
+/// An SSA value, numbered in order of definition within its block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Value(pub u32);
+
+/// Guest general purpose register number
+pub type Reg = u8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Width {
+    Byte,
+    Half,
+    Word,
+}
+
+impl Width {
+    pub fn bytes(self) -> u32 {
+        match self {
+            Width::Byte => 1,
+            Width::Half => 2,
+            Width::Word => 4,
+        }
+    }
+}
+
+/// Operations on two 32-bit values. Shifts only use the low 5 bits of the shift amount and
+/// comparisons produce 1 or 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Shl,
+    /// Logical shift right
+    Shr,
+    /// Arithmetic shift right
+    Sar,
+    Eq,
+    Ne,
+    /// Signed less than
+    Lt,
+    Ltu,
+    /// Signed greater or equal
+    Ge,
+    Geu,
+}
+
+impl BinOp {
+    pub fn apply(self, lhs: u32, rhs: u32) -> u32 {
+        match self {
+            BinOp::Add => lhs.wrapping_add(rhs),
+            BinOp::Sub => lhs.wrapping_sub(rhs),
+            BinOp::And => lhs & rhs,
+            BinOp::Or => lhs | rhs,
+            BinOp::Xor => lhs ^ rhs,
+            BinOp::Shl => lhs << (rhs & 0x1F),
+            BinOp::Shr => lhs >> (rhs & 0x1F),
+            BinOp::Sar => ((lhs as i32) >> (rhs & 0x1F)) as u32,
+            BinOp::Eq => (lhs == rhs) as u32,
+            BinOp::Ne => (lhs != rhs) as u32,
+            BinOp::Lt => ((lhs as i32) < (rhs as i32)) as u32,
+            BinOp::Ltu => (lhs < rhs) as u32,
+            BinOp::Ge => ((lhs as i32) >= (rhs as i32)) as u32,
+            BinOp::Geu => (lhs >= rhs) as u32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    /// Start of the guest instruction at this address. Everything up to the next marker or the
+    /// exit belongs to it.
+    Guest(u32),
+    Const {
+        dst: Value,
+        value: u32,
+    },
+    ReadReg {
+        dst: Value,
+        reg: Reg,
+    },
+    WriteReg {
+        reg: Reg,
+        src: Value,
+    },
+    /// Control and status registers, read and written with their side effects
+    ReadSys {
+        dst: Value,
+        reg: u16,
+    },
+    WriteSys {
+        reg: u16,
+        src: Value,
+    },
+    Binary {
+        dst: Value,
+        op: BinOp,
+        lhs: Value,
+        rhs: Value,
+    },
+    /// Load `width` bytes, zero- or sign-extended to 32 bits
+    Load {
+        dst: Value,
+        addr: Value,
+        width: Width,
+        signed: bool,
+    },
+    /// Store the low `width` bytes of `src`
+    Store {
+        addr: Value,
+        src: Value,
+        width: Width,
+    },
+    /// Fault with a misaligned address unless `addr` is a multiple of `align`
+    CheckAlign {
+        addr: Value,
+        align: u32,
+    },
+    /// Forget any code derived from guest memory, after the guest modified its own code
+    FlushCode,
+}
+
+/// How control leaves a block, once all its operations have executed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exit {
+    Jump(Value),
+    /// Go to `taken` when `cond` is non-zero, to `not_taken` otherwise
+    Branch {
+        cond: Value,
+        taken: u32,
+        not_taken: u32,
+    },
+    /// The last instruction asks the environment for a service, execution resumes at `next`
+    Syscall {
+        next: u32,
+    },
+    Breakpoint {
+        next: u32,
+    },
+    /// Stop in front of the instruction at this address, which the frontend executes itself
+    Unlifted(u32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    /// Guest address of the first instruction
+    pub start: u32,
+    pub ops: Vec<Op>,
+    pub exit: Exit,
+    /// Number of values defined by `ops`
+    pub values: u32,
+}
+
+impl Block {
+    /// The constant `value` was defined as, if it is one
+    pub fn constant(&self, value: Value) -> Option<u32> {
+        self.ops.iter().find_map(|op| match *op {
+            Op::Const { dst, value: constant } if dst == value => Some(constant),
+            _ => None,
+        })
+    }
+
+    /// Addresses the block continues at which are known without running it. Indirect jumps
+    /// have none, and neither does an exit in front of an unlifted instruction, which only the
+    /// frontend knows the successors of.
+    pub fn successors(&self) -> Vec<u32> {
+        match self.exit {
+            Exit::Jump(target) => self.constant(target).into_iter().collect(),
+            Exit::Branch {
+                taken, not_taken, ..
+            } => vec![taken, not_taken],
+            Exit::Syscall { next } | Exit::Breakpoint { next } => vec![next],
+            Exit::Unlifted(_) => Vec::new(),
+        }
+    }
+}
+
+/// Appends operations to a block, handing out fresh values
+#[derive(Debug, Clone, Default)]
+pub struct Builder {
+    ops: Vec<Op>,
+    values: u32,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Builder::default()
+    }
+
+    fn value(&mut self) -> Value {
+        self.values += 1;
+        Value(self.values - 1)
+    }
+
+    pub fn guest(&mut self, addr: u32) {
+        self.ops.push(Op::Guest(addr));
+    }
+
+    pub fn constant(&mut self, value: u32) -> Value {
+        let dst = self.value();
+        self.ops.push(Op::Const { dst, value });
+        dst
+    }
+
+    pub fn read_reg(&mut self, reg: Reg) -> Value {
+        let dst = self.value();
+        self.ops.push(Op::ReadReg { dst, reg });
+        dst
+    }
+
+    pub fn write_reg(&mut self, reg: Reg, src: Value) {
+        self.ops.push(Op::WriteReg { reg, src });
+    }
+
+    pub fn read_sys(&mut self, reg: u16) -> Value {
+        let dst = self.value();
+        self.ops.push(Op::ReadSys { dst, reg });
+        dst
+    }
+
+    pub fn write_sys(&mut self, reg: u16, src: Value) {
+        self.ops.push(Op::WriteSys { reg, src });
+    }
+
+    pub fn binary(&mut self, op: BinOp, lhs: Value, rhs: Value) -> Value {
+        let dst = self.value();
+        self.ops.push(Op::Binary { dst, op, lhs, rhs });
+        dst
+    }
+
+    pub fn load(&mut self, addr: Value, width: Width, signed: bool) -> Value {
+        let dst = self.value();
+        self.ops.push(Op::Load {
+            dst,
+            addr,
+            width,
+            signed,
+        });
+        dst
+    }
+
+    pub fn store(&mut self, addr: Value, src: Value, width: Width) {
+        self.ops.push(Op::Store { addr, src, width });
+    }
+
+    pub fn check_align(&mut self, addr: Value, align: u32) {
+        self.ops.push(Op::CheckAlign { addr, align });
+    }
+
+    pub fn flush_code(&mut self) {
+        self.ops.push(Op::FlushCode);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn finish(self, start: u32, exit: Exit) -> Block {
+        Block {
+            start,
+            ops: self.ops,
+            exit,
+            values: self.values,
+        }
+    }
+}
+
+/// A fault raised by IR semantics rather than by the guest's memory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Misaligned(pub u32);
+
+/// The guest state operations act on, implemented by each frontend
+pub trait Guest {
+    type Error: From<Misaligned>;
+
+    fn read_reg(&mut self, reg: Reg) -> u32;
+    fn write_reg(&mut self, reg: Reg, value: u32);
+    fn read_sys(&mut self, reg: u16) -> u32;
+    fn write_sys(&mut self, reg: u16, value: u32);
+    /// Load `width` bytes, zero-extended
+    fn load(&mut self, addr: u32, width: Width) -> Result<u32, Self::Error>;
+    fn store(&mut self, addr: u32, value: u32, width: Width) -> Result<(), Self::Error>;
+    /// Whether guest code was written since the last call, so code lifted from it is stale
+    fn code_modified(&mut self) -> bool;
+    fn flush_code(&mut self);
+    fn set_pc(&mut self, pc: u32);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_numbers_values_in_order() {
+        let mut builder = Builder::new();
+        builder.guest(0x100);
+        let a = builder.read_reg(1);
+        let b = builder.constant(4);
+        let sum = builder.binary(BinOp::Add, a, b);
+        builder.write_reg(2, sum);
+        let block = builder.finish(0x100, Exit::Jump(sum));
+
+        assert_eq!((a, b, sum), (Value(0), Value(1), Value(2)));
+        assert_eq!(block.values, 3);
+        assert_eq!(block.ops.len(), 5);
+    }
+
+    #[test]
+    fn static_successors() {
+        let mut builder = Builder::new();
+        builder.guest(0);
+        let target = builder.constant(0x40);
+        let direct = builder.clone().finish(0, Exit::Jump(target));
+        assert_eq!(direct.successors(), vec![0x40]);
+
+        let register = builder.read_reg(1);
+        let indirect = builder.finish(0, Exit::Jump(register));
+        assert!(indirect.successors().is_empty());
+    }
+
+    #[test]
+    fn binary_operations() {
+        assert_eq!(BinOp::Sub.apply(0, 1), 0xFFFF_FFFF);
+        assert_eq!(BinOp::Shl.apply(1, 33), 2);
+        assert_eq!(BinOp::Sar.apply(0x8000_0000, 31), 0xFFFF_FFFF);
+        assert_eq!(BinOp::Shr.apply(0x8000_0000, 31), 1);
+        assert_eq!(BinOp::Lt.apply(0xFFFF_FFFF, 0), 1);
+        assert_eq!(BinOp::Ltu.apply(0xFFFF_FFFF, 0), 0);
+        assert_eq!(BinOp::Ge.apply(0, 0xFFFF_FFFF), 1);
+        assert_eq!(BinOp::Geu.apply(0, 0xFFFF_FFFF), 0);
+    }
+}
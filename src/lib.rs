@@ -1,4 +1,6 @@
+pub mod backend;
 pub mod frontend;
+pub mod ir;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,79 @@
+//! Harness shared by the tests running the compliance suite on each execution engine
+#![allow(dead_code)]
+
+use emulator_rs::frontend::rv32i::cpu::{CPUStatus, CPU};
+use emulator_rs::frontend::rv32i::machine::MachineConfig;
+use emulator_rs::frontend::rv32i::recompile::Recompiled;
+
+pub const TESTS: &[&str] = &[
+    "add", "addi", "and", "andi", "auipc", "beq", "bge", "bgeu", "blt", "bltu", "bne", "fence_i",
+    "jal", "jalr", "lb", "lbu", "lh", "lhu", "lui", "lw", "or", "ori", "sb", "sh", "simple", "sll",
+    "slli", "slt", "slti", "sltiu", "sltu", "sra", "srai", "srl", "srli", "sub", "sw", "xor",
+    "xori",
+];
+
+/// What executes the guest code
+#[derive(Debug, Clone, Copy)]
+pub enum Engine {
+    Interpreter,
+    /// Lifted blocks on the IR interpreter
+    Ir,
+    /// Every block compiled the first time it is reached
+    Jit,
+    /// Code recompiled ahead of time, the interpreter runs the rest
+    Recompiled(Recompiled),
+}
+
+/// Run a compliance binary to completion on `engine`
+pub fn run(test: &str, engine: Engine) -> CPU {
+    let mut config = MachineConfig {
+        memory_size: 16384,
+        ..MachineConfig::default()
+    };
+    match engine {
+        Engine::Ir => config.ir = true,
+        Engine::Jit => {
+            config.jit = true;
+            config.jit_threshold = 0;
+        }
+        Engine::Interpreter | Engine::Recompiled(_) => {}
+    }
+    let mut cpu = CPU::with_config(&config);
+    let entry_point = cpu.load_elf(format!("tests/rv32i-compliance/{}", test));
+    if let Engine::Recompiled(code) = engine {
+        cpu.set_recompiled(Some(code));
+    }
+    cpu.boot(entry_point, "").unwrap();
+    // Like `CPU::run`, without printing every step
+    for _ in 0..1_000_000 {
+        match cpu.step() {
+            Ok(CPUStatus::Continue) => {}
+            _ => return cpu,
+        }
+    }
+    panic!("{} didn't finish", test);
+}
+
+/// Run `test` on `engine`, check that it passes and that it leaves the registers and pc exactly
+/// as the interpreter does
+pub fn run_against_interpreter(test: &str, engine: Engine) -> CPU {
+    let mut interpreted = run(test, Engine::Interpreter);
+    let mut cpu = run(test, engine);
+    assert_eq!(cpu.get_registers()[10], 0, "{} failed", test);
+    for reg in 0..32 {
+        assert_eq!(
+            cpu.get_registers()[reg],
+            interpreted.get_registers()[reg],
+            "{}: x{} differs from the interpreter",
+            test,
+            reg
+        );
+    }
+    assert_eq!(
+        cpu.get_registers().get_pc(),
+        interpreted.get_registers().get_pc(),
+        "{}: pc differs from the interpreter",
+        test
+    );
+    cpu
+}
@@ -1,11 +1,19 @@
-use emulator_rs::frontend::cpu::CPUError::DecodeError;
-use emulator_rs::frontend::DecodeError::InvalidInstruction;
+use emulator_rs::frontend::rv32i::cpu::CPU;
+use emulator_rs::frontend::rv32i::machine::MachineConfig;
+
+/// The tests fit in 16 KiB of RAM at the default base, keeping the CPU dumps short
+fn new_cpu() -> CPU {
+    CPU::with_config(&MachineConfig {
+        memory_size: 16384,
+        ..MachineConfig::default()
+    })
+}
 
 #[test]
 fn test_add() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/add".to_string());
-    cpu.get_registers().set_pc(entry_point + 0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -13,9 +21,9 @@ fn test_add() {
 
 #[test]
 fn test_addi() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/addi".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -23,9 +31,9 @@ fn test_addi() {
 
 #[test]
 fn test_and() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/and".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -33,9 +41,9 @@ fn test_and() {
 
 #[test]
 fn test_andi() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/andi".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -43,9 +51,9 @@ fn test_andi() {
 
 #[test]
 fn test_auipc() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/auipc".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -53,9 +61,9 @@ fn test_auipc() {
 
 #[test]
 fn test_beq() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/beq".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -63,9 +71,9 @@ fn test_beq() {
 
 #[test]
 fn test_bge() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/bge".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -73,9 +81,9 @@ fn test_bge() {
 
 #[test]
 fn test_bgeu() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/bgeu".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -83,9 +91,9 @@ fn test_bgeu() {
 
 #[test]
 fn test_blt() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/blt".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -93,9 +101,9 @@ fn test_blt() {
 
 #[test]
 fn test_bltu() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/bltu".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -103,9 +111,9 @@ fn test_bltu() {
 
 #[test]
 fn test_bne() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/bne".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -113,9 +121,9 @@ fn test_bne() {
 
 #[test]
 fn test_fence_i() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/fence_i".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -123,9 +131,9 @@ fn test_fence_i() {
 
 #[test]
 fn test_jal() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/jal".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -133,9 +141,9 @@ fn test_jal() {
 
 #[test]
 fn test_jalr() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/jalr".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -143,9 +151,9 @@ fn test_jalr() {
 
 #[test]
 fn test_lb() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/lb".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -153,9 +161,9 @@ fn test_lb() {
 
 #[test]
 fn test_lbu() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/lbu".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -163,9 +171,9 @@ fn test_lbu() {
 
 #[test]
 fn test_lh() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/lh".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -173,9 +181,9 @@ fn test_lh() {
 
 #[test]
 fn test_lhu() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/lhu".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -183,9 +191,9 @@ fn test_lhu() {
 
 #[test]
 fn test_lui() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/lui".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -193,9 +201,9 @@ fn test_lui() {
 
 #[test]
 fn test_lw() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/lw".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -203,9 +211,9 @@ fn test_lw() {
 
 #[test]
 fn test_or() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/or".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -213,9 +221,9 @@ fn test_or() {
 
 #[test]
 fn test_ori() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/ori".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -223,9 +231,9 @@ fn test_ori() {
 
 #[test]
 fn test_sb() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/sb".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -233,9 +241,9 @@ fn test_sb() {
 
 #[test]
 fn test_sh() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/sh".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -243,9 +251,9 @@ fn test_sh() {
 
 #[test]
 fn test_simple() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/simple".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -253,9 +261,9 @@ fn test_simple() {
 
 #[test]
 fn test_sll() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/sll".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -263,9 +271,9 @@ fn test_sll() {
 
 #[test]
 fn test_slli() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/slli".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -273,9 +281,9 @@ fn test_slli() {
 
 #[test]
 fn test_slt() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/slt".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -283,9 +291,9 @@ fn test_slt() {
 
 #[test]
 fn test_slti() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/slti".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -293,9 +301,9 @@ fn test_slti() {
 
 #[test]
 fn test_sltiu() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/sltiu".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -303,9 +311,9 @@ fn test_sltiu() {
 
 #[test]
 fn test_sltu() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/sltu".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -313,9 +321,9 @@ fn test_sltu() {
 
 #[test]
 fn test_sra() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/sra".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -323,9 +331,9 @@ fn test_sra() {
 
 #[test]
 fn test_srai() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/srai".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -333,9 +341,9 @@ fn test_srai() {
 
 #[test]
 fn test_srl() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/srl".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -343,9 +351,9 @@ fn test_srl() {
 
 #[test]
 fn test_srli() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/srli".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -353,9 +361,9 @@ fn test_srli() {
 
 #[test]
 fn test_sub() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/sub".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -363,9 +371,9 @@ fn test_sub() {
 
 #[test]
 fn test_sw() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/sw".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -373,9 +381,9 @@ fn test_sw() {
 
 #[test]
 fn test_xor() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/xor".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
@@ -383,9 +391,9 @@ fn test_xor() {
 
 #[test]
 fn test_xori() {
-    let mut cpu = emulator_rs::frontend::rv32i::cpu::CPU::new(0, 16384);
+    let mut cpu = new_cpu();
     let entry_point = cpu.load_elf("tests/rv32i-compliance/xori".to_string());
-    cpu.get_registers().set_pc(entry_point+0x100);
+    cpu.boot(entry_point, "").unwrap();
     println!("CPU : {:X?}", cpu);
     cpu.run().unwrap();
     assert_eq!(cpu.get_registers()[10], 0);
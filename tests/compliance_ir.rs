@@ -0,0 +1,12 @@
+//! The compliance suite again, with lifted blocks executed on the IR interpreter
+
+mod common;
+
+use common::{Engine, TESTS};
+
+#[test]
+fn compliance_on_ir_interpreter() {
+    for test in TESTS {
+        common::run_against_interpreter(test, Engine::Ir);
+    }
+}
@@ -0,0 +1,18 @@
+//! The compliance suite again, with every block compiled the first time it is reached
+#![cfg(target_arch = "x86_64")]
+
+mod common;
+
+use common::{Engine, TESTS};
+
+#[test]
+fn compliance_in_jit_mode() {
+    let mut compiled = 0;
+    for test in TESTS {
+        let jitted = common::run_against_interpreter(test, Engine::Jit);
+        let stats = jitted.get_jit_stats().unwrap();
+        assert!(stats.instructions > 0, "{} never ran compiled code", test);
+        compiled += stats.blocks_compiled;
+    }
+    assert!(compiled > 0);
+}
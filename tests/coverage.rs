@@ -0,0 +1,59 @@
+use emulator_rs::frontend::rv32i::cpu::CPU;
+use emulator_rs::frontend::rv32i::machine::MachineConfig;
+
+/// tests/debug/program has DWARF line information, see tests/debug/program.S
+#[test]
+fn program_coverage() {
+    let mut cpu = CPU::with_config(&MachineConfig {
+        memory_size: 16384,
+        coverage: true,
+        ..MachineConfig::default()
+    });
+    let entry_point = cpu.load_elf("tests/debug/program".to_string());
+    cpu.boot(entry_point, "").unwrap();
+    cpu.run().unwrap();
+    assert_eq!(cpu.get_registers()[10], 6);
+
+    let coverage = cpu.get_coverage().unwrap();
+    let image = cpu.get_image().unwrap();
+    assert_eq!((image.base, image.end), (0x8000_0000, 0x8000_0030));
+
+    let mut drcov = Vec::new();
+    coverage
+        .write_drcov(std::slice::from_ref(image), &mut drcov)
+        .unwrap();
+    let header = "DRCOV VERSION: 2\n\
+                  DRCOV FLAVOR: emulator-rs\n\
+                  Module Table: version 2, count 1\n\
+                  Columns: id, base, end, entry, path\n \
+                  0, 0x80000000, 0x80000030, 0x00000000, tests/debug/program\n\
+                  BB Table: 4 bbs\n";
+    assert_eq!(&drcov[..header.len()], header.as_bytes());
+    // _start up to the call, sum up to the loop, the loop, and the return up to the ecall. The
+    // boot ROM is outside of the binary.
+    assert_eq!(
+        &drcov[header.len()..],
+        &[
+            0x00, 0, 0, 0, 16, 0, 0, 0, //
+            0x10, 0, 0, 0, 4, 0, 0, 0, //
+            0x18, 0, 0, 0, 20, 0, 0, 0, //
+            0x20, 0, 0, 0, 16, 0, 0, 0,
+        ]
+    );
+
+    let mut lcov = Vec::new();
+    coverage.write_lcov(cpu.get_lines(), &mut lcov).unwrap();
+    let lcov = String::from_utf8(lcov).unwrap();
+    let file = cpu.get_lines().get_files()[0].clone();
+    assert!(file.ends_with("tests/debug/program.S"));
+    assert_eq!(
+        lcov,
+        format!(
+            "TN:\nSF:{}\n\
+             DA:15,1\nDA:16,1\nDA:17,1\nDA:18,1\nDA:19,0\n\
+             DA:25,1\nDA:26,1\nDA:28,3\nDA:29,3\nDA:30,3\nDA:31,1\n\
+             LF:11\nLH:10\nend_of_record\n",
+            file
+        )
+    );
+}
@@ -0,0 +1,57 @@
+// Guest program with DWARF debug information, used by the symbolization tests.
+//
+// main adds up sum(3) = 6 and passes it to fault, which runs the illegal instruction 0 on a 6.
+// Functions are not inlined and keep frame pointers.
+//
+// Rebuild with:
+//   rustc --edition 2021 --target riscv32i-unknown-none-elf -C opt-level=1 -g -C panic=abort \
+//     -C force-frame-pointers=yes -C force-unwind-tables=yes -C relocation-model=static \
+//     -C link-arg=-Ttext=0x80000000 -C link-arg=-e_start -o crash crash.rs
+
+#![no_std]
+#![no_main]
+
+use core::arch::global_asm;
+use core::hint::black_box;
+use core::panic::PanicInfo;
+
+global_asm!(
+    ".section .text._start",
+    ".globl _start",
+    "_start:",
+    "    li sp, 0x80004000",
+    "    call main",
+    "    ecall",
+);
+
+mod checks {
+    use core::arch::asm;
+
+    #[inline(never)]
+    pub fn sum(n: u32) -> u32 {
+        let mut total = 0;
+        for i in 0..n {
+            total += super::black_box(i) * 2;
+        }
+        total
+    }
+
+    #[inline(never)]
+    pub fn fault(total: u32) -> u32 {
+        if super::black_box(total) == 6 {
+            unsafe { asm!(".word 0") };
+        }
+        total + 1
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn main() -> u32 {
+    let total = checks::sum(black_box(3));
+    checks::fault(total)
+}
+
+#[panic_handler]
+fn panic(_: &PanicInfo) -> ! {
+    loop {}
+}
@@ -0,0 +1,142 @@
+use emulator_rs::frontend::rv32i::cpu::{CPUError, CPU};
+use emulator_rs::frontend::rv32i::machine::MachineConfig;
+use emulator_rs::frontend::rv32i::unwind::{Frame, Unwinder, Unwound};
+use emulator_rs::frontend::rv32i::DecodeError;
+
+fn boot(binary: &str, config: &MachineConfig) -> CPU {
+    let mut cpu = CPU::with_config(config);
+    let entry_point = cpu.load_elf(binary.to_string());
+    cpu.boot(entry_point, "").unwrap();
+    cpu
+}
+
+/// tests/debug/crash has DWARF debug information and runs into an illegal instruction, see
+/// tests/debug/crash.rs
+fn crash(config: &MachineConfig) -> CPU {
+    boot("tests/debug/crash", config)
+}
+
+fn pcs(frames: &[Frame]) -> Vec<u32> {
+    frames.iter().map(|frame| frame.pc).collect()
+}
+
+fn config() -> MachineConfig {
+    MachineConfig {
+        memory_size: 16384,
+        ..MachineConfig::default()
+    }
+}
+
+#[test]
+fn crash_report() {
+    let mut cpu = crash(&config());
+    let report = match cpu.run() {
+        Err(CPUError::Crash(report)) => report,
+        result => panic!("{:?}", result),
+    };
+
+    assert_eq!(
+        report.error,
+        CPUError::DecodeError(DecodeError::InvalidInstruction(0, 0))
+    );
+    assert_eq!(report.pc.addr, 0x8000_0084);
+    assert_eq!(
+        report.pc.function,
+        Some(("crash::checks::fault".to_string(), 0x24))
+    );
+    let (file, line) = report.pc.line.clone().unwrap();
+    assert!(file.ends_with("tests/debug/crash.rs"));
+    assert_eq!(line, 42);
+    let functions: Vec<(String, u32)> = report
+        .backtrace
+        .iter()
+        .map(|frame| frame.function.clone().unwrap())
+        .collect();
+    assert_eq!(
+        functions,
+        vec![
+            ("crash::checks::fault".to_string(), 0x24),
+            ("crash::main".to_string(), 0x2c),
+            ("_start".to_string(), 0x8),
+        ]
+    );
+
+    let report = report.to_string();
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(
+        lines[0],
+        format!(
+            "DecodeError(InvalidInstruction(0, 0)) at 0x80000084 <crash::checks::fault+0x24> \
+             at {}:42",
+            file
+        )
+    );
+    assert!(lines[2].starts_with("  #1 0x800000c8 <crash::main+0x2c> at "));
+    assert_eq!(lines[3], "  #2 0x80000008 <_start+0x8>");
+}
+
+#[test]
+fn backtrace_from_frame_pointers() {
+    let mut cpu = crash(&config());
+    assert!(cpu.run().is_err());
+    let frames = cpu.backtrace();
+    assert!(frames[1..]
+        .iter()
+        .all(|frame| frame.unwound == Unwound::Cfi));
+
+    // Without call frame information the frame records lead to the same callers
+    let registers = cpu.get_registers();
+    let pc = registers.get_pc();
+    let mut values = [0; 32];
+    for (index, value) in values.iter_mut().enumerate() {
+        *value = registers[index];
+    }
+    let from_frame_pointers = Unwinder::default().backtrace(cpu.get_memory(), pc, &values);
+
+    assert_eq!(pcs(&from_frame_pointers), pcs(&frames));
+    assert_eq!(from_frame_pointers[2].unwound, Unwound::FramePointer);
+}
+
+#[test]
+fn backtrace_of_leaf_function() {
+    // tests/debug/program describes sum, which has no stack frame, in .debug_frame
+    let mut cpu = boot("tests/debug/program", &config());
+    while cpu.get_registers().get_pc() != 0x8000_0020 {
+        cpu.step().unwrap();
+    }
+
+    let frames = cpu.backtrace();
+    assert_eq!(pcs(&frames), vec![0x8000_0020, 0x8000_000c]);
+    assert_eq!(frames[1].unwound, Unwound::Cfi);
+    assert_eq!(
+        cpu.symbolize(frames[1].pc).function,
+        Some(("_start".to_string(), 0xc))
+    );
+}
+
+#[test]
+fn crash_report_from_blocks() {
+    let mut cpu = crash(&MachineConfig {
+        ir: true,
+        ..config()
+    });
+    match cpu.run() {
+        Err(CPUError::Crash(report)) => {
+            assert_eq!(report.pc.addr, 0x8000_0084);
+            assert_eq!(report.backtrace.len(), 3);
+        }
+        result => panic!("{:?}", result),
+    }
+}
+
+#[test]
+fn annotated_disassembly() {
+    let cpu = crash(&config());
+    let listing = cpu.disassemble(0x8000_0060, 10);
+
+    assert!(listing.starts_with("\n80000060 <crash::checks::fault>:\n"));
+    assert!(listing.contains("80000060:  ff010113  addi sp, sp, -16\n"));
+    assert!(listing.contains("crash.rs:42\n80000084:  00000000  .word 0x00000000\n"));
+    assert!(listing.contains("bne a1, a2, 0x80000088 <crash::checks::fault+0x28>\n"));
+}
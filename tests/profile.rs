@@ -0,0 +1,29 @@
+use emulator_rs::frontend::rv32i::cpu::CPU;
+use emulator_rs::frontend::rv32i::machine::MachineConfig;
+
+#[test]
+fn compliance_binary_profile() {
+    let mut cpu = CPU::with_config(&MachineConfig {
+        memory_size: 16384,
+        call_profile: true,
+        ..MachineConfig::default()
+    });
+    let entry_point = cpu.load_elf("tests/rv32i-compliance/jalr".to_string());
+    cpu.boot(entry_point, "").unwrap();
+    cpu.run().unwrap();
+    assert_eq!(cpu.get_registers()[10], 0);
+
+    let profile = cpu.get_call_profile().unwrap();
+    let mut out = Vec::new();
+    profile
+        .write_callgrind(cpu.get_symbols(), "jalr", &mut out)
+        .unwrap();
+    let out = String::from_utf8(out).unwrap();
+
+    assert!(out.starts_with("# callgrind format\n"));
+    assert!(out.contains(&format!("summary: {} ", profile.get_instructions())));
+    // The symbols come from the binary, the boot ROM has none
+    for function in &["fn=_start", "fn=reset_vector", "fn=pass", "fn=0x"] {
+        assert!(out.contains(function), "{} missing", function);
+    }
+}
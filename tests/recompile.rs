@@ -0,0 +1,58 @@
+//! A compliance binary recompiled to Rust ahead of time. The generated code in `recompiled/` is
+//! checked in and has to match what `rv32-recompile` makes of the binary today; regenerate it with
+//! `cargo run --bin rv32-recompile tests/rv32i-compliance/jalr tests/recompiled/jalr.rs`.
+
+mod common;
+
+use common::{Engine, TESTS};
+use emulator_rs::backend::interpreter::Outcome;
+use emulator_rs::frontend::rv32i::cpu::{CPUResult, CPU};
+use emulator_rs::frontend::rv32i::recompile::{recompile, Recompiled};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[rustfmt::skip]
+#[path = "recompiled/jalr.rs"]
+mod jalr;
+
+/// Recompiled blocks run so far
+static BLOCKS_RUN: AtomicUsize = AtomicUsize::new(0);
+
+fn counted(cpu: &mut CPU, pc: u32) -> Option<CPUResult<Outcome>> {
+    let result = jalr::run(cpu, pc);
+    if result.is_some() {
+        BLOCKS_RUN.fetch_add(1, Ordering::Relaxed);
+    }
+    result
+}
+
+#[test]
+fn recompiled_code_is_up_to_date() {
+    let elf = std::fs::read("tests/rv32i-compliance/jalr").unwrap();
+    let source = recompile(&elf, "emulator_rs").unwrap();
+
+    assert!(
+        source == include_str!("recompiled/jalr.rs"),
+        "tests/recompiled/jalr.rs is out of date"
+    );
+}
+
+#[test]
+fn recompiled_binary_passes() {
+    let recompiled = Recompiled {
+        run: counted,
+        ..jalr::RECOMPILED
+    };
+    common::run_against_interpreter("jalr", Engine::Recompiled(recompiled));
+
+    assert!(BLOCKS_RUN.load(Ordering::Relaxed) > 0);
+    assert_eq!(jalr::ENTRY, 0x8000_0040);
+}
+
+#[test]
+fn compliance_binaries_recompile() {
+    for test in TESTS {
+        let elf = std::fs::read(format!("tests/rv32i-compliance/{}", test)).unwrap();
+        assert!(recompile(&elf, "emulator_rs").is_ok(), "{} failed", test);
+    }
+}
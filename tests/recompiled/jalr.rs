@@ -0,0 +1,522 @@
+// Generated by rv32-recompile, do not edit
+
+use emulator_rs::backend::interpreter::{Event, Outcome};
+use emulator_rs::backend::rust::fault_at;
+use emulator_rs::frontend::rv32i::cpu::{CPUResult, CPU};
+use emulator_rs::frontend::rv32i::lift::Hart;
+use emulator_rs::frontend::rv32i::recompile::Recompiled;
+use emulator_rs::ir::{Guest, Misaligned, Width};
+
+pub const BLOCKS: &[u32] = &[
+    0x80000040,
+    0x8000007c,
+    0x80000080,
+    0x80000140,
+    0x80000154,
+    0x80000158,
+    0x80000164,
+    0x8000016c,
+    0x80000178,
+    0x8000017c,
+    0x80000188,
+    0x80000190,
+    0x800001a0,
+    0x800001a4,
+    0x800001b0,
+    0x800001b8,
+    0x800001cc,
+    0x800001d0,
+    0x800001dc,
+    0x80000214,
+    0x80000218,
+    0x8000021c,
+    0x80000230,
+];
+
+pub fn dispatch<G: Guest>(guest: &mut G, pc: u32) -> Option<Result<Outcome, G::Error>> {
+    let result = match pc {
+        0x80000040 => block_80000040(guest),
+        0x8000007c => block_8000007c(guest),
+        0x80000080 => block_80000080(guest),
+        0x80000140 => block_80000140(guest),
+        0x80000154 => block_80000154(guest),
+        0x80000158 => block_80000158(guest),
+        0x80000164 => block_80000164(guest),
+        0x8000016c => block_8000016c(guest),
+        0x80000178 => block_80000178(guest),
+        0x8000017c => block_8000017c(guest),
+        0x80000188 => block_80000188(guest),
+        0x80000190 => block_80000190(guest),
+        0x800001a0 => block_800001a0(guest),
+        0x800001a4 => block_800001a4(guest),
+        0x800001b0 => block_800001b0(guest),
+        0x800001b8 => block_800001b8(guest),
+        0x800001cc => block_800001cc(guest),
+        0x800001d0 => block_800001d0(guest),
+        0x800001dc => block_800001dc(guest),
+        0x80000214 => block_80000214(guest),
+        0x80000218 => block_80000218(guest),
+        0x8000021c => block_8000021c(guest),
+        0x80000230 => block_80000230(guest),
+        _ => return None,
+    };
+    Some(result)
+}
+
+fn block_80000040<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    // 0x80000040
+    let v0: u32 = 0x8000008c;
+    if v0 % 4 != 0 {
+        guest.set_pc(0x80000040);
+        return Err(Misaligned(v0).into());
+    }
+    guest.set_pc(v0);
+    Ok(Outcome { retired: 1, event: Event::Continue })
+}
+
+fn block_8000007c<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    let mut stale = false;
+    // 0x8000007c
+    let v0 = guest.read_reg(3);
+    let v1: u32 = 0x539;
+    let v2 = v0 | v1;
+    guest.write_reg(3, v2);
+    // 0x80000080
+    let v3: u32 = 0x80001080;
+    guest.write_reg(30, v3);
+    // 0x80000084
+    let v4: u32 = 0xffffff80;
+    let v5 = v3.wrapping_add(v4);
+    fault_at(guest.store(v5, v2, Width::Word), guest, 0x80000084)?;
+    stale |= guest.code_modified();
+    if stale {
+        guest.set_pc(0x80000088);
+        return Ok(Outcome { retired: 3, event: Event::Continue });
+    }
+    // 0x80000088
+    let v6: u32 = 0x80000080;
+    if v6 % 4 != 0 {
+        guest.set_pc(0x80000088);
+        return Err(Misaligned(v6).into());
+    }
+    guest.set_pc(v6);
+    Ok(Outcome { retired: 4, event: Event::Continue })
+}
+
+fn block_80000080<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    let mut stale = false;
+    // 0x80000080
+    let v0: u32 = 0x80001080;
+    guest.write_reg(30, v0);
+    // 0x80000084
+    let v1: u32 = 0xffffff80;
+    let v2 = v0.wrapping_add(v1);
+    let v3 = guest.read_reg(3);
+    fault_at(guest.store(v2, v3, Width::Word), guest, 0x80000084)?;
+    stale |= guest.code_modified();
+    if stale {
+        guest.set_pc(0x80000088);
+        return Ok(Outcome { retired: 2, event: Event::Continue });
+    }
+    // 0x80000088
+    let v4: u32 = 0x80000080;
+    if v4 % 4 != 0 {
+        guest.set_pc(0x80000088);
+        return Err(Misaligned(v4).into());
+    }
+    guest.set_pc(v4);
+    Ok(Outcome { retired: 3, event: Event::Continue })
+}
+
+fn block_80000140<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    // 0x80000140
+    let v0: u32 = 0x0;
+    let v1: u32 = 0x2;
+    let v2 = v0.wrapping_add(v1);
+    guest.write_reg(3, v2);
+    // 0x80000144
+    let v3: u32 = 0x0;
+    let v4 = v0.wrapping_add(v3);
+    guest.write_reg(5, v4);
+    // 0x80000148
+    let v5: u32 = 0x80000148;
+    guest.write_reg(6, v5);
+    // 0x8000014c
+    let v6: u32 = 0x10;
+    let v7 = v5.wrapping_add(v6);
+    guest.write_reg(6, v7);
+    // 0x80000150
+    let v8: u32 = 0xfffffffe;
+    let v9 = v7 & v8;
+    let v10: u32 = 0x80000154;
+    guest.write_reg(5, v10);
+    if v9 % 4 != 0 {
+        guest.set_pc(0x80000150);
+        return Err(Misaligned(v9).into());
+    }
+    guest.set_pc(v9);
+    Ok(Outcome { retired: 5, event: Event::Continue })
+}
+
+fn block_80000154<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    // 0x80000154
+    let v0: u32 = 0x80000214;
+    if v0 % 4 != 0 {
+        guest.set_pc(0x80000154);
+        return Err(Misaligned(v0).into());
+    }
+    guest.set_pc(v0);
+    Ok(Outcome { retired: 1, event: Event::Continue })
+}
+
+fn block_80000158<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    // 0x80000158
+    let v0: u32 = 0x80000158;
+    guest.write_reg(6, v0);
+    // 0x8000015c
+    let v1: u32 = 0xfffffffc;
+    let v2 = v0.wrapping_add(v1);
+    guest.write_reg(6, v2);
+    // 0x80000160
+    let v3 = guest.read_reg(5);
+    let v4 = (v3 != v2) as u32;
+    guest.set_pc(if v4 != 0 { 0x80000214 } else { 0x80000164 });
+    Ok(Outcome { retired: 3, event: Event::Continue })
+}
+
+fn block_80000164<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    // 0x80000164
+    let v0: u32 = 0x0;
+    let v1: u32 = 0x4;
+    let v2 = v0.wrapping_add(v1);
+    guest.write_reg(3, v2);
+    // 0x80000168
+    let v3: u32 = 0x0;
+    let v4 = v0.wrapping_add(v3);
+    guest.write_reg(4, v4);
+    // 0x8000016c
+    let v5: u32 = 0x8000016c;
+    guest.write_reg(6, v5);
+    // 0x80000170
+    let v6: u32 = 0x10;
+    let v7 = v5.wrapping_add(v6);
+    guest.write_reg(6, v7);
+    // 0x80000174
+    let v8: u32 = 0xfffffffe;
+    let v9 = v7 & v8;
+    let v10: u32 = 0x80000178;
+    guest.write_reg(13, v10);
+    if v9 % 4 != 0 {
+        guest.set_pc(0x80000174);
+        return Err(Misaligned(v9).into());
+    }
+    guest.set_pc(v9);
+    Ok(Outcome { retired: 5, event: Event::Continue })
+}
+
+fn block_8000016c<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    // 0x8000016c
+    let v0: u32 = 0x8000016c;
+    guest.write_reg(6, v0);
+    // 0x80000170
+    let v1: u32 = 0x10;
+    let v2 = v0.wrapping_add(v1);
+    guest.write_reg(6, v2);
+    // 0x80000174
+    let v3: u32 = 0xfffffffe;
+    let v4 = v2 & v3;
+    let v5: u32 = 0x80000178;
+    guest.write_reg(13, v5);
+    if v4 % 4 != 0 {
+        guest.set_pc(0x80000174);
+        return Err(Misaligned(v4).into());
+    }
+    guest.set_pc(v4);
+    Ok(Outcome { retired: 3, event: Event::Continue })
+}
+
+fn block_80000178<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    // 0x80000178
+    let v0: u32 = 0x0;
+    let v1 = guest.read_reg(3);
+    let v2 = (v0 != v1) as u32;
+    guest.set_pc(if v2 != 0 { 0x80000214 } else { 0x8000017c });
+    Ok(Outcome { retired: 1, event: Event::Continue })
+}
+
+fn block_8000017c<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    // 0x8000017c
+    let v0 = guest.read_reg(4);
+    let v1: u32 = 0x1;
+    let v2 = v0.wrapping_add(v1);
+    guest.write_reg(4, v2);
+    // 0x80000180
+    let v3: u32 = 0x0;
+    let v4: u32 = 0x2;
+    let v5 = v3.wrapping_add(v4);
+    guest.write_reg(5, v5);
+    // 0x80000184
+    let v6 = (v2 != v5) as u32;
+    guest.set_pc(if v6 != 0 { 0x8000016c } else { 0x80000188 });
+    Ok(Outcome { retired: 3, event: Event::Continue })
+}
+
+fn block_80000188<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    // 0x80000188
+    let v0: u32 = 0x0;
+    let v1: u32 = 0x5;
+    let v2 = v0.wrapping_add(v1);
+    guest.write_reg(3, v2);
+    // 0x8000018c
+    let v3: u32 = 0x0;
+    let v4 = v0.wrapping_add(v3);
+    guest.write_reg(4, v4);
+    // 0x80000190
+    let v5: u32 = 0x80000190;
+    guest.write_reg(6, v5);
+    // 0x80000194
+    let v6: u32 = 0x14;
+    let v7 = v5.wrapping_add(v6);
+    guest.write_reg(6, v7);
+    // 0x80000198
+    // 0x8000019c
+    let v8: u32 = 0xfffffffe;
+    let v9 = v7 & v8;
+    let v10: u32 = 0x800001a0;
+    guest.write_reg(13, v10);
+    if v9 % 4 != 0 {
+        guest.set_pc(0x8000019c);
+        return Err(Misaligned(v9).into());
+    }
+    guest.set_pc(v9);
+    Ok(Outcome { retired: 6, event: Event::Continue })
+}
+
+fn block_80000190<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    // 0x80000190
+    let v0: u32 = 0x80000190;
+    guest.write_reg(6, v0);
+    // 0x80000194
+    let v1: u32 = 0x14;
+    let v2 = v0.wrapping_add(v1);
+    guest.write_reg(6, v2);
+    // 0x80000198
+    // 0x8000019c
+    let v3: u32 = 0xfffffffe;
+    let v4 = v2 & v3;
+    let v5: u32 = 0x800001a0;
+    guest.write_reg(13, v5);
+    if v4 % 4 != 0 {
+        guest.set_pc(0x8000019c);
+        return Err(Misaligned(v4).into());
+    }
+    guest.set_pc(v4);
+    Ok(Outcome { retired: 4, event: Event::Continue })
+}
+
+fn block_800001a0<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    // 0x800001a0
+    let v0: u32 = 0x0;
+    let v1 = guest.read_reg(3);
+    let v2 = (v0 != v1) as u32;
+    guest.set_pc(if v2 != 0 { 0x80000214 } else { 0x800001a4 });
+    Ok(Outcome { retired: 1, event: Event::Continue })
+}
+
+fn block_800001a4<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    // 0x800001a4
+    let v0 = guest.read_reg(4);
+    let v1: u32 = 0x1;
+    let v2 = v0.wrapping_add(v1);
+    guest.write_reg(4, v2);
+    // 0x800001a8
+    let v3: u32 = 0x0;
+    let v4: u32 = 0x2;
+    let v5 = v3.wrapping_add(v4);
+    guest.write_reg(5, v5);
+    // 0x800001ac
+    let v6 = (v2 != v5) as u32;
+    guest.set_pc(if v6 != 0 { 0x80000190 } else { 0x800001b0 });
+    Ok(Outcome { retired: 3, event: Event::Continue })
+}
+
+fn block_800001b0<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    // 0x800001b0
+    let v0: u32 = 0x0;
+    let v1: u32 = 0x6;
+    let v2 = v0.wrapping_add(v1);
+    guest.write_reg(3, v2);
+    // 0x800001b4
+    let v3: u32 = 0x0;
+    let v4 = v0.wrapping_add(v3);
+    guest.write_reg(4, v4);
+    // 0x800001b8
+    let v5: u32 = 0x800001b8;
+    guest.write_reg(6, v5);
+    // 0x800001bc
+    let v6: u32 = 0x18;
+    let v7 = v5.wrapping_add(v6);
+    guest.write_reg(6, v7);
+    // 0x800001c0
+    // 0x800001c4
+    // 0x800001c8
+    let v8: u32 = 0xfffffffe;
+    let v9 = v7 & v8;
+    let v10: u32 = 0x800001cc;
+    guest.write_reg(13, v10);
+    if v9 % 4 != 0 {
+        guest.set_pc(0x800001c8);
+        return Err(Misaligned(v9).into());
+    }
+    guest.set_pc(v9);
+    Ok(Outcome { retired: 7, event: Event::Continue })
+}
+
+fn block_800001b8<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    // 0x800001b8
+    let v0: u32 = 0x800001b8;
+    guest.write_reg(6, v0);
+    // 0x800001bc
+    let v1: u32 = 0x18;
+    let v2 = v0.wrapping_add(v1);
+    guest.write_reg(6, v2);
+    // 0x800001c0
+    // 0x800001c4
+    // 0x800001c8
+    let v3: u32 = 0xfffffffe;
+    let v4 = v2 & v3;
+    let v5: u32 = 0x800001cc;
+    guest.write_reg(13, v5);
+    if v4 % 4 != 0 {
+        guest.set_pc(0x800001c8);
+        return Err(Misaligned(v4).into());
+    }
+    guest.set_pc(v4);
+    Ok(Outcome { retired: 5, event: Event::Continue })
+}
+
+fn block_800001cc<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    // 0x800001cc
+    let v0: u32 = 0x0;
+    let v1 = guest.read_reg(3);
+    let v2 = (v0 != v1) as u32;
+    guest.set_pc(if v2 != 0 { 0x80000214 } else { 0x800001d0 });
+    Ok(Outcome { retired: 1, event: Event::Continue })
+}
+
+fn block_800001d0<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    // 0x800001d0
+    let v0 = guest.read_reg(4);
+    let v1: u32 = 0x1;
+    let v2 = v0.wrapping_add(v1);
+    guest.write_reg(4, v2);
+    // 0x800001d4
+    let v3: u32 = 0x0;
+    let v4: u32 = 0x2;
+    let v5 = v3.wrapping_add(v4);
+    guest.write_reg(5, v5);
+    // 0x800001d8
+    let v6 = (v2 != v5) as u32;
+    guest.set_pc(if v6 != 0 { 0x800001b8 } else { 0x800001dc });
+    Ok(Outcome { retired: 3, event: Event::Continue })
+}
+
+fn block_800001dc<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    // 0x800001dc
+    let v0: u32 = 0x0;
+    let v1: u32 = 0x1;
+    let v2 = v0.wrapping_add(v1);
+    guest.write_reg(5, v2);
+    // 0x800001e0
+    let v3: u32 = 0x800001e0;
+    guest.write_reg(6, v3);
+    // 0x800001e4
+    let v4: u32 = 0x1c;
+    let v5 = v3.wrapping_add(v4);
+    guest.write_reg(6, v5);
+    // 0x800001e8
+    let v6: u32 = 0xfffffffc;
+    let v7 = v5.wrapping_add(v6);
+    let v8: u32 = 0xfffffffe;
+    let v9 = v7 & v8;
+    if v9 % 4 != 0 {
+        guest.set_pc(0x800001e8);
+        return Err(Misaligned(v9).into());
+    }
+    guest.set_pc(v9);
+    Ok(Outcome { retired: 4, event: Event::Continue })
+}
+
+fn block_80000214<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    // 0x80000214
+    // 0x80000218
+    let v0 = guest.read_reg(3);
+    let v1: u32 = 0x0;
+    let v2 = (v0 == v1) as u32;
+    guest.set_pc(if v2 != 0 { 0x80000218 } else { 0x8000021c });
+    Ok(Outcome { retired: 2, event: Event::Continue })
+}
+
+fn block_80000218<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    // 0x80000218
+    let v0 = guest.read_reg(3);
+    let v1: u32 = 0x0;
+    let v2 = (v0 == v1) as u32;
+    guest.set_pc(if v2 != 0 { 0x80000218 } else { 0x8000021c });
+    Ok(Outcome { retired: 1, event: Event::Continue })
+}
+
+fn block_8000021c<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    // 0x8000021c
+    let v0 = guest.read_reg(3);
+    let v1: u32 = 0x1;
+    let v2 = v0 << (v1 & 0x1f);
+    guest.write_reg(3, v2);
+    // 0x80000220
+    let v3: u32 = 0x1;
+    let v4 = v2 | v3;
+    guest.write_reg(3, v4);
+    // 0x80000224
+    let v5: u32 = 0x0;
+    let v6: u32 = 0x5d;
+    let v7 = v5.wrapping_add(v6);
+    guest.write_reg(17, v7);
+    // 0x80000228
+    let v8: u32 = 0x0;
+    let v9 = v4.wrapping_add(v8);
+    guest.write_reg(10, v9);
+    // 0x8000022c
+    guest.set_pc(0x80000230);
+    Ok(Outcome { retired: 5, event: Event::Syscall })
+}
+
+fn block_80000230<G: Guest>(guest: &mut G) -> Result<Outcome, G::Error> {
+    // 0x80000230
+    // 0x80000234
+    let v0: u32 = 0x0;
+    let v1: u32 = 0x1;
+    let v2 = v0.wrapping_add(v1);
+    guest.write_reg(3, v2);
+    // 0x80000238
+    let v3: u32 = 0x5d;
+    let v4 = v0.wrapping_add(v3);
+    guest.write_reg(17, v4);
+    // 0x8000023c
+    let v5: u32 = 0x0;
+    let v6 = v0.wrapping_add(v5);
+    guest.write_reg(10, v6);
+    // 0x80000240
+    guest.set_pc(0x80000244);
+    Ok(Outcome { retired: 5, event: Event::Syscall })
+}
+
+pub const ENTRY: u32 = 0x80000040;
+
+pub const RECOMPILED: Recompiled = Recompiled {
+    blocks: BLOCKS,
+    run,
+};
+
+pub fn run(cpu: &mut CPU, pc: u32) -> Option<CPUResult<Outcome>> {
+    dispatch(&mut Hart::new(cpu), pc).map(|result| result.map_err(Into::into))
+}